@@ -8,7 +8,7 @@ extern crate test;
 macro_rules! benchmark {
     ($strct: ident, $md: ident) => {
         mod $md {
-            use csrf::{CsrfProtection, $strct};
+            use csrf::{CsrfConfig, CsrfProtection, $strct};
             use data_encoding::BASE64;
             use test::Bencher;
 
@@ -164,7 +164,65 @@ macro_rules! benchmark {
                     }
                 });
             }
+
+            #[bench]
+            fn parse_token_short(b: &mut Bencher) {
+                let protect = $strct::from_key(KEY_32);
+                bench_parse_token_short(b, &protect);
+            }
+
+            #[bench]
+            fn parse_token_short_uniform(b: &mut Bencher) {
+                let protect = $strct::from_key(KEY_32)
+                    .with_config(CsrfConfig::new().with_uniform_rejection_timing(true));
+                bench_parse_token_short(b, &protect);
+            }
+
+            /// Compare with `parse_token_bad_sig`, which it should match with uniform rejection timing.
+            fn bench_parse_token_short(b: &mut Bencher, protect: &$strct) {
+                let mut tokens = Vec::new();
+
+                for _ in 0..10 {
+                    let (token, _) = protect.generate_token_pair(Some(TOKEN), 3600)
+                        .expect("failed to generate token");
+                    let mut token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    token.pop();
+                    tokens.push(token)
+                }
+
+                b.iter(|| {
+                    for token in tokens.iter() {
+                        assert!(protect.parse_token(&token).is_err());
+                    }
+                });
+            }
+        }
+    }
+}
+
+mod hmac_cookie_only {
+    use csrf::{CsrfProtection, HmacCsrfProtection};
+    use test::Bencher;
+
+    const KEY_32: [u8; 32] = *b"01234567012345670123456701234567";
+    const TOKEN: &[u8; 64] = b"0123456701234567012345670123456701234567012345670123456701234567";
+
+    #[bench]
+    fn verify_cookie_only_success(b: &mut Bencher) {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let mut cookies = Vec::new();
+
+        for _ in 0..10 {
+            let (_, cookie) = protect.generate_token_pair(Some(TOKEN), 3600)
+                .expect("failed to generate cookie");
+            cookies.push(cookie.value().to_vec())
         }
+
+        b.iter(|| {
+            for cookie in cookies.iter() {
+                let _ = protect.verify_cookie_only(&cookie).expect("cookie not verified");
+            }
+        });
     }
 }
 
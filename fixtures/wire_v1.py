@@ -0,0 +1,95 @@
+#!/usr/bin/env python3
+"""Reference implementation of version 1 of the rust-csrf wire format.
+
+Generates the golden vectors in `wire_v1.json` from fixed inputs, using only the Python standard
+library and the `cryptography` package. The Rust tests in `src/wire.rs` check that the crate
+parses and verifies every vector, so the two implementations must agree byte for byte.
+
+    python3 fixtures/wire_v1.py > fixtures/wire_v1.json
+"""
+
+import hashlib
+import hmac
+import json
+import struct
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.poly1305 import Poly1305
+
+VERSION = b"\x01"
+
+KEY = b"01234567012345670123456701234567"
+VALUE = bytes(range(64))
+BINDING = b"session-1234"
+ISSUED_AT = 0
+EXPIRES = 4102444800
+PADDING = bytes(range(0xa0, 0xb0))
+GCM_NONCE = bytes(range(0xc0, 0xcc))
+CHACHA_NONCE = bytes(range(0xd0, 0xd8))
+
+
+def i64(value):
+    return struct.pack("<q", value)
+
+
+def hmac_token(binding):
+    body = VERSION + VALUE + i64(ISSUED_AT)
+    return body + hmac.new(KEY, body + binding, hashlib.sha256).digest()
+
+
+def hmac_cookie():
+    body = VERSION + VALUE + i64(ISSUED_AT) + i64(EXPIRES)
+    return body + hmac.new(KEY, body, hashlib.sha256).digest()
+
+
+def aes_gcm(plaintext, binding):
+    sealed = AESGCM(KEY).encrypt(GCM_NONCE, plaintext, VERSION + binding)
+    ciphertext, tag = sealed[:-16], sealed[-16:]
+    return VERSION + ciphertext + GCM_NONCE + tag
+
+
+def chacha20_poly1305(plaintext, binding):
+    # the original ChaCha20-Poly1305 construction, with a 64 bit nonce and a 64 bit block counter
+    def keystream(counter, length):
+        cipher = Cipher(algorithms.ChaCha20(KEY, struct.pack("<Q", counter) + CHACHA_NONCE), None)
+        return cipher.encryptor().update(b"\x00" * length)
+
+    aad = VERSION + binding
+    ciphertext = bytes(a ^ b for a, b in zip(plaintext, keystream(1, len(plaintext))))
+    mac = Poly1305(keystream(0, 32))
+    mac.update(aad + struct.pack("<Q", len(aad)) + ciphertext + struct.pack("<Q", len(ciphertext)))
+    return VERSION + ciphertext + CHACHA_NONCE + mac.finalize()
+
+
+def main():
+    token_plaintext = PADDING + i64(ISSUED_AT) + VALUE
+    cookie_plaintext = PADDING + i64(ISSUED_AT) + i64(EXPIRES) + VALUE
+
+    vectors = {
+        "key": KEY.hex(),
+        "value": VALUE.hex(),
+        "binding": BINDING.hex(),
+        "issued_at": ISSUED_AT,
+        "expires": EXPIRES,
+        "hmac": {
+            "token": hmac_token(b"").hex(),
+            "bound_token": hmac_token(BINDING).hex(),
+            "cookie": hmac_cookie().hex(),
+        },
+        "aes-gcm": {
+            "token": aes_gcm(token_plaintext, b"").hex(),
+            "bound_token": aes_gcm(token_plaintext, BINDING).hex(),
+            "cookie": aes_gcm(cookie_plaintext, b"").hex(),
+        },
+        "chacha20": {
+            "token": chacha20_poly1305(token_plaintext, b"").hex(),
+            "bound_token": chacha20_poly1305(token_plaintext, BINDING).hex(),
+            "cookie": chacha20_poly1305(cookie_plaintext, b"").hex(),
+        },
+    }
+    print(json.dumps(vectors, indent=2))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,123 @@
+//! Module containing the plug-in point for reading the current time
+
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// Tells the current time, in place of the system clock. See `with_clock` on the backends.
+///
+/// This is for tests that need to move time forward without sleeping, and for deployments that
+/// take their time from a trusted source. A clock that can not tell the time returns `None`,
+/// which `CsrfConfig::with_fail_closed_clock` turns into a rejection.
+pub trait Clock: Send + Sync {
+    /// Return the current time in whole seconds since the Unix epoch, or `None` if it can not be
+    /// determined.
+    fn now(&self) -> Option<i64>;
+}
+
+
+/// A `Clock` that reads `std::time::SystemTime`, and returns `None` if the system clock is set
+/// before the Unix epoch.
+///
+/// Without a clock, the backends read the time with `time::get_time`, which never fails and gives
+/// a negative time in that case, under which every cookie issued before the clock went wrong looks
+/// unexpired. This clock reports it as a failure instead, for use with
+/// `CsrfConfig::with_fail_closed_clock`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Option<i64> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        i64::try_from(since_epoch.as_secs()).ok()
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend"))]
+mod tests {
+    use clock::{Clock, SystemClock};
+    use config::CsrfConfig;
+    use core::{unix_time, CsrfError, CsrfProtection, HmacCsrfProtection, VerifyOutcome};
+    use fallback::FallbackCsrfProtection;
+    use test_util::{MockClock, KEY_32};
+
+    fn fail_closed() -> CsrfConfig {
+        CsrfConfig::new().with_fail_closed_clock(true)
+    }
+
+    #[test]
+    fn time_from_clock() {
+        let clock = MockClock::new(Some(1_000_000));
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_clock(clock.clone());
+        let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        let parsed = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert_eq!((parsed.issued_at(), parsed.expires()), (Some(1_000_000), 1_000_300));
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+
+        clock.set(Some(1_000_300));
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Err(CsrfError::Expired));
+        assert_eq!(protect.clone().verify_raw(token.value(), cookie.value()), Err(CsrfError::Expired));
+        assert_eq!(protect.derive_subkey(b"a").current_time(), Ok(1_000_300));
+    }
+
+    #[test]
+    fn refresh_and_absolute_max_from_clock() {
+        // the clock is stopped long before the system time, which would find the cookie expired
+        let clock = MockClock::new(Some(1_000_000));
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_clock(clock.clone());
+        let (token, cookie) = protect.generate_token_pair(None, 1000).expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+        clock.set(Some(1_000_100));
+        assert_eq!(protect.verify_with_refresh_policy(&token, &cookie, 1000, 0.5), VerifyOutcome::Valid);
+        assert!(protect.verify_token_pair_with_absolute_max(&token, &cookie, 3600));
+        assert!(!protect.verify_token_pair_with_absolute_max(&token, &cookie, 100));
+        assert!(cookie.describe_at(1_000_100).ends_with(", issued 100s ago, expires in 900s"));
+
+        clock.set(Some(1_000_600));
+        assert_eq!(protect.verify_with_refresh_policy(&token, &cookie, 1000, 0.5), VerifyOutcome::ValidButRefresh);
+        assert!(protect.verify_token_pair_with_absolute_max(&token, &cookie, 3600));
+    }
+
+    #[test]
+    fn failing_clock_rejects() {
+        let (token, cookie) = HmacCsrfProtection::from_key(KEY_32).generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(fail_closed()).with_clock(MockClock::new(None));
+        let parsed_token = HmacCsrfProtection::from_key(KEY_32).parse_token(token.value()).expect("token not parsed");
+        let parsed_cookie = HmacCsrfProtection::from_key(KEY_32).parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Err(CsrfError::InternalError));
+        assert!(!protect.verify_token_pair(&parsed_token, &parsed_cookie));
+        assert_eq!(protect.verify_with_refresh_policy(&parsed_token, &parsed_cookie, 300, 0.5),
+                   VerifyOutcome::Invalid(CsrfError::InternalError));
+        assert_eq!(protect.verify_token_pair_with_grace(&parsed_token, &parsed_cookie, 30),
+                   VerifyOutcome::Invalid(CsrfError::InternalError));
+        assert_eq!(protect.parse_token(token.value()).err(), Some(CsrfError::InternalError));
+        assert_eq!(protect.parse_cookie(cookie.value()).err(), Some(CsrfError::InternalError));
+        assert_eq!(protect.generate_token_pair(None, 300).err(), Some(CsrfError::InternalError));
+
+        let fallback = FallbackCsrfProtection::new(protect);
+        assert_eq!(fallback.verify_raw(token.value(), cookie.value()), Err(CsrfError::InternalError));
+    }
+
+    #[test]
+    fn failing_clock_falls_back_by_default() {
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_clock(MockClock::new(None));
+        let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+        assert!(protect.current_time().expect("no time") >= unix_time() - 1);
+    }
+
+    #[test]
+    fn system_clock() {
+        let now = SystemClock.now().expect("system clock before the epoch");
+        assert!((now - unix_time()).abs() <= 1);
+
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(fail_closed()).with_clock(SystemClock);
+        let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+    }
+}
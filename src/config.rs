@@ -0,0 +1,410 @@
+//! Module containing configuration shared by the `CsrfProtection` backends
+
+use std::cmp;
+
+use core::CsrfError;
+use encoding::TokenEncoding;
+use registry::Algorithm;
+use wire::{AEAD_PADDING_LEN, MAX_AEAD_PADDING_LEN};
+
+
+/// The nonce budget is stored as a multiple of 2^-32.
+const NONCE_BUDGET_UNIT: f64 = 4_294_967_296.0;
+
+/// Policy settings for a `CsrfProtection` backend.
+///
+/// The default configuration places no bounds on anything, matching the behavior of a backend
+/// constructed without one.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CsrfConfig {
+    min_ttl: Option<i64>,
+    max_ttl: Option<i64>,
+    rng_retries: u32,
+    aead_padding_len: Option<usize>,
+    compact: bool,
+    require_secure_transport: bool,
+    uniform_rejection_timing: bool,
+    allowed_algorithms: Option<Vec<Algorithm>>,
+    fail_closed_clock: bool,
+    // in units of 2^-32 of the birthday bound, so that the configuration stays `Eq`
+    nonce_budget: Option<u64>,
+    strict_nonce_budget: bool,
+    encoding: TokenEncoding,
+}
+
+impl CsrfConfig {
+    /// Create a configuration with no bounds.
+    pub fn new() -> Self {
+        CsrfConfig::default()
+    }
+
+    /// Reject cookie TTLs shorter than `min_ttl` seconds.
+    pub fn with_min_ttl(mut self, min_ttl: i64) -> Self {
+        self.min_ttl = Some(min_ttl);
+        self
+    }
+
+    /// Reject cookie TTLs longer than `max_ttl` seconds.
+    pub fn with_max_ttl(mut self, max_ttl: i64) -> Self {
+        self.max_ttl = Some(max_ttl);
+        self
+    }
+
+    /// Retry a failure to get random bytes from the system up to `retries` times, backing off
+    /// between attempts, before giving up with `CsrfError::EntropyUnavailable`.
+    pub fn with_rng_retries(mut self, retries: u32) -> Self {
+        self.rng_retries = retries;
+        self
+    }
+
+    /// Use `len` bytes of random padding at the start of the AEAD plaintext instead of the default
+    /// `AEAD_PADDING_LEN`. Zero gives the shortest tokens and cookies. The HMAC backend has no
+    /// padding and ignores this.
+    ///
+    /// The padding length is part of the wire format, so tokens and cookies issued with one
+    /// length are rejected by a protection configured with another. With a length of 7, the
+    /// current format is as long as the unversioned one, which is then no longer accepted.
+    ///
+    /// # Panics
+    /// This function panics if `len` is greater than `MAX_AEAD_PADDING_LEN`.
+    pub fn with_aead_padding_len(mut self, len: usize) -> Self {
+        assert!(len <= MAX_AEAD_PADDING_LEN, "AEAD padding length must be at most {}", MAX_AEAD_PADDING_LEN);
+        self.aead_padding_len = Some(len);
+        self
+    }
+
+    /// Generate AEAD tokens and cookies in the compact format, which has no padding and starts
+    /// with `COMPACT_WIRE_VERSION`, making them 16 bytes shorter than the default. The random
+    /// nonce already makes each one unique, so the padding adds little. The padding length set
+    /// with `with_aead_padding_len` is then only used for tokens and cookies in the padded format,
+    /// which are still accepted. The HMAC backend has no padding and ignores this.
+    ///
+    /// Because the compact format has its own version byte, a protection accepts it whether or
+    /// not this is set, so it can be enabled without invalidating outstanding tokens.
+    pub fn with_compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    /// Refuse to verify tokens on requests that did not arrive over HTTPS, since a cookie sent
+    /// over plain HTTP may already have been seen by an attacker. Whether a request was secure is
+    /// passed to `CsrfProtection::verify_raw_with_transport`.
+    pub fn with_require_secure_transport(mut self, require: bool) -> Self {
+        self.require_secure_transport = require;
+        self
+    }
+
+    /// Make a token or cookie that is rejected for its length or version byte take about as long
+    /// to reject as one that is rejected by the cryptographic check, by running that check over a
+    /// zeroed buffer of the expected length before returning the error.
+    ///
+    /// By default such input is rejected before any cryptography, so it is rejected measurably
+    /// faster, revealing whether a submitted value had a valid structure. The lengths and version
+    /// bytes are public, so this gives an attacker nothing secret, but some audits ask for it to be
+    /// removed. Parsing is still not constant time as a whole: decoding base64, the length of the
+    /// binding data, and the work after a successful check all vary with the input.
+    pub fn with_uniform_rejection_timing(mut self, uniform: bool) -> Self {
+        self.uniform_rejection_timing = uniform;
+        self
+    }
+
+    /// Refuse to parse tokens and cookies unless the protection's algorithm is one of
+    /// `algorithms`, rejecting them with `CsrfError::AlgorithmNotAllowed` before any parsing or
+    /// cryptography is attempted.
+    ///
+    /// The wire format does not say which algorithm issued a token, so this pins the algorithm of
+    /// the protection that parses it. Giving every protection in a `FallbackCsrfProtection` the
+    /// same configuration makes the fallback refuse to try the ones that are not allowed, even
+    /// if their keys would verify the token, so that an operator who only issues AES-GCM tokens
+    /// can be sure that a leftover or misconfigured HMAC alternate never accepts anything. A
+    /// protection over a custom `Aead` or `Mac` is only allowed if that primitive names its
+    /// algorithm. Generating tokens and cookies is not affected.
+    pub fn with_allowed_algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        self.allowed_algorithms = Some(algorithms.to_vec());
+        self
+    }
+
+    /// Reject every token and cookie with `CsrfError::InternalError`, and refuse to generate them,
+    /// whenever the protection's `Clock` can not tell the time. Expiry can not be checked without
+    /// the time, so this fails closed rather than risk accepting an expired cookie.
+    ///
+    /// By default a failure of the clock is logged and the system time is used instead, so that a
+    /// broken clock does not reject every request. A protection without a clock always uses the
+    /// system time, which never fails.
+    pub fn with_fail_closed_clock(mut self, fail_closed: bool) -> Self {
+        self.fail_closed_clock = fail_closed;
+        self
+    }
+
+    /// Log a warning once an AEAD backend has drawn `fraction` of the birthday bound of random
+    /// nonces under its key, which is 2^32 for the 8 byte nonce of ChaCha20Poly1305 and 2^48 for
+    /// the 12 byte nonce of AES-GCM. By the bound, two nonces have collided with a probability of
+    /// about 40%, and a repeated nonce breaks the cipher, so the key should be rotated well before
+    /// it. A busy site can reach the ChaCha20Poly1305 bound over a key's lifetime.
+    ///
+    /// The nonces of sealed values count along with those of tokens and cookies. They are drawn
+    /// under a key derived for `seal`, so this is conservative.
+    ///
+    /// The nonces are counted by each protection and shared with its clones, but not persisted, so
+    /// the count starts again at zero whenever the protection is constructed. The fraction is kept
+    /// to a resolution of 2^-32. The HMAC backend draws no nonces and ignores this.
+    ///
+    /// # Panics
+    /// This function panics if `fraction` is not greater than 0 and at most 1.
+    pub fn with_nonce_budget(mut self, fraction: f64) -> Self {
+        assert!(fraction > 0.0 && fraction <= 1.0, "nonce budget must be greater than 0 and at most 1");
+        self.nonce_budget = Some(cmp::max(1, (fraction * NONCE_BUDGET_UNIT) as u64));
+        self
+    }
+
+    /// Once the nonce budget set with `with_nonce_budget` is used up, refuse to generate tokens
+    /// and cookies with `CsrfError::InternalError` instead of only warning, to force a rotation to
+    /// a new key.
+    pub fn with_strict_nonce_budget(mut self, strict: bool) -> Self {
+        self.strict_nonce_budget = strict;
+        self
+    }
+
+    /// Send tokens and cookies in `encoding` rather than standard base64. This is used by
+    /// `generate_encoded_pair`, `verify_header_or_form` and the other methods that encode or decode
+    /// them as text, but not by the methods named for base64, which keep their encoding.
+    pub fn with_encoding(mut self, encoding: TokenEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Retrieve the shortest allowed cookie TTL, in seconds, if there is one.
+    pub fn min_ttl(&self) -> Option<i64> {
+        self.min_ttl
+    }
+
+    /// Retrieve the longest allowed cookie TTL, in seconds, if there is one.
+    pub fn max_ttl(&self) -> Option<i64> {
+        self.max_ttl
+    }
+
+    /// Retrieve how many times a failure to get random bytes is retried.
+    pub fn rng_retries(&self) -> u32 {
+        self.rng_retries
+    }
+
+    /// Retrieve the length of the random padding in the AEAD plaintext.
+    pub fn aead_padding_len(&self) -> usize {
+        self.aead_padding_len.unwrap_or(AEAD_PADDING_LEN)
+    }
+
+    /// Retrieve whether AEAD tokens and cookies are generated in the compact format.
+    pub fn compact(&self) -> bool {
+        self.compact
+    }
+
+    /// Retrieve whether verification requires a secure transport.
+    pub fn require_secure_transport(&self) -> bool {
+        self.require_secure_transport
+    }
+
+    /// Retrieve whether structurally invalid input is rejected as slowly as forged input.
+    pub fn uniform_rejection_timing(&self) -> bool {
+        self.uniform_rejection_timing
+    }
+
+    /// Retrieve whether a failure of the clock rejects tokens and cookies.
+    pub fn fail_closed_clock(&self) -> bool {
+        self.fail_closed_clock
+    }
+
+    /// Retrieve the fraction of the birthday bound of nonces that may be drawn under a key, if
+    /// there is a budget.
+    pub fn nonce_budget(&self) -> Option<f64> {
+        self.nonce_budget.map(|units| units as f64 / NONCE_BUDGET_UNIT)
+    }
+
+    /// Retrieve whether generation is refused once the nonce budget is used up.
+    pub fn strict_nonce_budget(&self) -> bool {
+        self.strict_nonce_budget
+    }
+
+    /// Retrieve the text encoding of tokens and cookies.
+    pub fn encoding(&self) -> TokenEncoding {
+        self.encoding
+    }
+
+    /// Return how many random nonces of `nonce_len` bytes may be drawn under a key within the
+    /// nonce budget, if there is one. This is at least 1.
+    pub(crate) fn nonce_limit(&self, nonce_len: usize) -> Option<u64> {
+        self.nonce_budget.map(|units| {
+            let bound = 1u128 << cmp::min(nonce_len * 4, 64);
+            cmp::max(1, cmp::min((bound * u128::from(units)) >> 32, u128::from(u64::MAX)) as u64)
+        })
+    }
+
+    /// Retrieve the algorithms that may be parsed, if they are restricted.
+    pub fn allowed_algorithms(&self) -> Option<&[Algorithm]> {
+        self.allowed_algorithms.as_deref()
+    }
+
+    /// Return `CsrfError::AlgorithmNotAllowed` if the allowed algorithms are restricted and
+    /// `algorithm` is not one of them. A protection with no known algorithm passes `None`.
+    pub fn check_algorithm(&self, algorithm: Option<Algorithm>) -> Result<(), CsrfError> {
+        match (&self.allowed_algorithms, algorithm) {
+            (Some(allowed), Some(algorithm)) if allowed.contains(&algorithm) => Ok(()),
+            (Some(allowed), _) => {
+                warn!("CSRF protection algorithm {:?} is not one of the allowed {:?}", algorithm, allowed);
+                Err(CsrfError::AlgorithmNotAllowed)
+            },
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Return the TTL unchanged if it is within the configured bounds, or
+    /// `CsrfError::InvalidTtl` if it is not.
+    pub fn check_ttl(&self, ttl_seconds: i64) -> Result<i64, CsrfError> {
+        if self.min_ttl.is_some_and(|min| ttl_seconds < min) ||
+           self.max_ttl.is_some_and(|max| ttl_seconds > max) {
+            warn!("CSRF cookie TTL {} is outside the bounds {:?}..={:?}",
+                  ttl_seconds,
+                  self.min_ttl,
+                  self.max_ttl);
+            return Err(CsrfError::InvalidTtl);
+        }
+        Ok(ttl_seconds)
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend"))]
+mod tests {
+    use config::CsrfConfig;
+    use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+    use registry::Algorithm;
+    use test_util::KEY_32;
+    use wire::{HMAC_COOKIE_LEN, HMAC_TOKEN_LEN};
+
+    fn config() -> CsrfConfig {
+        CsrfConfig::new().with_min_ttl(60).with_max_ttl(86400)
+    }
+
+    #[test]
+    fn unbounded_by_default() {
+        assert_eq!(CsrfConfig::new().check_ttl(-1), Ok(-1));
+        assert_eq!(CsrfConfig::new().check_ttl(i64::MAX), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn rng_retries_reach_protection() {
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(CsrfConfig::new().with_rng_retries(3));
+        assert_eq!(protect.rng_retries(), 3);
+        assert_eq!(HmacCsrfProtection::from_key(KEY_32).rng_retries(), 0);
+    }
+
+    #[test]
+    fn aead_padding_default() {
+        assert_eq!(CsrfConfig::new().aead_padding_len(), 16);
+        assert_eq!(CsrfConfig::new().with_aead_padding_len(0).aead_padding_len(), 0);
+    }
+
+    #[test]
+    fn secure_transport() {
+        let protect = HmacCsrfProtection::from_key(KEY_32)
+            .with_config(CsrfConfig::new().with_require_secure_transport(true));
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert!(protect.require_secure_transport());
+        assert_eq!(protect.verify_raw_with_transport(token.value(), cookie.value(), true), Ok(()));
+        assert_eq!(protect.verify_raw_with_transport(token.value(), cookie.value(), false),
+                   Err(CsrfError::InsecureTransport));
+    }
+
+    #[test]
+    fn insecure_transport_allowed_by_default() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert!(!protect.require_secure_transport());
+        assert_eq!(protect.verify_raw_with_transport(token.value(), cookie.value(), false), Ok(()));
+    }
+
+    #[test]
+    fn uniform_rejection_timing() {
+        for &uniform in &[false, true] {
+            let protect = HmacCsrfProtection::from_key(KEY_32)
+                .with_config(CsrfConfig::new().with_uniform_rejection_timing(uniform));
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            let mut forged = token.value().to_vec();
+            forged[HMAC_TOKEN_LEN - 1] ^= 0x01;
+            let short = &token.value()[..HMAC_TOKEN_LEN - 1];
+
+            assert_eq!(protect.parse_token(&forged).err(), Some(CsrfError::ValidationFailure));
+            assert_eq!(protect.parse_token(short).err(), Some(CsrfError::MalformedLength));
+            assert!(protect.parse_token(token.value()).is_ok());
+
+            let cookie = protect.generate_cookie(&[7; 64], 300).expect("couldn't generate cookie");
+            let mut forged = cookie.value().to_vec();
+            forged[HMAC_COOKIE_LEN - 1] ^= 0x01;
+            assert_eq!(protect.verify_cookie_only(&forged), Err(CsrfError::ValidationFailure));
+            assert_eq!(protect.verify_cookie_only(&forged[1..]), Err(CsrfError::MalformedLength));
+            assert!(protect.verify_cookie_only(cookie.value()).is_ok());
+        }
+
+        // the timings themselves are compared by the parse_token_short benches
+        assert!(CsrfConfig::new().with_uniform_rejection_timing(true).uniform_rejection_timing());
+        assert!(!CsrfConfig::new().uniform_rejection_timing());
+    }
+
+    #[test]
+    fn allowed_algorithms() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(CsrfConfig::new().allowed_algorithms(), None);
+
+        let pinned = protect.clone().with_config(CsrfConfig::new().with_allowed_algorithms(&[Algorithm::AesGcm]));
+        assert_eq!(pinned.parse_token(token.value()).err(), Some(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(pinned.parse_cookie(cookie.value()).err(), Some(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(pinned.parse_bound_token_streaming(token.value(), |_| ()).err(),
+                   Some(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(pinned.verify_raw(token.value(), cookie.value()), Err(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(pinned.verify_cookie_only(cookie.value()), Err(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(pinned.verify_cookie_only(b"not a cookie"), Err(CsrfError::AlgorithmNotAllowed));
+
+        let allowed = protect.with_config(CsrfConfig::new()
+            .with_allowed_algorithms(&[Algorithm::AesGcm, Algorithm::HmacSha256]));
+        assert_eq!(allowed.verify_raw(token.value(), cookie.value()), Ok(()));
+        assert!(allowed.verify_cookie_only(cookie.value()).is_ok());
+        assert_eq!(CsrfConfig::new().with_allowed_algorithms(&[]).check_algorithm(Some(Algorithm::AesGcm)),
+                   Err(CsrfError::AlgorithmNotAllowed));
+        assert_eq!(CsrfConfig::new().with_allowed_algorithms(&[Algorithm::AesGcm]).check_algorithm(None),
+                   Err(CsrfError::AlgorithmNotAllowed));
+    }
+
+    #[test]
+    fn ttl_within_range() {
+        assert_eq!(config().check_ttl(60), Ok(60));
+        assert_eq!(config().check_ttl(86400), Ok(86400));
+
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(config());
+        assert!(protect.generate_token_pair(None, 300).is_ok());
+    }
+
+    #[test]
+    fn ttl_below_floor() {
+        assert_eq!(config().check_ttl(59), Err(CsrfError::InvalidTtl));
+
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(config());
+        assert_eq!(protect.generate_token_pair(None, 0).err(), Some(CsrfError::InvalidTtl));
+    }
+
+    #[test]
+    fn ttl_above_ceiling() {
+        assert_eq!(config().check_ttl(86401), Err(CsrfError::InvalidTtl));
+
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_config(config());
+        assert_eq!(protect.generate_cookie(&[7; 64], 86400 * 365).err(), Some(CsrfError::InvalidTtl));
+
+        let cookie = protect.generate_cookie(&[7; 64], 300).expect("couldn't generate cookie");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert_eq!(protect.refresh_cookie(&cookie, 86400 * 365).err(), Some(CsrfError::InvalidTtl));
+    }
+}
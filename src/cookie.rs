@@ -0,0 +1,310 @@
+//! Module containing a builder for `Set-Cookie` header values
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+
+use core::{CsrfCookie, CsrfError, CSRF_COOKIE_NAME};
+use encoding::TokenEncoding;
+
+/// The cookie name prefix that browsers only accept on cookies that are `Secure`, have `Path=/`,
+/// and have no `Domain`, so that they can not be set or overwritten from a sibling subdomain.
+pub const HOST_COOKIE_PREFIX: &str = "__Host-";
+
+
+/// The values of the `SameSite` cookie attribute.
+///
+/// With the `serde` feature, it deserializes from the attribute value, such as `"Strict"`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum SameSite {
+    /// The cookie is only sent with requests that originate from the same site.
+    Strict,
+    /// The cookie is also sent with top level navigations from other sites.
+    Lax,
+    /// The cookie is sent with every request, including cross site ones. Browsers only accept
+    /// this in combination with `Secure`, so the builder always adds it.
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+
+/// Builds the value of a `Set-Cookie` header that sends a `CsrfCookie` to the end user.
+///
+/// By default the cookie is named `CSRF_COOKIE_NAME`, is `HttpOnly`, and has no other
+/// attributes, and its value is base64 encoded. A protection with another
+/// `CsrfConfig::with_encoding` needs the same encoding here, set with `encoding`.
+///
+/// ```
+/// use csrf::{CookieBuilder, CsrfCookie, SameSite};
+///
+/// let cookie = CsrfCookie::new(b"cookie bytes".to_vec());
+/// let header = CookieBuilder::new(&cookie)
+///     .path("/")
+///     .same_site(SameSite::None)
+///     .partitioned(true)
+///     .build()
+///     .expect("conflicting cookie attributes");
+///
+/// assert_eq!(header, "csrf=Y29va2llIGJ5dGVz; Path=/; Secure; HttpOnly; SameSite=None; Partitioned");
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieBuilder {
+    name: String,
+    value: Vec<u8>,
+    encoding: TokenEncoding,
+    max_age: Option<i64>,
+    domain: Option<String>,
+    path: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+    host_prefix: bool,
+}
+
+impl CookieBuilder {
+    /// Create a new builder for the given cookie.
+    pub fn new(cookie: &CsrfCookie) -> Self {
+        CookieBuilder {
+            name: CSRF_COOKIE_NAME.to_string(),
+            value: cookie.value().to_vec(),
+            encoding: TokenEncoding::Base64,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: true,
+            same_site: None,
+            partitioned: false,
+            host_prefix: false,
+        }
+    }
+
+    /// Use the given cookie name instead of `CSRF_COOKIE_NAME`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Encode the cookie value in `encoding` rather than base64. This should be the `encoding` of
+    /// the protection that verifies the cookie.
+    pub fn encoding(mut self, encoding: TokenEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set the `Max-Age` attribute. This should normally be the TTL the cookie was generated with.
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Set the `Domain` attribute.
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Set the `Path` attribute.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Set whether the `Secure` attribute is present. It is always present if `SameSite=None` or
+    /// `Partitioned` is used, since browsers reject those cookies otherwise.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Set whether the `HttpOnly` attribute is present.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the `SameSite` attribute.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Set whether the `Partitioned` attribute is present, storing the cookie separately for each
+    /// top level site (CHIPS). This is needed for widgets embedded in other sites.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    /// Set whether the cookie name is given the `HOST_COOKIE_PREFIX`, so that the browser only
+    /// accepts the cookie from this exact host. `Secure` and `Path=/` are added automatically.
+    /// Use `extract_host_cookie_from_header` to find the prefixed cookie in requests.
+    pub fn host_prefix(mut self, host_prefix: bool) -> Self {
+        self.host_prefix = host_prefix;
+        self
+    }
+
+    /// Return whether the `Secure` attribute will be present, taking into account the attributes
+    /// that force it.
+    pub fn is_secure(&self) -> bool {
+        self.secure || self.partitioned || self.host_prefix || self.same_site == Some(SameSite::None)
+    }
+
+    /// Build the `Set-Cookie` header value.
+    ///
+    /// This fails with `CsrfError::InvalidCookieAttributes` if `host_prefix` is set along with a
+    /// `Domain` or a `Path` other than `/`, since the browser would reject the cookie.
+    pub fn build(&self) -> Result<String, CsrfError> {
+        if self.host_prefix {
+            if self.domain.is_some() {
+                warn!("A {} cookie can not have a Domain.", HOST_COOKIE_PREFIX);
+                return Err(CsrfError::InvalidCookieAttributes);
+            }
+            if self.path.as_ref().is_some_and(|path| path != "/") {
+                warn!("A {} cookie must have Path=/.", HOST_COOKIE_PREFIX);
+                return Err(CsrfError::InvalidCookieAttributes);
+            }
+        }
+
+        let value = self.encoding.encode(&self.value);
+        let mut header = if self.host_prefix && !self.name.starts_with(HOST_COOKIE_PREFIX) {
+            format!("{}{}={}", HOST_COOKIE_PREFIX, self.name, value)
+        } else {
+            format!("{}={}", self.name, value)
+        };
+
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(ref domain) = self.domain {
+            header.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(ref path) = self.path {
+            header.push_str(&format!("; Path={}", path));
+        } else if self.host_prefix {
+            header.push_str("; Path=/");
+        }
+        if self.is_secure() {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str("; SameSite=");
+            header.push_str(same_site.as_str());
+        }
+        if self.partitioned {
+            header.push_str("; Partitioned");
+        }
+
+        Ok(header)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cookie::{CookieBuilder, SameSite};
+    use core::{CsrfCookie, CsrfError};
+    use encoding::TokenEncoding;
+
+    fn builder() -> CookieBuilder {
+        CookieBuilder::new(&CsrfCookie::new(b"cookie bytes".to_vec()))
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(builder().build().as_deref(), Ok("csrf=Y29va2llIGJ5dGVz; HttpOnly"));
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(builder().encoding(TokenEncoding::Hex).build().as_deref(),
+                   Ok("csrf=636f6f6b6965206279746573; HttpOnly"));
+    }
+
+    #[test]
+    fn all_attributes() {
+        let header = builder()
+            .name("__Secure-csrf")
+            .max_age(300)
+            .domain("example.com")
+            .path("/")
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .build()
+            .expect("conflicting cookie attributes");
+        assert_eq!(header,
+                   "__Secure-csrf=Y29va2llIGJ5dGVz; Max-Age=300; Domain=example.com; Path=/; Secure; \
+                    HttpOnly; SameSite=Lax");
+    }
+
+    #[test]
+    fn same_site_strict() {
+        assert_eq!(builder().same_site(SameSite::Strict).build().as_deref(),
+                   Ok("csrf=Y29va2llIGJ5dGVz; HttpOnly; SameSite=Strict"));
+    }
+
+    #[test]
+    fn same_site_lax() {
+        assert_eq!(builder().same_site(SameSite::Lax).http_only(false).build().as_deref(),
+                   Ok("csrf=Y29va2llIGJ5dGVz; SameSite=Lax"));
+    }
+
+    #[test]
+    fn same_site_none_forces_secure() {
+        let builder = builder().secure(false).same_site(SameSite::None);
+        assert!(builder.is_secure());
+        assert_eq!(builder.build().as_deref(), Ok("csrf=Y29va2llIGJ5dGVz; Secure; HttpOnly; SameSite=None"));
+    }
+
+    #[test]
+    fn partitioned() {
+        assert_eq!(builder().same_site(SameSite::None).partitioned(true).build().as_deref(),
+                   Ok("csrf=Y29va2llIGJ5dGVz; Secure; HttpOnly; SameSite=None; Partitioned"));
+    }
+
+    #[test]
+    fn host_prefix() {
+        let builder = builder().host_prefix(true).same_site(SameSite::Strict);
+        assert!(builder.is_secure());
+        assert_eq!(builder.build().as_deref(),
+                   Ok("__Host-csrf=Y29va2llIGJ5dGVz; Path=/; Secure; HttpOnly; SameSite=Strict"));
+        assert_eq!(builder.path("/").build().as_deref(),
+                   Ok("__Host-csrf=Y29va2llIGJ5dGVz; Path=/; Secure; HttpOnly; SameSite=Strict"));
+    }
+
+    #[test]
+    fn host_prefix_not_repeated() {
+        assert_eq!(builder().name("__Host-csrf").host_prefix(true).build().as_deref(),
+                   Ok("__Host-csrf=Y29va2llIGJ5dGVz; Path=/; Secure; HttpOnly"));
+    }
+
+    #[test]
+    fn host_prefix_rejects_domain() {
+        assert_eq!(builder().host_prefix(true).domain("example.com").build(),
+                   Err(CsrfError::InvalidCookieAttributes));
+    }
+
+    #[test]
+    fn host_prefix_rejects_path() {
+        assert_eq!(builder().path("/app").host_prefix(true).build(), Err(CsrfError::InvalidCookieAttributes));
+    }
+
+    #[test]
+    fn partitioned_forces_secure() {
+        assert_eq!(builder().partitioned(true).build().as_deref(),
+                   Ok("csrf=Y29va2llIGJ5dGVz; Secure; HttpOnly; Partitioned"));
+    }
+}
@@ -1,22 +1,51 @@
 //! Module containing the core functionality for CSRF protection
 
 use std::error::Error;
-use std::{fmt, mem, str};
-
+use std::convert::TryFrom;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{cmp, thread};
+use std::{fmt, str};
+
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
 use crypto::aead::{AeadEncryptor, AeadDecryptor};
+#[cfg(feature = "aes-gcm-backend")]
 use crypto::aes::KeySize;
+#[cfg(feature = "aes-gcm-backend")]
 use crypto::aes_gcm::AesGcm;
+#[cfg(feature = "chacha20-backend")]
 use crypto::chacha20poly1305::ChaCha20Poly1305;
-use crypto::hmac::Hmac;
-use crypto::mac::{Mac, MacResult};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
 use crypto::scrypt::{scrypt, ScryptParams};
 use crypto::sha2::Sha256;
-use data_encoding::{BASE64, BASE64URL};
+use crypto::util::fixed_time_eq;
+use data_encoding::{self, BASE64, BASE64URL, BASE64URL_NOPAD, HEXLOWER};
+#[cfg(feature = "hmac-backend")]
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{self, SHA256};
+#[cfg(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+use ring::hmac;
 use ring::rand::{SystemRandom, SecureRandom};
 use time;
 #[cfg(feature = "iron")]
 use typemap;
 
+use clock::Clock;
+use config::CsrfConfig;
+use encoding::TokenEncoding;
+#[cfg(all(feature = "jwt", any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend")))]
+use jwt::CsrfJwt;
+use primitives::{Aead, Mac, AEAD_TAG_LEN, MAC_TAG_LEN};
+use origin::OriginPolicy;
+use registry::Algorithm;
+use replay::ReplayGuard;
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+use secure_mem::LockedKey;
+use source::TokenValueSource;
+use wire::*;
+
 
 /// The name of the cookie for the CSRF validation data and signature.
 pub const CSRF_COOKIE_NAME: &'static str = "csrf";
@@ -30,36 +59,490 @@ pub const CSRF_HEADER: &'static str = "X-CSRF-Token";
 /// The name of the query parameter for the CSRF token.
 pub const CSRF_QUERY_STRING: &'static str = "csrf-token";
 
-const SCRYPT_SALT: &'static [u8; 21] = b"rust-csrf-scrypt-salt";
+/// The longest token that any of the crate's backends generates, in bytes: the token of a
+/// `StatelessTokenProtection` over AES-GCM with `MAX_AEAD_PADDING_LEN` bytes of padding and the 8
+/// byte key id prefix of a `FallbackCsrfProtection` with `with_key_ids`. A token from
+/// `generate_token_pair` is at least `STATELESS_HEADER_LEN` bytes shorter. A `CborEnvelope` or
+/// `CsrfJwt` wrapping is longer.
+pub const MAX_TOKEN_BYTES: usize =
+    STATELESS_HEADER_LEN + 8 + AES_GCM_TOKEN_LEN + MAX_AEAD_PADDING_LEN - AEAD_PADDING_LEN;
+
+/// The longest cookie that any of the crate's backends generates, in bytes: an AES-GCM cookie with
+/// `MAX_AEAD_PADDING_LEN` bytes of padding and a key id prefix.
+pub const MAX_COOKIE_BYTES: usize = 8 + AES_GCM_COOKIE_LEN + MAX_AEAD_PADDING_LEN - AEAD_PADDING_LEN;
+
+/// The length of `MAX_TOKEN_BYTES` bytes in padded base64, the longest token that `b64_string`
+/// returns. It bounds the URL safe encodings too.
+pub const MAX_TOKEN_B64_BYTES: usize = MAX_TOKEN_BYTES.div_ceil(3) * 4;
+
+/// The length of `MAX_COOKIE_BYTES` bytes in padded base64, the longest cookie value that
+/// `b64_string` returns, not counting the cookie's name and attributes.
+pub const MAX_COOKIE_B64_BYTES: usize = MAX_COOKIE_BYTES.div_ceil(3) * 4;
+
+/// The salt used with scrypt by `CsrfProtection::from_password`.
+pub const SCRYPT_SALT: &'static [u8; 21] = b"rust-csrf-scrypt-salt";
+
+const SUBKEY_LABEL: &[u8] = b"rust-csrf-subkey:";
+
+const SECRET_KEY_LABEL: &[u8] = b"rust-csrf-secret";
+
+const ROUTE_BINDING_LABEL: &[u8] = b"rust-csrf-route:";
+
+const FINGERPRINT_LABEL: &[u8] = b"rust-csrf-fingerprint:";
+
+#[cfg(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+const KEY_ID_LABEL: &[u8] = b"rust-csrf-key-id";
+
+/// The HKDF info used to derive the key for `seal` and `open`, keeping sealed values apart from
+/// tokens and cookies.
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+const SEAL_KEY_LABEL: &[u8] = b"rust-csrf-seal";
+
+/// The HKDF info used to derive the token key with `with_split_keys`.
+const TOKEN_KEY_LABEL: &[u8] = b"rust-csrf-token-key";
+
+/// The HKDF info used to derive the cookie key with `with_split_keys`.
+const COOKIE_KEY_LABEL: &[u8] = b"rust-csrf-cookie-key";
+
+/// The longest base64 encoded token or cookie that `parse_token_b64` and `parse_cookie_b64` will
+/// decode. This is comfortably longer than any token or cookie generated by this crate.
+pub const MAX_ENCODED_LEN: usize = 256;
+
+/// The largest plaintext that `seal` accepts.
+pub const MAX_SEALED_LEN: usize = 4096;
+
+/// How far in the future an issued at timestamp may be before it is rejected as implausible.
+const MAX_ISSUED_AT_SKEW: i64 = 60;
+
+/// The token value that `CsrfProtection::self_test` generates its tokens and cookies with.
+const SELF_TEST_VALUE: [u8; 64] = [0x5a; 64];
+
+/// Reject an empty token or cookie with a distinct error, since it is almost always missing
+/// rather than forged.
+fn check_present(transport: &[u8], missing: CsrfError) -> Result<(), CsrfError> {
+    if transport.is_empty() {
+        debug!("Transport was empty.");
+        return Err(missing);
+    }
+    Ok(())
+}
+
+/// The lengths of version 1 tokens from the built-in backends with the default padding.
+const BACKEND_TOKEN_LENS: [usize; 3] = [HMAC_TOKEN_LEN, AES_GCM_TOKEN_LEN, CHACHA20_TOKEN_LEN];
+
+/// The lengths of version 1 cookies from the built-in backends with the default padding.
+const BACKEND_COOKIE_LENS: [usize; 3] = [HMAC_COOKIE_LEN, AES_GCM_COOKIE_LEN, CHACHA20_COOKIE_LEN];
+
+/// Reject a transport buffer that is not `expected_len` long, but starts with the version byte
+/// and has one of `backend_lens` other than `own_len`, the length that this backend generates with
+/// the default padding, with `CsrfError::AlgorithmMismatch`. Other length mismatches are left to
+/// the backend's own checks.
+fn check_algorithm(transport: &[u8], expected_len: usize, own_len: usize, backend_lens: &[usize]) -> Result<(), CsrfError> {
+    if transport.len() != expected_len && transport[0] == WIRE_VERSION
+        && transport.len() != own_len && backend_lens.contains(&transport.len()) {
+        warn!("Transport had length {}, expected {}. It was probably generated by another backend.",
+              transport.len(), expected_len);
+        return Err(CsrfError::AlgorithmMismatch);
+    }
+    Ok(())
+}
+
+/// Check that a versioned transport buffer has the expected length and a supported version.
+fn check_wire_version(transport: &[u8], expected_len: usize) -> Result<(), CsrfError> {
+    check_version_byte(transport, WIRE_VERSION, expected_len)
+}
+
+/// Like `check_wire_version`, but require the version byte `version`.
+fn check_version_byte(transport: &[u8], version: u8, expected_len: usize) -> Result<(), CsrfError> {
+    if transport.len() != expected_len {
+        debug!("Transport had length {}, expected {}.", transport.len(), expected_len);
+        return Err(CsrfError::MalformedLength);
+    }
+
+    if transport[0] != version {
+        debug!("Transport had unsupported version {}.", transport[0]);
+        return Err(CsrfError::MalformedLength);
+    }
+
+    Ok(())
+}
+
+/// Return the current time in whole seconds since the Unix epoch.
+///
+/// Every timestamp in the crate is an `i64` of these seconds, on 32 bit targets too, so none of
+/// them wrap in 2038. TTLs are added with `saturating_add`, so an expiry that would not fit is
+/// clamped to `i64::MAX` instead of wrapping into the past.
+pub(crate) fn unix_time() -> i64 {
+    time::get_time().sec
+}
+
+/// Return `parsed`, but if it failed before reaching the cryptographic check, which is any error
+/// other than `CsrfError::ValidationFailure`, and the configuration asks for uniform rejection
+/// timing, first run `dummy_check` so that the rejection takes about as long as a forgery's.
+fn uniform_rejection<T, F: FnOnce()>(config: &CsrfConfig, parsed: Result<T, CsrfError>, dummy_check: F)
+                                     -> Result<T, CsrfError> {
+    if config.uniform_rejection_timing() && parsed.as_ref().err().is_some_and(|err| *err != CsrfError::ValidationFailure) {
+        dummy_check();
+    }
+    parsed
+}
+
+/// Check that an authenticated issued at timestamp is not implausibly far in the future of `now`.
+pub(crate) fn check_issued_at(issued_at: i64, now: i64) -> Result<i64, CsrfError> {
+    if issued_at > now.saturating_add(MAX_ISSUED_AT_SKEW) {
+        info!("CSRF issued at time was in the future. Issued at: {}, Current time: {}", issued_at, now);
+        return Err(CsrfError::ValidationFailure);
+    }
+    Ok(issued_at)
+}
+
+/// Copy the `N` bytes at `offset` into a fixed size array. Input that is too short fails with
+/// `CsrfError::ValidationFailure` instead of panicking.
+fn read_array<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], CsrfError> {
+    bytes.get(offset..offset.saturating_add(N))
+        .and_then(|slice| <[u8; N]>::try_from(slice).ok())
+        .ok_or_else(|| {
+            debug!("Input had length {}, too short to read {} bytes at {}.", bytes.len(), N, offset);
+            CsrfError::ValidationFailure
+        })
+}
+
+/// Read the little endian `i64` at `offset`, in the same way as `read_array`.
+pub(crate) fn read_i64(bytes: &[u8], offset: usize) -> Result<i64, CsrfError> {
+    read_array(bytes, offset).map(i64::from_le_bytes)
+}
+
+/// Prefix the version byte to the binding data so that it is authenticated by the AEAD.
+fn versioned_aad(version: u8, binding: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + binding.len());
+    aad.push(version);
+    aad.extend_from_slice(binding);
+    aad
+}
+
+/// Return the version byte and padding length that the AEAD backends generate tokens and cookies
+/// with under `config`.
+fn aead_layout(config: &CsrfConfig) -> (u8, usize) {
+    if config.compact() {
+        (COMPACT_WIRE_VERSION, 0)
+    } else {
+        (WIRE_VERSION, config.aead_padding_len())
+    }
+}
+
+/// Return the version byte and padding length of a received AEAD token or cookie, which is in the
+/// compact format if it says so, whether or not `config` generates that format.
+fn received_aead_layout(transport: &[u8], config: &CsrfConfig) -> (u8, usize) {
+    if transport[0] == COMPACT_WIRE_VERSION {
+        (COMPACT_WIRE_VERSION, 0)
+    } else {
+        (WIRE_VERSION, config.aead_padding_len())
+    }
+}
+
+/// Decode a standard or URL safe base64 token or cookie, rejecting overly long input before
+/// anything is allocated for it.
+///
+/// Surrounding ASCII whitespace, such as a newline left by a proxy, is ignored, but whitespace
+/// or any other character that is not base64 within the value is still rejected.
+pub(crate) fn decode_b64(encoded: &str) -> Result<Vec<u8>, CsrfError> {
+    let encoded = encoded.trim_ascii();
+    if encoded.len() > MAX_ENCODED_LEN {
+        debug!("Encoded input had length {}, more than {}.", encoded.len(), MAX_ENCODED_LEN);
+        return Err(CsrfError::ValidationFailure);
+    }
+
+    let decoded = BASE64.decode(encoded.as_bytes())
+        .or_else(|_| BASE64URL.decode(encoded.as_bytes()))?;
+    Ok(decoded)
+}
+
+/// Decode a URL safe base64 token with or without padding, with the same length check and
+/// handling of whitespace as `decode_b64`.
+pub(crate) fn decode_b64url_nopad(encoded: &str) -> Result<Vec<u8>, CsrfError> {
+    let encoded = encoded.trim_ascii();
+    if encoded.len() > MAX_ENCODED_LEN {
+        debug!("Encoded input had length {}, more than {}.", encoded.len(), MAX_ENCODED_LEN);
+        return Err(CsrfError::ValidationFailure);
+    }
+
+    let decoded = BASE64URL_NOPAD.decode(encoded.trim_end_matches('=').as_bytes())?;
+    Ok(decoded)
+}
+
+/// Use HKDF-SHA256 to derive the key used by `seal` and `open` from an existing key.
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+fn derive_seal_key(key: &[u8; 32]) -> [u8; 32] {
+    let mut seal_key = [0; 32];
+    hkdf_expand(Sha256::new(), key, SEAL_KEY_LABEL, &mut seal_key);
+    seal_key
+}
+
+/// Use HKDF-SHA256 to derive the separate token and cookie keys of `with_split_keys` from an
+/// existing key.
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+fn derive_split_keys(key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let (mut token_key, mut cookie_key) = ([0; 32], [0; 32]);
+    hkdf_expand(Sha256::new(), key, TOKEN_KEY_LABEL, &mut token_key);
+    hkdf_expand(Sha256::new(), key, COOKIE_KEY_LABEL, &mut cookie_key);
+    (token_key, cookie_key)
+}
+
+/// Return the HMAC-SHA256 of `KEY_ID_LABEL` under the key, truncated to 8 bytes.
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+fn key_id(key: &[u8; 32]) -> [u8; 8] {
+    let signature = hmac::sign(&hmac::SigningKey::new(&SHA256, key), KEY_ID_LABEL);
+    let mut id = [0; 8];
+    id.copy_from_slice(&signature.as_ref()[..8]);
+    id
+}
+
+/// Return a short fingerprint of a secret value that can be logged without revealing it.
+fn fingerprint(value: &[u8]) -> String {
+    HEXLOWER.encode(&digest::digest(&SHA256, value).as_ref()[0..4])
+}
+
+/// Using `scrypt` with params `n=12`, `r=8`, `p=1`, derive a key from a password and salt.
+fn password_key(password: &[u8], salt: &[u8]) -> [u8; 32] {
+    let params = if cfg!(test) {
+        // scrypt is *slow*, so use these params for testing
+        ScryptParams::new(1, 8, 1)
+    } else {
+        ScryptParams::new(12, 8, 1)
+    };
+
+    let mut key = [0; 32];
+    info!("Generating key material. This may take some time.");
+    scrypt(password, salt, &params, &mut key);
+    info!("Key material generated.");
+    key
+}
+
+/// Use HKDF-SHA256 to expand an existing key into an independent key bound to `context`.
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+fn derive_subkey(key: &[u8; 32], context: &[u8]) -> [u8; 32] {
+    let mut info = Vec::with_capacity(SUBKEY_LABEL.len() + context.len());
+    info.extend_from_slice(SUBKEY_LABEL);
+    info.extend_from_slice(context);
+
+    let mut subkey = [0; 32];
+    hkdf_expand(Sha256::new(), key, &info, &mut subkey);
+    subkey
+}
+
+/// Use HKDF-SHA256 to derive a key from a high-entropy secret of any length.
+fn secret_key(secret: &[u8]) -> [u8; 32] {
+    if secret.len() < 32 {
+        warn!("CSRF secret had length {}, less than 32. Use from_password for low-entropy input.",
+              secret.len());
+    }
+
+    let mut prk = [0; 32];
+    hkdf_extract(Sha256::new(), &[], secret, &mut prk);
+    let mut key = [0; 32];
+    hkdf_expand(Sha256::new(), &prk, SECRET_KEY_LABEL, &mut key);
+    key
+}
+
+/// Decode a base64 encoded 32 byte key. Surrounding whitespace is ignored, as are PEM style
+/// `-----BEGIN ...-----` and `-----END ...-----` lines, so the contents of a key file can be
+/// passed directly.
+fn decode_key_base64(encoded: &str) -> Result<[u8; 32], CsrfError> {
+    let body: String = encoded.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    let decoded = BASE64.decode(body.as_bytes()).map_err(|_| {
+        warn!("CSRF key was not valid base64");
+        CsrfError::InvalidKey
+    })?;
+
+    key_from_slice(&decoded)
+}
+
+/// Copy a raw key into an array, checking that it is exactly 32 bytes long.
+fn key_from_slice(key: &[u8]) -> Result<[u8; 32], CsrfError> {
+    <[u8; 32]>::try_from(key).map_err(|_| {
+        warn!("CSRF key had length {}, expected 32.", key.len());
+        CsrfError::InvalidKey
+    })
+}
+
+/// Read a base64 encoded 32 byte key, in the format accepted by `decode_key_base64`.
+fn read_key_base64<R: Read>(mut reader: R) -> Result<[u8; 32], CsrfError> {
+    let mut encoded = String::new();
+    reader.read_to_string(&mut encoded).map_err(|err| {
+        warn!("Failed to read CSRF key: {}", err);
+        CsrfError::InternalError
+    })?;
+    decode_key_base64(&encoded)
+}
+
+/// The token value, issued at time, and expiry of an authenticated HMAC cookie.
+type OpenedCookie<'a> = (&'a [u8], Option<i64>, i64);
+
+/// The ciphertext, nonce, and tag sections of an AEAD transport buffer.
+type AeadSections<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+/// Split an AEAD transport buffer into its ciphertext, nonce, and tag sections, checking that the
+/// sections add up to the buffer before any of them are handed to the cipher.
+fn split_aead_transport(transport: &[u8],
+                        ciphertext_len: usize,
+                        nonce_len: usize,
+                        tag_len: usize)
+                        -> Result<AeadSections<'_>, CsrfError> {
+    if transport.len() != ciphertext_len + nonce_len + tag_len {
+        debug!("AEAD transport had length {}, expected {}.",
+               transport.len(),
+               ciphertext_len + nonce_len + tag_len);
+        return Err(CsrfError::MalformedLength);
+    }
+
+    let (ciphertext, rest) = transport.split_at(ciphertext_len);
+    let (nonce, tag) = rest.split_at(nonce_len);
+    Ok((ciphertext, nonce, tag))
+}
 
 
 /// An `enum` of all CSRF related errors.
+///
+/// This `enum` is `#[non_exhaustive]`, so new variants may be added without a breaking release.
+/// Code outside this crate that matches on a `CsrfError` must include a `_ =>` arm.
+///
+/// ```
+/// use csrf::CsrfError;
+///
+/// fn status(err: &CsrfError) -> u16 {
+///     match *err {
+///         CsrfError::InternalError => 500,
+///         CsrfError::ValidationFailure => 403,
+///         CsrfError::MalformedLength => 400,
+///         _ => 403,
+///     }
+/// }
+///
+/// assert_eq!(status(&CsrfError::ValidationFailure), 403);
+/// ```
+///
+/// Without the wildcard arm, the match does not compile even though it names every variant.
+///
+/// ```compile_fail,E0004
+/// use csrf::CsrfError;
+///
+/// fn status(err: &CsrfError) -> u16 {
+///     match *err {
+///         CsrfError::InternalError => 500,
+///         CsrfError::ValidationFailure => 403,
+///         CsrfError::MalformedLength => 400,
+///     }
+/// }
+/// ```
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum CsrfError {
     /// There was an internal error.
     InternalError,
     /// There was CSRF token validation failure.
     ValidationFailure,
+    /// The CSRF token or cookie did not have the length or layout required by the wire format.
+    MalformedLength,
+    /// The name given for a `CsrfProtection` backend was not recognized.
+    UnknownAlgorithm,
+    /// The key was not valid base64 or did not have the required length.
+    InvalidKey,
+    /// The requested cookie TTL was outside the bounds allowed by the `CsrfConfig`.
+    InvalidTtl,
+    /// The CSRF token and cookie were both valid, but did not carry the same token value.
+    TokenMismatch,
+    /// The CSRF cookie was valid, but had expired, or a token or JWS that is checked on its own
+    /// was valid, but too old.
+    Expired,
+    /// The system random number generator could not provide random bytes. This may be
+    /// temporary, so the request can be retried.
+    EntropyUnavailable,
+    /// The CSRF token was valid, but had already been used.
+    TokenReplayed,
+    /// The CSRF cookie was empty, usually because it was never set. Unlike `ValidationFailure`,
+    /// this is expected on a first visit, and can be handled by issuing a fresh cookie.
+    MissingCookie,
+    /// The CSRF token was empty, usually because the request did not include one.
+    MissingToken,
+    /// The CSRF token or cookie was not valid base64.
+    DecodeError,
+    /// The CSRF token or cookie has the layout of one generated by a different backend. This
+    /// usually means the backend was changed without a `FallbackCsrfProtection` for the old one.
+    AlgorithmMismatch,
+    /// The request did not arrive over a secure transport, and the `CsrfConfig` requires one.
+    InsecureTransport,
+    /// The `Cookie` header held more than one CSRF cookie. Browsers send every cookie that
+    /// matches the request, so this usually means another subdomain has set a cookie with the same
+    /// name to shadow the real one, and the request should be treated as suspicious.
+    AmbiguousCookie,
+    /// The request's `Origin` or `Referer` header named an origin that the `OriginPolicy` does
+    /// not allow, or was missing and the policy requires one.
+    OriginMismatch,
+    /// The protection's algorithm is not one of those allowed by the `CsrfConfig`, so the CSRF
+    /// token or cookie was rejected without being parsed.
+    AlgorithmNotAllowed,
+    /// The CSRF token and cookie carried the same token value, but were issued further apart than
+    /// allowed, or one of them has no issued at time to compare.
+    IssuanceMismatch,
+    /// A `CookieBuilder` was given attributes that the browser would reject the cookie for, such
+    /// as a `Domain` or a `Path` other than `/` on a `__Host-` cookie.
+    InvalidCookieAttributes,
 }
 
-impl Error for CsrfError {
-    fn description(&self) -> &str {
+impl CsrfError {
+    // this match is deliberately exhaustive so that every new variant must be given a message
+    fn message(&self) -> &'static str {
         match *self {
             CsrfError::InternalError => "CSRF library error",
             CsrfError::ValidationFailure => "CSRF validation failed",
+            CsrfError::MalformedLength => "CSRF token or cookie had a malformed length",
+            CsrfError::UnknownAlgorithm => "CSRF protection algorithm not recognized",
+            CsrfError::InvalidKey => "CSRF key was invalid",
+            CsrfError::InvalidTtl => "CSRF cookie TTL was out of bounds",
+            CsrfError::TokenMismatch => "CSRF token did not match cookie",
+            CsrfError::Expired => "CSRF cookie expired",
+            CsrfError::EntropyUnavailable => "CSRF random number generator unavailable",
+            CsrfError::TokenReplayed => "CSRF token was already used",
+            CsrfError::MissingCookie => "CSRF cookie was missing",
+            CsrfError::MissingToken => "CSRF token was missing",
+            CsrfError::DecodeError => "CSRF token or cookie was not valid base64",
+            CsrfError::AlgorithmMismatch => "CSRF token or cookie was generated by a different algorithm",
+            CsrfError::InsecureTransport => "CSRF verification refused over an insecure transport",
+            CsrfError::AmbiguousCookie => "CSRF cookie was sent more than once",
+            CsrfError::OriginMismatch => "CSRF request origin was not allowed",
+            CsrfError::AlgorithmNotAllowed => "CSRF token or cookie algorithm is not allowed",
+            CsrfError::IssuanceMismatch => "CSRF token and cookie were issued too far apart",
+            CsrfError::InvalidCookieAttributes => "CSRF cookie has conflicting attributes",
         }
     }
 }
 
+impl Error for CsrfError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
 impl fmt::Display for CsrfError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        f.write_str(self.message())
+    }
+}
+
+impl From<data_encoding::DecodeError> for CsrfError {
+    fn from(err: data_encoding::DecodeError) -> Self {
+        debug!("Failed to decode base64: {}", err);
+        CsrfError::DecodeError
     }
 }
 
 
 /// A signed, encrypted CSRF token that is suitable to be displayed to end users.
-#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+#[derive(Eq, PartialEq, Clone, Hash)]
 pub struct CsrfToken {
     bytes: Vec<u8>,
 }
@@ -81,15 +564,42 @@ impl CsrfToken {
         BASE64URL.encode(&self.bytes)
     }
 
+    /// Retrieve the CSRF token as a URL safe base64 encoded string without `=` padding, for
+    /// query strings such as `CSRF_QUERY_STRING` where padding would have to be percent encoded.
+    pub fn b64_url_nopad_string(&self) -> String {
+        BASE64URL_NOPAD.encode(&self.bytes)
+    }
+
+    /// Retrieve the CSRF token in the given encoding.
+    pub fn encoded(&self, encoding: TokenEncoding) -> String {
+        encoding.encode(&self.bytes)
+    }
+
     /// Get be raw value of this token.
     pub fn value(&self) -> &[u8] {
         &self.bytes
     }
 }
 
+impl AsRef<[u8]> for CsrfToken {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Only the length and a fingerprint of the token are shown, never its bytes.
+impl fmt::Debug for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CsrfToken")
+            .field("len", &self.bytes.len())
+            .field("fingerprint", &fingerprint(&self.bytes))
+            .finish()
+    }
+}
+
 
 /// A signed, encrypted CSRF cookie that is suitable to be displayed to end users.
-#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+#[derive(Eq, PartialEq, Clone, Hash)]
 pub struct CsrfCookie {
     bytes: Vec<u8>,
 }
@@ -106,23 +616,112 @@ impl CsrfCookie {
         BASE64.encode(&self.bytes)
     }
 
+    /// Get the URL safe base64 value of this cookie.
+    pub fn b64_url_string(&self) -> String {
+        BASE64URL.encode(&self.bytes)
+    }
+
+    /// Get the value of this cookie in the given encoding.
+    pub fn encoded(&self, encoding: TokenEncoding) -> String {
+        encoding.encode(&self.bytes)
+    }
+
     /// Get be raw value of this cookie.
     pub fn value(&self) -> &[u8] {
         &self.bytes
     }
 }
 
+impl AsRef<[u8]> for CsrfCookie {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Only the length and a fingerprint of the cookie are shown, never its bytes.
+impl fmt::Debug for CsrfCookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CsrfCookie")
+            .field("len", &self.bytes.len())
+            .field("fingerprint", &fingerprint(&self.bytes))
+            .finish()
+    }
+}
+
 
 /// Internal represenation of an unencrypted CSRF token. This is not suitable to send to end users.
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct UnencryptedCsrfToken {
     token: Vec<u8>,
+    issued_at: Option<i64>,
+    wire: Option<Vec<u8>>,
 }
 
 impl UnencryptedCsrfToken {
     /// Create a new unenrypted token.
     pub fn new(token: Vec<u8>) -> Self {
-        UnencryptedCsrfToken { token: token }
+        UnencryptedCsrfToken {
+            token: token,
+            issued_at: None,
+            wire: None,
+        }
+    }
+
+    /// Set the time at which this token was issued, in seconds.
+    pub fn with_issued_at(mut self, issued_at: i64) -> Self {
+        self.issued_at = Some(issued_at);
+        self
+    }
+
+    /// Retrieve the time at which this token was issued, in seconds, if it is known. Tokens in
+    /// the unversioned wire format do not carry an issued at time.
+    pub fn issued_at(&self) -> Option<i64> {
+        self.issued_at
+    }
+
+    /// Return whether this token and `cookie` were issued no more than `max_issuance_gap` seconds
+    /// apart, in either order. Refreshing a cookie with `refresh_cookie` keeps its issued at time
+    /// while a reissued token gets a new one, so the gap should allow for the refresh interval.
+    ///
+    /// A token or cookie in the unversioned wire format has no issued at time, so the pair always
+    /// fails this check, as does every pair if `max_issuance_gap` is negative.
+    pub fn within_issuance_gap(&self, cookie: &UnencryptedCsrfCookie, max_issuance_gap: i64) -> bool {
+        match (self.issued_at, cookie.issued_at) {
+            (Some(token_issued_at), Some(cookie_issued_at)) => {
+                // the difference of two issued at times can overflow an i64, but never a u64
+                let within = max_issuance_gap >= 0 &&
+                             token_issued_at.abs_diff(cookie_issued_at) <= max_issuance_gap as u64;
+                if !within {
+                    debug!("Token {} and cookie were issued too far apart. Token: {}, Cookie: {}",
+                           self.short_debug(),
+                           token_issued_at,
+                           cookie_issued_at);
+                }
+                within
+            },
+            _ => {
+                debug!("Token {} or cookie has no issued at time. Issuance gap cannot be checked.", self.short_debug());
+                false
+            },
+        }
+    }
+
+    fn with_wire(mut self, wire: &[u8]) -> Self {
+        self.wire = Some(wire.to_vec());
+        self
+    }
+
+    /// Retrieve the encrypted bytes this token was parsed from, exactly as they were received, or
+    /// `None` if it was not created by `CsrfProtection::parse_token`.
+    pub fn raw_wire(&self) -> Option<&[u8]> {
+        self.wire.as_ref().map(|wire| &wire[..])
+    }
+
+    /// Return the `CsrfToken` this token was parsed from, so that it can be forwarded unchanged
+    /// without encrypting it again under a new nonce. Returns `None` if it was not created by
+    /// `CsrfProtection::parse_token`.
+    pub fn reencode(&self) -> Option<CsrfToken> {
+        self.wire.as_ref().map(|wire| CsrfToken::new(wire.clone()))
     }
 
     /// Retrieve the token value as bytes.
@@ -132,17 +731,92 @@ impl UnencryptedCsrfToken {
     }
 
     /// Retrieve the token value as bytes.
+    ///
+    /// The value of a token returned by `CsrfProtection::parse_token` is always 64 bytes long.
     pub fn value(&self) -> &[u8] {
         &self.token
     }
+
+    /// Retrieve the 64 byte token value as a fixed size array, for use as an unpredictable
+    /// per-request nonce.
+    ///
+    /// # Panics
+    /// This function panics if the token was created with `new` from a value that is not 64 bytes
+    /// long. Tokens returned by `CsrfProtection::parse_token` never cause a panic.
+    pub fn nonce_bytes(&self) -> &[u8; 64] {
+        <&[u8; 64]>::try_from(&self.token[..]).expect("CSRF token value was not 64 bytes")
+    }
+
+    /// Return a one line summary of this token that is safe to log. The token value is only
+    /// represented by its length and a short fingerprint of its hash.
+    pub fn describe(&self) -> String {
+        self.describe_at(unix_time())
+    }
+
+    /// Like `describe`, but the token's age is given as of `now`, in seconds, instead of the
+    /// current time.
+    pub fn describe_at(&self, now: i64) -> String {
+        let mut description = format!("token, {}-byte value, fingerprint {}", self.token.len(), fingerprint(&self.token));
+        if let Some(issued_at) = self.issued_at {
+            description.push_str(&format!(", issued {}s ago", now - issued_at));
+        }
+        description
+    }
+
+    /// Return a stable identifier for this token that is safe to log or use as an index, such as
+    /// a replay cache key.
+    ///
+    /// This is the first 16 bytes of a domain separated SHA-256 hash of the token value, so it
+    /// depends only on the value: parsing the same token twice gives the same fingerprint, even
+    /// from an AEAD backend that encrypts it under a new nonce each time. The hash is not keyed,
+    /// so it is the 64 random bytes of the value that keep it from being guessed. `TokenKey` wraps
+    /// it for use as a map key.
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let mut ctx = digest::Context::new(&SHA256);
+        ctx.update(FINGERPRINT_LABEL);
+        ctx.update(&self.token);
+
+        let mut fingerprint = [0; 16];
+        fingerprint.copy_from_slice(&ctx.finish().as_ref()[0..16]);
+        fingerprint
+    }
+
+    /// Return a label for this token such as `csrf:ab12…9f (64B)`, made of the first and last hex
+    /// digits of its `fingerprint` and its length, that is short enough to eyeball and safe to
+    /// paste into a bug report. Two labels that differ mean different token values, though equal
+    /// labels do not prove that the values are the same.
+    pub fn short_debug(&self) -> String {
+        let fingerprint = HEXLOWER.encode(&self.fingerprint());
+        format!("csrf:{}…{} ({}B)", &fingerprint[..4], &fingerprint[fingerprint.len() - 2..], self.token.len())
+    }
+}
+
+impl AsRef<[u8]> for UnencryptedCsrfToken {
+    fn as_ref(&self) -> &[u8] {
+        &self.token
+    }
+}
+
+/// Only the length and a fingerprint of the token value are shown, never the value itself or
+/// the received bytes.
+impl fmt::Debug for UnencryptedCsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnencryptedCsrfToken")
+            .field("len", &self.token.len())
+            .field("fingerprint", &fingerprint(&self.token))
+            .field("issued_at", &self.issued_at)
+            .finish()
+    }
 }
 
 
 /// Internal represenation of an unencrypted CSRF cookie. This is not suitable to send to end users.
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct UnencryptedCsrfCookie {
     expires: i64,
     token: Vec<u8>,
+    issued_at: Option<i64>,
+    wire: Option<Vec<u8>>,
 }
 
 impl UnencryptedCsrfCookie {
@@ -151,776 +825,5166 @@ impl UnencryptedCsrfCookie {
         UnencryptedCsrfCookie {
             expires: expires,
             token: token,
+            issued_at: None,
+            wire: None,
         }
     }
 
+    /// Set the time at which this cookie was issued, in seconds.
+    pub fn with_issued_at(mut self, issued_at: i64) -> Self {
+        self.issued_at = Some(issued_at);
+        self
+    }
+
+    /// Retrieve the time at which this cookie was issued, in seconds, if it is known. Cookies in
+    /// the unversioned wire format do not carry an issued at time.
+    pub fn issued_at(&self) -> Option<i64> {
+        self.issued_at
+    }
+
+    fn with_wire(mut self, wire: &[u8]) -> Self {
+        self.wire = Some(wire.to_vec());
+        self
+    }
+
+    /// Retrieve the encrypted bytes this cookie was parsed from, exactly as they were received,
+    /// or `None` if it was not created by `CsrfProtection::parse_cookie`.
+    pub fn raw_wire(&self) -> Option<&[u8]> {
+        self.wire.as_ref().map(|wire| &wire[..])
+    }
+
+    /// Return the `CsrfCookie` this cookie was parsed from, so that it can be forwarded unchanged
+    /// without encrypting it again under a new nonce. Returns `None` if it was not created by
+    /// `CsrfProtection::parse_cookie`.
+    pub fn reencode(&self) -> Option<CsrfCookie> {
+        self.wire.as_ref().map(|wire| CsrfCookie::new(wire.clone()))
+    }
+
     /// Retrieve the token value as bytes.
     pub fn value(&self) -> &[u8] {
         &self.token
     }
-}
-
-/// The base trait that allows a developer to add CSRF protection to an application.
-pub trait CsrfProtection: Send + Sync {
-    /// Use a key derivation function (KDF) to generate key material.
-    ///
-    /// # Panics
-    /// This function may panic if the underlying crypto library fails catastrophically.
-    fn from_password(password: &[u8]) -> Self;
 
-    /// Given a nonce and a time to live (TTL), create a cookie to send to the end user.
-    fn generate_cookie(&self, token_value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError>;
+    /// Retrieve the time at which this cookie expires, in seconds.
+    pub fn expires(&self) -> i64 {
+        self.expires
+    }
 
-    /// Given a nonce, create a token to send to the end user.
-    fn generate_token(&self, token_value: &[u8; 64]) -> Result<CsrfToken, CsrfError>;
+    /// Given the TTL the cookie was originally issued with, return whether at least `fraction`
+    /// of its lifetime has elapsed (or it has already expired), meaning a fresh cookie should be
+    /// issued on this response.
+    ///
+    /// For example, `needs_refresh(0.5, 3600)` returns `true` once the cookie has less than 30
+    /// minutes left to live.
+    pub fn needs_refresh(&self, fraction: f64, original_ttl: i64) -> bool {
+        self.needs_refresh_at(unix_time(), fraction, original_ttl)
+    }
 
-    /// Given a decoded byte array, deserialize, decrypt, and verify the cookie.
-    fn parse_cookie(&self, cookie: &[u8]) -> Result<UnencryptedCsrfCookie, CsrfError>;
+    /// Like `needs_refresh`, but the remaining lifetime is measured from `now`, in seconds,
+    /// instead of the current time.
+    pub fn needs_refresh_at(&self, now: i64, fraction: f64, original_ttl: i64) -> bool {
+        let remaining = self.expires.saturating_sub(now);
+        if remaining <= 0 {
+            return true;
+        }
 
-    /// Given a decoded byte array, deserialize, decrypt, and verify the token.
-    fn parse_token(&self, token: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError>;
+        let elapsed = original_ttl - remaining;
+        elapsed as f64 >= fraction * original_ttl as f64
+    }
 
-    /// Provide a random number generator for other functions.
-    fn rng(&self) -> &SystemRandom;
+    /// Return a one line summary of this cookie that is safe to log. The token value is only
+    /// represented by its length and a short fingerprint of its hash.
+    pub fn describe(&self) -> String {
+        self.describe_at(unix_time())
+    }
 
-    /// Given a token pair that has been parsed, decoded, decrypted, and verified, return whether
-    /// or not the token matches the cookie and they have not expired.
-    fn verify_token_pair(&self,
-                         token: &UnencryptedCsrfToken,
-                         cookie: &UnencryptedCsrfCookie)
-                         -> bool {
-        let tokens_match = token.token == cookie.token;
-        if !tokens_match {
-            debug!("Token did not match cookie: T: {:?}, C: {:?}", BASE64.encode(&token.token), BASE64.encode(&cookie.token));
+    /// Like `describe`, but the cookie's age and expiry are given as of `now`, in seconds,
+    /// instead of the current time.
+    pub fn describe_at(&self, now: i64) -> String {
+        let mut description = format!("cookie, {}-byte value, fingerprint {}", self.token.len(), fingerprint(&self.token));
+        if let Some(issued_at) = self.issued_at {
+            description.push_str(&format!(", issued {}s ago", now - issued_at));
         }
-
-        let now = time::precise_time_s() as i64;
-        let not_expired = cookie.expires > now;
-        if !not_expired {
-            debug!("Cookie expired. Expiration: {}, Current time: {}", cookie.expires, now);
+        if self.expires > now {
+            description.push_str(&format!(", expires in {}s", self.expires - now));
+        } else {
+            description.push_str(&format!(", expired {}s ago", now - self.expires));
         }
+        description
+    }
 
-        tokens_match && not_expired
+    /// Return whether this cookie carries the same token value as `other`, ignoring the expiry
+    /// and issued at times. This is useful to check that a refreshed cookie replaces the one it
+    /// was refreshed from. The token values are compared in constant time.
+    pub fn same_token(&self, other: &UnencryptedCsrfCookie) -> bool {
+        fixed_time_eq(&self.token, &other.token)
     }
 
-    /// Given a buffer, fill it with random bytes or error if this is not possible.
-    fn random_bytes(&self, buf: &mut [u8]) -> Result<(), CsrfError> {
-        self.rng()
-            .fill(buf)
-            .map_err(|_| {
-                warn!("Failed to get random bytes");
-                CsrfError::InternalError
-            })
+    /// Return whether fewer than `absolute_max` seconds have passed since the cookie was first
+    /// issued. Refreshing a cookie with `refresh_cookie` keeps its issued at time, so this bounds
+    /// the total lifetime of a token value no matter how often it is refreshed.
+    ///
+    /// Cookies in the unversioned wire format have no issued at time and always fail this check.
+    pub fn within_absolute_max(&self, absolute_max: i64) -> bool {
+        self.within_absolute_max_at(unix_time(), absolute_max)
     }
 
-    /// Given an optional previous token and a TTL, generate a matching token and cookie pair.
-    fn generate_token_pair(&self,
-                           previous_token_value: Option<&[u8; 64]>,
-                           ttl_seconds: i64)
-                           -> Result<(CsrfToken, CsrfCookie), CsrfError> {
-        let token = match previous_token_value {
-            Some(ref previous) => *previous.clone(),
+    /// Like `within_absolute_max`, but the cookie's age is measured at `now`, in seconds,
+    /// instead of the current time.
+    pub fn within_absolute_max_at(&self, now: i64, absolute_max: i64) -> bool {
+        match self.issued_at {
+            Some(issued_at) => {
+                let within = now - issued_at < absolute_max;
+                if !within {
+                    debug!("Cookie exceeded absolute max lifetime. Issued at: {}, Current time: {}", issued_at, now);
+                }
+                within
+            },
             None => {
-                debug!("Generating new CSRF token.");
-                let mut token = [0; 64];
-                self.random_bytes(&mut token)?;
-                token
+                debug!("Cookie has no issued at time. Absolute max lifetime cannot be checked.");
+                false
             },
-        };
-
-        match (self.generate_token(&token), self.generate_cookie(&token, ttl_seconds)) {
-            (Ok(t), Ok(c)) => Ok((t, c)),
-            _ => Err(CsrfError::ValidationFailure),
         }
     }
 }
 
-
-/// Uses HMAC to provide authenticated CSRF tokens and cookies.
-pub struct HmacCsrfProtection {
-    rng: SystemRandom,
-    hmac_key: [u8; 32],
+impl AsRef<[u8]> for UnencryptedCsrfCookie {
+    fn as_ref(&self) -> &[u8] {
+        &self.token
+    }
 }
 
-impl HmacCsrfProtection {
-    /// Given an HMAC key, return an `HmacCsrfProtection` instance.
-    pub fn from_key(hmac_key: [u8; 32]) -> Self {
-        HmacCsrfProtection {
-            rng: SystemRandom::new(),
-            hmac_key: hmac_key,
-        }
+/// Only the length and a fingerprint of the token value are shown, never the value itself or
+/// the received bytes.
+impl fmt::Debug for UnencryptedCsrfCookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnencryptedCsrfCookie")
+            .field("len", &self.token.len())
+            .field("fingerprint", &fingerprint(&self.token))
+            .field("expires", &self.expires)
+            .field("issued_at", &self.issued_at)
+            .finish()
     }
+}
 
-    fn hmac(&self) -> Hmac<Sha256> {
-        Hmac::new(Sha256::new(), &self.hmac_key)
-    }
+
+/// Which token to use when a request carries one in both the `CSRF_HEADER` header and the
+/// `CSRF_FORM_FIELD` form field.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum TokenPrecedence {
+    /// Prefer the header. This is the default.
+    #[default]
+    Header,
+    /// Prefer the form field.
+    Form,
 }
 
-impl CsrfProtection for HmacCsrfProtection {
-    /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
-    /// underlying crypto functions.
+impl TokenPrecedence {
+    /// Given the header and form field values of a request, if any, return the one to verify.
     ///
-    /// # Panics
-    /// This function may panic if the underlying crypto library fails catastrophically.
-    fn from_password(password: &[u8]) -> Self {
-        let params = if cfg!(test) {
-            // scrypt is *slow*, so use these params for testing
-            ScryptParams::new(1, 8, 1)
-        } else {
-            ScryptParams::new(12, 8, 1)
+    /// An empty value counts as absent, so that a blank hidden field does not hide a header. If
+    /// neither is present, `CsrfError::MissingToken` is returned.
+    pub fn select<'a>(self, header: Option<&'a str>, form: Option<&'a str>) -> Result<&'a str, CsrfError> {
+        let header = header.filter(|value| !value.is_empty());
+        let form = form.filter(|value| !value.is_empty());
+        let selected = match self {
+            TokenPrecedence::Header => header.or(form),
+            TokenPrecedence::Form => form.or(header),
         };
-
-        let mut aead_key = [0; 32];
-        info!("Generating key material. This may take some time.");
-        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
-        info!("Key material generated.");
-
-        HmacCsrfProtection::from_key(aead_key)
-    }
-
-    fn rng(&self) -> &SystemRandom {
-        &self.rng
+        selected.ok_or(CsrfError::MissingToken)
     }
+}
 
-    fn generate_cookie(&self, token_value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
-        let expires = time::precise_time_s() as i64 + ttl_seconds;
-        let expires_bytes = unsafe { mem::transmute::<i64, [u8; 8]>(expires) };
-
-        let mut hmac = self.hmac();
-        hmac.input(token_value);
-        hmac.input(&expires_bytes);
-        let mac = hmac.result();
-        let code = mac.code();
 
-        let mut transport = [0; 104];
+/// The result of `CsrfProtection::verify_with_refresh_policy` and
+/// `CsrfProtection::verify_token_pair_with_grace`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum VerifyOutcome {
+    /// The token matches the cookie, and the cookie does not need refreshing yet.
+    Valid,
+    /// The token matches the cookie, but the cookie is far enough through its lifetime that a
+    /// refreshed cookie should be issued on this response.
+    ValidButRefresh,
+    /// The token matches the cookie, but the cookie has expired.
+    Expired,
+    /// The token does not match the cookie. The error is `CsrfError::TokenMismatch`.
+    Invalid(CsrfError),
+    /// The token matches the cookie, and the cookie has expired, but by no more than the grace
+    /// period. The request should be accepted, and a fresh pair issued on this response.
+    Grace,
+}
 
-        for i in 0..64 {
-            transport[i] = token_value[i];
-        }
-        for i in 0..8 {
-            transport[i + 64] = expires_bytes[i];
-        }
-        for i in 0..32 {
-            transport[i + 72] = code[i];
+impl VerifyOutcome {
+    /// Return `true` if the request should be accepted, whether or not the cookie is refreshed.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            VerifyOutcome::Valid | VerifyOutcome::ValidButRefresh | VerifyOutcome::Grace => true,
+            VerifyOutcome::Expired | VerifyOutcome::Invalid(_) => false,
         }
-
-        Ok(CsrfCookie::new(transport.to_vec()))
     }
+}
 
-    fn generate_token(&self, token_value: &[u8; 64]) -> Result<CsrfToken, CsrfError> {
-        let mut hmac = self.hmac();
-        hmac.input(token_value);
-        let mac = hmac.result();
-        let code = mac.code();
-
-        let mut transport = [0; 96];
+/// The effective cryptographic parameters of a `CsrfProtection`, as returned by
+/// `security_parameters`, for audit and security review tooling.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct SecurityParameters {
+    key_bits: usize,
+    nonce_len: Option<usize>,
+    tag_len: usize,
+    token_value_bits: Option<usize>,
+    encrypted: bool,
+}
 
-        for i in 0..64 {
-            transport[i] = token_value[i];
+impl SecurityParameters {
+    /// The parameters of a backend that authenticates tokens and cookies with a MAC under a key of
+    /// `key_bits` bits, with tags of `tag_len` bytes, and sends token values in the clear.
+    pub fn mac(key_bits: usize, tag_len: usize) -> Self {
+        SecurityParameters {
+            key_bits,
+            nonce_len: None,
+            tag_len,
+            token_value_bits: Some(512),
+            encrypted: false,
         }
-        for i in 0..32 {
-            transport[i + 64] = code[i];
+    }
+
+    /// The parameters of a backend that encrypts tokens and cookies with an AEAD under a key of
+    /// `key_bits` bits, with random nonces of `nonce_len` bytes and tags of `tag_len` bytes.
+    pub fn aead(key_bits: usize, nonce_len: usize, tag_len: usize) -> Self {
+        SecurityParameters {
+            nonce_len: Some(nonce_len),
+            encrypted: true,
+            ..SecurityParameters::mac(key_bits, tag_len)
         }
+    }
 
-        Ok(CsrfToken::new(transport.to_vec()))
+    /// The parameters of a protection that neither authenticates nor encrypts anything.
+    pub fn unauthenticated() -> Self {
+        SecurityParameters::mac(0, 0)
     }
 
+    /// Set the entropy of token values, or `None` if it is not known, as when they are drawn
+    /// from a `TokenValueSource`. Random token values have 512 bits.
+    pub fn with_token_value_bits(mut self, bits: Option<usize>) -> Self {
+        self.token_value_bits = bits;
+        self
+    }
+
+    /// Retrieve the size of the key, in bits.
+    pub fn key_bits(&self) -> usize {
+        self.key_bits
+    }
+
+    /// Retrieve the length of the random nonce, in bytes, or `None` if there is no nonce.
+    pub fn nonce_len(&self) -> Option<usize> {
+        self.nonce_len
+    }
+
+    /// Retrieve the length of the MAC or AEAD tag, in bytes.
+    pub fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    /// Retrieve the entropy of token values, in bits, if it is known.
+    pub fn token_value_bits(&self) -> Option<usize> {
+        self.token_value_bits
+    }
+
+    /// Retrieve whether token values are encrypted, rather than only authenticated.
+    pub fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+}
+
+/// The base trait that allows a developer to add CSRF protection to an application.
+pub trait CsrfProtection: Send + Sync {
+    /// Use a key derivation function (KDF) to generate key material.
+    ///
+    /// This blocks the calling thread while scrypt runs, which takes on the order of a second. On
+    /// an async runtime, call it from the runtime's blocking thread pool, or await
+    /// `derive_from_password` from the `async` feature instead.
+    ///
+    /// # Panics
+    /// This function may panic if the underlying crypto library fails catastrophically.
+    fn from_password(password: &[u8]) -> Self where Self: Sized {
+        Self::from_password_and_salt(password, SCRYPT_SALT)
+    }
+
+    /// Like `from_password`, but use the given salt in the key derivation instead of the
+    /// built-in `SCRYPT_SALT`. This blocks just as `from_password` does.
+    ///
+    /// # Panics
+    /// This function may panic if the underlying crypto library fails catastrophically.
+    fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self where Self: Sized;
+
+    /// Return a boxed copy of this protection with the same key and configuration. `Clone` can not
+    /// be called through a `Box<dyn CsrfProtection>`, so this is how a boxed protection is
+    /// duplicated, for example to give each worker its own instance. Implementations that are
+    /// `Clone` can return `Box::new(self.clone())`.
+    fn clone_box(&self) -> Box<dyn CsrfProtection>;
+
+    /// Return a fingerprint of the key that identifies it in logs and metrics, for example to see
+    /// which key generation an instance holds during a rotation, without revealing the key.
+    ///
+    /// The fingerprint is the first 8 bytes of the HMAC-SHA256 of a fixed label under the key, so
+    /// the key can not be recovered from it, and every backend gives the same fingerprint for the
+    /// same key.
+    fn key_id(&self) -> [u8; 8];
+
+    /// Return the sizes of the key, nonce, tag and token values that this protection uses, so that
+    /// an audit endpoint can report them.
+    fn security_parameters(&self) -> SecurityParameters;
+
+    /// Given a nonce and a time to live (TTL), create a cookie to send to the end user.
+    fn generate_cookie(&self, token_value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        self.generate_cookie_at(token_value, ttl_seconds, self.current_time()?)
+    }
+
+    /// Like `generate_cookie`, but the cookie is issued at `now`, in seconds, instead of the
+    /// current time, so it expires at `now + ttl_seconds`.
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError>;
+
+    /// Given a cookie that has been parsed, decrypted, and verified, create a replacement cookie
+    /// with the same token value that expires `ttl_seconds` from now. The original issued at
+    /// time is kept, so the absolute lifetime of the token value is measured from when it was
+    /// first issued. A cookie with no issued at time is treated as being issued now.
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError>;
+
+    /// Given a nonce and some binding data, create a token to send to the end user that can only
+    /// be parsed when the same binding data is supplied. The binding data itself is not included
+    /// in the token.
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError>;
+
+    /// Given a nonce, create a token to send to the end user.
+    fn generate_token(&self, token_value: &[u8; 64]) -> Result<CsrfToken, CsrfError> {
+        self.generate_bound_token(token_value, &[])
+    }
+
+    /// Given a decoded byte array, deserialize, decrypt, and verify the cookie.
     fn parse_cookie(&self, cookie: &[u8]) -> Result<UnencryptedCsrfCookie, CsrfError> {
-        if cookie.len() != 104 {
-            debug!("Cookie too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
+        self.parse_cookie_at(cookie, self.current_time()?)
+    }
+
+    /// Like `parse_cookie`, but the plausibility of the cookie's issued at time is checked
+    /// against `now`, in seconds, instead of the current time.
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError>;
+
+    /// Given a base64 encoded cookie, as returned by `CsrfCookie::b64_string`, decode it and
+    /// parse it with `parse_cookie`.
+    ///
+    /// Input longer than `MAX_ENCODED_LEN` is rejected with `CsrfError::ValidationFailure` before
+    /// it is decoded, so a malicious client can not force a large allocation. Leading and trailing
+    /// ASCII whitespace is ignored, since proxies sometimes add it, but input that is otherwise
+    /// not valid base64 is rejected with `CsrfError::DecodeError`.
+    fn parse_cookie_b64(&self, cookie: &str) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.parse_cookie(&decode_b64(cookie)?)
+    }
+
+    /// Given a decoded byte array and the binding data the token was generated with, deserialize,
+    /// decrypt, and verify the token.
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError>;
+
+    /// Given a decoded byte array, deserialize, decrypt, and verify the token.
+    fn parse_token(&self, token: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.parse_bound_token(token, &[])
+    }
+
+    /// Given a base64 encoded token, as returned by `CsrfToken::b64_string` or
+    /// `CsrfToken::b64_url_string`, decode it and parse it with `parse_token`.
+    ///
+    /// Input longer than `MAX_ENCODED_LEN` is rejected with `CsrfError::ValidationFailure` before
+    /// it is decoded, so a malicious client can not force a large allocation. Leading and trailing
+    /// ASCII whitespace is ignored, since proxies sometimes add it, but input that is otherwise
+    /// not valid base64 is rejected with `CsrfError::DecodeError`.
+    fn parse_token_b64(&self, token: &str) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.parse_token(&decode_b64(token)?)
+    }
+
+    /// Given a URL safe base64 encoded token, as returned by `CsrfToken::b64_url_nopad_string`,
+    /// decode it and parse it with `parse_token`. Padded input from `CsrfToken::b64_url_string`
+    /// is also accepted, and overly long input is rejected as by `parse_token_b64`.
+    fn parse_token_b64url_nopad(&self, token: &str) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.parse_token(&decode_b64url_nopad(token)?)
+    }
+
+    /// Given a token in the protection's `encoding`, decode it and parse it with `parse_token`.
+    /// Input that is overly long or not in the encoding is rejected as by `TokenEncoding::decode`.
+    fn parse_token_encoded(&self, token: &str) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.parse_token(&self.encoding().decode(token)?)
+    }
+
+    /// Given a cookie in the protection's `encoding`, decode it and parse it with `parse_cookie`.
+    /// Input that is overly long or not in the encoding is rejected as by `TokenEncoding::decode`.
+    fn parse_cookie_encoded(&self, cookie: &str) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.parse_cookie(&self.encoding().decode(cookie)?)
+    }
+
+    /// Provide a random number generator for other functions.
+    fn rng(&self) -> &SystemRandom;
+
+    /// Return the wire format version of the tokens and cookies this protection generates.
+    fn wire_version(&self) -> u8 {
+        WIRE_VERSION
+    }
+
+    /// Return `true` if the tokens and cookies this protection generates keep the token value
+    /// confidential, and `false` if they only authenticate it and it can be read by anyone who
+    /// sees them. Binding data is never transmitted by any backend.
+    ///
+    /// The default is `false`, so a protection must opt in to claiming confidentiality.
+    fn supports_encryption(&self) -> bool {
+        false
+    }
+
+    /// Return the built-in algorithm of the tokens and cookies this protection parses, if it has
+    /// one. The default is `None`, for protections that are not built on a standard algorithm.
+    fn algorithm(&self) -> Option<Algorithm> {
+        None
+    }
+
+    /// Return the length in bytes of a decoded token generated by this protection.
+    ///
+    /// This allows input that is obviously not a token to be discarded before it is parsed.
+    /// Parsing still checks the length itself, and also accepts tokens in the unversioned wire
+    /// format, which are shorter.
+    fn expected_token_len(&self) -> usize;
+
+    /// Return the length in bytes of a decoded cookie generated by this protection.
+    ///
+    /// As with `expected_token_len`, cookies in the unversioned wire format are shorter, but are
+    /// still accepted by `parse_cookie`.
+    fn expected_cookie_len(&self) -> usize;
+
+    /// Return the length of the base64 encoding of a token generated by this protection, as
+    /// returned by `CsrfToken::b64_string`.
+    fn token_encoded_len(&self) -> usize {
+        BASE64.encode_len(self.expected_token_len())
+    }
+
+    /// Return the length of the base64 encoding of a cookie generated by this protection, as
+    /// returned by `CsrfCookie::b64_string`.
+    fn cookie_encoded_len(&self) -> usize {
+        BASE64.encode_len(self.expected_cookie_len())
+    }
+
+    /// Return the nonce that a token or cookie generated by this protection was sealed under, or
+    /// `None` if this protection does not use nonces. This is used to check that the token and
+    /// cookie of a new pair were not sealed under the same nonce.
+    fn transport_nonce<'a>(&self, _transport: &'a [u8]) -> Option<&'a [u8]> {
+        None
+    }
+
+    /// Return the part of a token that parsed that identifies it to `parse_and_verify_once`. This
+    /// must be the same however the token was framed, so that a token that still parses after
+    /// something unauthenticated was added or removed, such as a key id prefix, is not taken for a
+    /// different token. The default is the whole token.
+    fn replay_transport<'a>(&self, transport: &'a [u8]) -> &'a [u8] {
+        transport
+    }
+
+    /// Given a token pair that has been parsed, decoded, decrypted, and verified, return whether
+    /// or not the token matches the cookie and they have not expired.
+    fn verify_token_pair(&self,
+                         token: &UnencryptedCsrfToken,
+                         cookie: &UnencryptedCsrfCookie)
+                         -> bool {
+        self.current_time().is_ok_and(|now| self.verify_token_pair_as_of(token, cookie, now))
+    }
+
+    /// Like `verify_token_pair`, but the cookie is checked for expiry as of `now`, in seconds,
+    /// instead of the current time. This allows logged tokens to be audited deterministically
+    /// against the time they were originally presented.
+    fn verify_token_pair_as_of(&self,
+                               token: &UnencryptedCsrfToken,
+                               cookie: &UnencryptedCsrfCookie,
+                               now: i64)
+                               -> bool {
+        check_token_pair(token, cookie, now).is_ok()
+    }
+
+    /// Given a decoded token and cookie, parse them both and verify that the token matches the
+    /// cookie and they have not expired.
+    ///
+    /// The error from the first stage that fails is returned: the token's parse error, the
+    /// cookie's parse error, `CsrfError::TokenMismatch`, or `CsrfError::Expired`.
+    fn verify_raw(&self, token_bytes: &[u8], cookie_bytes: &[u8]) -> Result<(), CsrfError> {
+        self.verify_raw_at(token_bytes, cookie_bytes, self.current_time()?)
+    }
+
+    /// Like `verify_raw`, but the cookie is parsed and checked for expiry as of `now`, in seconds,
+    /// instead of the current time.
+    fn verify_raw_at(&self, token_bytes: &[u8], cookie_bytes: &[u8], now: i64) -> Result<(), CsrfError> {
+        let token = self.parse_token(token_bytes)?;
+        let cookie = self.parse_cookie_at(cookie_bytes, now)?;
+        check_token_pair(&token, &cookie, now)
+    }
+
+    /// Like `verify_raw`, but first return `CsrfError::InsecureTransport` if this protection
+    /// requires a secure transport and `is_secure` is `false`. `is_secure` should say whether the
+    /// request arrived over HTTPS, for example as reported by `is_forwarded_https`.
+    fn verify_raw_with_transport(&self,
+                                 token_bytes: &[u8],
+                                 cookie_bytes: &[u8],
+                                 is_secure: bool)
+                                 -> Result<(), CsrfError> {
+        if self.require_secure_transport() && !is_secure {
+            warn!("CSRF verification refused on an insecure connection");
+            return Err(CsrfError::InsecureTransport);
         }
+        self.verify_raw(token_bytes, cookie_bytes)
+    }
 
-        let mut cookie_bytes = [0; 64];
-        let mut expires_bytes = [0; 8];
-        let mut code = [0; 32];
+    /// Like `verify_raw`, but first check the request's origin against `policy`, so that both
+    /// CSRF defenses are applied in one call. `origin` is the value of the request's `Origin`
+    /// header, or of its `Referer` header if it has no `Origin`, and is checked as by
+    /// `OriginPolicy::check`.
+    fn verify_raw_with_origin(&self,
+                              token_bytes: &[u8],
+                              cookie_bytes: &[u8],
+                              origin: Option<&str>,
+                              policy: &OriginPolicy)
+                              -> Result<(), CsrfError> {
+        policy.check(origin)?;
+        self.verify_raw(token_bytes, cookie_bytes)
+    }
 
-        for i in 0..64 {
-            cookie_bytes[i] = cookie[i];
+    /// Given a base64 encoded token, parse it with `parse_token_b64` and check that it was issued
+    /// less than `max_age` seconds ago, without a cookie to match it against.
+    ///
+    /// This is for setups that hand out tokens as stateless signed nonces. It is much weaker than
+    /// the paired scheme: the token alone proves only that this server issued it, not that it was
+    /// issued to the user sending it, so any token that an attacker obtains for themselves is
+    /// accepted on anyone's behalf until it is `max_age` old. Prefer the paired scheme wherever
+    /// a cookie can be set.
+    ///
+    /// The age comes from the token's authenticated issued at time, so tokens of every backend
+    /// support it. A token that is too old fails with `CsrfError::Expired`, and one in the
+    /// unversioned wire format, which has no issued at time, fails with
+    /// `CsrfError::ValidationFailure`.
+    fn verify_token_only(&self, token_b64: &str, max_age: i64) -> Result<UnencryptedCsrfToken, CsrfError> {
+        let token = self.parse_token_b64(token_b64)?;
+        let now = self.current_time()?;
+        match token.issued_at() {
+            Some(issued_at) if now.saturating_sub(issued_at) < max_age => Ok(token),
+            Some(issued_at) => {
+                info!("CSRF token {} was too old. Issued at: {}, Current time: {}, Max age: {}",
+                      token.short_debug(),
+                      issued_at,
+                      now,
+                      max_age);
+                Err(CsrfError::Expired)
+            },
+            None => {
+                info!("CSRF token {} had no issued at time, so its age can not be checked", token.short_debug());
+                Err(CsrfError::ValidationFailure)
+            },
         }
-        for i in 0..8 {
-            expires_bytes[i] = cookie[i + 64]
+    }
+
+    /// Like `verify_raw`, but for an endpoint used by both scripts that send the base64 token in
+    /// a header and HTML forms that send it in a form field. The value to verify is chosen by
+    /// `precedence`, and `CsrfError::MissingToken` is returned if neither is present. The chosen
+    /// token and the cookie are decoded as by `verify_encoded`.
+    fn verify_header_or_form(&self,
+                             header: Option<&str>,
+                             form: Option<&str>,
+                             cookie: &str,
+                             precedence: TokenPrecedence)
+                             -> Result<(), CsrfError> {
+        let token = precedence.select(header, form)?;
+        self.verify_encoded(token, cookie)
+    }
+
+    /// Like `verify_raw`, but for a token and cookie in the protection's `encoding`, as generated
+    /// by `generate_encoded_pair`.
+    fn verify_encoded(&self, token: &str, cookie: &str) -> Result<(), CsrfError> {
+        let encoding = self.encoding();
+        self.verify_raw(&encoding.decode(token)?, &encoding.decode(cookie)?)
+    }
+
+    /// Like `verify_token_pair`, but also decide whether the cookie should be refreshed, so that
+    /// middleware can accept, accept and reissue, or reject a request in one step.
+    ///
+    /// `ttl_seconds` is the TTL cookies are issued with, and a valid cookie is due for refresh
+    /// once at least `refresh_fraction` of it has elapsed, as by
+    /// `UnencryptedCsrfCookie::needs_refresh`. The TTL is taken as an argument rather than from
+    /// the cookie because `refresh_cookie` keeps the original issued at time.
+    fn verify_with_refresh_policy(&self,
+                                  token: &UnencryptedCsrfToken,
+                                  cookie: &UnencryptedCsrfCookie,
+                                  ttl_seconds: i64,
+                                  refresh_fraction: f64)
+                                  -> VerifyOutcome {
+        let now = match self.current_time() {
+            Ok(now) => now,
+            Err(err) => return VerifyOutcome::Invalid(err),
+        };
+        match check_token_pair(token, cookie, now) {
+            Ok(()) if cookie.needs_refresh_at(now, refresh_fraction, ttl_seconds) => VerifyOutcome::ValidButRefresh,
+            Ok(()) => VerifyOutcome::Valid,
+            Err(CsrfError::Expired) => VerifyOutcome::Expired,
+            Err(err) => VerifyOutcome::Invalid(err),
         }
-        for i in 0..32 {
-            code[i] = cookie[i + 72];
+    }
+
+    /// Like `verify_token_pair`, but a cookie that expired no more than `grace_secs` seconds ago
+    /// gives `VerifyOutcome::Grace` rather than `VerifyOutcome::Expired`, so that a form submitted
+    /// just after its token lapsed can be accepted and a fresh pair issued with
+    /// `generate_token_pair`. A `grace_secs` of zero or less gives no grace.
+    ///
+    /// The grace period extends the cookie's TTL for every request that presents it, so keep it
+    /// to seconds or minutes.
+    fn verify_token_pair_with_grace(&self,
+                                    token: &UnencryptedCsrfToken,
+                                    cookie: &UnencryptedCsrfCookie,
+                                    grace_secs: i64)
+                                    -> VerifyOutcome {
+        let now = match self.current_time() {
+            Ok(now) => now,
+            Err(err) => return VerifyOutcome::Invalid(err),
+        };
+        match check_token_pair(token, cookie, now) {
+            Ok(()) => VerifyOutcome::Valid,
+            Err(CsrfError::Expired) if cookie.expires > now.saturating_sub(grace_secs.max(0)) => {
+                info!("CSRF cookie accepted within its grace period. Expiration: {}, Current time: {}",
+                      cookie.expires,
+                      now);
+                VerifyOutcome::Grace
+            },
+            Err(CsrfError::Expired) => VerifyOutcome::Expired,
+            Err(err) => VerifyOutcome::Invalid(err),
         }
+    }
 
-        let mac = MacResult::new(&code);
-        let mut hmac = self.hmac();
-        hmac.input(&cookie_bytes);
-        hmac.input(&expires_bytes);
-        let result = hmac.result();
+    /// Given the base64 token and cookie from a request, verify them as by `verify_raw`, and if
+    /// they are valid, return a fresh pair to set on the response.
+    ///
+    /// The new pair keeps the token value: the token is generated again from it, and the cookie
+    /// is refreshed with `refresh_cookie`, so it expires `ttl_seconds` from now but keeps its
+    /// original issued at time. Pages rendered before the reissue stay valid until the old
+    /// cookie is replaced.
+    fn verify_and_reissue(&self, token_b64: &str, cookie_b64: &str, ttl_seconds: i64) -> Result<CsrfPair, CsrfError> {
+        let now = self.current_time()?;
+        let token = self.parse_token_b64(token_b64)?;
+        let cookie = self.parse_cookie_at(&decode_b64(cookie_b64)?, now)?;
+        check_token_pair(&token, &cookie, now)?;
+
+        check_pair_nonces(self, self.generate_token(token.nonce_bytes())?, self.refresh_cookie(&cookie, ttl_seconds)?)
+    }
 
-        if result != mac {
-            info!("CSRF cookie had bad MAC");
-            return Err(CsrfError::ValidationFailure);
+    /// Generate a pair with a new token value that expires `ttl_seconds` from now, to replace the
+    /// session's current pair whenever its privilege level changes, such as on login, logout, or
+    /// entering a re-authenticated mode. A token that leaked before the change, for instance one
+    /// planted by an attacker who fixed the session's cookie in advance, then no longer verifies.
+    ///
+    /// Unlike `verify_and_reissue`, nothing carries over from the old pair, so forms rendered
+    /// before the change stop working and the page must render the new token.
+    fn regenerate_pair(&self, ttl_seconds: i64) -> Result<CsrfPair, CsrfError> {
+        debug!("Regenerating CSRF token pair.");
+        self.generate_token_pair(None, ttl_seconds)
+    }
+
+    /// Like `verify_token_pair`, but also require that the cookie was first issued less than
+    /// `absolute_max` seconds ago, regardless of how many times it has been refreshed.
+    fn verify_token_pair_with_absolute_max(&self,
+                                           token: &UnencryptedCsrfToken,
+                                           cookie: &UnencryptedCsrfCookie,
+                                           absolute_max: i64)
+                                           -> bool {
+        self.current_time().is_ok_and(|now| {
+            self.verify_token_pair_as_of(token, cookie, now) && cookie.within_absolute_max_at(now, absolute_max)
+        })
+    }
+
+    /// Like `verify_token_pair`, but also require that the token and cookie were issued no more
+    /// than `max_issuance_gap` seconds apart, as by `UnencryptedCsrfToken::within_issuance_gap`.
+    /// This rejects a token from one page load stitched onto a much older cookie that happens to
+    /// carry the same value.
+    fn verify_token_pair_with_max_issuance_gap(&self,
+                                               token: &UnencryptedCsrfToken,
+                                               cookie: &UnencryptedCsrfCookie,
+                                               max_issuance_gap: i64)
+                                               -> bool {
+        self.verify_token_pair(token, cookie) && token.within_issuance_gap(cookie, max_issuance_gap)
+    }
+
+    /// Like `verify_raw`, but also require that the token and cookie were issued no more than
+    /// `max_issuance_gap` seconds apart, as by `verify_token_pair_with_max_issuance_gap`. This
+    /// keeps a long lived cookie from extending the life of a token that was issued with a
+    /// short lived one, or long after it.
+    ///
+    /// Both carry their authenticated issued at time in the version 1 wire format, so this needs
+    /// nothing more in either of them. A pair that is otherwise valid but issued too far apart, or
+    /// that is in the unversioned format and has no issued at times, fails with
+    /// `CsrfError::IssuanceMismatch`.
+    fn verify_raw_with_max_issuance_gap(&self,
+                                        token_bytes: &[u8],
+                                        cookie_bytes: &[u8],
+                                        max_issuance_gap: i64)
+                                        -> Result<(), CsrfError> {
+        let now = self.current_time()?;
+        let token = self.parse_token(token_bytes)?;
+        let cookie = self.parse_cookie_at(cookie_bytes, now)?;
+        check_token_pair(&token, &cookie, now)?;
+        if token.within_issuance_gap(&cookie, max_issuance_gap) {
+            Ok(())
+        } else {
+            Err(CsrfError::IssuanceMismatch)
+        }
+    }
+
+    /// Like `verify_raw`, but the token can only be verified once. After the token and cookie
+    /// have been verified, the token is recorded with `guard`, and if it had already been
+    /// recorded, `CsrfError::TokenReplayed` is returned.
+    ///
+    /// This is intended for highly sensitive actions. Every form that submits to such an action
+    /// needs a freshly generated token, since each one is consumed on first use.
+    fn parse_and_verify_once(&self,
+                             token_bytes: &[u8],
+                             cookie_bytes: &[u8],
+                             guard: &dyn ReplayGuard)
+                             -> Result<(), CsrfError> {
+        let now = self.current_time()?;
+        let token = self.parse_token(token_bytes)?;
+        let cookie = self.parse_cookie_at(cookie_bytes, now)?;
+        check_token_pair(&token, &cookie, now)?;
+
+        // the encrypted token is unique to each generated token, while the value may be reused
+        let token_id = digest::digest(&SHA256, self.replay_transport(token_bytes));
+        if guard.check_and_consume(token_id.as_ref(), cookie.expires, now) {
+            Ok(())
+        } else {
+            Err(CsrfError::TokenReplayed)
         }
+    }
 
-        let expires = unsafe { mem::transmute::<[u8; 8], i64>(expires_bytes) };
+    /// Return how many times a failure to get random bytes is retried before giving up.
+    fn rng_retries(&self) -> u32 {
+        0
+    }
 
-        Ok(UnencryptedCsrfCookie::new(expires, cookie_bytes.to_vec()))
+    /// Return whether `verify_raw_with_transport` refuses requests that were not secure.
+    fn require_secure_transport(&self) -> bool {
+        false
     }
 
-    fn parse_token(&self, token: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
-        if token.len() != 96 {
-            debug!("Token too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
+    /// Return the shortest and longest cookie TTLs that this protection allows, in seconds.
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        (None, None)
+    }
+
+    /// Return the source that new token values are drawn from, or `None` if they are drawn from
+    /// `random_bytes`, which is the default.
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        None
+    }
+
+    /// Return the entropy of new token values in bits: 512 if they are random, or `None` if they
+    /// are drawn from a `TokenValueSource`, whose entropy is not known.
+    fn token_value_bits(&self) -> Option<usize> {
+        match self.token_value_source() {
+            Some(_) => None,
+            None => Some(512),
         }
+    }
+
+    /// Return the clock that the current time is read from, or `None` if it is read from the
+    /// system clock, which is the default.
+    fn clock(&self) -> Option<&dyn Clock> {
+        None
+    }
+
+    /// Return whether a failure of the clock rejects tokens and cookies, as set by
+    /// `CsrfConfig::with_fail_closed_clock`.
+    fn fail_closed_clock(&self) -> bool {
+        false
+    }
 
-        let mut token_bytes = [0; 64];
-        let mut code = [0; 32];
+    /// Return the text encoding that tokens and cookies are sent in, as set by
+    /// `CsrfConfig::with_encoding`. The default is `TokenEncoding::Base64`.
+    fn encoding(&self) -> TokenEncoding {
+        TokenEncoding::Base64
+    }
 
-        for i in 0..64 {
-            token_bytes[i] = token[i];
+    /// Return the current time in seconds since the Unix epoch, from `clock` if there is one.
+    ///
+    /// If the clock can not tell the time, this returns `CsrfError::InternalError` when
+    /// `fail_closed_clock` is set, and otherwise logs a warning and returns the system time.
+    fn current_time(&self) -> Result<i64, CsrfError> {
+        let clock = match self.clock() {
+            Some(clock) => clock,
+            None => return Ok(unix_time()),
+        };
+        match clock.now() {
+            Some(now) => Ok(now),
+            None if self.fail_closed_clock() => {
+                error!("The CSRF clock could not tell the time, so verification fails closed");
+                Err(CsrfError::InternalError)
+            },
+            None => {
+                warn!("The CSRF clock could not tell the time, so the system time is used");
+                Ok(unix_time())
+            },
         }
-        for i in 0..32 {
-            code[i] = token[i + 64];
+    }
+
+    /// Given a buffer, fill it with random bytes or error if this is not possible.
+    ///
+    /// A failure to get random bytes is retried up to `rng_retries` times with a short backoff,
+    /// and if every attempt fails, `CsrfError::EntropyUnavailable` is returned.
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<(), CsrfError> {
+        fill_with_retries(|buf| self.rng().fill(buf).map_err(|_| ()), buf, self.rng_retries())
+    }
+
+    /// Given an optional previous token and a TTL, generate a matching token and cookie pair.
+    fn generate_token_pair(&self,
+                           previous_token_value: Option<&[u8; 64]>,
+                           ttl_seconds: i64)
+                           -> Result<(CsrfToken, CsrfCookie), CsrfError> {
+        let token = token_value_or_random(self, previous_token_value)?;
+
+        check_pair_nonces(self, self.generate_token(&token)?, self.generate_cookie(&token, ttl_seconds)?)
+    }
+
+    /// Generate a token with a new token value, and return the value too, so that its cookie can be
+    /// generated later with `cookie_for_value`. This is for flows where the token is issued by one
+    /// request, such as an API call that returns it as JSON, and the cookie is set by another.
+    ///
+    /// The value is the secret that the token and cookie share, so it must be kept server side,
+    /// for example in the session, and never sent to the client on its own.
+    fn issue_token(&self) -> Result<(CsrfToken, [u8; 64]), CsrfError> {
+        let value = token_value_or_random(self, None)?;
+        Ok((self.generate_token(&value)?, value))
+    }
+
+    /// Generate a cookie that expires `ttl_seconds` from now for a token value returned by
+    /// `issue_token`, which verifies against that token. This is `generate_cookie`, under a name
+    /// that makes the second phase of the flow explicit.
+    fn cookie_for_value(&self, value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        self.generate_cookie(value, ttl_seconds)
+    }
+
+    /// Like `generate_token_pair`, but return the token and cookie in the protection's
+    /// `encoding`, ready to be sent and later parsed with `parse_token_encoded` and
+    /// `parse_cookie_encoded`. With the default encoding, these are as by their `b64_string`, and
+    /// can also be parsed with `parse_token_b64` and `parse_cookie_b64`.
+    fn generate_encoded_pair(&self,
+                             previous_token_value: Option<&[u8; 64]>,
+                             ttl_seconds: i64)
+                             -> Result<(String, String), CsrfError> {
+        let (token, cookie) = self.generate_token_pair(previous_token_value, ttl_seconds)?;
+        let encoding = self.encoding();
+        Ok((token.encoded(encoding), cookie.encoded(encoding)))
+    }
+
+    /// Like `generate_encoded_pair`, but in the URL safe variant of the protection's `encoding`,
+    /// as by `TokenEncoding::url_safe`. With the default encoding, this is URL safe base64, as by
+    /// `b64_url_string`, and `parse_token_b64` and `parse_cookie_b64` accept both alphabets.
+    fn generate_encoded_pair_url_safe(&self,
+                                      previous_token_value: Option<&[u8; 64]>,
+                                      ttl_seconds: i64)
+                                      -> Result<(String, String), CsrfError> {
+        let (token, cookie) = self.generate_token_pair(previous_token_value, ttl_seconds)?;
+        let encoding = self.encoding().url_safe();
+        Ok((token.encoded(encoding), cookie.encoded(encoding)))
+    }
+
+    /// Return an unbounded iterator that yields a fresh, independent token and cookie pair from
+    /// `generate_token_pair` each time it is advanced. This is useful for load testing and for
+    /// lazily filling a pool of pairs.
+    fn token_pairs(&self, ttl_seconds: i64) -> TokenPairs<'_, Self> where Self: Sized {
+        TokenPairs {
+            protect: self,
+            ttl_seconds,
         }
+    }
 
-        let mac = MacResult::new(&code);
-        let mut hmac = self.hmac();
-        hmac.input(&token_bytes);
-        let result = hmac.result();
+    /// Like `generate_token_pair`, but the token is bound to the request method and path it will
+    /// be submitted to, and can only be verified with `parse_and_verify_for` for the same route.
+    ///
+    /// This prevents a token captured from one endpoint being replayed against another, at the
+    /// cost of making tokens per endpoint: a page with forms for several routes needs a token for
+    /// each of them. The method and path are compared exactly, so `path` should be normalized the
+    /// same way (e.g. without a query string) at generation and verification time.
+    fn generate_token_for(&self,
+                          previous_token_value: Option<&[u8; 64]>,
+                          method: &str,
+                          path: &str,
+                          ttl_seconds: i64)
+                          -> Result<(CsrfToken, CsrfCookie), CsrfError> {
+        let token = token_value_or_random(self, previous_token_value)?;
+        let binding = route_binding(method, path);
+
+        check_pair_nonces(self, self.generate_bound_token(&token, &binding)?, self.generate_cookie(&token, ttl_seconds)?)
+    }
 
-        if result != mac {
-            info!("CSRF token had bad MAC");
-            return Err(CsrfError::ValidationFailure);
+    /// Given a decoded token and cookie from `generate_token_for`, parse them and verify that the
+    /// token was bound to the given request method and path, matches the cookie, and has not
+    /// expired.
+    fn parse_and_verify_for(&self,
+                            token: &[u8],
+                            cookie: &[u8],
+                            method: &str,
+                            path: &str)
+                            -> Result<(), CsrfError> {
+        let token = self.parse_bound_token(token, &route_binding(method, path))?;
+        let cookie = self.parse_cookie(cookie)?;
+
+        if self.verify_token_pair(&token, &cookie) {
+            Ok(())
+        } else {
+            Err(CsrfError::ValidationFailure)
+        }
+    }
+
+    /// Check that this protection upholds its security invariants, as a sanity gate at startup or
+    /// from a health check endpoint: a generated token and cookie parse and verify, flipping a bit
+    /// in any one of their bytes is rejected, a token does not verify against another value's
+    /// cookie, and an expired cookie is rejected.
+    ///
+    /// The checks run in memory on fixed token values, so nothing is drawn from a
+    /// `TokenValueSource`, and the cookie TTL is 300 seconds or the nearest that `ttl_bounds`
+    /// allows. An error from generating or parsing the good pair is returned as is, and a
+    /// violated invariant is logged and returned as `CsrfError::InternalError`.
+    fn self_test(&self) -> Result<(), CsrfError> {
+        let (min_ttl, max_ttl) = self.ttl_bounds();
+        let ttl = cmp::min(300, max_ttl.unwrap_or(300));
+        let ttl = cmp::max(ttl, min_ttl.unwrap_or(ttl));
+        let now = self.current_time()?;
+        let violated = |invariant: &str| {
+            error!("CSRF protection self test failed: {}", invariant);
+            Err(CsrfError::InternalError)
+        };
+
+        let token = self.generate_token(&SELF_TEST_VALUE)?;
+        let cookie = self.generate_cookie_at(&SELF_TEST_VALUE, ttl, now)?;
+        self.verify_raw_at(token.value(), cookie.value(), now)?;
+
+        for (transport, is_token) in [(token.value(), true), (cookie.value(), false)] {
+            let mut tampered = transport.to_vec();
+            for index in 0..tampered.len() {
+                tampered[index] ^= 0x01;
+                let parsed = if is_token {
+                    self.parse_token(&tampered).map(|_| ())
+                } else {
+                    self.parse_cookie_at(&tampered, now).map(|_| ())
+                };
+                if parsed.is_ok() {
+                    return violated(&format!("{} with byte {} flipped was accepted",
+                                             if is_token { "token" } else { "cookie" }, index));
+                }
+                tampered[index] ^= 0x01;
+            }
+        }
+
+        let mut other_value = SELF_TEST_VALUE;
+        other_value[0] ^= 0xff;
+        let other_token = self.generate_token(&other_value)?;
+        if self.verify_raw_at(other_token.value(), cookie.value(), now) != Err(CsrfError::TokenMismatch) {
+            return violated("token for another value matched the cookie");
+        }
+
+        let expired_at = now.saturating_add(ttl).saturating_add(1);
+        if self.verify_raw_at(token.value(), cookie.value(), expired_at) != Err(CsrfError::Expired) {
+            return violated("cookie was accepted after it expired");
         }
 
-        Ok(UnencryptedCsrfToken::new(token_bytes.to_vec()))
+        Ok(())
     }
 }
 
+impl Clone for Box<dyn CsrfProtection> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
 
-/// Uses AES-GCM to provide signed, encrypted CSRF tokens and cookies.
-pub struct AesGcmCsrfProtection {
+/// A token and the cookie it matches, as returned by `CsrfProtection::verify_and_reissue`.
+pub type CsrfPair = (CsrfToken, CsrfCookie);
+
+/// The iterator returned by `CsrfProtection::token_pairs`.
+pub struct TokenPairs<'a, P: CsrfProtection + ?Sized + 'a> {
+    protect: &'a P,
+    ttl_seconds: i64,
+}
+
+impl<'a, P: CsrfProtection + ?Sized> Iterator for TokenPairs<'a, P> {
+    type Item = Result<(CsrfToken, CsrfCookie), CsrfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.protect.generate_token_pair(None, self.ttl_seconds))
+    }
+}
+
+/// The delay before the first retry of a failed RNG fill. It doubles after each attempt.
+const RNG_RETRY_BACKOFF_MS: u64 = 1;
+
+/// The longest delay between retries of a failed RNG fill.
+const RNG_RETRY_MAX_BACKOFF_MS: u64 = 100;
+
+/// Fill the buffer using `fill`, retrying up to `retries` times with exponential backoff.
+fn fill_with_retries<F>(mut fill: F, buf: &mut [u8], retries: u32) -> Result<(), CsrfError>
+    where F: FnMut(&mut [u8]) -> Result<(), ()>
+{
+    let mut backoff = RNG_RETRY_BACKOFF_MS;
+    for attempt in 0..=retries {
+        if fill(buf).is_ok() {
+            return Ok(());
+        }
+
+        if attempt < retries {
+            warn!("Failed to get random bytes, retrying in {}ms", backoff);
+            thread::sleep(Duration::from_millis(backoff));
+            backoff = cmp::min(backoff * 2, RNG_RETRY_MAX_BACKOFF_MS);
+        }
+    }
+
+    warn!("Failed to get random bytes");
+    Err(CsrfError::EntropyUnavailable)
+}
+
+/// Check that the token matches the cookie and that the cookie has not expired as of `now`.
+fn check_token_pair(token: &UnencryptedCsrfToken,
+                    cookie: &UnencryptedCsrfCookie,
+                    now: i64)
+                    -> Result<(), CsrfError> {
+    let tokens_match = token.token == cookie.token;
+    if !tokens_match {
+        #[cfg(feature = "log-secrets")]
+        debug!("Token did not match cookie: T: {}, C: {}", BASE64.encode(&token.token), BASE64.encode(&cookie.token));
+        #[cfg(not(feature = "log-secrets"))]
+        debug!("Token did not match cookie: T: {}, C: {}", token.describe_at(now), cookie.describe_at(now));
+    }
+
+    let not_expired = cookie.expires > now;
+    if !not_expired {
+        debug!("Cookie expired. Expiration: {}, Current time: {}", cookie.expires, now);
+    }
+
+    if !tokens_match {
+        Err(CsrfError::TokenMismatch)
+    } else if !not_expired {
+        Err(CsrfError::Expired)
+    } else {
+        Ok(())
+    }
+}
+
+/// Return the given token value, or a fresh random one if there is none.
+pub(crate) fn token_value_or_random<P: CsrfProtection + ?Sized>(protect: &P,
+                                                                previous_token_value: Option<&[u8; 64]>)
+                                                                -> Result<[u8; 64], CsrfError> {
+    match previous_token_value {
+        Some(previous) => Ok(*previous),
+        None => {
+            debug!("Generating new CSRF token.");
+            let mut token = [0; 64];
+            match protect.token_value_source() {
+                Some(source) => source.next_value(&mut token)?,
+                None => protect.random_bytes(&mut token)?,
+            }
+            Ok(token)
+        },
+    }
+}
+
+/// Return the pair unchanged, or `CsrfError::InternalError` if the token and cookie were sealed
+/// under the same nonce. Each nonce is drawn separately, so a repeat means that the random number
+/// generator is returning the same bytes every time and nothing it produces can be trusted.
+fn check_pair_nonces<P: CsrfProtection + ?Sized>(protect: &P,
+                                                 token: CsrfToken,
+                                                 cookie: CsrfCookie)
+                                                 -> Result<CsrfPair, CsrfError> {
+    match (protect.transport_nonce(token.value()), protect.transport_nonce(cookie.value())) {
+        (Some(token_nonce), Some(cookie_nonce)) if token_nonce == cookie_nonce => {
+            warn!("CSRF token and cookie were sealed under the same nonce. The random number generator is broken.");
+            Err(CsrfError::InternalError)
+        },
+        _ => Ok((token, cookie)),
+    }
+}
+
+/// Copy the token value out of a parsed cookie so that it can be sealed into a new one.
+fn cookie_token_value(cookie: &UnencryptedCsrfCookie) -> Result<[u8; 64], CsrfError> {
+    if cookie.token.len() != 64 {
+        debug!("Cookie token value had length {}, expected 64.", cookie.token.len());
+        return Err(CsrfError::MalformedLength);
+    }
+
+    let mut token_value = [0; 64];
+    token_value.copy_from_slice(&cookie.token);
+    Ok(token_value)
+}
+
+/// Encode a request method and path as unambiguous token binding data.
+fn route_binding(method: &str, path: &str) -> Vec<u8> {
+    let mut binding = Vec::with_capacity(ROUTE_BINDING_LABEL.len() + 8 + method.len() + path.len());
+    binding.extend_from_slice(ROUTE_BINDING_LABEL);
+    binding.extend_from_slice(&(method.len() as u64).to_le_bytes());
+    binding.extend_from_slice(method.as_bytes());
+    binding.extend_from_slice(path.as_bytes());
+    binding
+}
+
+
+/// Uses a MAC to provide authenticated CSRF tokens and cookies.
+///
+/// This implements the HMAC wire format for any `Mac`. The built-in backend is
+/// `HmacCsrfProtection`, and another implementation can be plugged in by implementing `Mac`.
+pub struct MacCsrfProtection<M: Mac> {
     rng: SystemRandom,
-    aead_key: [u8; 32],
+    mac: M,
+    // the token and cookie MACs, if they are keyed separately by `with_split_keys`
+    split_keys: Option<(M, M)>,
+    config: CsrfConfig,
+    token_values: Option<Arc<dyn TokenValueSource>>,
+    clock: Option<Arc<dyn Clock>>,
 }
 
-impl AesGcmCsrfProtection {
-    /// Given an AES256 key, return an `AesGcmCsrfProtection` instance.
-    pub fn from_key(aead_key: [u8; 32]) -> Self {
-        AesGcmCsrfProtection {
+impl<M: Mac> MacCsrfProtection<M> {
+    /// Given a MAC, return a `MacCsrfProtection` instance that uses it.
+    pub fn new(mac: M) -> Self {
+        MacCsrfProtection {
             rng: SystemRandom::new(),
-            aead_key: aead_key,
+            mac,
+            split_keys: None,
+            config: CsrfConfig::default(),
+            token_values: None,
+            clock: None,
         }
     }
 
-    fn aead<'a>(&self, nonce: &[u8; 12]) -> AesGcm<'a> {
-        AesGcm::new(KeySize::KeySize256, &self.aead_key, nonce, &[])
+    /// Given a MAC key, return a `MacCsrfProtection` instance.
+    pub fn from_key(mac_key: [u8; 32]) -> Self {
+        MacCsrfProtection::new(M::from_key(mac_key))
     }
-}
 
-impl CsrfProtection for AesGcmCsrfProtection {
-    /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
-    /// underlying crypto functions.
-    ///
-    /// # Panics
-    /// This function may panic if the underlying crypto library fails catastrophically.
-    fn from_password(password: &[u8]) -> Self {
-        let params = if cfg!(test) {
-            // scrypt is *slow*, so use these params for testing
-            ScryptParams::new(1, 8, 1)
-        } else {
-            ScryptParams::new(12, 8, 1)
-        };
+    /// Replace the configuration of this protection.
+    pub fn with_config(mut self, config: CsrfConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retrieve the configuration of this protection.
+    pub fn config(&self) -> &CsrfConfig {
+        &self.config
+    }
+
+    /// Draw new token values from `source` instead of the random number generator. The source is
+    /// shared with clones of this protection and with those derived from it by `derive_subkey`.
+    pub fn with_token_value_source<S: TokenValueSource + 'static>(mut self, source: S) -> Self {
+        self.token_values = Some(Arc::new(source));
+        self
+    }
+
+    /// Read the current time from `clock` instead of the system clock, when generating cookies and
+    /// checking expiry. The clock is shared like a token value source.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Replace the configuration, token value source and clock of this protection with those of
+    /// `other`.
+    fn with_settings_of(mut self, other: &Self) -> Self {
+        self.config = other.config.clone();
+        self.token_values = other.token_values.clone();
+        self.clock = other.clock.clone();
+        self
+    }
+
+    /// Retrieve the MAC of this protection, under the key it was constructed with.
+    pub fn mac(&self) -> &M {
+        &self.mac
+    }
+
+    /// Set whether tokens and cookies are authenticated under separate keys, derived from this
+    /// protection's key as the MAC of `"rust-csrf-token-key" ‖ 0x01` and of
+    /// `"rust-csrf-cookie-key" ‖ 0x01`, which for HMAC-SHA256 is HKDF-Expand.
+    ///
+    /// With a shared key, a token and a cookie are told apart only by their lengths, and a token
+    /// bound to 8 bytes of binding data carries a valid tag for a cookie with those bytes as its
+    /// expiry. Separate keys rule out confusing one for the other at any length, and limit what a
+    /// weakness in either construction exposes of the other.
+    ///
+    /// The layouts are unchanged, but the keys are part of the wire format, so tokens and cookies
+    /// issued with one setting are rejected by a protection with the other. To switch, deploy a
+    /// `FallbackCsrfProtection` with the old setting as an alternate and without `with_key_ids`,
+    /// since `key_id` still identifies the key this protection was constructed with.
+    pub fn with_split_keys(mut self, split: bool) -> Self {
+        self.split_keys = if split {
+            Some((M::from_key(self.expand(&[TOKEN_KEY_LABEL])), M::from_key(self.expand(&[COOKIE_KEY_LABEL]))))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Retrieve whether tokens and cookies are authenticated under separate keys.
+    pub fn split_keys(&self) -> bool {
+        self.split_keys.is_some()
+    }
+
+    /// Given a base64 encoded MAC key, return a `MacCsrfProtection` instance.
+    /// The key may be wrapped in PEM style `-----BEGIN ...-----` and `-----END ...-----` lines.
+    ///
+    /// Returns `CsrfError::InvalidKey` if the key is not valid base64 or does not decode to
+    /// exactly 32 bytes.
+    pub fn from_key_base64(encoded: &str) -> Result<Self, CsrfError> {
+        decode_key_base64(encoded).map(MacCsrfProtection::from_key)
+    }
+
+    /// Like `from_key_base64`, but read the encoded key from `reader`, such as a key file.
+    pub fn from_key_reader<R: Read>(reader: R) -> Result<Self, CsrfError> {
+        read_key_base64(reader).map(MacCsrfProtection::from_key)
+    }
+
+    /// Derive the key from a secret that is already high-entropy, such as a random key of at least
+    /// 32 bytes from a secrets manager, using HKDF-SHA256. This is much faster than
+    /// `from_password`, but offers no protection against guessing, so it must never be used with
+    /// a password or other low-entropy input.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        MacCsrfProtection::from_key(secret_key(secret))
+    }
+
+    /// Derive a new, independent `MacCsrfProtection` whose key is bound to `context`.
+    ///
+    /// Tokens and cookies generated by a protection derived for one context can not be parsed by
+    /// a protection derived for any other context, or by this protection.
+    pub fn derive_subkey(&self, context: &[u8]) -> Self {
+        MacCsrfProtection::from_key(self.expand(&[SUBKEY_LABEL, context]))
+            .with_settings_of(self)
+            .with_split_keys(self.split_keys())
+    }
+
+    /// Expand the key into 32 bytes bound to the concatenation of `info`, as the MAC of
+    /// `info ‖ 0x01`. For HMAC-SHA256 this is a single block of HKDF-Expand, so it gives the same
+    /// result as `derive_subkey` on the AEAD backends without the raw key bytes.
+    fn expand(&self, info: &[&[u8]]) -> [u8; 32] {
+        let mut parts = info.to_vec();
+        parts.push(&[1]);
+        self.mac.sign(&parts)
+    }
+
+    /// The MAC that tokens are authenticated with.
+    fn token_mac(&self) -> &M {
+        self.split_keys.as_ref().map_or(&self.mac, |(token_mac, _)| token_mac)
+    }
+
+    /// The MAC that cookies are authenticated with.
+    fn cookie_mac(&self) -> &M {
+        self.split_keys.as_ref().map_or(&self.mac, |(_, cookie_mac)| cookie_mac)
+    }
+
+    /// Given a decoded cookie, check its MAC and expiry and return the expiry, without copying
+    /// out the token value.
+    ///
+    /// This is a cheaper alternative to `parse_cookie` for callers that only need to know whether
+    /// a cookie is authentic and unexpired. The MAC is recomputed directly over the input. An
+    /// authentic cookie that has expired fails with `CsrfError::Expired`, as in `verify_raw`. The
+    /// allowed algorithms and uniform rejection timing of the `CsrfConfig` apply as in
+    /// `parse_cookie`.
+    pub fn verify_cookie_only(&self, cookie: &[u8]) -> Result<i64, CsrfError> {
+        self.config.check_algorithm(self.algorithm())?;
+        let now = self.current_time()?;
+        let expires = uniform_rejection(&self.config, self.open_cookie_expiry(cookie, now), || {
+            self.cookie_mac().verify(&[&[0; HMAC_COOKIE_LEN - MAC_TAG_LEN]], &[0; MAC_TAG_LEN]);
+        })?;
+
+        if expires <= now {
+            debug!("Cookie expired. Expiration: {}, Current time: {}", expires, now);
+            return Err(CsrfError::Expired);
+        }
+
+        Ok(expires)
+    }
+
+    /// Check a decoded cookie's MAC for `verify_cookie_only` and return its expiry.
+    fn open_cookie_expiry(&self, cookie: &[u8], now: i64) -> Result<i64, CsrfError> {
+        check_present(cookie, CsrfError::MissingCookie)?;
+        check_algorithm(cookie, HMAC_COOKIE_LEN, HMAC_COOKIE_LEN, &BACKEND_COOKIE_LENS)?;
+        let (_, _, expires) = self.open_cookie(cookie, now)?;
+        Ok(expires)
+    }
+
+    /// Parse a decoded cookie for `parse_cookie_at`.
+    fn parse_cookie_transport(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        check_present(cookie, CsrfError::MissingCookie)?;
+        check_algorithm(cookie, self.expected_cookie_len(), HMAC_COOKIE_LEN, &BACKEND_COOKIE_LENS)?;
+
+        let (token, issued_at, expires) = self.open_cookie(cookie, now)?;
+        let parsed = UnencryptedCsrfCookie::new(expires, token.to_vec()).with_wire(cookie);
+        Ok(match issued_at {
+            Some(issued_at) => parsed.with_issued_at(issued_at),
+            None => parsed,
+        })
+    }
+
+    /// Parse a decoded token for `parse_bound_token`.
+    fn parse_token_transport(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        check_present(token, CsrfError::MissingToken)?;
+        check_algorithm(token, self.expected_token_len(), HMAC_TOKEN_LEN, &BACKEND_TOKEN_LENS)?;
+
+        if token.len() == 96 {
+            return self.parse_legacy_token(token, binding).map(|parsed| parsed.with_wire(token));
+        }
+
+        check_wire_version(token, HMAC_TOKEN_LEN)?;
+
+        if !self.token_mac().verify(&[&token[0..73], binding], &token[73..HMAC_TOKEN_LEN]) {
+            info!("CSRF token had bad MAC");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let issued_at = check_issued_at(read_i64(token, 65)?, self.current_time()?)?;
+        Ok(UnencryptedCsrfToken::new(token[1..65].to_vec()).with_issued_at(issued_at).with_wire(token))
+    }
+
+    /// Parse a decoded token in the unversioned wire format.
+    fn parse_legacy_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        if token.len() != 96 {
+            debug!("Token had the wrong length. Not parsed.");
+            return Err(CsrfError::MalformedLength);
+        }
+
+        if !self.token_mac().verify(&[&token[0..64], binding], &token[64..96]) {
+            info!("CSRF token had bad MAC");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        Ok(UnencryptedCsrfToken::new(token[0..64].to_vec()))
+    }
+
+    /// Check the MAC of a decoded cookie and return its token value, issued at time, and expiry.
+    fn open_cookie<'a>(&self, cookie: &'a [u8], now: i64) -> Result<OpenedCookie<'a>, CsrfError> {
+        if cookie.len() == 104 {
+            return self.open_legacy_cookie(cookie);
+        }
+
+        check_wire_version(cookie, HMAC_COOKIE_LEN)?;
+
+        if !self.cookie_mac().verify(&[&cookie[0..81]], &cookie[81..HMAC_COOKIE_LEN]) {
+            info!("CSRF cookie had bad MAC");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let issued_at = check_issued_at(read_i64(cookie, 65)?, now)?;
+        Ok((&cookie[1..65], Some(issued_at), read_i64(cookie, 73)?))
+    }
+
+    /// Check the MAC of a decoded cookie in the unversioned wire format.
+    fn open_legacy_cookie<'a>(&self, cookie: &'a [u8]) -> Result<OpenedCookie<'a>, CsrfError> {
+        if cookie.len() != 104 {
+            debug!("Cookie had the wrong length. Not parsed.");
+            return Err(CsrfError::MalformedLength);
+        }
+
+        if !self.cookie_mac().verify(&[&cookie[0..72]], &cookie[72..104]) {
+            info!("CSRF cookie had bad MAC");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        Ok((&cookie[0..64], None, read_i64(cookie, 64)?))
+    }
+
+    fn seal_cookie(&self, token_value: &[u8; 64], issued_at: i64, expires: i64) -> Result<CsrfCookie, CsrfError> {
+        let mut transport = Vec::with_capacity(HMAC_COOKIE_LEN);
+        transport.push(WIRE_VERSION);
+        transport.extend_from_slice(token_value);
+        transport.extend_from_slice(&issued_at.to_le_bytes());
+        transport.extend_from_slice(&expires.to_le_bytes());
+
+        let tag = self.cookie_mac().sign(&[&transport]);
+        transport.extend_from_slice(&tag);
+
+        Ok(CsrfCookie::new(transport))
+    }
+
+    fn seal_token(&self, token_value: &[u8; 64], binding: &[u8], issued_at: i64) -> Result<CsrfToken, CsrfError> {
+        let mut transport = Vec::with_capacity(HMAC_TOKEN_LEN);
+        transport.push(WIRE_VERSION);
+        transport.extend_from_slice(token_value);
+        transport.extend_from_slice(&issued_at.to_le_bytes());
+
+        let tag = self.token_mac().sign(&[&transport, binding]);
+        transport.extend_from_slice(&tag);
+
+        Ok(CsrfToken::new(transport))
+    }
+}
+
+/// Cloning is cheap: the clone has a clone of the MAC, the same configuration and token value
+/// source, and its own handle to the system random number generator.
+impl<M: Mac> Clone for MacCsrfProtection<M> {
+    fn clone(&self) -> Self {
+        let mut clone = MacCsrfProtection::new(self.mac.clone()).with_settings_of(self);
+        clone.split_keys = self.split_keys.clone();
+        clone
+    }
+}
+
+/// Equivalent to `from_key`.
+impl<M: Mac> From<[u8; 32]> for MacCsrfProtection<M> {
+    fn from(key: [u8; 32]) -> Self {
+        MacCsrfProtection::from_key(key)
+    }
+}
+
+/// Returns `CsrfError::InvalidKey` if the slice is not exactly 32 bytes long.
+impl<'a, M: Mac> TryFrom<&'a [u8]> for MacCsrfProtection<M> {
+    type Error = CsrfError;
+
+    fn try_from(key: &'a [u8]) -> Result<Self, CsrfError> {
+        key_from_slice(key).map(MacCsrfProtection::from_key)
+    }
+}
+
+impl<M: Mac> CsrfProtection for MacCsrfProtection<M> {
+    /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
+    /// underlying crypto functions.
+    ///
+    /// # Panics
+    /// This function may panic if the underlying crypto library fails catastrophically.
+    fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self {
+        MacCsrfProtection::from_key(password_key(password, salt))
+    }
+
+    fn clone_box(&self) -> Box<dyn CsrfProtection> {
+        Box::new(self.clone())
+    }
+
+    fn key_id(&self) -> [u8; 8] {
+        self.mac.key_id()
+    }
+
+    fn security_parameters(&self) -> SecurityParameters {
+        SecurityParameters::mac(256, MAC_TAG_LEN).with_token_value_bits(self.token_value_bits())
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        self.mac.algorithm()
+    }
+
+    fn rng(&self) -> &SystemRandom {
+        &self.rng
+    }
+
+    fn rng_retries(&self) -> u32 {
+        self.config.rng_retries()
+    }
+
+    fn require_secure_transport(&self) -> bool {
+        self.config.require_secure_transport()
+    }
+
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        (self.config.min_ttl(), self.config.max_ttl())
+    }
+
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        self.token_values.as_deref()
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        self.clock.as_deref()
+    }
+
+    fn fail_closed_clock(&self) -> bool {
+        self.config.fail_closed_clock()
+    }
+
+    fn encoding(&self) -> TokenEncoding {
+        self.config.encoding()
+    }
+
+    fn expected_token_len(&self) -> usize {
+        HMAC_TOKEN_LEN
+    }
+
+    fn expected_cookie_len(&self) -> usize {
+        HMAC_COOKIE_LEN
+    }
+
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        self.seal_cookie(token_value, now, now.saturating_add(ttl_seconds))
+    }
+
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        let now = self.current_time()?;
+        let token_value = cookie_token_value(cookie)?;
+        self.seal_cookie(&token_value, cookie.issued_at.unwrap_or(now), now.saturating_add(ttl_seconds))
+    }
+
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+        self.seal_token(token_value, binding, self.current_time()?)
+    }
+
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.config.check_algorithm(self.algorithm())?;
+        uniform_rejection(&self.config, self.parse_cookie_transport(cookie, now), || {
+            self.cookie_mac().verify(&[&[0; HMAC_COOKIE_LEN - MAC_TAG_LEN]], &[0; MAC_TAG_LEN]);
+        })
+    }
+
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.config.check_algorithm(self.algorithm())?;
+        uniform_rejection(&self.config, self.parse_token_transport(token, binding), || {
+            self.token_mac().verify(&[&[0; HMAC_TOKEN_LEN - MAC_TAG_LEN], binding], &[0; MAC_TAG_LEN]);
+        })
+    }
+}
+
+
+/// Uses an AEAD to provide signed, encrypted CSRF tokens and cookies.
+///
+/// This implements the AEAD wire format for any `Aead`, with the nonce length of that cipher. The
+/// built-in backends are `AesGcmCsrfProtection` and `ChaCha20Poly1305CsrfProtection`, and another
+/// cipher can be plugged in by implementing `Aead`.
+pub struct AeadCsrfProtection<A: Aead> {
+    rng: SystemRandom,
+    aead: A,
+    // the token and cookie ciphers, if they are keyed separately by `with_split_keys`
+    split_keys: Option<(A, A)>,
+    config: CsrfConfig,
+    token_values: Option<Arc<dyn TokenValueSource>>,
+    clock: Option<Arc<dyn Clock>>,
+    // how many nonces have been drawn under the key, shared with clones
+    nonces_used: Arc<AtomicU64>,
+    // the cipher for `seal` and `open`, derived on first use and shared with clones
+    seal_aead: Arc<OnceLock<Option<A>>>,
+}
+
+impl<A: Aead> AeadCsrfProtection<A> {
+    /// Given a cipher, return an `AeadCsrfProtection` instance that uses it.
+    pub fn new(aead: A) -> Self {
+        AeadCsrfProtection {
+            rng: SystemRandom::new(),
+            aead,
+            split_keys: None,
+            config: CsrfConfig::default(),
+            token_values: None,
+            clock: None,
+            nonces_used: Arc::new(AtomicU64::new(0)),
+            seal_aead: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Given a key for the cipher, return an `AeadCsrfProtection` instance.
+    pub fn from_key(aead_key: [u8; 32]) -> Self {
+        AeadCsrfProtection::new(A::from_key(aead_key))
+    }
+
+    /// Replace the configuration of this protection.
+    pub fn with_config(mut self, config: CsrfConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retrieve the configuration of this protection.
+    pub fn config(&self) -> &CsrfConfig {
+        &self.config
+    }
+
+    /// Draw new token values from `source` instead of the random number generator. The source is
+    /// shared with clones of this protection and with those derived from it by `derive_subkey`.
+    pub fn with_token_value_source<S: TokenValueSource + 'static>(mut self, source: S) -> Self {
+        self.token_values = Some(Arc::new(source));
+        self
+    }
+
+    /// Read the current time from `clock` instead of the system clock, when generating cookies and
+    /// checking expiry. The clock is shared like a token value source.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Replace the configuration, token value source and clock of this protection with those of
+    /// `other`.
+    fn with_settings_of(mut self, other: &Self) -> Self {
+        self.config = other.config.clone();
+        self.token_values = other.token_values.clone();
+        self.clock = other.clock.clone();
+        self
+    }
+
+    /// Retrieve the cipher of this protection, under the key it was constructed with.
+    pub fn aead(&self) -> &A {
+        &self.aead
+    }
+
+    /// Retrieve whether tokens and cookies are encrypted under separate keys, as set by
+    /// `with_split_keys` on the built-in backends.
+    pub fn split_keys(&self) -> bool {
+        self.split_keys.is_some()
+    }
+
+    /// Return how many random nonces this protection and its clones have drawn under its key
+    /// since it was constructed, as counted against `CsrfConfig::with_nonce_budget`.
+    pub fn nonces_used(&self) -> u64 {
+        self.nonces_used.load(Ordering::SeqCst)
+    }
+
+    /// Count a nonce against the nonce budget, or refuse it if the budget is used up and strict.
+    fn use_nonce(&self) -> Result<(), CsrfError> {
+        let limit = self.config.nonce_limit(self.aead.nonce_len());
+        let strict = self.config.strict_nonce_budget();
+        let used = self.nonces_used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| match limit {
+            Some(limit) if strict && used >= limit => None,
+            _ => Some(used.saturating_add(1)),
+        });
+        match used {
+            Ok(used) if Some(used + 1) == limit => {
+                warn!("{} nonces have been drawn under this CSRF key, which is its nonce budget. Rotate the key.",
+                      used + 1);
+                Ok(())
+            },
+            Ok(_) => Ok(()),
+            Err(used) => {
+                error!("{} nonces have been drawn under this CSRF key, so no more tokens are generated until it is rotated",
+                       used);
+                Err(CsrfError::InternalError)
+            },
+        }
+    }
+
+    /// The cipher that tokens are encrypted with.
+    fn token_aead(&self) -> &A {
+        self.split_keys.as_ref().map_or(&self.aead, |(token_aead, _)| token_aead)
+    }
+
+    /// The cipher that cookies are encrypted with.
+    fn cookie_aead(&self) -> &A {
+        self.split_keys.as_ref().map_or(&self.aead, |(_, cookie_aead)| cookie_aead)
+    }
+
+    /// Given a base64 encoded key for the cipher, return an `AeadCsrfProtection` instance.
+    /// The key may be wrapped in PEM style `-----BEGIN ...-----` and `-----END ...-----` lines.
+    ///
+    /// Returns `CsrfError::InvalidKey` if the key is not valid base64 or does not decode to
+    /// exactly 32 bytes.
+    pub fn from_key_base64(encoded: &str) -> Result<Self, CsrfError> {
+        decode_key_base64(encoded).map(AeadCsrfProtection::from_key)
+    }
+
+    /// Like `from_key_base64`, but read the encoded key from `reader`, such as a key file.
+    pub fn from_key_reader<R: Read>(reader: R) -> Result<Self, CsrfError> {
+        read_key_base64(reader).map(AeadCsrfProtection::from_key)
+    }
+
+    /// Derive the key from a secret that is already high-entropy, such as a random key of at least
+    /// 32 bytes from a secrets manager, using HKDF-SHA256. This is much faster than
+    /// `from_password`, but offers no protection against guessing, so it must never be used with
+    /// a password or other low-entropy input.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        AeadCsrfProtection::from_key(secret_key(secret))
+    }
+
+    /// Return the length of a transport buffer with `prefix_len` bytes before `plaintext_len`
+    /// bytes of ciphertext.
+    fn transport_len(&self, prefix_len: usize, plaintext_len: usize) -> usize {
+        prefix_len + plaintext_len + self.aead.nonce_len() + AEAD_TAG_LEN
+    }
+
+    /// Encrypt and authenticate an arbitrary value of at most `MAX_SEALED_LEN` bytes, such as a
+    /// flash message, so that it can be handed to the end user and later recovered with `open`.
+    ///
+    /// Sealed values use the cipher's `Aead::seal_cipher`, under a key derived from this
+    /// protection's key, so they can never be mistaken for tokens or cookies, or the other way
+    /// around. The result is `plaintext.len() + 29` bytes long with AES-GCM, and
+    /// `plaintext.len() + 25` with ChaCha20Poly1305. Values longer than `MAX_SEALED_LEN` are
+    /// rejected with `CsrfError::MalformedLength`. Each value draws a nonce, which counts against
+    /// `CsrfConfig::with_nonce_budget` along with those of tokens and cookies.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        if plaintext.len() > MAX_SEALED_LEN {
+            debug!("Value to seal had length {}, more than {}.", plaintext.len(), MAX_SEALED_LEN);
+            return Err(CsrfError::MalformedLength);
+        }
+
+        self.seal_transport(self.seal_aead()?, WIRE_VERSION, plaintext, &[])
+    }
+
+    /// Decrypt and authenticate a value created with `seal`.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        let overhead = self.transport_len(1, 0);
+        if sealed.len() < overhead || sealed.len() - overhead > MAX_SEALED_LEN {
+            debug!("Sealed value had length {}, which is not valid.", sealed.len());
+            return Err(CsrfError::MalformedLength);
+        }
+
+        self.open_transport(self.seal_aead()?, sealed, WIRE_VERSION, sealed.len() - overhead, &[])
+    }
+
+    /// The cipher for `seal` and `open`, which is derived the first time it is needed.
+    fn seal_aead(&self) -> Result<&A, CsrfError> {
+        self.seal_aead.get_or_init(|| self.aead.seal_cipher()).as_ref().ok_or_else(|| {
+            warn!("The CSRF cipher can not derive a key for sealed values");
+            CsrfError::InternalError
+        })
+    }
+
+    /// Encrypt the plaintext with `aead` into a versioned transport buffer made of the version
+    /// byte, the ciphertext, the nonce, and the tag. The version byte and the binding data are
+    /// authenticated as associated data.
+    fn seal_transport(&self, aead: &A, version: u8, plaintext: &[u8], binding: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        self.use_nonce()?;
+        let mut nonce = vec![0; self.aead.nonce_len()];
+        self.random_bytes(&mut nonce)?;
+
+        let mut ciphertext = vec![0; plaintext.len()];
+        let mut tag = [0; AEAD_TAG_LEN];
+        aead.seal(&nonce, &versioned_aad(version, binding), plaintext, &mut ciphertext, &mut tag);
+
+        let mut transport = Vec::with_capacity(self.transport_len(1, plaintext.len()));
+        transport.push(version);
+        transport.extend_from_slice(&ciphertext);
+        transport.extend_from_slice(&nonce);
+        transport.extend_from_slice(&tag);
+
+        Ok(transport)
+    }
+
+    /// Decrypt a transport buffer with `aead`, with the version byte `version` holding
+    /// `plaintext_len` bytes of plaintext.
+    fn open_transport(&self,
+                      aead: &A,
+                      transport: &[u8],
+                      version: u8,
+                      plaintext_len: usize,
+                      binding: &[u8])
+                      -> Result<Vec<u8>, CsrfError> {
+        check_version_byte(transport, version, self.transport_len(1, plaintext_len))?;
+        self.open_sections(aead, &transport[1..], plaintext_len, &versioned_aad(version, binding))
+    }
+
+    /// Decrypt the ciphertext, nonce, and tag sections of a transport buffer holding
+    /// `plaintext_len` bytes of plaintext with `aead`, authenticating `aad` along with them.
+    fn open_sections(&self, aead: &A, sections: &[u8], plaintext_len: usize, aad: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        let (ciphertext, nonce, tag) =
+            split_aead_transport(sections, plaintext_len, self.aead.nonce_len(), AEAD_TAG_LEN)?;
+        let tag: [u8; AEAD_TAG_LEN] = read_array(tag, 0)?;
+
+        let mut plaintext = vec![0; plaintext_len];
+        if !aead.open(nonce, aad, ciphertext, &tag, &mut plaintext) {
+            info!("Failed to decrypt CSRF transport");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        Ok(plaintext)
+    }
+
+    fn seal_cookie(&self, token_value: &[u8; 64], issued_at: i64, expires: i64) -> Result<CsrfCookie, CsrfError> {
+        let (version, padding) = aead_layout(&self.config);
+        let mut plaintext = vec![0; padding + 80];
+        self.random_bytes(&mut plaintext[0..padding])?;
+        plaintext[padding..padding + 8].copy_from_slice(&issued_at.to_le_bytes());
+        plaintext[padding + 8..padding + 16].copy_from_slice(&expires.to_le_bytes());
+        plaintext[padding + 16..].copy_from_slice(token_value);
+
+        Ok(CsrfCookie::new(self.seal_transport(self.cookie_aead(), version, &plaintext, &[])?))
+    }
+
+    fn seal_token(&self, token_value: &[u8; 64], binding: &[u8], issued_at: i64) -> Result<CsrfToken, CsrfError> {
+        let (version, padding) = aead_layout(&self.config);
+        let mut plaintext = vec![0; padding + 72];
+        self.random_bytes(&mut plaintext[0..padding])?;
+        plaintext[padding..padding + 8].copy_from_slice(&issued_at.to_le_bytes());
+        plaintext[padding + 8..].copy_from_slice(token_value);
+
+        Ok(CsrfToken::new(self.seal_transport(self.token_aead(), version, &plaintext, binding)?))
+    }
+
+    /// Parse a decoded cookie for `parse_cookie_at`.
+    fn parse_cookie_transport(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        check_present(cookie, CsrfError::MissingCookie)?;
+        let default_len = self.transport_len(1, AEAD_PADDING_LEN + 80);
+        check_algorithm(cookie, self.expected_cookie_len(), default_len, &BACKEND_COOKIE_LENS)?;
+
+        if cookie.len() == self.transport_len(0, 88) && cookie.len() != self.expected_cookie_len() {
+            return self.parse_legacy_cookie(cookie).map(|parsed| parsed.with_wire(cookie));
+        }
+
+        let (version, padding) = received_aead_layout(cookie, &self.config);
+        let plaintext = self.open_transport(self.cookie_aead(), cookie, version, padding + 80, &[])?;
+
+        // skip the padding
+        let issued_at = check_issued_at(read_i64(&plaintext, padding)?, now)?;
+        let expires = read_i64(&plaintext, padding + 8)?;
+
+        Ok(UnencryptedCsrfCookie::new(expires, plaintext[padding + 16..].to_vec())
+            .with_issued_at(issued_at)
+            .with_wire(cookie))
+    }
+
+    /// Parse a decoded token for `parse_bound_token`.
+    fn parse_token_transport(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        check_present(token, CsrfError::MissingToken)?;
+        let default_len = self.transport_len(1, AEAD_PADDING_LEN + 72);
+        check_algorithm(token, self.expected_token_len(), default_len, &BACKEND_TOKEN_LENS)?;
+
+        if token.len() == self.transport_len(0, 80) && token.len() != self.expected_token_len() {
+            return self.parse_legacy_token(token, binding).map(|parsed| parsed.with_wire(token));
+        }
+
+        let (version, padding) = received_aead_layout(token, &self.config);
+        let plaintext = self.open_transport(self.token_aead(), token, version, padding + 72, binding)?;
+
+        // skip the padding
+        let issued_at = check_issued_at(read_i64(&plaintext, padding)?, self.current_time()?)?;
+
+        Ok(UnencryptedCsrfToken::new(plaintext[padding + 8..].to_vec())
+            .with_issued_at(issued_at)
+            .with_wire(token))
+    }
+
+    /// Try to decrypt a zeroed transport buffer of `transport_len` bytes with `aead`, which always
+    /// fails, for `uniform_rejection`.
+    fn open_zeroed(&self, aead: &A, transport_len: usize, binding: &[u8]) {
+        let sections = vec![0; transport_len - 1];
+        let plaintext_len = sections.len() - self.aead.nonce_len() - AEAD_TAG_LEN;
+        let _ = self.open_sections(aead, &sections, plaintext_len, &versioned_aad(self.wire_version(), binding));
+    }
+
+    /// Parse a decoded cookie in the unversioned wire format.
+    fn parse_legacy_cookie(&self, cookie: &[u8]) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        let plaintext = self.open_sections(self.cookie_aead(), cookie, 88, &[])?;
+
+        // skip 16 bytes of padding
+        let expires = read_i64(&plaintext, 16)?;
+        let token: [u8; 64] = read_array(&plaintext, 24)?;
+
+        Ok(UnencryptedCsrfCookie::new(expires, token.to_vec()))
+    }
+
+    /// Parse a decoded token in the unversioned wire format.
+    fn parse_legacy_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        let plaintext = self.open_sections(self.token_aead(), token, 80, binding)?;
+
+        // skip 16 bytes of padding
+        let token: [u8; 64] = read_array(&plaintext, 16)?;
+
+        Ok(UnencryptedCsrfToken::new(token.to_vec()))
+    }
+}
+
+/// Cloning is cheap: the clone has a clone of the cipher, the same configuration and token value
+/// source, and its own handle to the system random number generator.
+impl<A: Aead> Clone for AeadCsrfProtection<A> {
+    fn clone(&self) -> Self {
+        let mut clone = AeadCsrfProtection::new(self.aead.clone()).with_settings_of(self);
+        clone.split_keys = self.split_keys.clone();
+        clone.nonces_used = self.nonces_used.clone();
+        clone.seal_aead = self.seal_aead.clone();
+        clone
+    }
+}
+
+/// Equivalent to `from_key`.
+impl<A: Aead> From<[u8; 32]> for AeadCsrfProtection<A> {
+    fn from(key: [u8; 32]) -> Self {
+        AeadCsrfProtection::from_key(key)
+    }
+}
+
+/// Returns `CsrfError::InvalidKey` if the slice is not exactly 32 bytes long.
+impl<'a, A: Aead> TryFrom<&'a [u8]> for AeadCsrfProtection<A> {
+    type Error = CsrfError;
+
+    fn try_from(key: &'a [u8]) -> Result<Self, CsrfError> {
+        key_from_slice(key).map(AeadCsrfProtection::from_key)
+    }
+}
+
+impl<A: Aead> CsrfProtection for AeadCsrfProtection<A> {
+    /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
+    /// underlying crypto functions.
+    ///
+    /// # Panics
+    /// This function may panic if the underlying crypto library fails catastrophically.
+    fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self {
+        AeadCsrfProtection::from_key(password_key(password, salt))
+    }
+
+    fn clone_box(&self) -> Box<dyn CsrfProtection> {
+        Box::new(self.clone())
+    }
+
+    fn key_id(&self) -> [u8; 8] {
+        self.aead.key_id()
+    }
+
+    fn security_parameters(&self) -> SecurityParameters {
+        SecurityParameters::aead(256, self.aead.nonce_len(), AEAD_TAG_LEN).with_token_value_bits(self.token_value_bits())
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        self.aead.algorithm()
+    }
+
+    fn rng(&self) -> &SystemRandom {
+        &self.rng
+    }
+
+    fn rng_retries(&self) -> u32 {
+        self.config.rng_retries()
+    }
+
+    fn require_secure_transport(&self) -> bool {
+        self.config.require_secure_transport()
+    }
+
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        (self.config.min_ttl(), self.config.max_ttl())
+    }
+
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        self.token_values.as_deref()
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        self.clock.as_deref()
+    }
+
+    fn fail_closed_clock(&self) -> bool {
+        self.config.fail_closed_clock()
+    }
+
+    fn encoding(&self) -> TokenEncoding {
+        self.config.encoding()
+    }
+
+    fn supports_encryption(&self) -> bool {
+        true
+    }
+
+    fn wire_version(&self) -> u8 {
+        aead_layout(&self.config).0
+    }
+
+    fn expected_token_len(&self) -> usize {
+        self.transport_len(1, aead_layout(&self.config).1 + 72)
+    }
+
+    fn expected_cookie_len(&self) -> usize {
+        self.transport_len(1, aead_layout(&self.config).1 + 80)
+    }
+
+    fn transport_nonce<'a>(&self, transport: &'a [u8]) -> Option<&'a [u8]> {
+        let nonce_len = self.aead.nonce_len();
+        transport.len().checked_sub(nonce_len + AEAD_TAG_LEN).map(|start| &transport[start..start + nonce_len])
+    }
+
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        self.seal_cookie(token_value, now, now.saturating_add(ttl_seconds))
+    }
+
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        let now = self.current_time()?;
+        let token_value = cookie_token_value(cookie)?;
+        self.seal_cookie(&token_value, cookie.issued_at.unwrap_or(now), now.saturating_add(ttl_seconds))
+    }
+
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+        self.seal_token(token_value, binding, self.current_time()?)
+    }
+
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.config.check_algorithm(self.algorithm())?;
+        uniform_rejection(&self.config, self.parse_cookie_transport(cookie, now), || {
+            self.open_zeroed(self.cookie_aead(), self.expected_cookie_len(), &[]);
+        })
+    }
+
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.config.check_algorithm(self.algorithm())?;
+        uniform_rejection(&self.config, self.parse_token_transport(token, binding), || {
+            self.open_zeroed(self.token_aead(), self.expected_token_len(), binding);
+        })
+    }
+}
+
+
+/// HMAC-SHA256 from `ring`, the MAC of `HmacCsrfProtection`.
+#[cfg(feature = "hmac-backend")]
+#[derive(Clone)]
+pub struct HmacSha256Mac {
+    signing_key: Arc<hmac::SigningKey>,
+}
+
+#[cfg(feature = "hmac-backend")]
+impl Mac for HmacSha256Mac {
+    fn from_key(key: [u8; 32]) -> Self {
+        HmacSha256Mac { signing_key: Arc::new(hmac::SigningKey::new(&SHA256, &key)) }
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> [u8; MAC_TAG_LEN] {
+        let mut context = hmac::SigningContext::with_key(&self.signing_key);
+        for part in parts {
+            context.update(part);
+        }
+
+        let mut tag = [0; MAC_TAG_LEN];
+        tag.copy_from_slice(context.sign().as_ref());
+        tag
+    }
+
+    fn key_id(&self) -> [u8; 8] {
+        let mut id = [0; 8];
+        id.copy_from_slice(&self.sign(&[KEY_ID_LABEL])[..8]);
+        id
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        Some(Algorithm::HmacSha256)
+    }
+}
+
+/// Uses HMAC to provide authenticated CSRF tokens and cookies.
+///
+/// Binding data passed to `generate_bound_token`, such as a session ID, is covered by the MAC
+/// along with the token, just as the AEAD backends authenticate it as associated data. The token
+/// will only pass `parse_bound_token` when the same binding data is supplied again.
+#[cfg(feature = "hmac-backend")]
+pub type HmacCsrfProtection = MacCsrfProtection<HmacSha256Mac>;
+
+#[cfg(feature = "hmac-backend")]
+impl MacCsrfProtection<HmacSha256Mac> {
+    /// Given a `ring` HMAC key, such as one from key management code that already uses `ring`,
+    /// return an `HmacCsrfProtection` instance. The raw key bytes are never needed, so a key from
+    /// `SigningKey::generate` can be used. A key made with `SigningKey::new(&SHA256, key)` gives
+    /// the same protection as `from_key(key)`.
+    ///
+    /// Returns `CsrfError::InvalidKey` if the key does not use SHA-256.
+    pub fn from_signing_key(signing_key: hmac::SigningKey) -> Result<Self, CsrfError> {
+        if !::std::ptr::eq(signing_key.digest_algorithm(), &SHA256) {
+            warn!("CSRF HMAC key used {:?}, not SHA-256", signing_key.digest_algorithm());
+            return Err(CsrfError::InvalidKey);
+        }
+        Ok(MacCsrfProtection::new(HmacSha256Mac { signing_key: Arc::new(signing_key) }))
+    }
+
+    /// Return a `CsrfJwt` that wraps this protection's tokens in a JWS signed with a key derived
+    /// from its key.
+    #[cfg(feature = "jwt")]
+    pub fn jwt(&self) -> CsrfJwt {
+        CsrfJwt::from_signing_key(&self.mac.signing_key)
+    }
+
+    /// Like `parse_bound_token`, but the binding data is streamed into the MAC by `feed` instead
+    /// of being passed as one slice, so that a large binding, such as a request body, never has
+    /// to be held in memory. `feed` is called once with a sink, and must pass the binding data to
+    /// it in order, in pieces of any size.
+    ///
+    /// Only tokens in the current wire format are accepted. Cookies carry no binding data, so
+    /// they are parsed with `parse_cookie` as usual.
+    pub fn parse_bound_token_streaming<F>(&self, token: &[u8], mut feed: F) -> Result<UnencryptedCsrfToken, CsrfError>
+        where F: FnMut(&mut dyn FnMut(&[u8]))
+    {
+        self.config.check_algorithm(self.algorithm())?;
+        check_present(token, CsrfError::MissingToken)?;
+        check_algorithm(token, HMAC_TOKEN_LEN, HMAC_TOKEN_LEN, &BACKEND_TOKEN_LENS)?;
+        check_wire_version(token, HMAC_TOKEN_LEN)?;
+
+        let mut context = hmac::SigningContext::with_key(&self.token_mac().signing_key);
+        context.update(&token[0..73]);
+        feed(&mut |part| context.update(part));
+
+        if verify_slices_are_equal(context.sign().as_ref(), &token[73..HMAC_TOKEN_LEN]).is_err() {
+            info!("CSRF token had bad MAC");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let issued_at = check_issued_at(read_i64(token, 65)?, self.current_time()?)?;
+        Ok(UnencryptedCsrfToken::new(token[1..65].to_vec()).with_issued_at(issued_at).with_wire(token))
+    }
+}
+
+
+/// AES-256-GCM from `rust-crypto` with a 12 byte nonce, the cipher of `AesGcmCsrfProtection`.
+#[cfg(feature = "aes-gcm-backend")]
+pub struct AesGcmAead {
+    key: LockedKey,
+}
+
+#[cfg(feature = "aes-gcm-backend")]
+impl Clone for AesGcmAead {
+    fn clone(&self) -> Self {
+        AesGcmAead::from_key(*self.key)
+    }
+}
+
+#[cfg(feature = "aes-gcm-backend")]
+impl Aead for AesGcmAead {
+    fn from_key(key: [u8; 32]) -> Self {
+        AesGcmAead { key: LockedKey::new(key) }
+    }
+
+    fn nonce_len(&self) -> usize {
+        12
+    }
+
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8], ciphertext: &mut [u8], tag: &mut [u8; AEAD_TAG_LEN]) {
+        AesGcm::new(KeySize::KeySize256, &self.key[..], nonce, aad).encrypt(plaintext, ciphertext, tag);
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8; AEAD_TAG_LEN], plaintext: &mut [u8]) -> bool {
+        AesGcm::new(KeySize::KeySize256, &self.key[..], nonce, aad).decrypt(ciphertext, plaintext, tag)
+    }
+
+    fn key_id(&self) -> [u8; 8] {
+        key_id(&self.key)
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        Some(Algorithm::AesGcm)
+    }
+
+    fn seal_cipher(&self) -> Option<Self> {
+        Some(AesGcmAead::from_key(derive_seal_key(&self.key)))
+    }
+}
+
+/// Uses AES-GCM to provide signed, encrypted CSRF tokens and cookies.
+#[cfg(feature = "aes-gcm-backend")]
+pub type AesGcmCsrfProtection = AeadCsrfProtection<AesGcmAead>;
+
+#[cfg(feature = "aes-gcm-backend")]
+impl AeadCsrfProtection<AesGcmAead> {
+    /// Derive a new, independent `AesGcmCsrfProtection` whose key is bound to `context`.
+    ///
+    /// Tokens and cookies generated by a protection derived for one context can not be parsed by
+    /// a protection derived for any other context, or by this protection.
+    pub fn derive_subkey(&self, context: &[u8]) -> Self {
+        AesGcmCsrfProtection::from_key(derive_subkey(&self.aead.key, context))
+            .with_settings_of(self)
+            .with_split_keys(self.split_keys())
+    }
+
+    /// Set whether tokens and cookies are encrypted under separate keys, derived from this
+    /// protection's key with HKDF-SHA256, as described by `MacCsrfProtection::with_split_keys`.
+    pub fn with_split_keys(mut self, split: bool) -> Self {
+        self.split_keys = if split {
+            let (token_key, cookie_key) = derive_split_keys(&self.aead.key);
+            Some((AesGcmAead::from_key(token_key), AesGcmAead::from_key(cookie_key)))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Return a `CsrfJwt` that wraps this protection's tokens in a JWS signed with a key derived
+    /// from its key.
+    #[cfg(feature = "jwt")]
+    pub fn jwt(&self) -> CsrfJwt {
+        CsrfJwt::from_key(*self.aead.key)
+    }
+}
+
+
+/// The original ChaCha20Poly1305 construction from `rust-crypto` with an 8 byte nonce, the cipher
+/// of `ChaCha20Poly1305CsrfProtection`.
+#[cfg(feature = "chacha20-backend")]
+pub struct ChaCha20Poly1305Aead {
+    key: LockedKey,
+}
+
+#[cfg(feature = "chacha20-backend")]
+impl Clone for ChaCha20Poly1305Aead {
+    fn clone(&self) -> Self {
+        ChaCha20Poly1305Aead::from_key(*self.key)
+    }
+}
+
+#[cfg(feature = "chacha20-backend")]
+impl Aead for ChaCha20Poly1305Aead {
+    fn from_key(key: [u8; 32]) -> Self {
+        ChaCha20Poly1305Aead { key: LockedKey::new(key) }
+    }
+
+    fn nonce_len(&self) -> usize {
+        8
+    }
+
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8], ciphertext: &mut [u8], tag: &mut [u8; AEAD_TAG_LEN]) {
+        ChaCha20Poly1305::new(&self.key[..], nonce, aad).encrypt(plaintext, ciphertext, tag);
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8; AEAD_TAG_LEN], plaintext: &mut [u8]) -> bool {
+        ChaCha20Poly1305::new(&self.key[..], nonce, aad).decrypt(ciphertext, plaintext, tag)
+    }
+
+    fn key_id(&self) -> [u8; 8] {
+        key_id(&self.key)
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        Some(Algorithm::ChaCha20Poly1305)
+    }
+
+    fn seal_cipher(&self) -> Option<Self> {
+        Some(ChaCha20Poly1305Aead::from_key(derive_seal_key(&self.key)))
+    }
+}
+
+/// Uses ChaCha20Poly1305 to provide signed, encrypted CSRF tokens and cookies.
+#[cfg(feature = "chacha20-backend")]
+pub type ChaCha20Poly1305CsrfProtection = AeadCsrfProtection<ChaCha20Poly1305Aead>;
+
+#[cfg(feature = "chacha20-backend")]
+impl AeadCsrfProtection<ChaCha20Poly1305Aead> {
+    /// Derive a new, independent `ChaCha20Poly1305CsrfProtection` whose key is bound to `context`.
+    ///
+    /// Tokens and cookies generated by a protection derived for one context can not be parsed by
+    /// a protection derived for any other context, or by this protection.
+    pub fn derive_subkey(&self, context: &[u8]) -> Self {
+        ChaCha20Poly1305CsrfProtection::from_key(derive_subkey(&self.aead.key, context))
+            .with_settings_of(self)
+            .with_split_keys(self.split_keys())
+    }
+
+    /// Set whether tokens and cookies are encrypted under separate keys, derived from this
+    /// protection's key with HKDF-SHA256, as described by `MacCsrfProtection::with_split_keys`.
+    pub fn with_split_keys(mut self, split: bool) -> Self {
+        self.split_keys = if split {
+            let (token_key, cookie_key) = derive_split_keys(&self.aead.key);
+            Some((ChaCha20Poly1305Aead::from_key(token_key), ChaCha20Poly1305Aead::from_key(cookie_key)))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Return a `CsrfJwt` that wraps this protection's tokens in a JWS signed with a key derived
+    /// from its key.
+    #[cfg(feature = "jwt")]
+    pub fn jwt(&self) -> CsrfJwt {
+        CsrfJwt::from_key(*self.aead.key)
+    }
+}
+
+
+/// **INSECURE.** A protection that does no cryptography at all, for fast and deterministic
+/// integration tests of code that uses this crate. It is only available with the `test-util`
+/// feature, which must never be enabled outside of `[dev-dependencies]`.
+///
+/// Tokens and cookies are the plain version 1 layout with no MAC and no encryption, so anyone can
+/// forge them:
+///
+/// * token: `0x01 ‖ value ‖ issued_at ‖ binding`
+/// * cookie: `0x01 ‖ value ‖ issued_at ‖ expires`
+///
+/// Parsing still checks the length, version byte and binding, and verification still checks that
+/// the token matches the cookie and that neither has expired, so the middleware under test sees
+/// the same errors as with a real backend.
+#[cfg(feature = "test-util")]
+pub struct InsecureTestCsrfProtection {
+    rng: SystemRandom,
+    config: CsrfConfig,
+    token_values: Option<Arc<dyn TokenValueSource>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+#[cfg(feature = "test-util")]
+impl InsecureTestCsrfProtection {
+    /// Return an `InsecureTestCsrfProtection`. There is no key.
+    pub fn new() -> Self {
+        InsecureTestCsrfProtection {
+            rng: SystemRandom::new(),
+            config: CsrfConfig::default(),
+            token_values: None,
+            clock: None,
+        }
+    }
+
+    /// Replace the configuration of this protection.
+    pub fn with_config(mut self, config: CsrfConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retrieve the configuration of this protection.
+    pub fn config(&self) -> &CsrfConfig {
+        &self.config
+    }
+
+    /// Draw new token values from `source` instead of the random number generator, so that a test
+    /// can know the values it will be handed. The source is shared with clones of this protection.
+    pub fn with_token_value_source<S: TokenValueSource + 'static>(mut self, source: S) -> Self {
+        self.token_values = Some(Arc::new(source));
+        self
+    }
+
+    /// Read the current time from `clock` instead of the system clock, when generating cookies and
+    /// checking expiry. The clock is shared like a token value source.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Replace the configuration, token value source and clock of this protection with those of
+    /// `other`.
+    fn with_settings_of(mut self, other: &Self) -> Self {
+        self.config = other.config.clone();
+        self.token_values = other.token_values.clone();
+        self.clock = other.clock.clone();
+        self
+    }
+
+    fn encode_cookie(&self, token_value: &[u8; 64], issued_at: i64, expires: i64) -> CsrfCookie {
+        let mut transport = Vec::with_capacity(81);
+        transport.push(WIRE_VERSION);
+        transport.extend_from_slice(token_value);
+        transport.extend_from_slice(&issued_at.to_le_bytes());
+        transport.extend_from_slice(&expires.to_le_bytes());
+        CsrfCookie::new(transport)
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for InsecureTestCsrfProtection {
+    fn default() -> Self {
+        InsecureTestCsrfProtection::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clone for InsecureTestCsrfProtection {
+    fn clone(&self) -> Self {
+        InsecureTestCsrfProtection::new().with_settings_of(self)
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl CsrfProtection for InsecureTestCsrfProtection {
+    /// Ignore the password and salt and return `InsecureTestCsrfProtection::new()`.
+    fn from_password_and_salt(_password: &[u8], _salt: &[u8]) -> Self {
+        InsecureTestCsrfProtection::new()
+    }
+
+    fn clone_box(&self) -> Box<dyn CsrfProtection> {
+        Box::new(self.clone())
+    }
+
+    /// There is no key, so this is always all zeros.
+    fn key_id(&self) -> [u8; 8] {
+        [0; 8]
+    }
+
+    fn security_parameters(&self) -> SecurityParameters {
+        SecurityParameters::unauthenticated().with_token_value_bits(self.token_value_bits())
+    }
+
+    fn rng(&self) -> &SystemRandom {
+        &self.rng
+    }
+
+    fn rng_retries(&self) -> u32 {
+        self.config.rng_retries()
+    }
+
+    fn require_secure_transport(&self) -> bool {
+        self.config.require_secure_transport()
+    }
+
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        (self.config.min_ttl(), self.config.max_ttl())
+    }
+
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        self.token_values.as_deref()
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        self.clock.as_deref()
+    }
+
+    fn fail_closed_clock(&self) -> bool {
+        self.config.fail_closed_clock()
+    }
+
+    fn encoding(&self) -> TokenEncoding {
+        self.config.encoding()
+    }
+
+    /// The length of an unbound token. A bound token is longer by the length of its binding.
+    fn expected_token_len(&self) -> usize {
+        73
+    }
+
+    fn expected_cookie_len(&self) -> usize {
+        81
+    }
+
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        Ok(self.encode_cookie(token_value, now, now.saturating_add(ttl_seconds)))
+    }
+
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        let ttl_seconds = self.config.check_ttl(ttl_seconds)?;
+        let now = self.current_time()?;
+        let token_value = cookie_token_value(cookie)?;
+        Ok(self.encode_cookie(&token_value, cookie.issued_at.unwrap_or(now), now.saturating_add(ttl_seconds)))
+    }
+
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+        let mut transport = Vec::with_capacity(73 + binding.len());
+        transport.push(WIRE_VERSION);
+        transport.extend_from_slice(token_value);
+        transport.extend_from_slice(&self.current_time()?.to_le_bytes());
+        transport.extend_from_slice(binding);
+        Ok(CsrfToken::new(transport))
+    }
+
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        check_present(cookie, CsrfError::MissingCookie)?;
+        check_algorithm(cookie, self.expected_cookie_len(), self.expected_cookie_len(), &BACKEND_COOKIE_LENS)?;
+        check_wire_version(cookie, 81)?;
+
+        let issued_at = check_issued_at(read_i64(cookie, 65)?, now)?;
+        Ok(UnencryptedCsrfCookie::new(read_i64(cookie, 73)?, cookie[1..65].to_vec())
+            .with_issued_at(issued_at)
+            .with_wire(cookie))
+    }
+
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        check_present(token, CsrfError::MissingToken)?;
+        check_algorithm(token, 73 + binding.len(), 73 + binding.len(), &BACKEND_TOKEN_LENS)?;
+        check_wire_version(token, 73 + binding.len())?;
+
+        if &token[73..] != binding {
+            info!("CSRF token had the wrong binding");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let issued_at = check_issued_at(read_i64(token, 65)?, self.current_time()?)?;
+        Ok(UnencryptedCsrfToken::new(token[1..65].to_vec()).with_issued_at(issued_at).with_wire(token))
+    }
+}
+
+
+#[cfg(feature = "iron")]
+impl typemap::Key for CsrfToken {
+    type Value = CsrfToken;
+}
+
+
+#[cfg(test)]
+mod tests {
+    // TODO write test that ensures encrypted messages don't contain the plaintext
+    // TODO test that checks tokens are repeated when given Some
+    // TODO use macros for writing all of these
+
+    #[cfg(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    macro_rules! test_cases {
+        ($strct: ident, $md: ident) => {
+            mod $md {
+                use $crate::core::{unix_time, CsrfError, CsrfProtection, UnencryptedCsrfCookie,
+                                   UnencryptedCsrfToken, MAX_ENCODED_LEN, SCRYPT_SALT, $strct};
+                use data_encoding::{BASE64, BASE64URL};
+                use $crate::test_util::KEY_32;
+                use $crate::wire::WIRE_VERSION;
+
+                #[test]
+                fn from_password_and_salt() {
+                    let default = $strct::from_password(b"correct horse battery staple");
+                    let same = $strct::from_password_and_salt(b"correct horse battery staple", SCRYPT_SALT);
+                    let salted = $strct::from_password_and_salt(b"correct horse battery staple", b"other salt");
+
+                    let token = default.generate_token(&[7; 64]).expect("couldn't generate token");
+                    assert!(same.parse_token(token.value()).is_ok());
+                    assert!(salted.parse_token(token.value()).is_err());
+                }
+
+                #[test]
+                fn from_password() {
+                    let _ = $strct::from_password(b"correct horse battery staple");
+                }
+
+                #[test]
+                fn verification_succeeds() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    let token = protect.parse_token(&token).expect("token not parsed");
+                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
+                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
+                    assert!(protect.verify_token_pair(&token, &cookie),
+                            "could not verify token/cookie pair");
+                }
+
+                #[test]
+                fn encoded_pair_verifies() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_encoded_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (url_token, url_cookie) = protect.generate_encoded_pair_url_safe(Some(&[7; 64]), 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert!(!url_token.contains(['+', '/']));
+                    assert!(!url_cookie.contains(['+', '/']));
+
+                    for (token, cookie) in [(token, cookie), (url_token, url_cookie)].iter() {
+                        let token_bytes = BASE64.decode(token.as_bytes())
+                            .or_else(|_| BASE64URL.decode(token.as_bytes()))
+                            .expect("token not base64");
+                        let token = protect.parse_token(&token_bytes).expect("token not parsed");
+                        let cookie = protect.parse_cookie_b64(cookie).expect("cookie not parsed");
+                        assert!(protect.verify_token_pair(&token, &cookie));
+                    }
+                }
+
+                #[test]
+                fn modified_cookie_sig_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (_, mut cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let cookie_len = cookie.bytes.len();
+                    cookie.bytes[cookie_len - 1] ^= 0x01;
+                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
+                    assert!(protect.parse_cookie(&cookie).is_err());
+                }
+
+                #[test]
+                fn modified_cookie_value_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (_, mut cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    cookie.bytes[0] ^= 0x01;
+                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
+                    assert!(protect.parse_cookie(&cookie).is_err());
+                }
+
+                #[test]
+                fn modified_token_sig_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (mut token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/token pair");
+                    let token_len = token.bytes.len();
+                    token.bytes[token_len - 1] ^= 0x01;
+                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    assert!(protect.parse_token(&token).is_err());
+                }
+
+                #[test]
+                fn modified_token_value_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (mut token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/token pair");
+                    token.bytes[0] ^= 0x01;
+                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    assert!(protect.parse_token(&token).is_err());
+                }
+
+                #[test]
+                fn wrong_length_cookie_is_malformed() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (_, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let mut cookie = cookie.bytes.clone();
+                    cookie.push(0);
+                    assert_eq!(protect.parse_cookie(&cookie), Err(CsrfError::MalformedLength));
+                    cookie.truncate(cookie.len() - 2);
+                    assert_eq!(protect.parse_cookie(&cookie), Err(CsrfError::MalformedLength));
+                }
+
+                #[test]
+                fn wrong_length_token_is_malformed() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let mut token = token.bytes.clone();
+                    token.push(0);
+                    assert_eq!(protect.parse_token(&token), Err(CsrfError::MalformedLength));
+                    token.truncate(token.len() - 2);
+                    assert_eq!(protect.parse_token(&token), Err(CsrfError::MalformedLength));
+                }
+
+                #[test]
+                fn tampered_cookie_tag_fails_validation() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (_, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    // drop part of the tag and pad the end so the total length still matches
+                    let mut cookie = cookie.bytes.clone();
+                    let cookie_len = cookie.len();
+                    cookie.drain(cookie_len - 20..cookie_len - 16);
+                    cookie.extend_from_slice(&[0; 4]);
+                    assert_eq!(cookie.len(), cookie_len);
+                    assert_eq!(protect.parse_cookie(&cookie), Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn tampered_token_tag_fails_validation() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    // drop part of the tag and pad the end so the total length still matches
+                    let mut token = token.bytes.clone();
+                    let token_len = token.len();
+                    token.drain(token_len - 20..token_len - 16);
+                    token.extend_from_slice(&[0; 4]);
+                    assert_eq!(token.len(), token_len);
+                    assert_eq!(protect.parse_token(&token), Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn mismatched_cookie_token_fail() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/token pair");
+                    let (_, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/token pair");
+
+                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    let token = protect.parse_token(&token).expect("token not parsed");
+                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
+                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
+                    assert!(!protect.verify_token_pair(&token, &cookie),
+                            "verified token/cookie pair when failure expected");
+                }
+
+                #[test]
+                fn subkeys_are_independent() {
+                    let protect = $strct::from_key(KEY_32);
+                    let admin = protect.derive_subkey(b"admin");
+                    let public = protect.derive_subkey(b"public");
+
+                    for &(issuer, others) in [(&admin, [&public, &protect]),
+                                              (&public, [&admin, &protect])].iter() {
+                        let (token, cookie) = issuer.generate_token_pair(None, 300)
+                            .expect("couldn't generate token/cookie pair");
+                        let parsed_token = issuer.parse_token(token.value()).expect("token not parsed");
+                        let parsed_cookie = issuer.parse_cookie(cookie.value()).expect("cookie not parsed");
+                        assert!(issuer.verify_token_pair(&parsed_token, &parsed_cookie));
+
+                        for other in others.iter() {
+                            assert!(other.parse_token(token.value()).is_err());
+                            assert!(other.parse_cookie(cookie.value()).is_err());
+                        }
+                    }
+                }
+
+                #[test]
+                fn subkeys_are_deterministic() {
+                    let first = $strct::from_key(KEY_32).derive_subkey(b"admin");
+                    let second = $strct::from_key(KEY_32).derive_subkey(b"admin");
+                    let (token, cookie) = first.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert!(second.parse_token(token.value()).is_ok());
+                    assert!(second.parse_cookie(cookie.value()).is_ok());
+                }
+
+                #[test]
+                fn route_bound_token_verifies_for_route() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_for(None, "POST", "/account/delete", 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.parse_and_verify_for(token.value(), cookie.value(), "POST", "/account/delete"),
+                               Ok(()));
+                }
+
+                #[test]
+                fn route_bound_token_fails_for_other_route() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_for(None, "POST", "/account/delete", 300)
+                        .expect("couldn't generate token/cookie pair");
+                    for &(method, path) in [("PUT", "/account/delete"),
+                                            ("POST", "/account/update"),
+                                            ("POS", "T/account/delete")].iter() {
+                        assert_eq!(protect.parse_and_verify_for(token.value(), cookie.value(), method, path),
+                                   Err(CsrfError::ValidationFailure));
+                    }
+                    assert!(protect.parse_token(token.value()).is_err());
+                }
+
+                #[test]
+                fn unbound_token_fails_for_route() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.parse_and_verify_for(token.value(), cookie.value(), "POST", "/"),
+                               Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn issued_at_round_trips() {
+                    let protect = $strct::from_key(KEY_32);
+                    let before = unix_time();
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let after = unix_time();
+
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    for issued_at in [token.issued_at(), cookie.issued_at()].iter() {
+                        let issued_at = issued_at.expect("issued at not persisted");
+                        assert!(before <= issued_at && issued_at <= after);
+                    }
+                    assert!(protect.verify_token_pair(&token, &cookie));
+                }
+
+                #[test]
+                fn expiry_after_2038_round_trips() {
+                    let protect = $strct::from_key(KEY_32);
+                    // 2100-01-01T00:00:00Z, well past the last second an `i32` can hold.
+                    let now = 4_102_444_800;
+                    let ttl = 10 * 365 * 24 * 60 * 60;
+                    let encoded = protect.generate_cookie_at(&[7; 64], ttl, now).expect("couldn't generate cookie");
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+
+                    let cookie = protect.parse_cookie_at(encoded.value(), now).expect("cookie not parsed");
+                    assert_eq!(cookie.issued_at(), Some(now));
+                    assert_eq!(cookie.expires(), now + ttl);
+                    assert!(cookie.expires() > i64::from(u32::MAX));
+                    assert_eq!(protect.verify_raw_at(token.value(), encoded.value(), now + ttl - 1), Ok(()));
+                    assert_eq!(protect.verify_raw_at(token.value(), encoded.value(), now + ttl),
+                               Err(CsrfError::Expired));
+
+                    let long_lived = protect.generate_cookie(&[7; 64], i64::from(i32::MAX))
+                        .expect("couldn't generate cookie");
+                    let long_lived = protect.parse_cookie(long_lived.value()).expect("cookie not parsed");
+                    assert!(long_lived.expires() > i64::from(i32::MAX));
+
+                    let unbounded = protect.generate_cookie_at(&[7; 64], i64::MAX, now).expect("couldn't generate cookie");
+                    let unbounded = protect.parse_cookie_at(unbounded.value(), now).expect("cookie not parsed");
+                    assert_eq!(unbounded.expires(), i64::MAX);
+                }
+
+                #[test]
+                fn verify_token_only() {
+                    let protect = $strct::from_key(KEY_32);
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let parsed = protect.verify_token_only(&token.b64_string(), 300).expect("token not verified");
+                    assert_eq!(parsed.value(), &[7; 64][..]);
+
+                    let mut tampered = token.value().to_vec();
+                    tampered[1] ^= 0x01;
+                    assert_eq!(protect.verify_token_only(&BASE64.encode(&tampered), 300).err(),
+                               Some(CsrfError::ValidationFailure));
+
+                    let aged = protect.seal_token(&[7; 64], &[], unix_time() - 600).expect("couldn't seal token");
+                    assert_eq!(protect.verify_token_only(&aged.b64_string(), 300).err(), Some(CsrfError::Expired));
+                    assert!(protect.verify_token_only(&aged.b64_string(), 3600).is_ok());
+                }
+
+                #[test]
+                fn future_issued_at_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let value = [7; 64];
+                    let issued_at = unix_time() + 3600;
+
+                    let token = protect.seal_token(&value, &[], issued_at).expect("couldn't seal token");
+                    assert_eq!(protect.parse_token(token.value()).map(|_| ()),
+                               Err(CsrfError::ValidationFailure));
+
+                    let cookie = protect.seal_cookie(&value, issued_at, issued_at + 300)
+                        .expect("couldn't seal cookie");
+                    assert_eq!(protect.parse_cookie(cookie.value()).map(|_| ()),
+                               Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn refresh_keeps_issued_at() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let cookie = protect.seal_cookie(&[7; 64], now - 600, now + 10)
+                        .expect("couldn't seal cookie");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    let refreshed = protect.refresh_cookie(&cookie, 300).expect("couldn't refresh cookie");
+                    let refreshed = protect.parse_cookie(refreshed.value()).expect("cookie not parsed");
+                    assert_eq!(refreshed.issued_at(), Some(now - 600));
+                    assert_eq!(refreshed.value(), cookie.value());
+                    assert!(refreshed.expires() >= now + 300);
+                }
+
+                #[test]
+                fn refreshed_cookie_within_absolute_max() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.seal_cookie(&[7; 64], now - 600, now + 10)
+                        .expect("couldn't seal cookie");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    let refreshed = protect.refresh_cookie(&cookie, 300).expect("couldn't refresh cookie");
+                    let refreshed = protect.parse_cookie(refreshed.value()).expect("cookie not parsed");
+                    assert!(protect.verify_token_pair_with_absolute_max(&token, &refreshed, 3600));
+                }
+
+                #[test]
+                fn refreshed_cookie_past_absolute_max_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.seal_cookie(&[7; 64], now - 7200, now + 10)
+                        .expect("couldn't seal cookie");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    let refreshed = protect.refresh_cookie(&cookie, 300).expect("couldn't refresh cookie");
+                    let refreshed = protect.parse_cookie(refreshed.value()).expect("cookie not parsed");
+                    assert!(protect.verify_token_pair(&token, &refreshed));
+                    assert!(!protect.verify_token_pair_with_absolute_max(&token, &refreshed, 3600));
+                }
+
+                #[test]
+                fn pair_within_issuance_gap() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let token = protect.seal_token(&[7; 64], b"", now - 60).expect("couldn't seal token");
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.seal_cookie(&[7; 64], now - 360, now + 300)
+                        .expect("couldn't seal cookie");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    assert!(protect.verify_token_pair_with_max_issuance_gap(&token, &cookie, 300));
+                    assert!(protect.verify_token_pair_with_max_issuance_gap(&token, &cookie, 3600));
+                }
+
+                #[test]
+                fn pair_beyond_issuance_gap_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let token = protect.seal_token(&[7; 64], b"", now).expect("couldn't seal token");
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.seal_cookie(&[7; 64], now - 7200, now + 300)
+                        .expect("couldn't seal cookie");
+                    let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    assert!(protect.verify_token_pair(&token, &cookie));
+                    assert!(!protect.verify_token_pair_with_max_issuance_gap(&token, &cookie, 3600));
+
+                    // the gap is symmetric, so a token older than its cookie is held to it too
+                    let old_token = protect.seal_token(&[7; 64], b"", now - 7200).expect("couldn't seal token");
+                    let old_token = protect.parse_token(old_token.value()).expect("token not parsed");
+                    let new_cookie = protect.seal_cookie(&[7; 64], now, now + 300).expect("couldn't seal cookie");
+                    let new_cookie = protect.parse_cookie(new_cookie.value()).expect("cookie not parsed");
+                    assert!(!protect.verify_token_pair_with_max_issuance_gap(&old_token, &new_cookie, 3600));
+                }
+
+                #[test]
+                fn raw_issuance_gap() {
+                    let protect = $strct::from_key(KEY_32);
+                    let now = unix_time();
+                    let token = protect.seal_token(&[7; 64], b"", now - 60).expect("couldn't seal token");
+                    let short_cookie = protect.seal_cookie(&[7; 64], now - 120, now + 300).expect("couldn't seal cookie");
+                    let long_cookie = protect.seal_cookie(&[7; 64], now - 86400, now + 86400).expect("couldn't seal cookie");
+                    let later_cookie = protect.seal_cookie(&[7; 64], now, now + 86400).expect("couldn't seal cookie");
+
+                    assert_eq!(protect.verify_raw_with_max_issuance_gap(token.value(), short_cookie.value(), 300), Ok(()));
+                    assert_eq!(protect.verify_raw_with_max_issuance_gap(token.value(), later_cookie.value(), 300), Ok(()));
+                    assert_eq!(protect.verify_raw(token.value(), long_cookie.value()), Ok(()));
+                    assert_eq!(protect.verify_raw_with_max_issuance_gap(token.value(), long_cookie.value(), 300),
+                               Err(CsrfError::IssuanceMismatch));
+
+                    // the other checks come first
+                    let expired = protect.seal_cookie(&[7; 64], now - 86400, now - 1).expect("couldn't seal cookie");
+                    assert_eq!(protect.verify_raw_with_max_issuance_gap(token.value(), expired.value(), 300),
+                               Err(CsrfError::Expired));
+                    let other = protect.seal_cookie(&[8; 64], now - 86400, now + 300).expect("couldn't seal cookie");
+                    assert_eq!(protect.verify_raw_with_max_issuance_gap(token.value(), other.value(), 300),
+                               Err(CsrfError::TokenMismatch));
+                }
+
+                mod properties {
+                    use proptest::collection::vec;
+                    use proptest::prelude::*;
+                    use proptest::sample::Index;
+
+                    use $crate::core::{CsrfProtection, $strct};
+
+                    fn protection(key: Vec<u8>) -> $strct {
+                        let mut key_32 = [0; 32];
+                        key_32.copy_from_slice(&key);
+                        $strct::from_key(key_32)
+                    }
+
+                    fn token_value(value: Vec<u8>) -> [u8; 64] {
+                        let mut value_64 = [0; 64];
+                        value_64.copy_from_slice(&value);
+                        value_64
+                    }
+
+                    proptest! {
+                        #![proptest_config(ProptestConfig::with_cases(64))]
+
+                        #[test]
+                        fn untampered_pair_verifies(key in vec(any::<u8>(), 32),
+                                                    value in vec(any::<u8>(), 64),
+                                                    ttl in 1i64..(1 << 32)) {
+                            let protect = protection(key);
+                            let value = token_value(value);
+                            let (token, cookie) = protect.generate_token_pair(Some(&value), ttl)
+                                .expect("couldn't generate token/cookie pair");
+                            let token = protect.parse_token(token.value()).expect("token not parsed");
+                            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                            prop_assert_eq!(token.value(), &value[..]);
+                            prop_assert_eq!(cookie.value(), &value[..]);
+                            prop_assert!(protect.verify_token_pair(&token, &cookie));
+                        }
+
+                        #[test]
+                        fn tampered_token_fails(key in vec(any::<u8>(), 32),
+                                                value in vec(any::<u8>(), 64),
+                                                index in any::<Index>(),
+                                                bit in 0u8..8) {
+                            let protect = protection(key);
+                            let value = token_value(value);
+                            let (token, cookie) = protect.generate_token_pair(Some(&value), 300)
+                                .expect("couldn't generate token/cookie pair");
+
+                            let mut token = token.value().to_vec();
+                            let i = index.index(token.len());
+                            token[i] ^= 1 << bit;
+                            prop_assert!(protect.verify_raw(&token, cookie.value()).is_err());
+                        }
+
+                        #[test]
+                        fn tampered_cookie_fails(key in vec(any::<u8>(), 32),
+                                                 value in vec(any::<u8>(), 64),
+                                                 index in any::<Index>(),
+                                                 bit in 0u8..8) {
+                            let protect = protection(key);
+                            let value = token_value(value);
+                            let (token, cookie) = protect.generate_token_pair(Some(&value), 300)
+                                .expect("couldn't generate token/cookie pair");
+
+                            let mut cookie = cookie.value().to_vec();
+                            let i = index.index(cookie.len());
+                            cookie[i] ^= 1 << bit;
+                            prop_assert!(protect.verify_raw(token.value(), &cookie).is_err());
+                        }
+                    }
+                }
+
+                #[test]
+                fn nonce_bytes_equal_value() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    assert_eq!(&token.nonce_bytes()[..], token.value());
+                }
+
+                #[test]
+                fn wire_version() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    assert_eq!(protect.wire_version(), WIRE_VERSION);
+                    assert_eq!(token.value()[0], protect.wire_version());
+                    assert_eq!(cookie.value()[0], protect.wire_version());
+                }
+
+                #[test]
+                fn missing_vs_invalid() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    assert_eq!(protect.parse_cookie(&[]).map(|_| ()), Err(CsrfError::MissingCookie));
+                    assert_eq!(protect.parse_token(&[]).map(|_| ()), Err(CsrfError::MissingToken));
+                    assert_eq!(protect.parse_cookie_b64("").map(|_| ()), Err(CsrfError::MissingCookie));
+                    assert_eq!(protect.parse_token_b64("").map(|_| ()), Err(CsrfError::MissingToken));
+
+                    let mut cookie = cookie.value().to_vec();
+                    let mut token = token.value().to_vec();
+                    let (cookie_len, token_len) = (cookie.len(), token.len());
+                    cookie[cookie_len - 1] ^= 0x01;
+                    token[token_len - 1] ^= 0x01;
+                    assert_eq!(protect.parse_cookie(&cookie).map(|_| ()), Err(CsrfError::ValidationFailure));
+                    assert_eq!(protect.parse_token(&token).map(|_| ()), Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn parse_b64() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let parsed_token = protect.parse_token_b64(&token.b64_url_string()).expect("token not parsed");
+                    let parsed_cookie = protect.parse_cookie_b64(&cookie.b64_string()).expect("cookie not parsed");
+                    assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+
+                    assert_eq!(protect.parse_token_b64("not base64!").map(|_| ()), Err(CsrfError::DecodeError));
+                }
+
+                #[test]
+                fn parse_b64_ignores_surrounding_whitespace() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (token_b64, cookie_b64) = (token.b64_string(), cookie.b64_string());
+                    let token_nopad = token.b64_url_nopad_string();
+
+                    for &(before, after) in &[("  ", ""), ("", "  "), ("\t", " \r\n"), ("", "\n")] {
+                        let token = format!("{}{}{}", before, token_b64, after);
+                        let cookie = format!("{}{}{}", before, cookie_b64, after);
+                        let parsed_token = protect.parse_token_b64(&token).expect("token not parsed");
+                        let parsed_cookie = protect.parse_cookie_b64(&cookie).expect("cookie not parsed");
+                        assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+                        let nopad = format!("{}{}{}", before, token_nopad, after);
+                        assert!(protect.parse_token_b64url_nopad(&nopad).is_ok());
+                    }
+
+                    let mid = token_b64.len() / 2;
+                    for garbage in &[" ", "\n", "!", "\u{a0}"] {
+                        let token = format!("{}{}{}", &token_b64[..mid], garbage, &token_b64[mid..]);
+                        assert_eq!(protect.parse_token_b64(&token).map(|_| ()), Err(CsrfError::DecodeError));
+                    }
+                    assert_eq!(protect.parse_cookie_b64(&format!("\u{a0}{}", cookie_b64)).map(|_| ()),
+                               Err(CsrfError::DecodeError));
+                    assert_eq!(protect.parse_cookie_b64(" \n").map(|_| ()), Err(CsrfError::MissingCookie));
+                }
+
+                #[test]
+                fn parse_b64url_nopad() {
+                    use core::CsrfToken;
+
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    for encoded in [token.b64_url_nopad_string(), token.b64_url_string()].iter() {
+                        let parsed_token = protect.parse_token_b64url_nopad(encoded).expect("token not parsed");
+                        assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+                    }
+
+                    for len in 62..65 {
+                        let encoded = CsrfToken::new(vec![0xfb; len]).b64_url_nopad_string();
+                        assert!(!encoded.contains('='), "{}", encoded);
+                        assert!(!encoded.contains('+') && !encoded.contains('/'), "{}", encoded);
+                    }
+
+                    assert_eq!(protect.parse_token_b64url_nopad("").map(|_| ()), Err(CsrfError::MissingToken));
+                    assert_eq!(protect.parse_token_b64url_nopad("not base64!").map(|_| ()), Err(CsrfError::DecodeError));
+                    let huge = "A".repeat(1 << 20);
+                    assert_eq!(protect.parse_token_b64url_nopad(&huge).map(|_| ()), Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn parse_b64_rejects_huge_input() {
+                    let protect = $strct::from_key(KEY_32);
+                    let huge = "A".repeat(1 << 20);
+                    assert_eq!(protect.parse_token_b64(&huge).map(|_| ()), Err(CsrfError::ValidationFailure));
+                    assert_eq!(protect.parse_cookie_b64(&huge).map(|_| ()), Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn no_panic_on_any_length() {
+                    let protect = $strct::from_key(KEY_32);
+                    for len in 0..MAX_ENCODED_LEN {
+                        for &fill in [0x00, 0x01, 0xff].iter() {
+                            let input = vec![fill; len];
+                            assert!(protect.parse_token(&input).is_err());
+                            assert!(protect.parse_cookie(&input).is_err());
+                        }
+                    }
+                }
+
+                #[test]
+                fn raw_wire_round_trip() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+                    let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    assert_eq!(parsed_token.raw_wire(), Some(token.value()));
+                    assert_eq!(parsed_cookie.raw_wire(), Some(cookie.value()));
+                    assert_eq!(parsed_token.reencode(), Some(token));
+                    assert_eq!(parsed_cookie.reencode(), Some(cookie));
+
+                    assert_eq!(UnencryptedCsrfToken::new(vec![0; 64]).raw_wire(), None);
+                    assert_eq!(UnencryptedCsrfCookie::new(0, vec![0; 64]).reencode(), None);
+                }
+
+                #[test]
+                fn fingerprint_survives_reissue() {
+                    let protect = $strct::from_key(KEY_32);
+                    let first = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let second = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let other = protect.generate_token(&[8; 64]).expect("couldn't generate token");
+
+                    let fingerprint = protect.parse_token(first.value()).expect("token not parsed").fingerprint();
+                    assert_eq!(protect.parse_token(first.value()).expect("token not parsed").fingerprint(), fingerprint);
+                    assert_eq!(protect.parse_token(second.value()).expect("token not parsed").fingerprint(), fingerprint);
+                    assert_ne!(protect.parse_token(other.value()).expect("token not parsed").fingerprint(), fingerprint);
+                }
+
+                #[test]
+                fn key_id_stable_and_distinct() {
+                    let key_id = $strct::from_key(KEY_32).key_id();
+                    assert_eq!($strct::from_key(KEY_32).key_id(), key_id);
+                    assert_eq!($strct::from_key(KEY_32).clone_box().key_id(), key_id);
+                    assert_ne!($strct::from_key([1; 32]).key_id(), key_id);
+                    assert_ne!(&key_id[..], &KEY_32[..8]);
+                }
+
+                #[test]
+                fn verify_header_or_form() {
+                    use core::TokenPrecedence;
+
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (other, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (token, other, cookie) = (token.b64_string(), other.b64_string(), cookie.b64_string());
+
+                    for &precedence in &[TokenPrecedence::Header, TokenPrecedence::Form] {
+                        assert_eq!(protect.verify_header_or_form(Some(&token), None, &cookie, precedence), Ok(()));
+                        assert_eq!(protect.verify_header_or_form(None, Some(&token), &cookie, precedence), Ok(()));
+                        assert_eq!(protect.verify_header_or_form(Some(""), Some(&token), &cookie, precedence), Ok(()));
+                        assert_eq!(protect.verify_header_or_form(None, None, &cookie, precedence),
+                                   Err(CsrfError::MissingToken));
+                    }
+
+                    assert_eq!(protect.verify_header_or_form(Some(&token), Some(&other), &cookie, TokenPrecedence::Header),
+                               Ok(()));
+                    assert_eq!(protect.verify_header_or_form(Some(&token), Some(&other), &cookie, TokenPrecedence::Form),
+                               Err(CsrfError::TokenMismatch));
+                }
+
+                #[test]
+                fn from_secret() {
+                    let secret = [0x42; 48];
+                    let protect = $strct::from_secret(&secret);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let same = $strct::from_secret(&secret);
+                    let token_parsed = same.parse_token(token.value()).expect("token not parsed");
+                    let cookie_parsed = same.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    assert!(same.verify_token_pair(&token_parsed, &cookie_parsed));
+
+                    let other = $strct::from_secret(&[0x43; 48]);
+                    assert!(other.parse_token(token.value()).is_err());
+                    assert!(other.parse_cookie(cookie.value()).is_err());
+
+                    // the secret is not used as the key directly
+                    let token = $strct::from_secret(&KEY_32).generate_token(&[7; 64])
+                        .expect("couldn't generate token");
+                    assert!($strct::from_key(KEY_32).parse_token(token.value()).is_err());
+                }
+
+                #[test]
+                fn key_conversions() {
+                    use std::convert::TryFrom;
+
+                    let protect = $strct::from(KEY_32);
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+
+                    let from_slice = $strct::try_from(&KEY_32[..]).expect("key not accepted");
+                    assert!(from_slice.parse_token(token.value()).is_ok());
+
+                    assert_eq!($strct::try_from(&KEY_32[..31]).err(), Some(CsrfError::InvalidKey));
+                    assert_eq!($strct::try_from(&[0; 33][..]).err(), Some(CsrfError::InvalidKey));
+                    assert_eq!($strct::try_from(&[][..]).err(), Some(CsrfError::InvalidKey));
+                }
+
+                #[test]
+                fn clone_box() {
+                    let boxed: Box<dyn CsrfProtection> = Box::new($strct::from_key(KEY_32));
+                    let clone = boxed.clone();
+                    assert_eq!(clone.expected_token_len(), boxed.expected_token_len());
+
+                    let (token, cookie) = boxed.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let token = clone.parse_token(token.value()).expect("token not parsed");
+                    let cookie = clone.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    assert!(clone.verify_token_pair(&token, &cookie));
+                }
+
+                #[test]
+                fn as_ref_bytes() {
+                    fn bytes(value: impl AsRef<[u8]>) -> Vec<u8> {
+                        value.as_ref().to_vec()
+                    }
+
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(bytes(&token), token.value());
+                    assert_eq!(bytes(&cookie), cookie.value());
+
+                    let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+                    let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    assert_eq!(bytes(&parsed_token), parsed_token.value());
+                    assert_eq!(bytes(&parsed_cookie), parsed_cookie.value());
+                    assert_eq!(bytes(&parsed_token), bytes(&parsed_cookie));
+                }
+
+                #[test]
+                fn token_pairs_distinct_and_valid() {
+                    use std::collections::HashSet;
+
+                    let protect = $strct::from_key(KEY_32);
+                    let pairs = protect.token_pairs(300).take(100)
+                        .collect::<Result<Vec<_>, _>>()
+                        .expect("couldn't generate token/cookie pairs");
+                    assert_eq!(pairs.len(), 100);
+
+                    let mut values = HashSet::new();
+                    for &(ref token, ref cookie) in pairs.iter() {
+                        let token = protect.parse_token(token.value()).expect("token not parsed");
+                        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                        assert!(protect.verify_token_pair(&token, &cookie));
+                        assert!(values.insert(token.value().to_vec()));
+                    }
+                }
+
+                #[test]
+                fn clone_shares_key() {
+                    let protect = $strct::from_key(KEY_32);
+                    let clone = protect.clone();
+                    let (token, cookie) = clone.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+
+                    let worker = ::std::thread::spawn(move || {
+                        clone.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair")
+                    });
+                    let (token, cookie) = worker.join().expect("worker panicked");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+                }
+
+                #[test]
+                fn expected_lengths() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.expected_token_len(), token.value().len());
+                    assert_eq!(protect.expected_cookie_len(), cookie.value().len());
+
+                    let mut token = token.value().to_vec();
+                    token.push(0);
+                    assert_eq!(protect.parse_token(&token).map(|_| ()), Err(CsrfError::MalformedLength));
+
+                    let mut cookie = cookie.value().to_vec();
+                    cookie.push(0);
+                    assert_eq!(protect.parse_cookie(&cookie).map(|_| ()), Err(CsrfError::MalformedLength));
+                }
+
+                #[test]
+                fn encoded_lengths() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.token_encoded_len(), token.b64_string().len());
+                    assert_eq!(protect.cookie_encoded_len(), cookie.b64_string().len());
+                }
+
+                #[test]
+                fn pinned_now_expiry_boundary() {
+                    let protect = $strct::from_key(KEY_32);
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let cookie = protect.generate_cookie_at(&[7; 64], 300, 1000)
+                        .expect("couldn't generate cookie");
+
+                    let parsed = protect.parse_cookie_at(cookie.value(), 1000).expect("cookie not parsed");
+                    assert_eq!(parsed.issued_at(), Some(1000));
+                    assert_eq!(parsed.expires(), 1300);
+
+                    assert_eq!(protect.verify_raw_at(token.value(), cookie.value(), 1299), Ok(()));
+                    assert_eq!(protect.verify_raw_at(token.value(), cookie.value(), 1300),
+                               Err(CsrfError::Expired));
+                }
+
+                #[test]
+                fn verify_as_of_logged_time() {
+                    let protect = $strct::from_key(KEY_32);
+                    let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+                    let cookie = protect.generate_cookie_at(&[7; 64], 300, 1000)
+                        .expect("couldn't generate cookie");
+
+                    let token = protect.parse_token(token.value()).expect("token not parsed");
+                    let cookie = protect.parse_cookie_at(cookie.value(), 1100).expect("cookie not parsed");
+
+                    assert!(protect.verify_token_pair_as_of(&token, &cookie, 1100));
+                    assert!(!protect.verify_token_pair_as_of(&token, &cookie, 1300));
+                    assert!(!protect.verify_token_pair(&token, &cookie));
+                }
+
+                #[test]
+                fn pinned_now_before_issued_at_fails() {
+                    let protect = $strct::from_key(KEY_32);
+                    let cookie = protect.generate_cookie_at(&[7; 64], 300, 1000)
+                        .expect("couldn't generate cookie");
+                    assert!(protect.parse_cookie_at(cookie.value(), 940).is_ok());
+                    assert_eq!(protect.parse_cookie_at(cookie.value(), 939).map(|_| ()),
+                               Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn verify_raw_success() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+                }
+
+                #[test]
+                fn verify_raw_bad_token() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let mut tampered = token.value().to_vec();
+                    let last = tampered.len() - 1;
+                    tampered[last] ^= 0x01;
+                    assert_eq!(protect.verify_raw(&tampered, cookie.value()),
+                               Err(CsrfError::ValidationFailure));
+                    assert_eq!(protect.verify_raw(&token.value()[1..], cookie.value()),
+                               Err(CsrfError::MalformedLength));
+                }
+
+                #[test]
+                fn verify_raw_bad_cookie() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let mut tampered = cookie.value().to_vec();
+                    let last = tampered.len() - 1;
+                    tampered[last] ^= 0x01;
+                    assert_eq!(protect.verify_raw(token.value(), &tampered),
+                               Err(CsrfError::ValidationFailure));
+                }
+
+                #[test]
+                fn verify_raw_mismatch() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (_, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()),
+                               Err(CsrfError::TokenMismatch));
+                }
+
+                #[test]
+                fn verify_raw_expired() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, -1)
+                        .expect("couldn't generate token/cookie pair");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()), Err(CsrfError::Expired));
+                }
+
+                #[test]
+                fn verify_and_reissue() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let original = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+
+                    let (new_token, new_cookie) = protect.verify_and_reissue(&token.b64_string(), &cookie.b64_string(), 600)
+                        .expect("pair not reissued");
+                    let parsed_token = protect.parse_token(new_token.value()).expect("token not parsed");
+                    let parsed_cookie = protect.parse_cookie(new_cookie.value()).expect("cookie not parsed");
+                    assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+                    assert!(parsed_cookie.same_token(&original));
+                    assert_eq!(parsed_cookie.issued_at(), original.issued_at());
+                    assert!(parsed_cookie.expires() > original.expires());
+
+                    // the old token still matches the reissued cookie
+                    assert_eq!(protect.verify_raw(token.value(), new_cookie.value()), Ok(()));
+                }
+
+                #[test]
+                fn regenerate_pair() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let (new_token, new_cookie) = protect.regenerate_pair(600).expect("pair not regenerated");
+                    assert_eq!(protect.verify_raw(new_token.value(), new_cookie.value()), Ok(()));
+                    assert_eq!(protect.verify_raw(token.value(), new_cookie.value()), Err(CsrfError::TokenMismatch));
+                    assert_eq!(protect.verify_raw(new_token.value(), cookie.value()), Err(CsrfError::TokenMismatch));
+
+                    let parsed_cookie = protect.parse_cookie(new_cookie.value()).expect("cookie not parsed");
+                    let original = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+                    assert!(!parsed_cookie.same_token(&original));
+                }
+
+                #[test]
+                fn self_test() {
+                    use config::CsrfConfig;
+                    use domain::DomainSeparatedCsrfProtection;
+                    use fallback::FallbackCsrfProtection;
+
+                    assert_eq!($strct::from_key(KEY_32).self_test(), Ok(()));
+                    for config in &[CsrfConfig::new().with_min_ttl(3600), CsrfConfig::new().with_max_ttl(60)] {
+                        let protect = $strct::from_key(KEY_32).with_config(config.clone());
+                        assert_eq!(protect.ttl_bounds(), (config.min_ttl(), config.max_ttl()));
+                        assert_eq!(protect.self_test(), Ok(()));
+                    }
+
+                    let fallback = FallbackCsrfProtection::new($strct::from_key(KEY_32)).with_key_ids(true);
+                    assert_eq!(fallback.self_test(), Ok(()));
+                    assert_eq!(DomainSeparatedCsrfProtection::new($strct::from_key(KEY_32), b"app").self_test(), Ok(()));
+                }
+
+                #[test]
+                fn two_phase_issuance() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, value) = protect.issue_token().expect("couldn't issue token");
+                    let (other_token, other_value) = protect.issue_token().expect("couldn't issue token");
+                    assert_ne!(value, other_value);
+                    assert_eq!(protect.parse_token(token.value()).expect("token not parsed").value(), &value[..]);
+
+                    let cookie = protect.clone().cookie_for_value(&value, 300).expect("couldn't generate cookie");
+                    assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+                    assert_eq!(protect.verify_raw(other_token.value(), cookie.value()), Err(CsrfError::TokenMismatch));
+                    let expired = protect.cookie_for_value(&value, -1).expect("couldn't generate cookie");
+                    assert_eq!(protect.verify_raw(token.value(), expired.value()), Err(CsrfError::Expired));
+                }
+
+                #[test]
+                fn within_declared_maxima() {
+                    use config::CsrfConfig;
+                    use core::{MAX_COOKIE_B64_BYTES, MAX_COOKIE_BYTES, MAX_TOKEN_B64_BYTES, MAX_TOKEN_BYTES};
+                    use fallback::FallbackCsrfProtection;
+                    use stateless::StatelessTokenProtection;
+                    use wire::MAX_AEAD_PADDING_LEN;
+
+                    let configs = [CsrfConfig::new(),
+                                   CsrfConfig::new().with_compact(true),
+                                   CsrfConfig::new().with_aead_padding_len(MAX_AEAD_PADDING_LEN)];
+                    for config in &configs {
+                        let protect = $strct::from_key(KEY_32).with_config(config.clone());
+                        let fallback = FallbackCsrfProtection::new(protect.clone()).with_key_ids(true);
+                        let protections: [&dyn CsrfProtection; 2] = [&protect, &fallback];
+                        for protect in &protections {
+                            let (token, cookie) = protect.generate_token_pair(None, 300)
+                                .expect("couldn't generate token/cookie pair");
+                            assert!(token.value().len() <= MAX_TOKEN_BYTES);
+                            assert!(cookie.value().len() <= MAX_COOKIE_BYTES);
+                            assert!(token.b64_string().len() <= MAX_TOKEN_B64_BYTES);
+                            assert!(cookie.b64_string().len() <= MAX_COOKIE_B64_BYTES);
+                            assert!(protect.expected_token_len() <= MAX_TOKEN_BYTES);
+                            assert!(protect.expected_cookie_len() <= MAX_COOKIE_BYTES);
+
+                            let (token, cookie) = protect.generate_encoded_pair_url_safe(None, 300)
+                                .expect("couldn't generate token/cookie pair");
+                            assert!(token.len() <= MAX_TOKEN_B64_BYTES);
+                            assert!(cookie.len() <= MAX_COOKIE_B64_BYTES);
+                        }
+
+                        let stateless = StatelessTokenProtection::new(fallback);
+                        let (token, _) = stateless.issue_token(300).expect("token not issued");
+                        assert!(token.value().len() <= MAX_TOKEN_BYTES);
+                        assert!(token.b64_string().len() <= MAX_TOKEN_B64_BYTES);
+                        assert!(stateless.verify_token(&token.b64_string(), 300).is_ok());
+                    }
+                }
+
+                #[test]
+                fn verify_and_reissue_failures() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (other_token, _) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+                    let (expired_token, expired_cookie) = protect.generate_token_pair(None, -1)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let mut tampered = token.value().to_vec();
+                    let last = tampered.len() - 1;
+                    tampered[last] ^= 0x01;
+                    let tampered = BASE64.encode(&tampered);
+
+                    let (token, cookie) = (token.b64_string(), cookie.b64_string());
+                    let cases = [
+                        ("", &cookie[..], CsrfError::MissingToken),
+                        (&token[..], "", CsrfError::MissingCookie),
+                        ("not base64!", &cookie[..], CsrfError::DecodeError),
+                        (&token[..], "not base64!", CsrfError::DecodeError),
+                        (&tampered[..], &cookie[..], CsrfError::ValidationFailure),
+                        (&other_token.b64_string()[..], &cookie[..], CsrfError::TokenMismatch),
+                        (&expired_token.b64_string()[..], &expired_cookie.b64_string()[..], CsrfError::Expired),
+                    ];
+                    for &(token, cookie, ref err) in cases.iter() {
+                        assert_eq!(protect.verify_and_reissue(token, cookie, 300).map(|_| ()), Err(err.clone()));
+                    }
+                }
+
+                #[test]
+                fn unknown_version_is_malformed() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, 300)
+                        .expect("couldn't generate token/cookie pair");
+
+                    let mut token = token.value().to_vec();
+                    token[0] = 2;
+                    assert_eq!(protect.parse_token(&token).map(|_| ()), Err(CsrfError::MalformedLength));
+
+                    let mut cookie = cookie.value().to_vec();
+                    cookie[0] = 2;
+                    assert_eq!(protect.parse_cookie(&cookie).map(|_| ()), Err(CsrfError::MalformedLength));
+                }
+
+                #[test]
+                fn expired_token_fail() {
+                    let protect = $strct::from_key(KEY_32);
+                    let (token, cookie) = protect.generate_token_pair(None, -1)
+                        .expect("couldn't generate token/cookie pair");
+                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
+                    let token = protect.parse_token(&token).expect("token not parsed");
+                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
+                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
+                    assert!(!protect.verify_token_pair(&token, &cookie),
+                            "verified token/cookie pair when failure expected");
+                }
+            }
+        }
+    }
+
+    mod rng_retries {
+        use std::cell::Cell;
+
+        use core::{fill_with_retries, CsrfError};
+
+        /// A fill function that fails the given number of times before succeeding.
+        fn flaky(failures: u32, calls: &Cell<u32>) -> impl FnMut(&mut [u8]) -> Result<(), ()> + '_ {
+            move |buf| {
+                calls.set(calls.get() + 1);
+                if calls.get() <= failures {
+                    Err(())
+                } else {
+                    for b in buf.iter_mut() {
+                        *b = 0xa5;
+                    }
+                    Ok(())
+                }
+            }
+        }
+
+        #[test]
+        fn succeeds_within_retry_budget() {
+            let calls = Cell::new(0);
+            let mut buf = [0; 16];
+            assert_eq!(fill_with_retries(flaky(2, &calls), &mut buf, 3), Ok(()));
+            assert_eq!(calls.get(), 3);
+            assert_eq!(buf, [0xa5; 16]);
+        }
+
+        #[test]
+        fn fails_when_retries_exhausted() {
+            let calls = Cell::new(0);
+            let mut buf = [0; 16];
+            assert_eq!(fill_with_retries(flaky(2, &calls), &mut buf, 1),
+                       Err(CsrfError::EntropyUnavailable));
+            assert_eq!(calls.get(), 2);
+        }
+
+        #[test]
+        fn no_retries_by_default() {
+            let calls = Cell::new(0);
+            let mut buf = [0; 16];
+            assert_eq!(fill_with_retries(flaky(1, &calls), &mut buf, 0),
+                       Err(CsrfError::EntropyUnavailable));
+            assert_eq!(calls.get(), 1);
+        }
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    mod error {
+        use data_encoding::BASE64;
+
+        use core::{CsrfError, CsrfProtection, HmacCsrfProtection, UnencryptedCsrfToken};
+        use test_util::KEY_32;
+
+        #[test]
+        fn display_uses_message() {
+            assert_eq!(CsrfError::ValidationFailure.to_string(), "CSRF validation failed");
+            assert_eq!(CsrfError::MalformedLength.to_string(),
+                       "CSRF token or cookie had a malformed length");
+        }
+
+        fn decode_and_parse(protect: &HmacCsrfProtection, encoded: &str)
+                            -> Result<UnencryptedCsrfToken, CsrfError> {
+            let bytes = BASE64.decode(encoded.as_bytes())?;
+            let token = protect.parse_token(&bytes)?;
+            Ok(token)
+        }
+
+        #[test]
+        fn decode_error_converts() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            assert_eq!(decode_and_parse(&protect, "*not base64*").map(|_| ()), Err(CsrfError::DecodeError));
+
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert!(decode_and_parse(&protect, &token.b64_string()).is_ok());
+        }
+    }
+
+    mod unencrypted_cookie {
+        use data_encoding::{BASE64, BASE64URL, HEXLOWER};
+
+        use core::{unix_time, UnencryptedCsrfCookie, UnencryptedCsrfToken};
+
+        fn cookie_expiring_in(seconds: i64) -> UnencryptedCsrfCookie {
+            UnencryptedCsrfCookie::new(unix_time() + seconds, vec![0; 64])
+        }
+
+        #[test]
+        fn needs_refresh_at_half_life() {
+            let cookie = cookie_expiring_in(500);
+            assert!(cookie.needs_refresh(0.5, 1000));
+            assert!(!cookie.needs_refresh(0.6, 1000));
+        }
+
+        #[test]
+        fn needs_refresh_at_ninety_percent() {
+            let cookie = cookie_expiring_in(100);
+            assert!(cookie.needs_refresh(0.5, 1000));
+            assert!(cookie.needs_refresh(0.9, 1000));
+            assert!(!cookie.needs_refresh(0.95, 1000));
+        }
+
+        #[test]
+        fn needs_refresh_when_expired() {
+            let cookie = cookie_expiring_in(-1);
+            assert!(cookie.needs_refresh(1.0, 1000));
+            assert!(cookie.needs_refresh(0.0, 1000));
+        }
+
+        #[test]
+        fn needs_refresh_as_of() {
+            let cookie = UnencryptedCsrfCookie::new(1_001_000, vec![0; 64]).with_issued_at(1_000_000);
+            assert!(!cookie.needs_refresh_at(1_000_499, 0.5, 1000));
+            assert!(cookie.needs_refresh_at(1_000_500, 0.5, 1000));
+            assert!(cookie.needs_refresh_at(1_001_000, 1.0, 1000));
+            assert!(cookie.within_absolute_max_at(1_003_599, 3600));
+            assert!(!cookie.within_absolute_max_at(1_003_600, 3600));
+        }
+
+        #[test]
+        fn fresh_cookie_does_not_need_refresh() {
+            let cookie = cookie_expiring_in(1000);
+            assert!(!cookie.needs_refresh(0.5, 1000));
+        }
+
+        #[test]
+        fn describe_does_not_leak_value() {
+            let value: Vec<u8> = (0..64).map(|i| i as u8 ^ 0x5a).collect();
+            let cookie = UnencryptedCsrfCookie::new(unix_time() + 142, value.clone())
+                .with_issued_at(unix_time());
+            let token = UnencryptedCsrfToken::new(value.clone());
+
+            for description in [cookie.describe(), token.describe()].iter() {
+                assert!(description.contains("64-byte value"));
+                for encoded in [BASE64.encode(&value), BASE64URL.encode(&value), HEXLOWER.encode(&value)].iter() {
+                    assert!(!description.contains(&encoded[0..16]));
+                }
+                assert!(!description.as_bytes().windows(8).any(|w| value.windows(8).any(|v| v == w)));
+            }
+            assert!(cookie.describe().contains("expires in 14"));
+            assert_eq!(token.describe().split("fingerprint ").nth(1).map(|f| f.len()), Some(8));
+        }
+
+        #[test]
+        fn short_debug_does_not_leak_value() {
+            let value: Vec<u8> = (0..64).map(|i| i as u8 ^ 0x5a).collect();
+            let token = UnencryptedCsrfToken::new(value.clone());
+            let label = token.short_debug();
+            let fingerprint = HEXLOWER.encode(&token.fingerprint());
+
+            assert_eq!(label, format!("csrf:{}…{} (64B)", &fingerprint[0..4], &fingerprint[30..32]));
+            assert!(label.chars().count() <= 18, "{}", label);
+            for encoded in [BASE64.encode(&value), BASE64URL.encode(&value), HEXLOWER.encode(&value)].iter() {
+                assert!(!label.contains(&encoded[0..4]), "{}", label);
+            }
+            assert_eq!(UnencryptedCsrfToken::new(value.clone()).short_debug(), label);
+            assert_ne!(UnencryptedCsrfToken::new(vec![0; 64]).short_debug(), label);
+            assert_eq!(UnencryptedCsrfToken::new(vec![0; 3]).short_debug().chars().count(), 17);
+        }
+
+        #[test]
+        fn debug_does_not_leak_value() {
+            use core::{CsrfCookie, CsrfToken};
+
+            let value: Vec<u8> = (0..64).map(|i| i as u8 ^ 0x5a).collect();
+            let cookie = UnencryptedCsrfCookie::new(1234, value.clone()).with_issued_at(1000);
+            let token = UnencryptedCsrfToken::new(value.clone()).with_issued_at(1000);
+
+            let outputs = [format!("{:?}", cookie), format!("{:?}", token),
+                           format!("{:?}", CsrfCookie::new(value.clone())),
+                           format!("{:?}", CsrfToken::new(value.clone()))];
+            for output in outputs.iter() {
+                assert!(output.contains("len: 64"), "{}", output);
+                assert!(output.contains("fingerprint: "), "{}", output);
+                let decimal: Vec<String> = value[0..8].iter().map(|b| b.to_string()).collect();
+                assert!(!output.contains(&decimal.join(", ")), "{}", output);
+                for encoded in [BASE64.encode(&value), BASE64URL.encode(&value), HEXLOWER.encode(&value)].iter() {
+                    assert!(!output.contains(&encoded[0..16]), "{}", output);
+                }
+            }
+            assert!(outputs[0].contains("expires: 1234"));
+            assert!(outputs[0].starts_with("UnencryptedCsrfCookie {"));
+        }
+
+        #[test]
+        fn issuance_gap_extremes() {
+            let token = UnencryptedCsrfToken::new(vec![7; 64]).with_issued_at(i64::MAX);
+            let cookie = UnencryptedCsrfCookie::new(i64::MAX, vec![7; 64]).with_issued_at(i64::MIN);
+            assert!(!token.within_issuance_gap(&cookie, 3600));
+            assert!(!token.within_issuance_gap(&cookie, i64::MAX));
+            let cookie = UnencryptedCsrfCookie::new(i64::MAX, vec![7; 64]).with_issued_at(0);
+            assert!(token.within_issuance_gap(&cookie, i64::MAX));
+
+            let token = UnencryptedCsrfToken::new(vec![7; 64]).with_issued_at(1000);
+            let cookie = UnencryptedCsrfCookie::new(1234, vec![7; 64]).with_issued_at(1000);
+            assert!(token.within_issuance_gap(&cookie, 0));
+            assert!(!token.within_issuance_gap(&cookie, -1));
+            assert!(!token.within_issuance_gap(&cookie, i64::MIN));
+        }
+
+        #[test]
+        fn token_fingerprint_stable_and_distinct() {
+            let value: Vec<u8> = (0..64).map(|i| i as u8 ^ 0x5a).collect();
+            let mut other = value.clone();
+            other[63] ^= 1;
+
+            let token = UnencryptedCsrfToken::new(value.clone());
+            assert_eq!(token.fingerprint(), UnencryptedCsrfToken::new(value.clone()).with_issued_at(1000).fingerprint());
+            assert_ne!(token.fingerprint(), UnencryptedCsrfToken::new(other).fingerprint());
+            assert!(!value.windows(4).any(|w| token.fingerprint().windows(4).any(|f| f == w)));
+        }
+
+        #[test]
+        fn same_token_different_expiry() {
+            let cookie = cookie_expiring_in(100);
+            let refreshed = cookie_expiring_in(1000).with_issued_at(0);
+            assert!(cookie.same_token(&refreshed));
+            assert!(refreshed.same_token(&cookie));
+        }
+
+        #[test]
+        fn different_token() {
+            let cookie = cookie_expiring_in(100);
+            let mut value = vec![0; 64];
+            value[63] = 1;
+            let other = UnencryptedCsrfCookie::new(cookie.expires(), value);
+            assert!(!cookie.same_token(&other));
+            assert!(!cookie.same_token(&UnencryptedCsrfCookie::new(cookie.expires(), vec![0; 32])));
+        }
+    }
+
+    #[cfg(all(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod seal {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use proptest::sample::Index;
+
+        use core::{AesGcmAead, AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   MAX_SEALED_LEN};
+        use primitives::Aead;
+        use test_util::KEY_32;
+
+        #[test]
+        fn empty_and_max_length() {
+            let protect = AesGcmCsrfProtection::from_key(KEY_32);
+            for len in [0, MAX_SEALED_LEN].iter() {
+                let plaintext = vec![7; *len];
+                let sealed = protect.seal(&plaintext).expect("couldn't seal");
+                assert_eq!(sealed.len(), len + 29);
+                assert_eq!(protect.open(&sealed), Ok(plaintext));
+            }
+            assert_eq!(protect.seal(&vec![7; MAX_SEALED_LEN + 1]), Err(CsrfError::MalformedLength));
+            assert_eq!(protect.open(&[1; 28]), Err(CsrfError::MalformedLength));
+        }
+
+        #[test]
+        fn other_key_fails() {
+            let sealed = ChaCha20Poly1305CsrfProtection::from_key(KEY_32).seal(b"flash message")
+                .expect("couldn't seal");
+            let other = ChaCha20Poly1305CsrfProtection::from_key(*b"76543210765432107654321076543210");
+            assert_eq!(other.open(&sealed), Err(CsrfError::ValidationFailure));
+        }
+
+        #[test]
+        fn seal_key_follows_protection_key() {
+            let protect = AesGcmCsrfProtection::from_key(KEY_32);
+            let sealed = protect.seal(b"flash message").expect("couldn't seal");
+            assert_eq!(protect.clone().open(&sealed), Ok(b"flash message".to_vec()));
+            assert_eq!(AesGcmCsrfProtection::new(AesGcmAead::from_key(KEY_32)).open(&sealed),
+                       Ok(b"flash message".to_vec()));
+            assert_eq!(protect.derive_subkey(b"other").open(&sealed), Err(CsrfError::ValidationFailure));
+            assert_eq!(protect.with_split_keys(true).open(&sealed), Ok(b"flash message".to_vec()));
+        }
+
+        #[test]
+        fn sealed_values_are_not_tokens() {
+            let protect = AesGcmCsrfProtection::from_key(KEY_32);
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert_eq!(protect.open(token.value()), Err(CsrfError::ValidationFailure));
+
+            // a sealed value with the same length as a token is still not a token
+            let sealed = protect.seal(&[0; 88]).expect("couldn't seal");
+            assert_eq!(sealed.len(), token.value().len());
+            assert_eq!(protect.parse_token(&sealed).map(|_| ()), Err(CsrfError::ValidationFailure));
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn aes_gcm_round_trip(plaintext in vec(any::<u8>(), 0..512)) {
+                let protect = AesGcmCsrfProtection::from_key(KEY_32);
+                let sealed = protect.seal(&plaintext).expect("couldn't seal");
+                prop_assert_eq!(protect.open(&sealed), Ok(plaintext));
+            }
+
+            #[test]
+            fn chacha20poly1305_round_trip(plaintext in vec(any::<u8>(), 0..512)) {
+                let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32);
+                let sealed = protect.seal(&plaintext).expect("couldn't seal");
+                prop_assert_eq!(protect.open(&sealed), Ok(plaintext));
+            }
+
+            #[test]
+            fn aes_gcm_tamper_fails(plaintext in vec(any::<u8>(), 0..512),
+                                    index in any::<Index>(),
+                                    bit in 0u8..8) {
+                let protect = AesGcmCsrfProtection::from_key(KEY_32);
+                let mut sealed = protect.seal(&plaintext).expect("couldn't seal");
+                let i = index.index(sealed.len());
+                sealed[i] ^= 1 << bit;
+                prop_assert!(protect.open(&sealed).is_err());
+            }
+
+            #[test]
+            fn chacha20poly1305_tamper_fails(plaintext in vec(any::<u8>(), 0..512),
+                                             index in any::<Index>(),
+                                             bit in 0u8..8) {
+                let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32);
+                let mut sealed = protect.seal(&plaintext).expect("couldn't seal");
+                let i = index.index(sealed.len());
+                sealed[i] ^= 1 << bit;
+                prop_assert!(protect.open(&sealed).is_err());
+            }
+        }
+    }
+
+    #[cfg(all(feature = "aes-gcm-backend", feature = "hmac-backend"))]
+    mod key_loading {
+        use data_encoding::BASE64;
+
+        use core::{AesGcmCsrfProtection, CsrfError, CsrfProtection, HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        fn assert_same_key<P: CsrfProtection>(loaded: P, expected: P) {
+            let token = expected.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert!(loaded.parse_token(token.value()).is_ok());
+        }
+
+        #[test]
+        fn base64_key() {
+            let loaded = AesGcmCsrfProtection::from_key_base64(&BASE64.encode(&KEY_32))
+                .expect("key not loaded");
+            assert_same_key(loaded, AesGcmCsrfProtection::from_key(KEY_32));
+        }
+
+        #[test]
+        fn pem_key_from_reader() {
+            let pem = format!("-----BEGIN CSRF KEY-----\n{}\n-----END CSRF KEY-----\n",
+                              BASE64.encode(&KEY_32));
+            let loaded = HmacCsrfProtection::from_key_reader(pem.as_bytes()).expect("key not loaded");
+            assert_same_key(loaded, HmacCsrfProtection::from_key(KEY_32));
+        }
+
+        #[test]
+        fn short_key() {
+            let encoded = BASE64.encode(&KEY_32[0..31]);
+            assert_eq!(AesGcmCsrfProtection::from_key_base64(&encoded).err(),
+                       Some(CsrfError::InvalidKey));
+        }
+
+        #[test]
+        fn invalid_base64() {
+            assert_eq!(HmacCsrfProtection::from_key_base64("not base64!").err(),
+                       Some(CsrfError::InvalidKey));
+        }
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    mod hmac_session_binding {
+        use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        #[test]
+        fn token_bound_to_session() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let token = protect.generate_bound_token(&[7; 64], b"session-a")
+                .expect("couldn't generate token");
+
+            let parsed = protect.parse_bound_token(token.value(), b"session-a").expect("token not parsed");
+            assert_eq!(parsed.value(), &[7; 64][..]);
+
+            assert_eq!(protect.parse_bound_token(token.value(), b"session-b").map(|_| ()),
+                       Err(CsrfError::ValidationFailure));
+            assert_eq!(protect.parse_token(token.value()).map(|_| ()), Err(CsrfError::ValidationFailure));
+        }
+
+        #[test]
+        fn unbound_token_fails_for_session() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert_eq!(protect.parse_bound_token(token.value(), b"session-a").map(|_| ()),
+                       Err(CsrfError::ValidationFailure));
+        }
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    mod hmac_verify_cookie_only {
+        use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        #[test]
+        fn accepts_valid_cookie() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let (_, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            let expires = protect.verify_cookie_only(cookie.value()).expect("cookie not verified");
+            let parsed = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert_eq!(expires, parsed.expires);
+        }
+
+        #[test]
+        fn rejects_tampered_cookie() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let (_, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            for i in [1, 65, 73, 112].iter() {
+                let mut cookie = cookie.value().to_vec();
+                cookie[*i] ^= 0x01;
+                assert_eq!(protect.verify_cookie_only(&cookie), Err(CsrfError::ValidationFailure));
+            }
+        }
+
+        #[test]
+        fn rejects_expired_cookie() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let (_, cookie) = protect.generate_token_pair(None, -1)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.verify_cookie_only(cookie.value()), Err(CsrfError::Expired));
+        }
+
+        #[test]
+        fn rejects_wrong_length() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            assert_eq!(protect.verify_cookie_only(&[0; 103]), Err(CsrfError::MalformedLength));
+        }
+    }
+
+    /// Tokens and cookies generated before the wire format carried a version byte must still
+    /// be accepted. These were generated with `KEY_32`, the value `VALUE_64`, and a TTL of
+    /// `1 << 40` seconds.
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod legacy_format {
+        use data_encoding::HEXLOWER;
+
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        const VALUE_64: &[u8] = b"0123456701234567012345670123456701234567012345670123456701234567";
+
+        fn check<P: CsrfProtection>(protect: P, token_hex: &str, cookie_hex: &str) {
+            let token = HEXLOWER.decode(token_hex.as_bytes()).expect("token not hex");
+            let cookie = HEXLOWER.decode(cookie_hex.as_bytes()).expect("cookie not hex");
+            assert_eq!(protect.verify_raw_with_max_issuance_gap(&token, &cookie, i64::MAX),
+                       Err(CsrfError::IssuanceMismatch));
+
+            let token = protect.parse_token(&token).expect("legacy token not parsed");
+            let cookie = protect.parse_cookie(&cookie).expect("legacy cookie not parsed");
+
+            assert_eq!(token.value(), VALUE_64);
+            assert_eq!(cookie.value(), VALUE_64);
+            assert_eq!(token.issued_at(), None);
+            assert_eq!(cookie.issued_at(), None);
+            assert!(protect.verify_token_pair(&token, &cookie));
+            assert!(!protect.verify_token_pair_with_absolute_max(&token, &cookie, i64::MAX));
+            assert!(!protect.verify_token_pair_with_max_issuance_gap(&token, &cookie, i64::MAX));
+        }
+
+        #[test]
+        fn hmac() {
+            check(HmacCsrfProtection::from_key(KEY_32),
+                  "3031323334353637303132333435363730313233343536373031323334353637\
+                   3031323334353637303132333435363730313233343536373031323334353637\
+                   82b562eafa41c9569a037dd10e44d2f8f2079b0fd0f26b10a803d89716c89c22",
+                  "3031323334353637303132333435363730313233343536373031323334353637\
+                   3031323334353637303132333435363730313233343536373031323334353637\
+                   c3020000000100005fc03eddbdd4cf0289162158a211f45bb47cf7bd74fabf53\
+                   9d458cceb0d46839");
+        }
+
+        #[test]
+        fn aesgcm() {
+            check(AesGcmCsrfProtection::from_key(KEY_32),
+                  "747bbbd8136262293cdab3425b5a7c44a56a857fa480a766e64588592cfc8d99\
+                   4f89889b375e50b549adf1fa099401dc8ca07fbbc5fcb535b17dfaa33e36ba1e\
+                   440a2f0e455d1cd4016bebd23f9cd04de83a51ca5dc2f6c0bf6bf4a1eea7f661\
+                   8f283e67975bdc9308752a36",
+                  "82db5ca2b4612d1b452208e96411f45afdcc1983bae7a691b6da9906dcfa66d7\
+                   8bdce60795d77a7d022b711f54b378697ffe9c1822694017bde7eec676d87269\
+                   aad5508737c8269bbbb3e270151c7a6312a3245965846186f32a988e1366d66f\
+                   baa4098d685525979f6a885eee3b3653acadfe0a");
+        }
+
+        #[test]
+        fn chacha20poly1305() {
+            check(ChaCha20Poly1305CsrfProtection::from_key(KEY_32),
+                  "31591cbdbeed2aa700261f52618e4fcc3dd8009b6693e3f456203589960c2087\
+                   4b91565e0457a49acdbe81bbc13438763835ca7f8b6926151c902c1566dbaf74\
+                   7d79854c41f81781c76413b0b3433a6f477bd22cb0dcf521de417243d9d21702\
+                   fd91ed89df95ae74",
+                  "165a8d16be71e7ead265f814383816b550b269af0c28a96b8ec4e02e3047ee5d\
+                   476c1f06853fe97353f551d8a4d4a53f467546a0e934c111b2f770d591aed0dd\
+                   43ba789ff7e70d50e44e964708d37ff8811ed6a8efb3d7c054605201d75ba24c\
+                   cc10a0ffe81dac3576d67968145fedb4");
+        }
+
+        /// An HMAC pair in the versioned wire format, generated with the `rust-crypto` HMAC
+        /// implementation before the backend moved to `ring`, with an issued at time of `0`.
+        #[test]
+        fn hmac_versioned_rust_crypto_vector() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let token = HEXLOWER.decode("0130313233343536373031323334353637303132333435363730313233343536\
+                                         3730313233343536373031323334353637303132333435363730313233343536\
+                                         37000000000000000087a3173d4bb66a5bc6049ed7b2958fcfc7f3033d795372\
+                                         f49a5080a34b4f9fb2".as_bytes())
+                .expect("token not hex");
+            let cookie = HEXLOWER.decode("0130313233343536373031323334353637303132333435363730313233343536\
+                                          3730313233343536373031323334353637303132333435363730313233343536\
+                                          37000000000000000000000000000100004b7f34ba6752f13d92df56f2bb82e9\
+                                          30b274fa03d40986cae8832dd0ee55ec05".as_bytes())
+                .expect("cookie not hex");
+
+            let token = protect.parse_token(&token).expect("token not parsed");
+            let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
+            assert_eq!(token.value(), VALUE_64);
+            assert_eq!(token.issued_at(), Some(0));
+            assert_eq!(cookie.issued_at(), Some(0));
+            assert_eq!(cookie.expires(), 1 << 40);
+            assert!(protect.verify_token_pair(&token, &cookie));
+        }
+
+        #[test]
+        fn garbage_hmac_expiry_fails_verification() {
+            use primitives::Mac;
+
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+
+            // a correctly MACed cookie in the unversioned format whose expiry bytes are garbage
+            let mut cookie = vec![7; 64];
+            cookie.extend_from_slice(&[0xff, 0x00, 0xa5, 0x5a, 0x12, 0x34, 0x56, 0x80]);
+            let tag = protect.cookie_mac().sign(&[&cookie]);
+            cookie.extend_from_slice(tag.as_ref());
+            assert_eq!(cookie.len(), 104);
+
+            let parsed = protect.parse_cookie(&cookie).expect("cookie not parsed");
+            assert!(parsed.expires() < 0);
+            assert!(!protect.verify_token_pair(&token, &parsed));
+            assert_eq!(protect.verify_cookie_only(&cookie), Err(CsrfError::Expired));
+
+            // and without a valid MAC it is rejected before the expiry is looked at
+            cookie[103] ^= 0x01;
+            assert_eq!(protect.parse_cookie(&cookie).map(|_| ()), Err(CsrfError::ValidationFailure));
+        }
+    }
+
+    mod read_array {
+        use core::{read_array, read_i64, CsrfError};
+
+        #[test]
+        fn reads_in_bounds() {
+            let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+            assert_eq!(read_array::<3>(&bytes, 6), Ok([7, 8, 9]));
+            assert_eq!(read_i64(&bytes, 1), Ok(i64::from_le_bytes([2, 3, 4, 5, 6, 7, 8, 9])));
+        }
+
+        #[test]
+        fn fails_out_of_bounds() {
+            let bytes = [0; 8];
+            assert_eq!(read_array::<4>(&bytes, 5), Err(CsrfError::ValidationFailure));
+            assert_eq!(read_i64(&bytes, 1), Err(CsrfError::ValidationFailure));
+            assert_eq!(read_array::<4>(&bytes, usize::MAX), Err(CsrfError::ValidationFailure));
+        }
+    }
+
+    mod uniform_rejection {
+        use std::cell::Cell;
+
+        use config::CsrfConfig;
+        use core::{uniform_rejection, CsrfError};
+
+        /// Return how many times `uniform_rejection` ran the dummy check for `parsed`.
+        fn dummy_checks(config: &CsrfConfig, parsed: Result<(), CsrfError>) -> usize {
+            let checks = Cell::new(0);
+            assert_eq!(uniform_rejection(config, parsed.clone(), || checks.set(checks.get() + 1)), parsed);
+            checks.get()
+        }
+
+        #[test]
+        fn dummy_check_runs_for_early_rejections() {
+            let uniform = CsrfConfig::new().with_uniform_rejection_timing(true);
+            assert_eq!(dummy_checks(&uniform, Err(CsrfError::MalformedLength)), 1);
+            assert_eq!(dummy_checks(&uniform, Err(CsrfError::AlgorithmMismatch)), 1);
+            assert_eq!(dummy_checks(&uniform, Err(CsrfError::ValidationFailure)), 0);
+            assert_eq!(dummy_checks(&uniform, Ok(())), 0);
+            assert_eq!(dummy_checks(&CsrfConfig::new(), Err(CsrfError::MalformedLength)), 0);
+        }
+    }
+
+    mod decode_b64 {
+        use core::{decode_b64, CsrfError, MAX_ENCODED_LEN};
+
+        #[test]
+        fn accepts_at_limit() {
+            let encoded = "A".repeat(MAX_ENCODED_LEN);
+            assert_eq!(decode_b64(&encoded).map(|v| v.len()), Ok(MAX_ENCODED_LEN / 4 * 3));
+        }
+
+        #[test]
+        fn rejects_over_limit() {
+            let encoded = "A".repeat(MAX_ENCODED_LEN + 4);
+            assert_eq!(decode_b64(&encoded), Err(CsrfError::ValidationFailure));
+        }
+    }
+
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod expected_lengths {
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfProtection,
+                   HmacCsrfProtection};
+
+        #[test]
+        fn expected_lengths_per_backend() {
+            let key = *b"01234567012345670123456701234567";
+
+            let hmac = HmacCsrfProtection::from_key(key);
+            assert_eq!((hmac.expected_token_len(), hmac.expected_cookie_len()), (105, 113));
+
+            let aes_gcm = AesGcmCsrfProtection::from_key(key);
+            assert_eq!((aes_gcm.expected_token_len(), aes_gcm.expected_cookie_len()), (117, 125));
+
+            let chacha = ChaCha20Poly1305CsrfProtection::from_key(key);
+            assert_eq!((chacha.expected_token_len(), chacha.expected_cookie_len()), (113, 121));
+        }
+    }
+
+    #[cfg(all(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod aead_padding {
+        use config::CsrfConfig;
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection};
+        use test_util::KEY_32;
+
+        fn padded(len: usize) -> CsrfConfig {
+            CsrfConfig::new().with_aead_padding_len(len)
+        }
+
+        fn round_trip<P: CsrfProtection>(protect: P, token_len: usize, cookie_len: usize) {
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!((token.value().len(), cookie.value().len()), (token_len, cookie_len));
+            assert_eq!((protect.expected_token_len(), protect.expected_cookie_len()), (token_len, cookie_len));
+
+            let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+            let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+
+            let parsed_token = protect.parse_token_b64(&token.b64_string()).expect("token not parsed");
+            let parsed_cookie = protect.parse_cookie_b64(&cookie.b64_string()).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+        }
+
+        fn cross_padding_fails<P: CsrfProtection>(issuer: P, verifier: P) {
+            let (token, cookie) = issuer.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(verifier.parse_token(token.value()).map(|_| ()), Err(CsrfError::MalformedLength));
+            assert_eq!(verifier.parse_cookie(cookie.value()).map(|_| ()), Err(CsrfError::MalformedLength));
+        }
+
+        #[test]
+        fn aes_gcm_round_trip() {
+            for &(padding, token_len, cookie_len) in &[(0, 101, 109), (16, 117, 125), (32, 133, 141)] {
+                round_trip(AesGcmCsrfProtection::from_key(KEY_32).with_config(padded(padding)),
+                           token_len, cookie_len);
+            }
+        }
+
+        #[test]
+        fn chacha20_round_trip() {
+            for &(padding, token_len, cookie_len) in &[(0, 97, 105), (16, 113, 121), (32, 129, 137)] {
+                round_trip(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(padded(padding)),
+                           token_len, cookie_len);
+            }
+        }
+
+        #[test]
+        fn longest_padding_round_trips() {
+            round_trip(AesGcmCsrfProtection::from_key(KEY_32).with_config(padded(64)), 165, 173);
+        }
+
+        #[test]
+        fn aes_gcm_cross_padding_fails() {
+            for &(issued, verified) in &[(0, 16), (16, 0), (16, 32), (32, 16)] {
+                cross_padding_fails(AesGcmCsrfProtection::from_key(KEY_32).with_config(padded(issued)),
+                                    AesGcmCsrfProtection::from_key(KEY_32).with_config(padded(verified)));
+            }
+        }
+
+        #[test]
+        fn chacha20_cross_padding_fails() {
+            for &(issued, verified) in &[(0, 16), (16, 0), (16, 32), (32, 16)] {
+                cross_padding_fails(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(padded(issued)),
+                                    ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(padded(verified)));
+            }
+        }
+
+        #[test]
+        #[should_panic]
+        fn too_much_padding() {
+            padded(65);
+        }
+    }
+
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod compact {
+        use config::CsrfConfig;
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   HmacCsrfProtection};
+        use test_util::KEY_32;
+        use wire::{AES_GCM_COOKIE_LEN, AES_GCM_TOKEN_LEN, CHACHA20_COOKIE_LEN, CHACHA20_TOKEN_LEN,
+                   COMPACT_WIRE_VERSION, HMAC_COOKIE_LEN, HMAC_TOKEN_LEN, WIRE_VERSION};
+
+        fn compact() -> CsrfConfig {
+            CsrfConfig::new().with_compact(true)
+        }
+
+        fn round_trip<P: CsrfProtection>(protect: P, token_len: usize, cookie_len: usize) {
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!((token.value().len(), cookie.value().len()), (token_len, cookie_len));
+            assert_eq!((protect.expected_token_len(), protect.expected_cookie_len()), (token_len, cookie_len));
+            assert_eq!(protect.wire_version(), COMPACT_WIRE_VERSION);
+            assert_eq!((token.value()[0], cookie.value()[0]), (COMPACT_WIRE_VERSION, COMPACT_WIRE_VERSION));
+
+            let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+            let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+
+            let bound = protect.generate_bound_token(&[7; 64], b"session").expect("couldn't generate token");
+            assert!(protect.parse_bound_token(bound.value(), b"session").is_ok());
+            assert_eq!(protect.parse_bound_token(bound.value(), b"other").err(), Some(CsrfError::ValidationFailure));
+        }
+
+        fn tamper_fails<P: CsrfProtection>(protect: P) {
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+
+            for i in 1..token.value().len() {
+                let mut tampered = token.value().to_vec();
+                tampered[i] ^= 0x01;
+                assert_eq!(protect.verify_raw(&tampered, cookie.value()), Err(CsrfError::ValidationFailure), "byte {}", i);
+            }
+            for i in 1..cookie.value().len() {
+                let mut tampered = cookie.value().to_vec();
+                tampered[i] ^= 0x01;
+                assert_eq!(protect.verify_raw(token.value(), &tampered), Err(CsrfError::ValidationFailure), "byte {}", i);
+            }
+
+            for transport in [token.value(), cookie.value()].iter() {
+                // relabelled as the padded format, the length no longer matches
+                let mut relabelled = transport.to_vec();
+                relabelled[0] = WIRE_VERSION;
+                assert!(protect.parse_token(&relabelled).is_err());
+                assert!(protect.parse_cookie(&relabelled).is_err());
+            }
+        }
+
+        /// A token or cookie whose version byte claims the other format has the wrong length for the
+        /// sections of that format, so it is malformed before anything is decrypted.
+        fn relabelled_layout_is_malformed<P: CsrfProtection>(compact: P, padded: P) {
+            for &(issuer, version) in &[(&compact, WIRE_VERSION), (&padded, COMPACT_WIRE_VERSION)] {
+                let (token, cookie) = issuer.generate_token_pair(None, 300)
+                    .expect("couldn't generate token/cookie pair");
+                let mut token = token.value().to_vec();
+                let mut cookie = cookie.value().to_vec();
+                token[0] = version;
+                cookie[0] = version;
+                for verifier in [&compact, &padded].iter() {
+                    assert_eq!(verifier.parse_token(&token).err(), Some(CsrfError::MalformedLength));
+                    assert_eq!(verifier.parse_cookie(&cookie).err(), Some(CsrfError::MalformedLength));
+                }
+            }
+        }
+
+        /// Tokens and cookies in either format are accepted whether or not compact is configured.
+        fn formats_interoperate<P: CsrfProtection>(compact: P, padded: P) {
+            for &(issuer, verifier) in &[(&compact, &padded), (&padded, &compact)] {
+                let (token, cookie) = issuer.generate_token_pair(None, 300)
+                    .expect("couldn't generate token/cookie pair");
+                assert_eq!(verifier.verify_raw(token.value(), cookie.value()), Ok(()));
+            }
+        }
+
+        #[test]
+        fn aes_gcm() {
+            round_trip(AesGcmCsrfProtection::from_key(KEY_32).with_config(compact()),
+                       AES_GCM_TOKEN_LEN - 16, AES_GCM_COOKIE_LEN - 16);
+            tamper_fails(AesGcmCsrfProtection::from_key(KEY_32).with_config(compact()));
+            formats_interoperate(AesGcmCsrfProtection::from_key(KEY_32).with_config(compact()),
+                                 AesGcmCsrfProtection::from_key(KEY_32));
+            relabelled_layout_is_malformed(AesGcmCsrfProtection::from_key(KEY_32).with_config(compact()),
+                                           AesGcmCsrfProtection::from_key(KEY_32));
+        }
 
-        let mut aead_key = [0; 32];
-        info!("Generating key material. This may take some time.");
-        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
-        info!("Key material generated.");
+        #[test]
+        fn chacha20() {
+            round_trip(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(compact()),
+                       CHACHA20_TOKEN_LEN - 16, CHACHA20_COOKIE_LEN - 16);
+            tamper_fails(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(compact()));
+            formats_interoperate(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(compact()),
+                                 ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+            relabelled_layout_is_malformed(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(compact()),
+                                           ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+        }
 
-        AesGcmCsrfProtection::from_key(aead_key)
-    }
+        #[test]
+        fn ignores_padding_len() {
+            let protect = AesGcmCsrfProtection::from_key(KEY_32).with_config(compact().with_aead_padding_len(32));
+            assert_eq!(protect.expected_token_len(), AES_GCM_TOKEN_LEN - 16);
 
-    fn rng(&self) -> &SystemRandom {
-        &self.rng
+            let padded = AesGcmCsrfProtection::from_key(KEY_32).with_config(CsrfConfig::new().with_aead_padding_len(32));
+            let (token, cookie) = padded.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+        }
+
+        #[test]
+        fn hmac_unchanged() {
+            let protect = HmacCsrfProtection::from_key(KEY_32).with_config(compact());
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!((token.value().len(), cookie.value().len()), (HMAC_TOKEN_LEN, HMAC_COOKIE_LEN));
+            assert_eq!(protect.wire_version(), WIRE_VERSION);
+        }
     }
 
-    fn generate_cookie(&self, token_value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
-        let expires = time::precise_time_s() as i64 + ttl_seconds;
-        let expires_bytes = unsafe { mem::transmute::<i64, [u8; 8]>(expires) };
+    #[cfg(feature = "hmac-backend")]
+    mod ring_keys {
+        use crypto::hkdf::hkdf_expand;
+        use crypto::sha2::Sha256;
+        use ring::digest::{SHA256, SHA512};
+        use ring::hmac::SigningKey;
+        use ring::rand::SystemRandom;
+
+        use core::{CsrfError, CsrfProtection, HmacCsrfProtection, SUBKEY_LABEL};
+        use test_util::KEY_32;
+
+        #[test]
+        fn generated_key_round_trip() {
+            let key = SigningKey::generate(&SHA256, &SystemRandom::new()).expect("couldn't generate key");
+            let protect = HmacCsrfProtection::from_signing_key(key).expect("key rejected");
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+            assert_eq!(protect.clone().verify_raw(token.value(), cookie.value()), Ok(()));
+        }
 
-        let mut nonce = [0; 12];
-        self.random_bytes(&mut nonce)?;
+        #[test]
+        fn same_as_from_key() {
+            let from_ring = HmacCsrfProtection::from_signing_key(SigningKey::new(&SHA256, &KEY_32)).expect("key rejected");
+            let from_bytes = HmacCsrfProtection::from_key(KEY_32);
 
-        let mut padding = [0; 16];
-        self.random_bytes(&mut padding)?;
+            for &(issuer, verifier) in &[(&from_ring, &from_bytes), (&from_bytes, &from_ring)] {
+                let (token, cookie) = issuer.generate_token_pair(None, 300)
+                    .expect("couldn't generate token/cookie pair");
+                assert_eq!(verifier.verify_raw(token.value(), cookie.value()), Ok(()));
+            }
+        }
 
-        let mut plaintext = [0; 88];
+        #[test]
+        fn derive_subkey_unchanged() {
+            let derived = HmacCsrfProtection::from_signing_key(SigningKey::new(&SHA256, &KEY_32))
+                .expect("key rejected")
+                .derive_subkey(b"tenant-a");
+            let mut subkey = [0; 32];
+            hkdf_expand(Sha256::new(), &KEY_32, &[SUBKEY_LABEL, b"tenant-a"].concat(), &mut subkey);
+            let expected = HmacCsrfProtection::from_key(subkey);
+
+            let (token, cookie) = derived.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(expected.verify_raw(token.value(), cookie.value()), Ok(()));
+        }
 
-        for i in 0..16 {
-            plaintext[i] = padding[i];
+        #[test]
+        fn other_digest_rejected() {
+            assert_eq!(HmacCsrfProtection::from_signing_key(SigningKey::new(&SHA512, &KEY_32)).err(),
+                       Some(CsrfError::InvalidKey));
         }
-        for i in 0..8 {
-            plaintext[i + 16] = expires_bytes[i];
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    mod streaming_binding {
+        use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        fn binding() -> Vec<u8> {
+            (0..1 << 20).map(|i| (i * 7) as u8).collect()
         }
-        for i in 0..64 {
-            plaintext[i + 24] = token_value[i];
+
+        #[test]
+        fn matches_one_shot() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let binding = binding();
+            let token = protect.generate_bound_token(&[7; 64], &binding).expect("couldn't generate token");
+            let one_shot = protect.parse_bound_token(token.value(), &binding).expect("token not parsed");
+
+            for &chunk in &[1, 1000, 4096, binding.len()] {
+                let streamed = protect.parse_bound_token_streaming(token.value(), |sink| {
+                    for part in binding.chunks(chunk) {
+                        sink(part);
+                    }
+                }).expect("token not parsed");
+                assert_eq!(streamed, one_shot, "chunk {}", chunk);
+            }
         }
 
-        let mut ciphertext = [0; 88];
-        let mut tag = [0; 16];
-        let mut aead = self.aead(&nonce);
+        #[test]
+        fn empty_stream_matches_unbound() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            let streamed = protect.parse_bound_token_streaming(token.value(), |_| {}).expect("token not parsed");
+            assert_eq!(Ok(streamed), protect.parse_token(token.value()));
+        }
 
-        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+        #[test]
+        fn different_stream_rejected() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            let mut binding = binding();
+            let token = protect.generate_bound_token(&[7; 64], &binding).expect("couldn't generate token");
 
-        let mut transport = [0; 116];
+            let truncated = protect.parse_bound_token_streaming(token.value(), |sink| sink(&binding[1..]));
+            assert_eq!(truncated.err(), Some(CsrfError::ValidationFailure));
 
-        for i in 0..88 {
-            transport[i] = ciphertext[i];
-        }
-        for i in 0..12 {
-            transport[i + 88] = nonce[i];
-        }
-        for i in 0..16 {
-            transport[i + 100] = tag[i];
+            let last = binding.len() - 1;
+            binding[last] ^= 0x01;
+            let altered = protect.parse_bound_token_streaming(token.value(), |sink| sink(&binding));
+            assert_eq!(altered.err(), Some(CsrfError::ValidationFailure));
+            assert_eq!(protect.parse_bound_token(token.value(), &binding).err(), Some(CsrfError::ValidationFailure));
         }
 
-        Ok(CsrfCookie::new(transport.to_vec()))
+        #[test]
+        fn malformed_rejected() {
+            let protect = HmacCsrfProtection::from_key(KEY_32);
+            assert_eq!(protect.parse_bound_token_streaming(&[], |_| {}).err(), Some(CsrfError::MissingToken));
+            assert_eq!(protect.parse_bound_token_streaming(&[1; 104], |_| {}).err(), Some(CsrfError::MalformedLength));
+        }
     }
 
-    fn generate_token(&self, token_value: &[u8; 64]) -> Result<CsrfToken, CsrfError> {
-        let mut nonce = [0; 12];
-        self.random_bytes(&mut nonce)?;
+    #[cfg(feature = "aes-gcm-backend")]
+    mod pair_nonces {
+        use ring::rand::SystemRandom;
 
-        let mut padding = [0; 16];
-        self.random_bytes(&mut padding)?;
+        use core::{AesGcmCsrfProtection, CsrfCookie, CsrfError, CsrfProtection, CsrfToken,
+                   SecurityParameters, UnencryptedCsrfCookie, UnencryptedCsrfToken};
+        use test_util::KEY_32;
 
-        let mut plaintext = [0; 80];
+        /// Simulates a random number generator stuck on a fixed output by zeroing every nonce the
+        /// inner protection seals under.
+        #[derive(Clone)]
+        struct StuckRng(AesGcmCsrfProtection);
 
-        for i in 0..16 {
-            plaintext[i] = padding[i];
-        }
-        for i in 0..64 {
-            plaintext[i + 16] = token_value[i];
+        fn zero_nonce(mut transport: Vec<u8>) -> Vec<u8> {
+            let len = transport.len();
+            for byte in transport[len - 28..len - 16].iter_mut() {
+                *byte = 0;
+            }
+            transport
         }
 
-        let mut ciphertext = [0; 80];
-        let mut tag = vec![0; 16];
-        let mut aead = self.aead(&nonce);
-
-        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+        impl CsrfProtection for StuckRng {
+            fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self {
+                StuckRng(AesGcmCsrfProtection::from_password_and_salt(password, salt))
+            }
 
-        let mut transport = [0; 108];
+            fn clone_box(&self) -> Box<dyn CsrfProtection> {
+                Box::new(self.clone())
+            }
 
-        for i in 0..80 {
-            transport[i] = ciphertext[i];
-        }
-        for i in 0..12 {
-            transport[i + 80] = nonce[i];
-        }
-        for i in 0..16 {
-            transport[i + 92] = tag[i];
-        }
+            fn key_id(&self) -> [u8; 8] {
+                self.0.key_id()
+            }
 
-        Ok(CsrfToken::new(transport.to_vec()))
-    }
+            fn security_parameters(&self) -> SecurityParameters {
+                self.0.security_parameters()
+            }
 
-    fn parse_cookie(&self, cookie: &[u8]) -> Result<UnencryptedCsrfCookie, CsrfError> {
-        if cookie.len() != 116 {
-            debug!("Cookie too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
-        }
+            fn rng(&self) -> &SystemRandom {
+                self.0.rng()
+            }
 
-        let mut ciphertext = [0; 88];
-        let mut nonce = [0; 12];
-        let mut tag = [0; 16];
+            fn expected_token_len(&self) -> usize {
+                self.0.expected_token_len()
+            }
 
-        for i in 0..88 {
-            ciphertext[i] = cookie[i];
-        }
-        for i in 0..12 {
-            nonce[i] = cookie[i + 88];
-        }
-        for i in 0..16 {
-            tag[i] = cookie[i + 100];
-        }
+            fn expected_cookie_len(&self) -> usize {
+                self.0.expected_cookie_len()
+            }
 
-        let mut plaintext = [0; 88];
-        let mut aead = self.aead(&nonce);
-        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
-            info!("Failed to decrypt CSRF cookie");
-            return Err(CsrfError::ValidationFailure);
-        }
+            fn transport_nonce<'a>(&self, transport: &'a [u8]) -> Option<&'a [u8]> {
+                self.0.transport_nonce(transport)
+            }
 
-        let mut expires_bytes = [0; 8];
-        let mut token = [0; 64];
+            fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+                let cookie = self.0.generate_cookie_at(token_value, ttl_seconds, now)?;
+                Ok(CsrfCookie::new(zero_nonce(cookie.value().to_vec())))
+            }
 
-        // skip 16 bytes of padding
-        for i in 0..8 {
-            expires_bytes[i] = plaintext[i + 16];
-        }
-        for i in 0..64 {
-            token[i] = plaintext[i + 24];
-        }
+            fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+                let cookie = self.0.refresh_cookie(cookie, ttl_seconds)?;
+                Ok(CsrfCookie::new(zero_nonce(cookie.value().to_vec())))
+            }
 
-        let expires = unsafe { mem::transmute::<[u8; 8], i64>(expires_bytes) };
+            fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+                let token = self.0.generate_bound_token(token_value, binding)?;
+                Ok(CsrfToken::new(zero_nonce(token.value().to_vec())))
+            }
 
-        Ok(UnencryptedCsrfCookie::new(expires, token.to_vec()))
-    }
+            fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+                self.0.parse_cookie_at(cookie, now)
+            }
 
-    fn parse_token(&self, token: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
-        if token.len() != 108 {
-            debug!("Token too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
+            fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+                self.0.parse_bound_token(token, binding)
+            }
         }
 
-        let mut ciphertext = [0; 80];
-        let mut nonce = [0; 12];
-        let mut tag = [0; 16];
+        #[test]
+        fn distinct_nonces() {
+            let protect = AesGcmCsrfProtection::from_key(KEY_32);
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
 
-        for i in 0..80 {
-            ciphertext[i] = token[i];
-        }
-        for i in 0..12 {
-            nonce[i] = token[i + 80];
-        }
-        for i in 0..16 {
-            tag[i] = token[i + 92];
+            let token_nonce = protect.transport_nonce(token.value()).expect("token had no nonce");
+            let cookie_nonce = protect.transport_nonce(cookie.value()).expect("cookie had no nonce");
+            assert_eq!((token_nonce.len(), cookie_nonce.len()), (12, 12));
+            assert_ne!(token_nonce, cookie_nonce);
         }
 
-        let mut plaintext = [0; 80];
-        let mut aead = self.aead(&nonce);
-        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
-            info!("Failed to decrypt CSRF token");
-            return Err(CsrfError::ValidationFailure);
+        #[test]
+        fn stuck_rng_rejected() {
+            let protect = StuckRng(AesGcmCsrfProtection::from_key(KEY_32));
+            assert_eq!(protect.generate_token_pair(None, 300).err(), Some(CsrfError::InternalError));
+            assert_eq!(protect.generate_token_for(None, "POST", "/", 300).err(), Some(CsrfError::InternalError));
         }
 
-        let mut token = [0; 64];
+        #[test]
+        fn stuck_rng_rejected_on_reissue() {
+            let healthy = AesGcmCsrfProtection::from_key(KEY_32);
+            let (token, cookie) = healthy.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
 
-        // skip 16 bytes of padding
-        for i in 0..64 {
-            token[i] = plaintext[i + 16];
+            let protect = StuckRng(healthy);
+            assert_eq!(protect.verify_and_reissue(&token.b64_string(), &cookie.b64_string(), 300).err(),
+                       Some(CsrfError::InternalError));
         }
-
-        Ok(UnencryptedCsrfToken::new(token.to_vec()))
     }
-}
-
-
-/// Uses ChaCha20Poly1305 to provide signed, encrypted CSRF tokens and cookies.
-pub struct ChaCha20Poly1305CsrfProtection {
-    rng: SystemRandom,
-    aead_key: [u8; 32],
-}
 
-impl ChaCha20Poly1305CsrfProtection {
-    /// Given a key, return a `ChaCha20Poly1305CsrfProtection` instance.
-    pub fn from_key(aead_key: [u8; 32]) -> Self {
-        ChaCha20Poly1305CsrfProtection {
-            rng: SystemRandom::new(),
-            aead_key: aead_key,
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod supports_encryption {
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfProtection,
+                   HmacCsrfProtection};
+        use test_util::KEY_32;
+
+        #[test]
+        fn per_backend() {
+            assert!(!HmacCsrfProtection::from_key(KEY_32).supports_encryption());
+            assert!(AesGcmCsrfProtection::from_key(KEY_32).supports_encryption());
+            assert!(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).supports_encryption());
         }
-    }
 
-    fn aead(&self, nonce: &[u8; 8]) -> ChaCha20Poly1305 {
-        ChaCha20Poly1305::new(&self.aead_key, nonce, &[])
+        #[test]
+        fn through_box() {
+            let boxed: Box<dyn CsrfProtection> = Box::new(AesGcmCsrfProtection::from_key(KEY_32));
+            assert!(boxed.supports_encryption());
+        }
     }
-}
-
-impl CsrfProtection for ChaCha20Poly1305CsrfProtection {
-    /// Using `scrypt` with params `n=12`, `r=8`, `p=1`, generate the key material used for the
-    /// underlying crypto functions.
-    ///
-    /// # Panics
-    /// This function may panic if the underlying crypto library fails catastrophically.
-    fn from_password(password: &[u8]) -> Self {
-        let params = if cfg!(test) {
-            // scrypt is *slow*, so use these params for testing
-            ScryptParams::new(1, 8, 1)
-        } else {
-            ScryptParams::new(12, 8, 1)
-        };
 
-        let mut aead_key = [0; 32];
-        info!("Generating key material. This may take some time.");
-        scrypt(password, SCRYPT_SALT, &params, &mut aead_key);
-        info!("Key material generated.");
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod key_id {
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfProtection,
+                   HmacCsrfProtection};
+        use domain::DomainSeparatedCsrfProtection;
+        use fallback::FallbackCsrfProtection;
+        use test_util::KEY_32;
+
+        #[test]
+        fn same_across_backends() {
+            let key_id = HmacCsrfProtection::from_key(KEY_32).key_id();
+            assert_eq!(AesGcmCsrfProtection::from_key(KEY_32).key_id(), key_id);
+            assert_eq!(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).key_id(), key_id);
+        }
 
-        ChaCha20Poly1305CsrfProtection::from_key(aead_key)
+        #[test]
+        fn wrappers_report_their_primary() {
+            let key_id = HmacCsrfProtection::from_key(KEY_32).key_id();
+            let domain = DomainSeparatedCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32), b"appA");
+            let fallback = FallbackCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32))
+                .with_alternate(HmacCsrfProtection::from_key([1; 32]));
+            assert_eq!(domain.key_id(), key_id);
+            assert_eq!(fallback.key_id(), key_id);
+        }
     }
 
-    fn rng(&self) -> &SystemRandom {
-        &self.rng
-    }
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod split_keys {
+        use crypto::hkdf::hkdf_expand;
+        use crypto::sha2::Sha256;
+
+        use config::CsrfConfig;
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   HmacCsrfProtection, COOKIE_KEY_LABEL, TOKEN_KEY_LABEL};
+        use test_util::KEY_32;
+
+        fn assert_split<P: CsrfProtection + Clone>(split: P, derived: P, shared: P) {
+            assert_eq!(split.self_test(), Ok(()));
+            assert_eq!(split.key_id(), shared.key_id());
+
+            let (token, cookie) = split.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert_eq!(split.clone().verify_raw(token.value(), cookie.value()), Ok(()));
+            assert_eq!((token.value().len(), cookie.value().len()),
+                       (shared.expected_token_len(), shared.expected_cookie_len()));
+            assert_eq!(shared.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+            assert_eq!(shared.parse_cookie(cookie.value()).err(), Some(CsrfError::ValidationFailure));
+
+            let (token, cookie) = shared.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert_eq!(split.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+            assert_eq!(split.parse_cookie(cookie.value()).err(), Some(CsrfError::ValidationFailure));
+
+            let (token, cookie) = derived.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert_eq!(derived.verify_raw(token.value(), cookie.value()), Ok(()));
+            assert!(split.parse_token(token.value()).is_err());
+        }
 
-    fn generate_cookie(&self, token_value: &[u8; 64], ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
-        let expires = time::precise_time_s() as i64 + ttl_seconds;
-        let expires_bytes = unsafe { mem::transmute::<i64, [u8; 8]>(expires) };
+        fn hkdf(label: &[u8]) -> [u8; 32] {
+            let mut key = [0; 32];
+            hkdf_expand(Sha256::new(), &KEY_32, label, &mut key);
+            key
+        }
 
-        let mut nonce = [0; 8];
-        self.random_bytes(&mut nonce)?;
+        #[test]
+        fn round_trip() {
+            let hmac = HmacCsrfProtection::from_key(KEY_32).with_split_keys(true);
+            assert!(hmac.split_keys() && hmac.clone().split_keys() && hmac.derive_subkey(b"a").split_keys());
+            assert!(!hmac.clone().with_split_keys(false).split_keys());
+            assert_split(hmac.clone(), hmac.derive_subkey(b"a"), HmacCsrfProtection::from_key(KEY_32));
 
-        let mut padding = [0; 16];
-        self.random_bytes(&mut padding)?;
+            let aes = AesGcmCsrfProtection::from_key(KEY_32).with_split_keys(true);
+            assert!(aes.split_keys() && aes.clone().split_keys() && aes.derive_subkey(b"a").split_keys());
+            assert_split(aes.clone(), aes.derive_subkey(b"a"), AesGcmCsrfProtection::from_key(KEY_32));
 
-        let mut plaintext = [0; 88];
+            let chacha = ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_split_keys(true);
+            assert!(chacha.split_keys() && chacha.clone().split_keys() && chacha.derive_subkey(b"a").split_keys());
+            assert_split(chacha.clone(), chacha.derive_subkey(b"a"), ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+        }
 
-        for i in 0..16 {
-            plaintext[i] = padding[i];
+        #[test]
+        fn keys_are_hkdf_expand() {
+            let hmac = HmacCsrfProtection::from_key(KEY_32).with_split_keys(true);
+            let (token, cookie) = hmac.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert!(HmacCsrfProtection::from_key(hkdf(TOKEN_KEY_LABEL)).parse_token(token.value()).is_ok());
+            assert!(HmacCsrfProtection::from_key(hkdf(COOKIE_KEY_LABEL)).parse_cookie(cookie.value()).is_ok());
+
+            let aes = AesGcmCsrfProtection::from_key(KEY_32).with_split_keys(true);
+            let (token, cookie) = aes.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert!(AesGcmCsrfProtection::from_key(hkdf(TOKEN_KEY_LABEL)).parse_token(token.value()).is_ok());
+            assert!(AesGcmCsrfProtection::from_key(hkdf(COOKIE_KEY_LABEL)).parse_cookie(cookie.value()).is_ok());
         }
-        for i in 0..8 {
-            plaintext[i + 16] = expires_bytes[i];
+
+        /// A token bound to 8 bytes carries a valid tag for a cookie with those bytes as its expiry,
+        /// unless the keys are split.
+        #[test]
+        fn hmac_token_not_a_cookie() {
+            for &split in &[false, true] {
+                let protect = HmacCsrfProtection::from_key(KEY_32).with_split_keys(split);
+                let expires = i64::MAX.to_le_bytes();
+                let token = protect.generate_bound_token(&[7; 64], &expires).expect("couldn't generate token");
+                let forged_cookie = [&token.value()[..73], &expires, &token.value()[73..]].concat();
+                assert_eq!(forged_cookie.len(), protect.expected_cookie_len());
+                assert_eq!(protect.parse_cookie(&forged_cookie).is_ok(), !split);
+
+                let cookie = protect.generate_cookie(&[7; 64], 300).expect("couldn't generate cookie");
+                let forged_token = [&cookie.value()[..73], &cookie.value()[81..]].concat();
+                assert_eq!(protect.parse_bound_token(&forged_token, &cookie.value()[73..81]).is_ok(), !split);
+            }
         }
-        for i in 0..64 {
-            plaintext[i + 24] = token_value[i];
+
+        /// With 8 more bytes of padding, a token is as long as a cookie, and the AEAD associated
+        /// data of an unbound token is the same as a cookie's.
+        #[test]
+        fn aead_token_not_a_cookie() {
+            let issuer = AesGcmCsrfProtection::from_key(KEY_32).with_split_keys(true)
+                .with_config(CsrfConfig::new().with_aead_padding_len(24));
+            let parser = AesGcmCsrfProtection::from_key(KEY_32).with_split_keys(true);
+            let token = issuer.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert_eq!(token.value().len(), parser.expected_cookie_len());
+            assert_eq!(parser.parse_cookie(token.value()).err(), Some(CsrfError::ValidationFailure));
+
+            let cookie = parser.generate_cookie(&[7; 64], 300).expect("couldn't generate cookie");
+            assert_eq!(issuer.parse_token(cookie.value()).err(), Some(CsrfError::ValidationFailure));
         }
+    }
 
-        let mut ciphertext = [0; 88];
-        let mut tag = [0; 16];
-        let mut aead = self.aead(&nonce);
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod security_parameters {
+        use std::sync::atomic::{AtomicU64, Ordering};
 
-        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   HmacCsrfProtection, SecurityParameters};
+        use domain::DomainSeparatedCsrfProtection;
+        use fallback::FallbackCsrfProtection;
+        use source::TokenValueSource;
+        use test_util::KEY_32;
 
-        let mut transport = [0; 112];
+        struct CounterSource(AtomicU64);
 
-        for i in 0..88 {
-            transport[i] = ciphertext[i];
-        }
-        for i in 0..8 {
-            transport[i + 88] = nonce[i];
+        impl TokenValueSource for CounterSource {
+            fn next_value(&self, buf: &mut [u8; 64]) -> Result<(), CsrfError> {
+                buf[..8].copy_from_slice(&self.0.fetch_add(1, Ordering::SeqCst).to_le_bytes());
+                Ok(())
+            }
         }
-        for i in 0..16 {
-            transport[i + 96] = tag[i];
+
+        fn parameters(params: SecurityParameters) -> (usize, Option<usize>, usize, Option<usize>, bool) {
+            (params.key_bits(), params.nonce_len(), params.tag_len(), params.token_value_bits(), params.encrypted())
         }
 
-        Ok(CsrfCookie::new(transport.to_vec()))
-    }
+        #[test]
+        fn known_values() {
+            assert_eq!(parameters(HmacCsrfProtection::from_key(KEY_32).security_parameters()),
+                       (256, None, 32, Some(512), false));
+            assert_eq!(parameters(AesGcmCsrfProtection::from_key(KEY_32).security_parameters()),
+                       (256, Some(12), 16, Some(512), true));
+            assert_eq!(parameters(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).security_parameters()),
+                       (256, Some(8), 16, Some(512), true));
+        }
 
-    fn generate_token(&self, token_value: &[u8; 64]) -> Result<CsrfToken, CsrfError> {
-        let mut nonce = [0; 8];
-        self.random_bytes(&mut nonce)?;
+        #[test]
+        fn wrappers_report_inner() {
+            let chacha = ChaCha20Poly1305CsrfProtection::from_key(KEY_32);
+            let fallback = FallbackCsrfProtection::new(chacha.clone());
+            assert_eq!(fallback.security_parameters(), chacha.security_parameters());
+            let domain = DomainSeparatedCsrfProtection::new(chacha.clone(), b"app");
+            assert_eq!(domain.security_parameters(), chacha.security_parameters());
+            assert_eq!(chacha.clone_box().security_parameters(), chacha.security_parameters());
+        }
 
-        let mut padding = [0; 16];
-        self.random_bytes(&mut padding)?;
+        #[test]
+        fn token_value_source_entropy_unknown() {
+            let hmac = HmacCsrfProtection::from_key(KEY_32).with_token_value_source(CounterSource(AtomicU64::new(0)));
+            assert_eq!(parameters(hmac.security_parameters()), (256, None, 32, None, false));
+            let aes = AesGcmCsrfProtection::from_key(KEY_32).with_token_value_source(CounterSource(AtomicU64::new(0)));
+            assert_eq!(aes.security_parameters().token_value_bits(), None);
+            assert_eq!(FallbackCsrfProtection::new(aes).security_parameters().token_value_bits(), None);
+        }
+    }
 
-        let mut plaintext = [0; 80];
+    #[cfg(all(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod nonce_budget {
+        use config::CsrfConfig;
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection};
+        use test_util::KEY_32;
+
+        // 4 nonces under the 8 byte ChaCha20Poly1305 nonce, and 2^18 under the 12 byte AES-GCM one
+        const FRACTION: f64 = 1e-9;
+
+        #[test]
+        fn counts_nonces() {
+            let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32);
+            assert_eq!(protect.nonces_used(), 0);
+            let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.nonces_used(), 2);
+
+            // clones share the count, while a derived key starts its own
+            let clone = protect.clone();
+            clone.generate_token(&[7; 64]).expect("couldn't generate token");
+            assert_eq!(protect.nonces_used(), 3);
+            assert_eq!(protect.derive_subkey(b"a").nonces_used(), 0);
+
+            // parsing draws no nonces
+            assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+            assert_eq!(protect.nonces_used(), 3);
+        }
 
-        for i in 0..16 {
-            plaintext[i] = padding[i];
+        #[test]
+        fn budget_warns() {
+            let config = CsrfConfig::new().with_nonce_budget(FRACTION);
+            assert_eq!(config.nonce_limit(8), Some(4));
+            assert_eq!(config.nonce_limit(12), Some(1 << 18));
+            assert!(config.nonce_budget().is_some_and(|fraction| (fraction - FRACTION).abs() < 1e-9));
+            assert_eq!(CsrfConfig::new().with_nonce_budget(1.0).nonce_limit(8), Some(1 << 32));
+            assert_eq!(CsrfConfig::new().nonce_limit(8), None);
+
+            let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(config);
+            for _ in 0..3 {
+                protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            }
+            assert_eq!(protect.nonces_used(), 6);
         }
-        for i in 0..64 {
-            plaintext[i + 16] = token_value[i];
+
+        #[test]
+        fn strict_budget_covers_seal() {
+            let config = CsrfConfig::new().with_nonce_budget(FRACTION).with_strict_nonce_budget(true);
+            let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(config);
+            protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            let sealed = protect.seal(b"flash message").expect("value not sealed");
+            protect.clone().seal(b"flash message").expect("value not sealed");
+            assert_eq!(protect.nonces_used(), 4);
+
+            assert_eq!(protect.seal(b"flash message").err(), Some(CsrfError::InternalError));
+            assert_eq!(protect.generate_token(&[7; 64]).err(), Some(CsrfError::InternalError));
+            assert_eq!(protect.open(&sealed), Ok(b"flash message".to_vec()));
+            assert_eq!(protect.nonces_used(), 4);
         }
 
-        let mut ciphertext = [0; 80];
-        let mut tag = vec![0; 16];
-        let mut aead = self.aead(&nonce);
+        #[test]
+        fn strict_budget_refuses() {
+            let config = CsrfConfig::new().with_nonce_budget(FRACTION).with_strict_nonce_budget(true);
+            let protect = ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(config.clone());
+            let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.nonces_used(), 4);
+
+            assert_eq!(protect.generate_token_pair(None, 300).err(), Some(CsrfError::InternalError));
+            assert_eq!(protect.clone().generate_cookie(&[7; 64], 300).err(), Some(CsrfError::InternalError));
+            assert_eq!(protect.nonces_used(), 4);
+            assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+
+            // a rotated key has a fresh budget
+            assert!(protect.derive_subkey(b"rotated").generate_token_pair(None, 300).is_ok());
+            let aes = AesGcmCsrfProtection::from_key(KEY_32).with_config(config);
+            for _ in 0..3 {
+                aes.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            }
+        }
+    }
 
-        aead.encrypt(&plaintext, &mut ciphertext, &mut tag);
+    #[cfg(feature = "hmac-backend")]
+    mod refresh_policy {
+        use core::{unix_time, CsrfError, CsrfProtection, HmacCsrfProtection, UnencryptedCsrfCookie,
+                   UnencryptedCsrfToken, VerifyOutcome};
 
-        let mut transport = [0; 104];
+        fn outcome(token_value: u8, expires_in: i64) -> VerifyOutcome {
+            let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567");
+            let token = UnencryptedCsrfToken::new(vec![token_value; 64]);
+            let cookie = UnencryptedCsrfCookie::new(unix_time() + expires_in, vec![7; 64]);
+            protect.verify_with_refresh_policy(&token, &cookie, 1000, 0.5)
+        }
 
-        for i in 0..80 {
-            transport[i] = ciphertext[i];
+        #[test]
+        fn valid() {
+            assert_eq!(outcome(7, 900), VerifyOutcome::Valid);
+            assert!(outcome(7, 900).is_valid());
         }
-        for i in 0..8 {
-            transport[i + 80] = nonce[i];
+
+        #[test]
+        fn valid_but_refresh() {
+            assert_eq!(outcome(7, 400), VerifyOutcome::ValidButRefresh);
+            assert!(outcome(7, 400).is_valid());
         }
-        for i in 0..16 {
-            transport[i + 88] = tag[i];
+
+        #[test]
+        fn expired() {
+            assert_eq!(outcome(7, -10), VerifyOutcome::Expired);
+            assert!(!outcome(7, -10).is_valid());
         }
 
-        Ok(CsrfToken::new(transport.to_vec()))
+        #[test]
+        fn invalid() {
+            assert_eq!(outcome(8, 900), VerifyOutcome::Invalid(CsrfError::TokenMismatch));
+            // a mismatch is reported even if the cookie has also expired
+            assert_eq!(outcome(8, -10), VerifyOutcome::Invalid(CsrfError::TokenMismatch));
+            assert!(!outcome(8, 900).is_valid());
+        }
     }
 
-    fn parse_cookie(&self, cookie: &[u8]) -> Result<UnencryptedCsrfCookie, CsrfError> {
-        if cookie.len() != 112 {
-            debug!("Cookie too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
+    #[cfg(feature = "hmac-backend")]
+    mod grace {
+        use core::{unix_time, CsrfError, CsrfProtection, HmacCsrfProtection, UnencryptedCsrfCookie,
+                   UnencryptedCsrfToken, VerifyOutcome};
+
+        fn outcome(token_value: u8, expires_in: i64, grace_secs: i64) -> VerifyOutcome {
+            let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567");
+            let token = UnencryptedCsrfToken::new(vec![token_value; 64]);
+            let cookie = UnencryptedCsrfCookie::new(unix_time() + expires_in, vec![7; 64]);
+            protect.verify_token_pair_with_grace(&token, &cookie, grace_secs)
         }
 
-        let mut ciphertext = [0; 88];
-        let mut nonce = [0; 8];
-        let mut tag = [0; 16];
+        #[test]
+        fn unexpired() {
+            assert_eq!(outcome(7, 60, 30), VerifyOutcome::Valid);
+        }
 
-        for i in 0..88 {
-            ciphertext[i] = cookie[i];
+        #[test]
+        fn just_expired() {
+            assert_eq!(outcome(7, 0, 30), VerifyOutcome::Grace);
+            assert_eq!(outcome(7, -1, 30), VerifyOutcome::Grace);
+            assert!(outcome(7, 0, 30).is_valid());
+            assert_eq!(outcome(7, 0, 0), VerifyOutcome::Expired);
+            assert_eq!(outcome(7, 0, -30), VerifyOutcome::Expired);
         }
-        for i in 0..8 {
-            nonce[i] = cookie[i + 88];
+
+        #[test]
+        fn within_grace() {
+            assert_eq!(outcome(7, -20, 30), VerifyOutcome::Grace);
         }
-        for i in 0..16 {
-            tag[i] = cookie[i + 96];
+
+        #[test]
+        fn beyond_grace() {
+            assert_eq!(outcome(7, -30, 30), VerifyOutcome::Expired);
+            assert_eq!(outcome(7, -3600, 30), VerifyOutcome::Expired);
+            assert!(!outcome(7, -30, 30).is_valid());
         }
 
-        let mut plaintext = [0; 88];
-        let mut aead = self.aead(&nonce);
-        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
-            info!("Failed to decrypt CSRF cookie");
-            return Err(CsrfError::ValidationFailure);
+        #[test]
+        fn mismatch() {
+            assert_eq!(outcome(8, 60, 30), VerifyOutcome::Invalid(CsrfError::TokenMismatch));
+            assert_eq!(outcome(8, -20, 30), VerifyOutcome::Invalid(CsrfError::TokenMismatch));
         }
+    }
 
-        let mut expires_bytes = [0; 8];
-        let mut token = [0; 64];
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod algorithm_mismatch {
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+                   HmacCsrfProtection};
+        use test_util::KEY_32;
 
-        // skip 16 bytes of padding
-        for i in 0..8 {
-            expires_bytes[i] = plaintext[i + 16];
-        }
-        for i in 0..64 {
-            token[i] = plaintext[i + 24];
+        fn protections() -> Vec<Box<dyn CsrfProtection>> {
+            vec![Box::new(HmacCsrfProtection::from_key(KEY_32)),
+                 Box::new(AesGcmCsrfProtection::from_key(KEY_32)),
+                 Box::new(ChaCha20Poly1305CsrfProtection::from_key(KEY_32))]
         }
 
-        let expires = unsafe { mem::transmute::<[u8; 8], i64>(expires_bytes) };
+        #[test]
+        fn other_backend_rejected() {
+            for (i, issuer) in protections().iter().enumerate() {
+                let (token, cookie) = issuer.generate_token_pair(None, 300)
+                    .expect("couldn't generate token/cookie pair");
+
+                for (j, parser) in protections().iter().enumerate() {
+                    if i == j {
+                        continue;
+                    }
+                    assert_eq!(parser.parse_token(token.value()).err(), Some(CsrfError::AlgorithmMismatch));
+                    assert_eq!(parser.parse_cookie(cookie.value()).err(), Some(CsrfError::AlgorithmMismatch));
+                }
+            }
+        }
 
-        Ok(UnencryptedCsrfCookie::new(expires, token.to_vec()))
-    }
+        #[test]
+        fn hmac_verify_cookie_only() {
+            let cookie = AesGcmCsrfProtection::from_key(KEY_32).generate_cookie(&[7; 64], 300)
+                .expect("couldn't generate cookie");
+            assert_eq!(HmacCsrfProtection::from_key(KEY_32).verify_cookie_only(cookie.value()),
+                       Err(CsrfError::AlgorithmMismatch));
+        }
 
-    fn parse_token(&self, token: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
-        if token.len() != 104 {
-            debug!("Token too small. Not parsed.");
-            return Err(CsrfError::ValidationFailure);
+        #[test]
+        fn other_lengths_still_malformed() {
+            for parser in protections().iter() {
+                assert_eq!(parser.parse_token(&[1; 50]).err(), Some(CsrfError::MalformedLength));
+                // another backend's length, but without the version byte
+                assert_ne!(parser.parse_cookie(&[0; 125]).err(), Some(CsrfError::AlgorithmMismatch));
+            }
         }
+    }
 
-        let mut ciphertext = [0; 80];
-        let mut nonce = [0; 8];
-        let mut tag = [0; 16];
+    mod token_precedence {
+        use core::{CsrfError, TokenPrecedence};
 
-        for i in 0..80 {
-            ciphertext[i] = token[i];
+        #[test]
+        fn header_only() {
+            assert_eq!(TokenPrecedence::Header.select(Some("h"), None), Ok("h"));
+            assert_eq!(TokenPrecedence::Form.select(Some("h"), None), Ok("h"));
         }
-        for i in 0..8 {
-            nonce[i] = token[i + 80];
+
+        #[test]
+        fn form_only() {
+            assert_eq!(TokenPrecedence::Header.select(None, Some("f")), Ok("f"));
+            assert_eq!(TokenPrecedence::Form.select(None, Some("f")), Ok("f"));
         }
-        for i in 0..16 {
-            tag[i] = token[i + 88];
+
+        #[test]
+        fn both_present() {
+            assert_eq!(TokenPrecedence::default().select(Some("h"), Some("f")), Ok("h"));
+            assert_eq!(TokenPrecedence::Header.select(Some("h"), Some("f")), Ok("h"));
+            assert_eq!(TokenPrecedence::Form.select(Some("h"), Some("f")), Ok("f"));
         }
 
-        let mut plaintext = [0; 80];
-        let mut aead = self.aead(&nonce);
-        if !aead.decrypt(&ciphertext, &mut plaintext, &tag) {
-            info!("Failed to decrypt CSRF token");
-            return Err(CsrfError::ValidationFailure);
+        #[test]
+        fn neither_present() {
+            assert_eq!(TokenPrecedence::Header.select(None, None), Err(CsrfError::MissingToken));
+            assert_eq!(TokenPrecedence::Form.select(Some(""), Some("")), Err(CsrfError::MissingToken));
         }
+    }
 
-        let mut token = [0; 64];
+    #[cfg(feature = "hmac-backend")]
+    mod log_redaction {
+        use std::sync::{Mutex, Once};
 
-        // skip 16 bytes of padding
-        for i in 0..64 {
-            token[i] = plaintext[i + 16];
-        }
+        use data_encoding::BASE64;
+        use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
 
-        Ok(UnencryptedCsrfToken::new(token.to_vec()))
-    }
-}
+        use core::{CsrfProtection, HmacCsrfProtection};
 
+        static INSTALL: Once = Once::new();
+        static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
-#[cfg(feature = "iron")]
-impl typemap::Key for CsrfToken {
-    type Value = CsrfToken;
-}
+        struct Capture;
 
+        impl Log for Capture {
+            fn enabled(&self, _: &LogMetadata) -> bool {
+                true
+            }
 
-#[cfg(test)]
-mod tests {
-    // TODO write test that ensures encrypted messages don't contain the plaintext
-    // TODO test that checks tokens are repeated when given Some
-    // TODO use macros for writing all of these
+            fn log(&self, record: &LogRecord) {
+                CAPTURED.lock().expect("log capture poisoned").push(record.args().to_string());
+            }
+        }
 
-    macro_rules! test_cases {
-        ($strct: ident, $md: ident) => {
-            mod $md {
-                use $crate::core::{CsrfProtection, $strct};
-                use data_encoding::BASE64;
+        /// Fail to verify a mismatched pair, and return the base64 token value and whether it was
+        /// found in the captured log output.
+        fn logs_after_mismatch() -> (String, bool) {
+            INSTALL.call_once(|| {
+                log::set_logger(|max| {
+                    max.set(LogLevelFilter::Debug);
+                    Box::new(Capture)
+                }).expect("logger already installed");
+            });
+
+            let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567");
+            let token = protect.generate_token(&[0x5a; 64]).expect("couldn't generate token");
+            let cookie = protect.generate_cookie(&[0xa5; 64], 300).expect("couldn't generate cookie");
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(!protect.verify_token_pair(&token, &cookie));
+
+            let encoded = BASE64.encode(token.value());
+            let logs = CAPTURED.lock().expect("log capture poisoned");
+            assert!(logs.iter().any(|line| line.starts_with("Token did not match cookie")));
+            let found = logs.iter().any(|line| line.contains(&encoded));
+            (encoded, found)
+        }
 
-                const KEY_32: [u8; 32] = *b"01234567012345670123456701234567";
+        #[cfg(not(feature = "log-secrets"))]
+        #[test]
+        fn mismatch_logs_fingerprint_only() {
+            let (encoded, found) = logs_after_mismatch();
+            assert!(!found, "token value {} was logged", encoded);
+        }
 
-                #[test]
-                fn from_password() {
-                    let _ = $strct::from_password(b"correct horse battery staple");
-                }
+        #[cfg(feature = "log-secrets")]
+        #[test]
+        fn mismatch_logs_secrets_when_enabled() {
+            let (encoded, found) = logs_after_mismatch();
+            assert!(found, "token value {} was not logged", encoded);
+        }
+    }
 
-                #[test]
-                fn verification_succeeds() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (token, cookie) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/cookie pair");
-                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
-                    let token = protect.parse_token(&token).expect("token not parsed");
-                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
-                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
-                    assert!(protect.verify_token_pair(&token, &cookie),
-                            "could not verify token/cookie pair");
-                }
+    #[cfg(feature = "test-util")]
+    mod insecure_test {
+        use core::{CsrfError, CsrfProtection, InsecureTestCsrfProtection};
+
+        #[test]
+        fn round_trip() {
+            let protect = InsecureTestCsrfProtection::new();
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(token.value().len(), protect.expected_token_len());
+            assert_eq!(cookie.value().len(), protect.expected_cookie_len());
+
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&token, &cookie));
+            assert_eq!(token.value(), cookie.value());
+        }
 
-                #[test]
-                fn modified_cookie_sig_fails() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (_, mut cookie) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/cookie pair");
-                    let cookie_len = cookie.bytes.len();
-                    cookie.bytes[cookie_len - 1] ^= 0x01;
-                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
-                    assert!(protect.parse_cookie(&cookie).is_err());
-                }
+        #[test]
+        fn bound_token() {
+            let protect = InsecureTestCsrfProtection::new();
+            let token = protect.generate_bound_token(&[7; 64], b"session-a").expect("couldn't generate token");
 
-                #[test]
-                fn modified_cookie_value_fails() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (_, mut cookie) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/cookie pair");
-                    cookie.bytes[0] ^= 0x01;
-                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
-                    assert!(protect.parse_cookie(&cookie).is_err());
-                }
+            let parsed = protect.parse_bound_token(token.value(), b"session-a").expect("token not parsed");
+            assert_eq!(parsed.value(), &[7; 64][..]);
+            assert_eq!(protect.parse_bound_token(token.value(), b"session-b").map(|_| ()),
+                       Err(CsrfError::ValidationFailure));
+            assert_eq!(protect.parse_token(token.value()).map(|_| ()), Err(CsrfError::MalformedLength));
+        }
 
-                #[test]
-                fn modified_token_sig_fails() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (mut token, _) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/token pair");
-                    let token_len = token.bytes.len();
-                    token.bytes[token_len - 1] ^= 0x01;
-                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
-                    assert!(protect.parse_token(&token).is_err());
-                }
+        #[test]
+        fn mismatch_fails() {
+            let protect = InsecureTestCsrfProtection::new();
+            let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+            let cookie = protect.generate_cookie(&[8; 64], 300).expect("couldn't generate cookie");
 
-                #[test]
-                fn modified_token_value_fails() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (mut token, _) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/token pair");
-                    token.bytes[0] ^= 0x01;
-                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
-                    assert!(protect.parse_token(&token).is_err());
-                }
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(!protect.verify_token_pair(&token, &cookie));
+        }
 
-                #[test]
-                fn mismatched_cookie_token_fail() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (token, _) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/token pair");
-                    let (_, cookie) = protect.generate_token_pair(None, 300)
-                        .expect("couldn't generate token/token pair");
+        #[test]
+        fn expired_fails() {
+            let protect = InsecureTestCsrfProtection::new();
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
 
-                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
-                    let token = protect.parse_token(&token).expect("token not parsed");
-                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
-                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
-                    assert!(!protect.verify_token_pair(&token, &cookie),
-                            "verified token/cookie pair when failure expected");
-                }
+            assert!(protect.verify_token_pair_as_of(&token, &cookie, cookie.expires() - 1));
+            assert!(!protect.verify_token_pair_as_of(&token, &cookie, cookie.expires() + 1));
+        }
 
-                #[test]
-                fn expired_token_fail() {
-                    let protect = $strct::from_key(KEY_32);
-                    let (token, cookie) = protect.generate_token_pair(None, -1)
-                        .expect("couldn't generate token/cookie pair");
-                    let ref token = BASE64.decode(token.b64_string().as_bytes()).expect("token not base64");
-                    let token = protect.parse_token(&token).expect("token not parsed");
-                    let ref cookie = BASE64.decode(cookie.b64_string().as_bytes()).expect("cookie not base64");
-                    let cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
-                    assert!(!protect.verify_token_pair(&token, &cookie),
-                            "verified token/cookie pair when failure expected");
-                }
-            }
+        #[test]
+        fn self_test_fails() {
+            assert_eq!(InsecureTestCsrfProtection::new().self_test(), Err(CsrfError::InternalError));
+        }
+
+        #[test]
+        fn no_security() {
+            let params = InsecureTestCsrfProtection::new().security_parameters();
+            assert_eq!((params.key_bits(), params.tag_len(), params.encrypted()), (0, 0, false));
+        }
+
+        #[test]
+        fn malformed_rejected() {
+            let protect = InsecureTestCsrfProtection::new();
+            assert_eq!(protect.parse_cookie(&[]).map(|_| ()), Err(CsrfError::MissingCookie));
+            assert_eq!(protect.parse_cookie(&[1; 80]).map(|_| ()), Err(CsrfError::MalformedLength));
+            assert_eq!(protect.parse_token(&[2; 73]).map(|_| ()), Err(CsrfError::MalformedLength));
         }
     }
 
+    #[cfg(feature = "aes-gcm-backend")]
     test_cases!(AesGcmCsrfProtection, aesgcm);
+    #[cfg(feature = "chacha20-backend")]
     test_cases!(ChaCha20Poly1305CsrfProtection, chacha20poly1305);
+    #[cfg(feature = "hmac-backend")]
     test_cases!(HmacCsrfProtection, hmac);
 }
@@ -0,0 +1,112 @@
+//! Module containing a `Future` that derives a protection from a password off the async runtime
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use core::{CsrfError, CsrfProtection};
+
+
+/// Return a `Future` that resolves to `P::from_password_and_salt(password, salt)`, computed on a
+/// thread of its own so that scrypt does not block the async runtime that polls it.
+///
+/// `from_password` takes on the order of a second with the production scrypt parameters, which
+/// stalls every task scheduled on the same worker when it is called during startup. This works
+/// with any executor, since the thread wakes the task itself once the key is derived.
+///
+/// The future is cancellation safe: dropping it, for example because the runtime's timeout around
+/// it expired, leaves the derivation to finish on its thread and throws the result away. It
+/// resolves to `CsrfError::InternalError` if the thread can not be spawned or the derivation
+/// panics.
+pub fn derive_from_password<P>(password: &[u8], salt: &[u8]) -> DeriveFromPassword<P>
+    where P: CsrfProtection + 'static
+{
+    let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+    let (password, salt) = (password.to_vec(), salt.to_vec());
+    let thread_shared = shared.clone();
+
+    let spawned = thread::Builder::new().name("csrf-derive-key".to_string()).spawn(move || {
+        let derived = panic::catch_unwind(AssertUnwindSafe(|| P::from_password_and_salt(&password, &salt)))
+            .map_err(|_| {
+                error!("Deriving the CSRF key from a password panicked");
+                CsrfError::InternalError
+            });
+        let waker = {
+            let mut shared = thread_shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            shared.result = Some(derived);
+            shared.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+
+    if let Err(err) = spawned {
+        error!("Could not spawn a thread to derive the CSRF key: {}", err);
+        shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).result = Some(Err(CsrfError::InternalError));
+    }
+
+    DeriveFromPassword { shared }
+}
+
+/// The state shared between `DeriveFromPassword` and the thread that derives the key.
+struct Shared<P> {
+    result: Option<Result<P, CsrfError>>,
+    waker: Option<Waker>,
+}
+
+/// The `Future` returned by `derive_from_password`.
+pub struct DeriveFromPassword<P> {
+    shared: Arc<Mutex<Shared<P>>>,
+}
+
+impl<P> Future for DeriveFromPassword<P> {
+    type Output = Result<P, CsrfError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend"))]
+mod tests {
+    use futures::executor::block_on;
+    use futures::future::join;
+
+    use core::{CsrfProtection, HmacCsrfProtection, SCRYPT_SALT};
+    use derive::derive_from_password;
+
+    #[test]
+    fn derives_working_protection() {
+        let protect: HmacCsrfProtection = block_on(derive_from_password(b"hunter2", SCRYPT_SALT))
+            .expect("key not derived");
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(HmacCsrfProtection::from_password(b"hunter2").verify_raw(token.value(), cookie.value()), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_derivations() {
+        let (first, second) = block_on(join(derive_from_password::<HmacCsrfProtection>(b"hunter2", b"first"),
+                                             derive_from_password::<HmacCsrfProtection>(b"hunter2", b"second")));
+        let (first, second) = (first.expect("key not derived"), second.expect("key not derived"));
+        assert_ne!(first.key_id(), second.key_id());
+        assert_eq!(first.key_id(), HmacCsrfProtection::from_password_and_salt(b"hunter2", b"first").key_id());
+    }
+
+    #[test]
+    fn dropped_before_completion() {
+        drop(derive_from_password::<HmacCsrfProtection>(b"hunter2", SCRYPT_SALT));
+    }
+}
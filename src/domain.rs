@@ -0,0 +1,225 @@
+//! Module containing a `CsrfProtection` that scopes tokens to a domain separation tag
+
+use ring::rand::SystemRandom;
+
+use clock::Clock;
+use core::{CsrfCookie, CsrfError, CsrfProtection, CsrfToken, SecurityParameters,
+           UnencryptedCsrfCookie, UnencryptedCsrfToken};
+use encoding::TokenEncoding;
+use registry::Algorithm;
+use source::TokenValueSource;
+
+const DOMAIN_LABEL: &[u8] = b"rust-csrf-domain:";
+
+
+/// Wraps a `CsrfProtection` so that its tokens are scoped to a static tag, such as the name of
+/// the application.
+///
+/// The tag is mixed into the binding data of every token, so it is covered by the HMAC or the
+/// AEAD associated data when the token is generated and again when it is parsed. A token
+/// generated under one tag fails to parse under any other tag, or with the inner protection
+/// alone, even when they share a key. The tag is never transmitted, so tokens are no longer than
+/// those of the inner protection.
+///
+/// This is lighter than deriving a separate key with `derive_subkey`, but weaker: cookies are not
+/// scoped, and every application still holds the key for all the others. Prefer separate keys
+/// where possible.
+pub struct DomainSeparatedCsrfProtection<P: CsrfProtection> {
+    inner: P,
+    tag: &'static [u8],
+}
+
+impl<P: CsrfProtection> DomainSeparatedCsrfProtection<P> {
+    /// Given an inner protection and a tag, return a protection whose tokens are scoped to the
+    /// tag.
+    pub fn new(inner: P, tag: &'static [u8]) -> Self {
+        DomainSeparatedCsrfProtection { inner, tag }
+    }
+
+    /// Retrieve the inner protection.
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Retrieve the tag.
+    pub fn tag(&self) -> &'static [u8] {
+        self.tag
+    }
+
+    /// Prefix `binding` with the tag, length prefixed so that no tag and binding pair can be
+    /// mistaken for another.
+    fn tagged(&self, binding: &[u8]) -> Vec<u8> {
+        let mut tagged = Vec::with_capacity(DOMAIN_LABEL.len() + 8 + self.tag.len() + binding.len());
+        tagged.extend_from_slice(DOMAIN_LABEL);
+        tagged.extend_from_slice(&(self.tag.len() as u64).to_le_bytes());
+        tagged.extend_from_slice(self.tag);
+        tagged.extend_from_slice(binding);
+        tagged
+    }
+}
+
+impl<P: CsrfProtection + Clone> Clone for DomainSeparatedCsrfProtection<P> {
+    fn clone(&self) -> Self {
+        DomainSeparatedCsrfProtection::new(self.inner.clone(), self.tag)
+    }
+}
+
+impl<P: CsrfProtection + Clone + 'static> CsrfProtection for DomainSeparatedCsrfProtection<P> {
+    /// Derive the inner protection from the password and salt, with an empty tag.
+    fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self {
+        DomainSeparatedCsrfProtection::new(P::from_password_and_salt(password, salt), b"")
+    }
+
+    fn clone_box(&self) -> Box<dyn CsrfProtection> {
+        Box::new(self.clone())
+    }
+
+    /// The inner protection's key, since the tag does not change it.
+    fn key_id(&self) -> [u8; 8] {
+        self.inner.key_id()
+    }
+
+    fn security_parameters(&self) -> SecurityParameters {
+        self.inner.security_parameters()
+    }
+
+    fn rng(&self) -> &SystemRandom {
+        self.inner.rng()
+    }
+
+    fn rng_retries(&self) -> u32 {
+        self.inner.rng_retries()
+    }
+
+    fn require_secure_transport(&self) -> bool {
+        self.inner.require_secure_transport()
+    }
+
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        self.inner.ttl_bounds()
+    }
+
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        self.inner.token_value_source()
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        self.inner.clock()
+    }
+
+    fn fail_closed_clock(&self) -> bool {
+        self.inner.fail_closed_clock()
+    }
+
+    fn encoding(&self) -> TokenEncoding {
+        self.inner.encoding()
+    }
+
+    fn supports_encryption(&self) -> bool {
+        self.inner.supports_encryption()
+    }
+
+    fn wire_version(&self) -> u8 {
+        self.inner.wire_version()
+    }
+
+    fn algorithm(&self) -> Option<Algorithm> {
+        self.inner.algorithm()
+    }
+
+    fn expected_token_len(&self) -> usize {
+        self.inner.expected_token_len()
+    }
+
+    fn expected_cookie_len(&self) -> usize {
+        self.inner.expected_cookie_len()
+    }
+
+    fn transport_nonce<'a>(&self, transport: &'a [u8]) -> Option<&'a [u8]> {
+        self.inner.transport_nonce(transport)
+    }
+
+    fn replay_transport<'a>(&self, transport: &'a [u8]) -> &'a [u8] {
+        self.inner.replay_transport(transport)
+    }
+
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+        self.inner.generate_cookie_at(token_value, ttl_seconds, now)
+    }
+
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        self.inner.refresh_cookie(cookie, ttl_seconds)
+    }
+
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+        self.inner.generate_bound_token(token_value, &self.tagged(binding))
+    }
+
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.inner.parse_cookie_at(cookie, now)
+    }
+
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.inner.parse_bound_token(token, &self.tagged(binding))
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+mod tests {
+    use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+               HmacCsrfProtection};
+    use domain::DomainSeparatedCsrfProtection;
+    use test_util::KEY_32;
+
+    fn check_separated<P: CsrfProtection + Clone + 'static>(inner: P) {
+        let app_a = DomainSeparatedCsrfProtection::new(inner.clone(), b"appA");
+        let app_b = DomainSeparatedCsrfProtection::new(inner.clone(), b"appB");
+
+        let (token, cookie) = app_a.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let parsed_token = app_a.parse_token(token.value()).expect("token not parsed");
+        let parsed_cookie = app_a.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(app_a.verify_token_pair(&parsed_token, &parsed_cookie));
+
+        assert_eq!(app_b.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+        assert_eq!(inner.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+        assert_eq!(app_b.verify_raw(token.value(), cookie.value()), Err(CsrfError::ValidationFailure));
+
+        let inner_token = inner.generate_token(&[7; 64]).expect("couldn't generate token");
+        assert_eq!(app_a.parse_token(inner_token.value()).err(), Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn hmac_separated() {
+        check_separated(HmacCsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn aes_gcm_separated() {
+        check_separated(AesGcmCsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn chacha20_separated() {
+        check_separated(ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn binding_still_checked() {
+        let protect = DomainSeparatedCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32), b"appA");
+        let token = protect.generate_bound_token(&[7; 64], b"session-a").expect("couldn't generate token");
+
+        assert!(protect.parse_bound_token(token.value(), b"session-a").is_ok());
+        assert_eq!(protect.parse_bound_token(token.value(), b"session-b").err(),
+                   Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn tag_and_binding_do_not_run_together() {
+        let short = DomainSeparatedCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32), b"app");
+        let long = DomainSeparatedCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32), b"appA");
+        let token = short.generate_bound_token(&[7; 64], b"A").expect("couldn't generate token");
+        assert!(long.parse_bound_token(token.value(), b"").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Module containing the text encodings that tokens and cookies can be sent in
+
+use data_encoding::{BASE64, BASE64URL, BASE64URL_NOPAD, HEXLOWER, HEXLOWER_PERMISSIVE};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+
+use core::{decode_b64, decode_b64url_nopad, CsrfError, MAX_ENCODED_LEN};
+
+
+/// The longest hex encoded token or cookie that `TokenEncoding::Hex` will decode, which decodes
+/// to as many bytes as the longest base64 input allowed by `MAX_ENCODED_LEN`.
+const MAX_HEX_ENCODED_LEN: usize = MAX_ENCODED_LEN / 4 * 3 * 2;
+
+/// The text encoding that a protection sends tokens and cookies in, selected for both with
+/// `CsrfConfig::with_encoding`. It is used by `generate_encoded_pair`, `parse_token_encoded`,
+/// `parse_cookie_encoded`, `verify_encoded` and `verify_header_or_form`, and should also be given
+/// to `CookieBuilder::encoding`, `CsrfNames::with_encoding` and `TokenResponse::new_encoded`.
+///
+/// Decoding is as lenient as the base64 helpers: surrounding ASCII whitespace is ignored, both
+/// base64 variants accept either alphabet, and hex accepts either case. Input longer than any
+/// token or cookie could be is rejected with `CsrfError::ValidationFailure` before it is decoded.
+///
+/// With the `serde` feature, it deserializes from `"base64"`, `"base64url"`, `"base64url-nopad"`
+/// or `"hex"`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum TokenEncoding {
+    /// Standard, padded base64, as by `b64_string`. This is the default.
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "base64"))]
+    Base64,
+    /// URL safe, padded base64, as by `b64_url_string`.
+    #[cfg_attr(feature = "serde", serde(rename = "base64url"))]
+    Base64Url,
+    /// URL safe base64 without padding, as by `CsrfToken::b64_url_nopad_string`.
+    #[cfg_attr(feature = "serde", serde(rename = "base64url-nopad"))]
+    Base64UrlNoPad,
+    /// Lowercase hexadecimal, which is twice as long as the raw bytes.
+    #[cfg_attr(feature = "serde", serde(rename = "hex"))]
+    Hex,
+}
+
+impl TokenEncoding {
+    /// Return the URL safe variant of this encoding: URL safe base64 for standard base64, and
+    /// this encoding itself otherwise.
+    pub fn url_safe(&self) -> TokenEncoding {
+        match *self {
+            TokenEncoding::Base64 => TokenEncoding::Base64Url,
+            encoding => encoding,
+        }
+    }
+
+    /// Encode the bytes of a token or cookie.
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match *self {
+            TokenEncoding::Base64 => BASE64.encode(bytes),
+            TokenEncoding::Base64Url => BASE64URL.encode(bytes),
+            TokenEncoding::Base64UrlNoPad => BASE64URL_NOPAD.encode(bytes),
+            TokenEncoding::Hex => HEXLOWER.encode(bytes),
+        }
+    }
+
+    /// Decode a token or cookie, failing with `CsrfError::DecodeError` if it is not in this
+    /// encoding.
+    pub fn decode(&self, encoded: &str) -> Result<Vec<u8>, CsrfError> {
+        match *self {
+            TokenEncoding::Base64 | TokenEncoding::Base64Url => decode_b64(encoded),
+            TokenEncoding::Base64UrlNoPad => decode_b64url_nopad(encoded),
+            TokenEncoding::Hex => {
+                let encoded = encoded.trim_ascii();
+                if encoded.len() > MAX_HEX_ENCODED_LEN {
+                    debug!("Encoded input had length {}, more than {}.", encoded.len(), MAX_HEX_ENCODED_LEN);
+                    return Err(CsrfError::ValidationFailure);
+                }
+                Ok(HEXLOWER_PERMISSIVE.decode(encoded.as_bytes())?)
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use core::{CsrfError, MAX_COOKIE_BYTES};
+    use encoding::{TokenEncoding, MAX_HEX_ENCODED_LEN};
+
+    const ENCODINGS: [TokenEncoding; 4] =
+        [TokenEncoding::Base64, TokenEncoding::Base64Url, TokenEncoding::Base64UrlNoPad, TokenEncoding::Hex];
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 2, 3, 105, MAX_COOKIE_BYTES].iter() {
+            let bytes: Vec<u8> = (0..*len).map(|i| (i * 37 + 250) as u8).collect();
+            for encoding in ENCODINGS.iter() {
+                let encoded = encoding.encode(&bytes);
+                assert_eq!(encoding.decode(&encoded), Ok(bytes.clone()), "{:?}", encoding);
+                assert_eq!(encoding.decode(&format!(" {}\r\n", encoded)), Ok(bytes.clone()), "{:?}", encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn alphabets() {
+        let bytes = [0xfb, 0xff, 0xbf];
+        assert_eq!(TokenEncoding::Base64.encode(&bytes), "+/+/");
+        assert_eq!(TokenEncoding::Base64Url.encode(&bytes), "-_-_");
+        assert_eq!(TokenEncoding::Base64UrlNoPad.encode(&[0xfb]), "-w");
+        assert_eq!(TokenEncoding::Hex.encode(&bytes), "fbffbf");
+        assert_eq!(TokenEncoding::Hex.decode("FBffBF"), Ok(bytes.to_vec()));
+        assert_eq!(TokenEncoding::Base64.decode("-_-_"), Ok(bytes.to_vec()));
+        assert_eq!(TokenEncoding::Base64.url_safe(), TokenEncoding::Base64Url);
+        assert_eq!(TokenEncoding::Hex.url_safe(), TokenEncoding::Hex);
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(TokenEncoding::Hex.decode("fbf"), Err(CsrfError::DecodeError));
+        assert_eq!(TokenEncoding::Hex.decode("zz"), Err(CsrfError::DecodeError));
+        assert_eq!(TokenEncoding::Base64.decode("not base64!"), Err(CsrfError::DecodeError));
+        assert_eq!(TokenEncoding::Hex.decode(&"ab".repeat(MAX_HEX_ENCODED_LEN / 2)).map(|v| v.len()),
+                   Ok(MAX_HEX_ENCODED_LEN / 2));
+        assert_eq!(TokenEncoding::Hex.decode(&"ab".repeat(MAX_HEX_ENCODED_LEN / 2 + 1)),
+                   Err(CsrfError::ValidationFailure));
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    #[test]
+    fn through_protection() {
+        use config::CsrfConfig;
+        use core::{CsrfProtection, HmacCsrfProtection, TokenPrecedence};
+        use fallback::FallbackCsrfProtection;
+
+        for encoding in ENCODINGS.iter() {
+            let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567")
+                .with_config(CsrfConfig::new().with_encoding(*encoding));
+            assert_eq!(protect.encoding(), *encoding);
+            assert_eq!(FallbackCsrfProtection::new(protect.clone()).encoding(), *encoding);
+
+            let (token, cookie) = protect.generate_encoded_pair(None, 300).expect("couldn't generate token/cookie pair");
+            let parsed_token = protect.parse_token_encoded(&token).expect("token not parsed");
+            let parsed_cookie = protect.parse_cookie_encoded(&cookie).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie), "{:?}", encoding);
+            assert_eq!(protect.verify_encoded(&token, &cookie), Ok(()), "{:?}", encoding);
+            assert_eq!(protect.verify_header_or_form(Some(&token), None, &cookie, TokenPrecedence::Header), Ok(()));
+        }
+
+        let hex = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567")
+            .with_config(CsrfConfig::new().with_encoding(TokenEncoding::Hex));
+        let (token, cookie) = hex.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert_eq!(hex.verify_encoded(&token.b64_string(), &cookie.b64_string()), Err(CsrfError::DecodeError));
+        assert_eq!(hex.verify_encoded(&token.encoded(TokenEncoding::Hex), &cookie.encoded(TokenEncoding::Hex)), Ok(()));
+        assert!(hex.parse_token_b64(&token.b64_string()).is_ok());
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    #[test]
+    fn hex_through_cookie_builder() {
+        use config::CsrfConfig;
+        use cookie::CookieBuilder;
+        use core::{CsrfProtection, HmacCsrfProtection, TokenPrecedence};
+        use extract::extract_cookie_from_header;
+        use form::CsrfNames;
+
+        let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567")
+            .with_config(CsrfConfig::new().with_encoding(TokenEncoding::Hex));
+        let (token, cookie) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+
+        let header = CookieBuilder::new(&cookie).encoding(protect.encoding()).build()
+            .expect("conflicting cookie attributes");
+        let cookie_value = extract_cookie_from_header(&header, "csrf").expect("cookie not found");
+        let token_value = token.encoded(protect.encoding());
+        assert_eq!(protect.verify_header_or_form(Some(&token_value), None, cookie_value, TokenPrecedence::Header),
+                   Ok(()));
+
+        let names = CsrfNames::new().with_encoding(protect.encoding());
+        let (html, header) = names.render_html_and_cookie(&(token, cookie), |cookie| cookie)
+            .expect("conflicting cookie attributes");
+        let cookie_value = extract_cookie_from_header(&header, "csrf").expect("cookie not found");
+        assert!(html.contains(&format!(r#"value="{}""#, token_value)));
+        assert_eq!(protect.verify_header_or_form(None, Some(&token_value), cookie_value, TokenPrecedence::Form),
+                   Ok(()));
+
+        let (token, cookie) = protect.generate_encoded_pair_url_safe(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.verify_encoded(&token, &cookie), Ok(()));
+    }
+}
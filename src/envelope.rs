@@ -0,0 +1,294 @@
+//! Module containing a compact binary envelope for carrying tokens over non-HTML transports
+
+use std::fmt;
+
+use core::{CsrfCookie, CsrfError, CsrfToken};
+use wire::{is_compatible, WIRE_VERSION};
+
+// CBOR major types, already shifted into the top three bits of the initial byte
+const MAJOR_UNSIGNED: u8 = 0 << 5;
+const MAJOR_BYTES: u8 = 2 << 5;
+const MAJOR_TEXT: u8 = 3 << 5;
+const MAJOR_ARRAY: u8 = 4 << 5;
+
+
+/// A token or cookie together with the name of the backend that issued it and its wire format
+/// version, for transports such as gRPC metadata or WebSocket frames where base64 is wasteful.
+///
+/// The envelope is encoded as the CBOR (RFC 8949) array `[version, algorithm, bytes]`, where
+/// `version` is an unsigned integer, `algorithm` is a text string such as the names accepted by
+/// `protection_from_name`, and `bytes` is a byte string holding the raw token or cookie. Only the
+/// shortest, definite length encodings are produced, and decoding accepts no others.
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct CborEnvelope {
+    version: u8,
+    algorithm: String,
+    bytes: Vec<u8>,
+}
+
+impl CborEnvelope {
+    /// Wrap a token issued by the named backend.
+    pub fn for_token(token: &CsrfToken, algorithm: &str) -> Self {
+        CborEnvelope::new(token.value(), algorithm)
+    }
+
+    /// Wrap a cookie issued by the named backend.
+    pub fn for_cookie(cookie: &CsrfCookie, algorithm: &str) -> Self {
+        CborEnvelope::new(cookie.value(), algorithm)
+    }
+
+    fn new(bytes: &[u8], algorithm: &str) -> Self {
+        CborEnvelope {
+            version: WIRE_VERSION,
+            algorithm: algorithm.to_string(),
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    /// Retrieve the wire format version of the wrapped token or cookie.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Retrieve the name of the backend that issued the wrapped token or cookie.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Get the raw value of the wrapped token or cookie.
+    pub fn value(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Unwrap the envelope as a token.
+    pub fn into_token(self) -> CsrfToken {
+        CsrfToken::new(self.bytes)
+    }
+
+    /// Unwrap the envelope as a cookie.
+    pub fn into_cookie(self) -> CsrfCookie {
+        CsrfCookie::new(self.bytes)
+    }
+
+    /// Encode the envelope as CBOR.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.algorithm.len() + self.bytes.len());
+        write_head(&mut out, MAJOR_ARRAY, 3);
+        write_head(&mut out, MAJOR_UNSIGNED, u64::from(self.version));
+        write_head(&mut out, MAJOR_TEXT, self.algorithm.len() as u64);
+        out.extend_from_slice(self.algorithm.as_bytes());
+        write_head(&mut out, MAJOR_BYTES, self.bytes.len() as u64);
+        out.extend_from_slice(&self.bytes);
+        out
+    }
+
+    /// Decode an envelope from CBOR.
+    ///
+    /// Input that is not exactly one envelope is rejected with `CsrfError::MalformedLength`, as is
+    /// an envelope whose version is not supported by this version of the crate.
+    pub fn from_cbor(encoded: &[u8]) -> Result<Self, CsrfError> {
+        let mut reader = Reader { bytes: encoded, pos: 0 };
+
+        if reader.head(MAJOR_ARRAY)? != 3 {
+            debug!("CBOR envelope was not a three element array.");
+            return Err(CsrfError::MalformedLength);
+        }
+
+        let version = reader.head(MAJOR_UNSIGNED)?;
+        if version > u64::from(u8::MAX) || !is_compatible(version as u8) {
+            debug!("CBOR envelope had unsupported version {}.", version);
+            return Err(CsrfError::MalformedLength);
+        }
+
+        let algorithm = reader.string(MAJOR_TEXT)?;
+        let algorithm = String::from_utf8(algorithm.to_vec()).map_err(|_| {
+            debug!("CBOR envelope algorithm was not UTF-8.");
+            CsrfError::MalformedLength
+        })?;
+        let bytes = reader.string(MAJOR_BYTES)?.to_vec();
+
+        if reader.pos != encoded.len() {
+            debug!("CBOR envelope had {} trailing bytes.", encoded.len() - reader.pos);
+            return Err(CsrfError::MalformedLength);
+        }
+
+        Ok(CborEnvelope {
+            version: version as u8,
+            algorithm,
+            bytes,
+        })
+    }
+}
+
+
+/// Like `CsrfToken`, the wrapped bytes are not shown.
+impl fmt::Debug for CborEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CborEnvelope")
+            .field("version", &self.version)
+            .field("algorithm", &self.algorithm)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+
+/// Write a CBOR initial byte and argument in the shortest form.
+fn write_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= u64::from(u8::MAX) {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= u64::from(u16::MAX) {
+        out.push(major | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u64::from(u32::MAX) {
+        out.push(major | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CsrfError> {
+        let end = self.pos.checked_add(len).ok_or(CsrfError::MalformedLength)?;
+        let taken = self.bytes.get(self.pos..end).ok_or_else(|| {
+            debug!("CBOR envelope was truncated.");
+            CsrfError::MalformedLength
+        })?;
+        self.pos = end;
+        Ok(taken)
+    }
+
+    /// Read an initial byte of the given major type and return its argument.
+    fn head(&mut self, major: u8) -> Result<u64, CsrfError> {
+        let initial = self.take(1)?[0];
+        if initial & 0xe0 != major {
+            debug!("CBOR envelope had major type {}, expected {}.", initial >> 5, major >> 5);
+            return Err(CsrfError::MalformedLength);
+        }
+
+        let (value, min) = match initial & 0x1f {
+            info @ 0..=23 => return Ok(u64::from(info)),
+            24 => (u64::from(self.take(1)?[0]), 24),
+            25 => (self.take(2)?.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)), 0x100),
+            26 => (self.take(4)?.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)), 0x1_0000),
+            27 => (self.take(8)?.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)), 0x1_0000_0000),
+            _ => {
+                debug!("CBOR envelope used an indefinite length or reserved encoding.");
+                return Err(CsrfError::MalformedLength);
+            },
+        };
+
+        if value < min {
+            debug!("CBOR envelope used a non-shortest encoding.");
+            return Err(CsrfError::MalformedLength);
+        }
+        Ok(value)
+    }
+
+    /// Read a definite length byte or text string.
+    fn string(&mut self, major: u8) -> Result<&'a [u8], CsrfError> {
+        let len = self.head(major)?;
+        if len > (self.bytes.len() - self.pos) as u64 {
+            debug!("CBOR envelope was truncated.");
+            return Err(CsrfError::MalformedLength);
+        }
+        self.take(len as usize)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use core::{CsrfCookie, CsrfError, CsrfToken};
+    use envelope::CborEnvelope;
+    use wire::WIRE_VERSION;
+
+    #[test]
+    fn encodes_expected_bytes() {
+        let token = CsrfToken::new(vec![1, 2, 3]);
+        let encoded = CborEnvelope::for_token(&token, "hmac").to_cbor();
+        assert_eq!(encoded, vec![0x83, 0x01, 0x64, b'h', b'm', b'a', b'c', 0x43, 1, 2, 3]);
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let token = CsrfToken::new((0..117).collect());
+        let envelope = CborEnvelope::from_cbor(&CborEnvelope::for_token(&token, "aes-gcm").to_cbor())
+            .expect("envelope not decoded");
+        assert_eq!(envelope.version(), WIRE_VERSION);
+        assert_eq!(envelope.algorithm(), "aes-gcm");
+        assert_eq!(envelope.into_token(), token);
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let cookie = CsrfCookie::new((0..=255).collect());
+        let envelope = CborEnvelope::from_cbor(&CborEnvelope::for_cookie(&cookie, "chacha20").to_cbor())
+            .expect("envelope not decoded");
+        assert_eq!(envelope.algorithm(), "chacha20");
+        assert_eq!(envelope.into_cookie(), cookie);
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    #[test]
+    fn verifies_after_round_trip() {
+        use core::{CsrfProtection, HmacCsrfProtection};
+
+        let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567");
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        let token = CborEnvelope::from_cbor(&CborEnvelope::for_token(&token, "hmac").to_cbor())
+            .expect("token envelope not decoded")
+            .into_token();
+        let cookie = CborEnvelope::from_cbor(&CborEnvelope::for_cookie(&cookie, "hmac").to_cbor())
+            .expect("cookie envelope not decoded")
+            .into_cookie();
+
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+    }
+
+    #[test]
+    fn malformed_rejected() {
+        let valid = CborEnvelope::for_token(&CsrfToken::new(vec![1, 2, 3]), "hmac").to_cbor();
+
+        let mut trailing = valid.clone();
+        trailing.push(0);
+
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            valid[..valid.len() - 1].to_vec(),
+            trailing,
+            // two element array
+            vec![0x82, 0x01, 0x60],
+            // unsupported version
+            vec![0x83, 0x02, 0x60, 0x40],
+            // algorithm as a byte string
+            vec![0x83, 0x01, 0x40, 0x40],
+            // non-shortest version
+            vec![0x83, 0x18, 0x01, 0x60, 0x40],
+            // indefinite length byte string
+            vec![0x83, 0x01, 0x60, 0x5f, 0xff],
+            // byte string longer than the input
+            vec![0x83, 0x01, 0x60, 0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            // algorithm not UTF-8
+            vec![0x83, 0x01, 0x61, 0xff, 0x40],
+        ];
+
+        for case in cases {
+            assert_eq!(CborEnvelope::from_cbor(&case), Err(CsrfError::MalformedLength), "{:?}", case);
+        }
+    }
+}
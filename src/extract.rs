@@ -0,0 +1,468 @@
+//! Module containing helpers for extracting CSRF tokens from incoming requests
+
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "async")]
+use futures_core::Stream;
+
+use cookie::HOST_COOKIE_PREFIX;
+use core::CsrfError;
+#[cfg(feature = "async")]
+use core::MAX_ENCODED_LEN;
+
+/// The longest field value that `extract_token_from_body` buffers, which is the longest encoded
+/// token that will be decoded with every byte percent encoded.
+#[cfg(feature = "async")]
+const MAX_FORM_VALUE_LEN: usize = 3 * MAX_ENCODED_LEN;
+
+
+/// Incremental scanner for `application/x-www-form-urlencoded` bodies that looks for the first
+/// occurrence of a single field. Only the key or value currently being read is buffered, and
+/// values of non-matching fields are skipped without being stored. The value of the field is
+/// buffered up to `max_value_len` bytes.
+struct FormFieldScanner {
+    field: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    max_value_len: usize,
+    state: ScanState,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum ScanState {
+    Key,
+    Value,
+    Skip,
+}
+
+impl FormFieldScanner {
+    fn new(field: &str, max_value_len: usize) -> Self {
+        FormFieldScanner {
+            field: field.as_bytes().to_vec(),
+            key: Vec::new(),
+            value: Vec::new(),
+            max_value_len,
+            state: ScanState::Key,
+        }
+    }
+
+    /// Feed the next chunk of the body, returning the decoded value once the field is found, or
+    /// `CsrfError::ValidationFailure` once its value is longer than `max_value_len`.
+    fn feed(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, CsrfError> {
+        for &byte in chunk {
+            match (self.state, byte) {
+                (ScanState::Key, b'=') => {
+                    self.state = if form_urldecode(&self.key) == self.field {
+                        ScanState::Value
+                    } else {
+                        ScanState::Skip
+                    };
+                    self.key.clear();
+                },
+                (ScanState::Value, b'&') => return Ok(Some(form_urldecode(&self.value))),
+                (_, b'&') => {
+                    self.key.clear();
+                    self.state = ScanState::Key;
+                },
+                (ScanState::Key, b) => {
+                    self.key.push(b);
+                    // a percent encoded key is at most three times as long as the decoded one
+                    if self.key.len() > self.field.len() * 3 {
+                        self.key.clear();
+                        self.state = ScanState::Skip;
+                    }
+                },
+                (ScanState::Value, b) => {
+                    if self.value.len() >= self.max_value_len {
+                        debug!("Form field value was longer than {} bytes.", self.max_value_len);
+                        return Err(CsrfError::ValidationFailure);
+                    }
+                    self.value.push(b);
+                },
+                (ScanState::Skip, _) => (),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Signal the end of the body, returning the decoded value if the last pair was the field.
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        match self.state {
+            ScanState::Value => Some(form_urldecode(&self.value)),
+            // a bare key with no `=` is treated as having an empty value
+            ScanState::Key if !self.key.is_empty() && form_urldecode(&self.key) == self.field => {
+                Some(Vec::new())
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Decode a single `application/x-www-form-urlencoded` key or value. Malformed percent escapes
+/// are passed through unchanged.
+fn form_urldecode(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => Some(hi << 4 | lo),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            },
+        }
+    }
+    decoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+/// Given a complete `application/x-www-form-urlencoded` body, return the decoded value of the
+/// first occurrence of `field`, or `None` if the field is not present.
+pub fn extract_token_from_form(body: &[u8], field: &str) -> Option<Vec<u8>> {
+    // the value is never longer than the body, so the scanner never gives up on it
+    let mut scanner = FormFieldScanner::new(field, body.len());
+    scanner.feed(body).unwrap_or(None).or_else(|| scanner.finish())
+}
+
+/// Given the value of a `Cookie` request header, return the raw value of the cookie called `name`.
+/// Whitespace around each cookie is ignored, and a value wrapped in double quotes is returned
+/// without them. The value is not decoded, so a CSRF cookie is still base64 encoded.
+///
+/// A header with no such cookie is rejected with `CsrfError::MissingCookie`. A header with more
+/// than one is rejected with `CsrfError::AmbiguousCookie` rather than picking one of them, since
+/// a cookie set by another subdomain can shadow the real one, and the browser does not say which
+/// is which.
+pub fn extract_cookie_from_header<'a>(header: &'a str, name: &str) -> Result<&'a str, CsrfError> {
+    let mut values = header.split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|&(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim());
+
+    let value = match (values.next(), values.next()) {
+        (Some(value), None) => value,
+        (None, _) => {
+            debug!("Cookie header had no {:?} cookie.", name);
+            return Err(CsrfError::MissingCookie);
+        },
+        (Some(_), Some(_)) => {
+            warn!("Cookie header had more than one {:?} cookie.", name);
+            return Err(CsrfError::AmbiguousCookie);
+        },
+    };
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Ok(&value[1..value.len() - 1])
+    } else {
+        Ok(value)
+    }
+}
+
+/// Like `extract_cookie_from_header`, but return the value of the cookie called `name` with the
+/// `HOST_COOKIE_PREFIX`, as set by `CookieBuilder::host_prefix`. A cookie called `name` without
+/// the prefix is ignored, since it could have been set by another subdomain.
+pub fn extract_host_cookie_from_header<'a>(header: &'a str, name: &str) -> Result<&'a str, CsrfError> {
+    extract_cookie_from_header(header, &format!("{}{}", HOST_COOKIE_PREFIX, name))
+}
+
+/// Given the value of a request header that carries the token, such as `CSRF_HEADER` or
+/// `Authorization`, return the base64 token ready for `parse_token_b64`.
+///
+/// Surrounding whitespace is ignored. If `prefix` is given, as for gateways that send
+/// `Authorization: CSRF <token>` or `X-CSRF-Token: Bearer <token>`, the header must start with
+/// it, compared case-insensitively, and it is stripped along with any whitespace that follows it.
+/// A header without the prefix, or with nothing after it, is rejected with
+/// `CsrfError::MissingToken`.
+pub fn extract_token_from_header<'a>(header: &'a str, prefix: Option<&str>) -> Result<&'a str, CsrfError> {
+    let mut token = header.trim();
+    if let Some(prefix) = prefix {
+        token = match token.get(..prefix.len()) {
+            Some(found) if found.eq_ignore_ascii_case(prefix) => token[prefix.len()..].trim_start(),
+            _ => {
+                debug!("CSRF header did not start with the {:?} prefix.", prefix);
+                return Err(CsrfError::MissingToken);
+            },
+        };
+    }
+
+    if token.is_empty() {
+        debug!("CSRF header was empty.");
+        return Err(CsrfError::MissingToken);
+    }
+    Ok(token)
+}
+
+/// Given the value of an `X-Forwarded-Proto` request header, if any, return whether the client
+/// connected over HTTPS. When the header lists several protocols, the first one is the client's.
+/// Only trust this header when it is set by a proxy in front of the application, since clients
+/// can send it too.
+pub fn is_forwarded_https(header: Option<&str>) -> bool {
+    header.and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Given a stream of `application/x-www-form-urlencoded` body chunks, return a `Future` that
+/// resolves to the decoded value of the first occurrence of `field`, or `None` if the body ends
+/// without it.
+///
+/// The body is parsed incrementally as chunks arrive, so a field that spans chunk boundaries is
+/// handled, and the stream is not polled any further once the field has been found. An error
+/// from the stream resolves the future to `CsrfError::InternalError`. A value longer than any
+/// percent encoded token could be resolves it to `CsrfError::ValidationFailure`, without
+/// buffering or reading the rest of the body.
+///
+/// The stream must be `Unpin`. Streams that are not can be passed as `Box::pin(body)`.
+#[cfg(feature = "async")]
+pub fn extract_token_from_body<S, B, E>(body: S, field: &str) -> ExtractTokenFromBody<S>
+    where S: Stream<Item = Result<B, E>> + Unpin,
+          B: AsRef<[u8]>
+{
+    ExtractTokenFromBody {
+        body,
+        scanner: FormFieldScanner::new(field, MAX_FORM_VALUE_LEN),
+    }
+}
+
+/// The `Future` returned by `extract_token_from_body`.
+#[cfg(feature = "async")]
+pub struct ExtractTokenFromBody<S> {
+    body: S,
+    scanner: FormFieldScanner,
+}
+
+#[cfg(feature = "async")]
+impl<S, B, E> Future for ExtractTokenFromBody<S>
+    where S: Stream<Item = Result<B, E>> + Unpin,
+          B: AsRef<[u8]>
+{
+    type Output = Result<Option<Vec<u8>>, CsrfError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.body).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    match this.scanner.feed(chunk.as_ref()) {
+                        Ok(None) => (),
+                        found => return Poll::Ready(found),
+                    }
+                },
+                Poll::Ready(Some(Err(_))) => {
+                    warn!("Failed to read request body");
+                    return Poll::Ready(Err(CsrfError::InternalError));
+                },
+                Poll::Ready(None) => return Poll::Ready(Ok(this.scanner.finish())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_field_found() {
+        let body = b"a=1&csrf-token=abc%2Bdef%3D%3D&b=2";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), Some(b"abc+def==".to_vec()));
+    }
+
+    #[test]
+    fn form_field_last() {
+        let body = b"a=1&csrf-token=abc+def";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), Some(b"abc def".to_vec()));
+    }
+
+    #[test]
+    fn form_field_absent() {
+        let body = b"a=1&csrf-tokens=abc&xcsrf-token=def";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), None);
+    }
+
+    #[test]
+    fn form_field_first_occurrence_wins() {
+        let body = b"csrf-token=first&csrf-token=second";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), Some(b"first".to_vec()));
+    }
+
+    #[test]
+    fn form_field_encoded_key() {
+        let body = b"csrf%2Dtoken=abc";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn form_field_malformed_escape() {
+        let body = b"csrf-token=abc%2";
+        assert_eq!(extract_token_from_form(body, "csrf-token"), Some(b"abc%2".to_vec()));
+    }
+
+    #[test]
+    fn cookie_at_start() {
+        assert_eq!(extract_cookie_from_header("csrf=abc+/=; a=1; b=2", "csrf"), Ok("abc+/="));
+    }
+
+    #[test]
+    fn cookie_in_middle() {
+        assert_eq!(extract_cookie_from_header("a=1;  csrf = abc ;b=2", "csrf"), Ok("abc"));
+    }
+
+    #[test]
+    fn cookie_at_end() {
+        assert_eq!(extract_cookie_from_header("a=1; b=2; csrf=abc", "csrf"), Ok("abc"));
+    }
+
+    #[test]
+    fn cookie_quoted() {
+        assert_eq!(extract_cookie_from_header("a=1; csrf=\"abc==\"", "csrf"), Ok("abc=="));
+    }
+
+    #[test]
+    fn cookie_absent() {
+        assert_eq!(extract_cookie_from_header("a=1; xcsrf=abc; csrfx=def; csrf", "csrf"),
+                   Err(CsrfError::MissingCookie));
+        assert_eq!(extract_cookie_from_header("", "csrf"), Err(CsrfError::MissingCookie));
+    }
+
+    #[test]
+    fn cookie_duplicated() {
+        assert_eq!(extract_cookie_from_header("csrf=abc; a=1; csrf=def", "csrf"), Err(CsrfError::AmbiguousCookie));
+        assert_eq!(extract_cookie_from_header("csrf=abc; csrf=abc", "csrf"), Err(CsrfError::AmbiguousCookie));
+        assert_eq!(extract_cookie_from_header("csrf=abc; xcsrf=def; csrfx=ghi", "csrf"), Ok("abc"));
+    }
+
+    #[test]
+    fn host_cookie() {
+        use cookie::CookieBuilder;
+        use core::{CsrfCookie, CSRF_COOKIE_NAME};
+
+        let set_cookie = CookieBuilder::new(&CsrfCookie::new(b"cookie bytes".to_vec())).host_prefix(true).build()
+            .expect("conflicting cookie attributes");
+        let cookie = set_cookie.split(';').next().expect("no cookie pair");
+        let header = format!("csrf=planted; {}; other=1", cookie);
+
+        assert_eq!(extract_host_cookie_from_header(&header, CSRF_COOKIE_NAME), Ok("Y29va2llIGJ5dGVz"));
+        assert_eq!(extract_cookie_from_header(&header, CSRF_COOKIE_NAME), Ok("planted"));
+        assert_eq!(extract_host_cookie_from_header("csrf=planted", CSRF_COOKIE_NAME),
+                   Err(CsrfError::MissingCookie));
+    }
+
+    #[test]
+    fn header_prefixed() {
+        assert_eq!(extract_token_from_header("CSRF dG9rZW4=", Some("CSRF")), Ok("dG9rZW4="));
+        assert_eq!(extract_token_from_header("  bearer \t dG9rZW4= ", Some("Bearer")), Ok("dG9rZW4="));
+        assert_eq!(extract_token_from_header("Bearer dG9rZW4=", Some("Bearer ")), Ok("dG9rZW4="));
+    }
+
+    #[test]
+    fn header_unprefixed() {
+        assert_eq!(extract_token_from_header(" dG9rZW4= ", None), Ok("dG9rZW4="));
+        assert_eq!(extract_token_from_header("dG9rZW4=", Some("Bearer")), Err(CsrfError::MissingToken));
+        assert_eq!(extract_token_from_header("", None), Err(CsrfError::MissingToken));
+    }
+
+    #[test]
+    fn header_wrong_prefix() {
+        assert_eq!(extract_token_from_header("Basic dG9rZW4=", Some("Bearer")), Err(CsrfError::MissingToken));
+        assert_eq!(extract_token_from_header("Bearer", Some("Bearer")), Err(CsrfError::MissingToken));
+        assert_eq!(extract_token_from_header("Bearer   ", Some("Bearer")), Err(CsrfError::MissingToken));
+        assert_eq!(extract_token_from_header("é", Some("B")), Err(CsrfError::MissingToken));
+    }
+
+    #[test]
+    fn forwarded_https() {
+        assert!(is_forwarded_https(Some("https")));
+        assert!(is_forwarded_https(Some(" HTTPS ")));
+        assert!(is_forwarded_https(Some("https, http")));
+        assert!(!is_forwarded_https(Some("http, https")));
+        assert!(!is_forwarded_https(Some("http")));
+        assert!(!is_forwarded_https(None));
+    }
+
+    #[cfg(feature = "async")]
+    mod async_body {
+        use std::cell::Cell;
+
+        use bytes::Bytes;
+        use futures::executor::block_on;
+        use futures::stream::{self, StreamExt};
+
+        use core::CsrfError;
+        use extract::{extract_token_from_body, MAX_FORM_VALUE_LEN};
+
+        fn chunked(chunks: &[&'static [u8]]) -> Vec<Result<Bytes, ()>> {
+            chunks.iter().map(|c| Ok(Bytes::from_static(c))).collect()
+        }
+
+        #[test]
+        fn field_spans_chunks() {
+            let body = stream::iter(chunked(&[b"a=1&cs", b"rf-tok", b"en=abc%", b"2Bd", b"ef&b=2"]));
+            let value = block_on(extract_token_from_body(body, "csrf-token"));
+            assert_eq!(value, Ok(Some(b"abc+def".to_vec())));
+        }
+
+        #[test]
+        fn field_at_end_of_body() {
+            let body = stream::iter(chunked(&[b"a=1&csrf-token=a", b"bc"]));
+            let value = block_on(extract_token_from_body(body, "csrf-token"));
+            assert_eq!(value, Ok(Some(b"abc".to_vec())));
+        }
+
+        #[test]
+        fn field_absent() {
+            let body = stream::iter(chunked(&[b"a=1&", b"b=2"]));
+            let value = block_on(extract_token_from_body(body, "csrf-token"));
+            assert_eq!(value, Ok(None));
+        }
+
+        #[test]
+        fn overlong_value_stops_reading() {
+            let polled = Cell::new(0);
+            let value = vec![b'A'; MAX_FORM_VALUE_LEN];
+            let body = stream::iter(chunked(&[b"a=1&csrf-token="]))
+                .chain(stream::repeat(Ok(Bytes::from_static(&[b'A'; 64]))).take(1 << 20))
+                .inspect(|_| polled.set(polled.get() + 1));
+            assert_eq!(block_on(extract_token_from_body(body, "csrf-token")), Err(CsrfError::ValidationFailure));
+            assert_eq!(polled.get(), 1 + MAX_FORM_VALUE_LEN / 64 + 1);
+
+            let mut chunks = chunked(&[b"csrf-token="]);
+            chunks.push(Ok(Bytes::from(value.clone())));
+            let body = stream::iter(chunks);
+            assert_eq!(block_on(extract_token_from_body(body, "csrf-token")), Ok(Some(value)));
+        }
+
+        #[test]
+        fn stream_error() {
+            let body = stream::iter(vec![Ok(Bytes::from_static(b"a=1&")), Err(())]);
+            let value = block_on(extract_token_from_body(body, "csrf-token"));
+            assert_eq!(value, Err(CsrfError::InternalError));
+        }
+    }
+}
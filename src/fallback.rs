@@ -0,0 +1,554 @@
+//! Module containing a `CsrfProtection` that accepts tokens from several backends
+
+use ring::rand::SystemRandom;
+
+use clock::Clock;
+use core::{CsrfCookie, CsrfError, CsrfProtection, CsrfToken, SecurityParameters,
+           UnencryptedCsrfCookie, UnencryptedCsrfToken};
+use encoding::TokenEncoding;
+use registry::Algorithm;
+use source::TokenValueSource;
+
+/// The length of the key id that `with_key_ids` prefixes to tokens and cookies.
+const KEY_ID_LEN: usize = 8;
+
+
+/// Wraps a primary `CsrfProtection` with an ordered list of alternates.
+///
+/// New tokens and cookies are only ever generated by the primary, but parsing tries the primary
+/// and then each alternate in turn until one succeeds. This allows a deployment to migrate from
+/// one algorithm or key to another while still accepting tokens issued before the switch.
+///
+/// The backends' wire formats have different lengths, so an input that belongs to another
+/// backend is rejected by a length check before any crypto is attempted.
+///
+/// When many previous keys are retained, `with_key_ids` avoids trying each of them in turn by
+/// prefixing new tokens and cookies with the `key_id` of the primary, so that parsing can go
+/// straight to the protection that issued them.
+///
+/// It implements `CsrfProtection` when the primary is `Clone` and `'static`, as every backend in
+/// this crate is, so that `clone_box` can copy it.
+pub struct FallbackCsrfProtection<P: CsrfProtection> {
+    primary: P,
+    alternates: Vec<Box<dyn CsrfProtection>>,
+    // the key ids of the primary and then each alternate, so they are not computed on every parse
+    key_ids: Vec<[u8; KEY_ID_LEN]>,
+    embed_key_id: bool,
+}
+
+impl<P: CsrfProtection> FallbackCsrfProtection<P> {
+    /// Given a primary protection, return a `FallbackCsrfProtection` with no alternates.
+    pub fn new(primary: P) -> Self {
+        FallbackCsrfProtection {
+            key_ids: vec![primary.key_id()],
+            primary,
+            alternates: Vec::new(),
+            embed_key_id: false,
+        }
+    }
+
+    /// Add an alternate protection that is tried after the primary and all previously added
+    /// alternates.
+    pub fn with_alternate<A: CsrfProtection + 'static>(mut self, alternate: A) -> Self {
+        self.key_ids.push(alternate.key_id());
+        self.alternates.push(Box::new(alternate));
+        self
+    }
+
+    /// Set whether new tokens and cookies are prefixed with the 8 byte `key_id` of the primary.
+    ///
+    /// Whether or not this is set, a token or cookie that starts with the key id of the primary
+    /// or an alternate is parsed by that protection alone, and any other input is tried against
+    /// each protection in turn as usual. Tokens and cookies issued before this was set therefore
+    /// keep parsing. One with an unknown key id is tried against each protection like any other
+    /// input, so it is usually rejected by their length checks, but not when its length matches
+    /// another backend: a prefixed HMAC token or cookie has the length of a ChaCha20-Poly1305 one,
+    /// so a ChaCha20-Poly1305 alternate may try to open it, and it then fails validation.
+    ///
+    /// The prefix is only understood by a `FallbackCsrfProtection`, so every instance that parses
+    /// the new tokens must be deployed before any instance sets this.
+    pub fn with_key_ids(mut self, embed_key_id: bool) -> Self {
+        self.embed_key_id = embed_key_id;
+        self
+    }
+
+    /// Retrieve the primary protection.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Return a protection for migrating a password-derived key from `old_salt` to `new_salt`.
+    ///
+    /// The primary is derived with `new_salt` and generates all new tokens and cookies, while an
+    /// alternate derived with `old_salt` keeps parsing those issued before the switch. To move
+    /// off the built-in salt, pass `SCRYPT_SALT` as `old_salt`. The procedure is:
+    ///
+    /// 1. Deploy with `salt_migration(password, old_salt, new_salt)`.
+    /// 2. Wait until every cookie issued under `old_salt` has expired, which is at most the
+    ///    longest TTL in use.
+    /// 3. Deploy with `P::from_password_and_salt(password, new_salt)` alone.
+    pub fn salt_migration(password: &[u8], old_salt: &[u8], new_salt: &[u8]) -> Self
+        where P: 'static
+    {
+        FallbackCsrfProtection::new(P::from_password_and_salt(password, new_salt))
+            .with_alternate(P::from_password_and_salt(password, old_salt))
+    }
+
+    /// The length of the key id prefixed to new tokens and cookies, which is zero unless
+    /// `with_key_ids` is set.
+    fn key_id_len(&self) -> usize {
+        if self.embed_key_id { KEY_ID_LEN } else { 0 }
+    }
+
+    /// Prefix a transport buffer from the primary with its key id, if `with_key_ids` is set.
+    fn with_key_id(&self, transport: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(self.key_id_len() + transport.len());
+        prefixed.extend_from_slice(&self.key_ids[0][..self.key_id_len()]);
+        prefixed.extend_from_slice(transport);
+        prefixed
+    }
+
+    /// Parse with the protection whose key id prefixes `transport`, or if there is none, with each
+    /// protection in turn.
+    fn route<T, F>(&self, transport: &[u8], parse: F) -> Result<T, CsrfError>
+        where F: Fn(&dyn CsrfProtection, &[u8]) -> Result<T, CsrfError>
+    {
+        match self.split_key_id(transport) {
+            Some((0, rest)) => parse(&self.primary, rest),
+            Some((index, rest)) => parse(self.alternates[index - 1].as_ref(), rest),
+            None => self.try_each(|p| parse(p, transport)),
+        }
+    }
+
+    /// If `transport` is prefixed with the key id of one of the protections, return the index of
+    /// that protection, where the primary is 0, and the rest of the transport.
+    fn split_key_id<'a>(&self, transport: &'a [u8]) -> Option<(usize, &'a [u8])> {
+        if transport.len() <= KEY_ID_LEN {
+            return None;
+        }
+        let (key_id, rest) = transport.split_at(KEY_ID_LEN);
+        self.key_ids.iter().position(|id| &id[..] == key_id).map(|index| (index, rest))
+    }
+
+    fn try_each<T, F>(&self, parse: F) -> Result<T, CsrfError>
+        where F: Fn(&dyn CsrfProtection) -> Result<T, CsrfError>
+    {
+        let mut error = CsrfError::MalformedLength;
+        let protections = Some(&self.primary as &dyn CsrfProtection)
+            .into_iter()
+            .chain(self.alternates.iter().map(|p| p.as_ref()));
+
+        for protection in protections {
+            match parse(protection) {
+                Ok(parsed) => return Ok(parsed),
+                // keep the most specific error, since a length mismatch only means "not mine", and
+                // a protection whose algorithm is not allowed never looked at the input
+                Err(CsrfError::MalformedLength) => (),
+                Err(CsrfError::AlgorithmMismatch) if error == CsrfError::MalformedLength => {
+                    error = CsrfError::AlgorithmMismatch
+                },
+                Err(CsrfError::AlgorithmMismatch) => (),
+                Err(CsrfError::AlgorithmNotAllowed)
+                    if error == CsrfError::MalformedLength || error == CsrfError::AlgorithmMismatch => {
+                    error = CsrfError::AlgorithmNotAllowed
+                },
+                Err(CsrfError::AlgorithmNotAllowed) => (),
+                Err(err) => error = err,
+            }
+        }
+
+        Err(error)
+    }
+}
+
+impl<P: CsrfProtection + Clone> Clone for FallbackCsrfProtection<P> {
+    fn clone(&self) -> Self {
+        FallbackCsrfProtection {
+            primary: self.primary.clone(),
+            alternates: self.alternates.clone(),
+            key_ids: self.key_ids.clone(),
+            embed_key_id: self.embed_key_id,
+        }
+    }
+}
+
+impl<P: CsrfProtection + Clone + 'static> CsrfProtection for FallbackCsrfProtection<P> {
+    /// Derive the primary protection from the password and salt. There are no alternates.
+    fn from_password_and_salt(password: &[u8], salt: &[u8]) -> Self {
+        FallbackCsrfProtection::new(P::from_password_and_salt(password, salt))
+    }
+
+    fn clone_box(&self) -> Box<dyn CsrfProtection> {
+        Box::new(self.clone())
+    }
+
+    /// The primary's key, which generates all new tokens and cookies.
+    fn key_id(&self) -> [u8; 8] {
+        self.primary.key_id()
+    }
+
+    /// The primary's parameters, which apply to all new tokens and cookies.
+    fn security_parameters(&self) -> SecurityParameters {
+        self.primary.security_parameters()
+    }
+
+    fn rng(&self) -> &SystemRandom {
+        self.primary.rng()
+    }
+
+    fn rng_retries(&self) -> u32 {
+        self.primary.rng_retries()
+    }
+
+    fn require_secure_transport(&self) -> bool {
+        self.primary.require_secure_transport()
+    }
+
+    fn ttl_bounds(&self) -> (Option<i64>, Option<i64>) {
+        self.primary.ttl_bounds()
+    }
+
+    fn token_value_source(&self) -> Option<&dyn TokenValueSource> {
+        self.primary.token_value_source()
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        self.primary.clock()
+    }
+
+    fn fail_closed_clock(&self) -> bool {
+        self.primary.fail_closed_clock()
+    }
+
+    fn encoding(&self) -> TokenEncoding {
+        self.primary.encoding()
+    }
+
+    /// Whether the primary encrypts. Tokens from the alternates are still accepted even if it
+    /// does and they do not, so this only describes newly generated tokens and cookies.
+    fn supports_encryption(&self) -> bool {
+        self.primary.supports_encryption()
+    }
+
+    fn wire_version(&self) -> u8 {
+        self.primary.wire_version()
+    }
+
+    /// The primary's algorithm. Each alternate parses with its own.
+    fn algorithm(&self) -> Option<Algorithm> {
+        self.primary.algorithm()
+    }
+
+    /// The length of tokens generated by the primary, including the key id if `with_key_ids` is
+    /// set. Tokens from the alternates may differ.
+    fn expected_token_len(&self) -> usize {
+        self.key_id_len() + self.primary.expected_token_len()
+    }
+
+    /// The length of cookies generated by the primary, including the key id if `with_key_ids` is
+    /// set. Cookies from the alternates may differ.
+    fn expected_cookie_len(&self) -> usize {
+        self.key_id_len() + self.primary.expected_cookie_len()
+    }
+
+    fn transport_nonce<'a>(&self, transport: &'a [u8]) -> Option<&'a [u8]> {
+        transport.get(self.key_id_len()..).and_then(|transport| self.primary.transport_nonce(transport))
+    }
+
+    /// The token without its key id, which is not authenticated, so that a token verifies only
+    /// once whether it is sent with its key id or without. A token is parsed without its key id
+    /// exactly when it starts with one, so this is the transport that the token was parsed from.
+    fn replay_transport<'a>(&self, transport: &'a [u8]) -> &'a [u8] {
+        match self.split_key_id(transport) {
+            Some((0, rest)) => self.primary.replay_transport(rest),
+            Some((index, rest)) => self.alternates[index - 1].replay_transport(rest),
+            None => transport,
+        }
+    }
+
+    fn generate_cookie_at(&self, token_value: &[u8; 64], ttl_seconds: i64, now: i64) -> Result<CsrfCookie, CsrfError> {
+        let cookie = self.primary.generate_cookie_at(token_value, ttl_seconds, now)?;
+        Ok(CsrfCookie::new(self.with_key_id(cookie.value())))
+    }
+
+    fn refresh_cookie(&self, cookie: &UnencryptedCsrfCookie, ttl_seconds: i64) -> Result<CsrfCookie, CsrfError> {
+        let cookie = self.primary.refresh_cookie(cookie, ttl_seconds)?;
+        Ok(CsrfCookie::new(self.with_key_id(cookie.value())))
+    }
+
+    fn generate_bound_token(&self, token_value: &[u8; 64], binding: &[u8]) -> Result<CsrfToken, CsrfError> {
+        let token = self.primary.generate_bound_token(token_value, binding)?;
+        Ok(CsrfToken::new(self.with_key_id(token.value())))
+    }
+
+    fn parse_cookie_at(&self, cookie: &[u8], now: i64) -> Result<UnencryptedCsrfCookie, CsrfError> {
+        self.route(cookie, |p, cookie| p.parse_cookie_at(cookie, now))
+    }
+
+    fn parse_bound_token(&self, token: &[u8], binding: &[u8]) -> Result<UnencryptedCsrfToken, CsrfError> {
+        self.route(token, |p, token| p.parse_bound_token(token, binding))
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+mod tests {
+    use config::CsrfConfig;
+    use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+               HmacCsrfProtection, SCRYPT_SALT};
+    use domain::DomainSeparatedCsrfProtection;
+    use fallback::FallbackCsrfProtection;
+    use registry::Algorithm;
+    use replay::InMemoryReplayGuard;
+    use test_util::{KEY_32, OTHER_KEY_32};
+    use wire::{CHACHA20_COOKIE_LEN, CHACHA20_TOKEN_LEN, WIRE_VERSION};
+
+    fn fallback() -> FallbackCsrfProtection<AesGcmCsrfProtection> {
+        FallbackCsrfProtection::new(AesGcmCsrfProtection::from_key(KEY_32))
+            .with_alternate(HmacCsrfProtection::from_key(KEY_32))
+            .with_alternate(ChaCha20Poly1305CsrfProtection::from_key(KEY_32))
+    }
+
+    fn assert_parses<P: CsrfProtection>(issuer: &P) {
+        let protect = fallback();
+        let (token, cookie) = issuer.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+    }
+
+    #[test]
+    fn primary_parses() {
+        assert_parses(&AesGcmCsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn alternates_parse() {
+        assert_parses(&HmacCsrfProtection::from_key(KEY_32));
+        assert_parses(&ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn generates_with_primary() {
+        let protect = fallback();
+        let primary = AesGcmCsrfProtection::from_key(KEY_32);
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert!(primary.parse_token(token.value()).is_ok());
+        assert!(primary.parse_cookie(cookie.value()).is_ok());
+    }
+
+    #[test]
+    fn unknown_key_rejected() {
+        let protect = fallback();
+        let issuer = HmacCsrfProtection::from_key(OTHER_KEY_32);
+        let (token, cookie) = issuer.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.parse_token(token.value()), Err(CsrfError::ValidationFailure));
+        assert_eq!(protect.parse_cookie(cookie.value()), Err(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn unknown_format_rejected() {
+        let protect = fallback();
+        assert_eq!(protect.parse_token(&[0; 10]), Err(CsrfError::MalformedLength));
+        assert_eq!(protect.parse_cookie(&[0; 10]), Err(CsrfError::MalformedLength));
+    }
+
+    #[test]
+    fn clone_box_keeps_alternates() {
+        let boxed: Box<dyn CsrfProtection> = Box::new(fallback());
+        let clone = boxed.clone_box();
+        drop(boxed);
+
+        let (token, cookie) = HmacCsrfProtection::from_key(KEY_32).generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = clone.parse_token(token.value()).expect("token not parsed");
+        let cookie = clone.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(clone.verify_token_pair(&token, &cookie));
+    }
+
+    #[test]
+    fn supports_encryption_follows_primary() {
+        assert!(fallback().supports_encryption());
+        let hmac_primary = FallbackCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32))
+            .with_alternate(AesGcmCsrfProtection::from_key(KEY_32));
+        assert!(!hmac_primary.supports_encryption());
+    }
+
+    #[test]
+    fn disallowed_alternate_not_tried() {
+        let config = CsrfConfig::new().with_allowed_algorithms(&[Algorithm::AesGcm]);
+        let protect = FallbackCsrfProtection::new(AesGcmCsrfProtection::from_key(KEY_32).with_config(config.clone()))
+            .with_alternate(HmacCsrfProtection::from_key(KEY_32).with_config(config.clone()))
+            .with_alternate(ChaCha20Poly1305CsrfProtection::from_key(KEY_32).with_config(config));
+
+        // the HMAC alternate has the key that issued these, but is pinned out
+        for issuer in &[HmacCsrfProtection::from_key(KEY_32).clone_box(),
+                        ChaCha20Poly1305CsrfProtection::from_key(KEY_32).clone_box()] {
+            let (token, cookie) = issuer.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.parse_token(token.value()).err(), Some(CsrfError::AlgorithmNotAllowed));
+            assert_eq!(protect.parse_cookie(cookie.value()).err(), Some(CsrfError::AlgorithmNotAllowed));
+            assert_eq!(fallback().verify_raw(token.value(), cookie.value()), Ok(()));
+        }
+
+        let (token, cookie) = AesGcmCsrfProtection::from_key(KEY_32).generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+        let mut forged = token.value().to_vec();
+        forged[1] ^= 0x01;
+        assert_eq!(protect.parse_token(&forged).err(), Some(CsrfError::ValidationFailure));
+        assert_eq!(protect.algorithm(), Some(Algorithm::AesGcm));
+    }
+
+    #[test]
+    fn unknown_backend_reports_mismatch() {
+        let protect = FallbackCsrfProtection::new(AesGcmCsrfProtection::from_key(KEY_32))
+            .with_alternate(ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+        let token = HmacCsrfProtection::from_key(KEY_32).generate_token(&[7; 64])
+            .expect("couldn't generate token");
+        assert_eq!(protect.parse_token(token.value()).err(), Some(CsrfError::AlgorithmMismatch));
+    }
+
+    fn rotated() -> FallbackCsrfProtection<HmacCsrfProtection> {
+        FallbackCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32))
+            .with_alternate(HmacCsrfProtection::from_key([1; 32]))
+            .with_alternate(HmacCsrfProtection::from_key(OTHER_KEY_32))
+            .with_key_ids(true)
+    }
+
+    fn prefixed(key_id: [u8; 8], transport: &[u8]) -> Vec<u8> {
+        [&key_id[..], transport].concat()
+    }
+
+    #[test]
+    fn key_ids_prefix_new_pairs() {
+        let protect = rotated();
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(&token.value()[..8], &protect.key_id()[..]);
+        assert_eq!(&cookie.value()[..8], &protect.key_id()[..]);
+        assert_eq!(token.value().len(), protect.expected_token_len());
+        assert_eq!(cookie.value().len(), protect.expected_cookie_len());
+
+        let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+        let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+    }
+
+    #[test]
+    fn key_id_routes_to_its_key() {
+        let protect = rotated();
+        let old = HmacCsrfProtection::from_key(OTHER_KEY_32);
+        let (token, cookie) = old.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        let token = protect.parse_token(&prefixed(old.key_id(), token.value())).expect("token not parsed");
+        let cookie = protect.parse_cookie(&prefixed(old.key_id(), cookie.value())).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+
+        // the key id alone picks the protection, so the other keys are never tried
+        let (token, _) = old.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        let wrong_id = HmacCsrfProtection::from_key([1; 32]).key_id();
+        assert_eq!(protect.parse_token(&prefixed(wrong_id, token.value())).err(),
+                   Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn stripped_key_id_is_still_replayed() {
+        let protect = rotated();
+        let guard = InMemoryReplayGuard::new();
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard), Ok(()));
+        assert_eq!(protect.parse_and_verify_once(&token.value()[8..], cookie.value(), &guard),
+                   Err(CsrfError::TokenReplayed));
+
+        // and the other way around, for a token that was first sent without its key id
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(protect.parse_and_verify_once(&token.value()[8..], cookie.value(), &guard), Ok(()));
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard),
+                   Err(CsrfError::TokenReplayed));
+
+        let domain = DomainSeparatedCsrfProtection::new(rotated(), b"app");
+        let (token, cookie) = domain.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(domain.parse_and_verify_once(token.value(), cookie.value(), &guard), Ok(()));
+        assert_eq!(domain.parse_and_verify_once(&token.value()[8..], cookie.value(), &guard),
+                   Err(CsrfError::TokenReplayed));
+    }
+
+    #[test]
+    fn unknown_key_id_fails_fast() {
+        let protect = rotated();
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        let mut token = token.value().to_vec();
+        let mut cookie = cookie.value().to_vec();
+        token[0] ^= 1;
+        cookie[0] ^= 1;
+        assert_eq!(protect.parse_token(&token).err(), Some(CsrfError::MalformedLength));
+        assert_eq!(protect.parse_cookie(&cookie).err(), Some(CsrfError::MalformedLength));
+
+        // a prefixed HMAC token or cookie has the length of a ChaCha20-Poly1305 one, so an unknown
+        // key id that starts with the wire version reaches the crypto of that alternate
+        let protect = FallbackCsrfProtection::new(HmacCsrfProtection::from_key(KEY_32))
+            .with_alternate(ChaCha20Poly1305CsrfProtection::from_key(KEY_32))
+            .with_key_ids(true);
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!(token.value().len(), CHACHA20_TOKEN_LEN);
+        assert_eq!(cookie.value().len(), CHACHA20_COOKIE_LEN);
+
+        let mut unknown_id = protect.key_id();
+        unknown_id[0] = WIRE_VERSION;
+        unknown_id[1] ^= 1;
+        let token = prefixed(unknown_id, &token.value()[8..]);
+        let cookie = prefixed(unknown_id, &cookie.value()[8..]);
+        assert_eq!(protect.parse_token(&token).err(), Some(CsrfError::ValidationFailure));
+        assert_eq!(protect.parse_cookie(&cookie).err(), Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn key_ids_accept_unprefixed() {
+        let protect = rotated();
+        let (token, cookie) = HmacCsrfProtection::from_key([1; 32]).generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+
+        // and a protection without key ids still routes prefixed tokens
+        let (token, _) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert!(rotated().with_key_ids(false).parse_token(token.value()).is_ok());
+    }
+
+    #[test]
+    fn salt_migration() {
+        let password = b"correct horse battery staple";
+        let old = HmacCsrfProtection::from_password(password);
+        let new = HmacCsrfProtection::from_password_and_salt(password, b"per-deployment salt");
+        let protect = FallbackCsrfProtection::<HmacCsrfProtection>::salt_migration(
+            password, SCRYPT_SALT, b"per-deployment salt");
+
+        // pairs issued before the migration still verify during the overlap
+        let (token, cookie) = old.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("old token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("old cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+
+        // new pairs are issued under the new salt only
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert!(new.parse_token(token.value()).is_ok());
+        assert!(new.parse_cookie(cookie.value()).is_ok());
+        assert!(old.parse_token(token.value()).is_err());
+        assert!(old.parse_cookie(cookie.value()).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Module containing helpers for rendering tokens into server-rendered HTML forms
+
+use cookie::CookieBuilder;
+use core::{CsrfError, CsrfPair, CSRF_COOKIE_NAME, CSRF_FORM_FIELD};
+use encoding::TokenEncoding;
+
+
+/// The names under which a token and its cookie are sent, so that the code that renders them and
+/// the code that extracts them from requests agree.
+///
+/// By default these are `CSRF_FORM_FIELD` and `CSRF_COOKIE_NAME`, and the token and cookie are
+/// rendered base64 encoded. Set the `encoding` of the protection with `with_encoding`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CsrfNames {
+    form_field: String,
+    cookie: String,
+    encoding: TokenEncoding,
+}
+
+impl CsrfNames {
+    /// Create the default names.
+    pub fn new() -> Self {
+        CsrfNames::default()
+    }
+
+    /// Use the given form field name instead of `CSRF_FORM_FIELD`.
+    pub fn with_form_field(mut self, form_field: &str) -> Self {
+        self.form_field = form_field.to_string();
+        self
+    }
+
+    /// Use the given cookie name instead of `CSRF_COOKIE_NAME`.
+    pub fn with_cookie(mut self, cookie: &str) -> Self {
+        self.cookie = cookie.to_string();
+        self
+    }
+
+    /// Render the token and cookie in `encoding` rather than base64. This should be the `encoding`
+    /// of the protection that verifies them.
+    pub fn with_encoding(mut self, encoding: TokenEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Retrieve the name of the form field that carries the token.
+    pub fn form_field(&self) -> &str {
+        &self.form_field
+    }
+
+    /// Retrieve the name of the cookie.
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    /// Retrieve the encoding that the token and cookie are rendered in.
+    pub fn encoding(&self) -> TokenEncoding {
+        self.encoding
+    }
+
+    /// Render a token pair as the hidden form input that carries the token and the `Set-Cookie`
+    /// header value that carries the cookie, in that order.
+    ///
+    /// The cookie's other attributes are set by `cookie_opts`, which is given a `CookieBuilder`
+    /// for the cookie. The cookie is then named by these names and encoded in their encoding, even
+    /// if `cookie_opts` changed them, so that the two can not drift apart. This fails with
+    /// `CsrfError::InvalidCookieAttributes` if `CookieBuilder::build` does.
+    ///
+    /// ```
+    /// use csrf::{CookieBuilder, CsrfCookie, CsrfNames, CsrfToken, SameSite};
+    ///
+    /// let pair = (CsrfToken::new(b"token bytes".to_vec()), CsrfCookie::new(b"cookie bytes".to_vec()));
+    /// let (html, cookie) = CsrfNames::new()
+    ///     .render_html_and_cookie(&pair, |cookie: CookieBuilder| cookie.same_site(SameSite::Strict))
+    ///     .expect("conflicting cookie attributes");
+    ///
+    /// assert_eq!(html, r#"<input type="hidden" name="csrf-token" value="dG9rZW4gYnl0ZXM=">"#);
+    /// assert_eq!(cookie, "csrf=Y29va2llIGJ5dGVz; HttpOnly; SameSite=Strict");
+    /// ```
+    pub fn render_html_and_cookie<F>(&self, pair: &CsrfPair, cookie_opts: F)
+        -> Result<(String, String), CsrfError>
+        where F: FnOnce(CookieBuilder) -> CookieBuilder
+    {
+        let (token, cookie) = pair;
+        let html = format!(r#"<input type="hidden" name="{}" value="{}">"#,
+                           escape_attribute(&self.form_field),
+                           escape_attribute(&token.encoded(self.encoding)));
+        let header = cookie_opts(CookieBuilder::new(cookie)).name(&self.cookie).encoding(self.encoding).build()?;
+        Ok((html, header))
+    }
+}
+
+impl Default for CsrfNames {
+    fn default() -> Self {
+        CsrfNames {
+            form_field: CSRF_FORM_FIELD.to_string(),
+            cookie: CSRF_COOKIE_NAME.to_string(),
+            encoding: TokenEncoding::Base64,
+        }
+    }
+}
+
+
+/// Escape a value for use inside a quoted HTML attribute. Encoded tokens never need it, but names
+/// are chosen by the caller.
+fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cookie::{CookieBuilder, SameSite};
+    use core::{CsrfCookie, CsrfToken};
+    use encoding::TokenEncoding;
+    use form::{escape_attribute, CsrfNames};
+
+    fn pair() -> (CsrfToken, CsrfCookie) {
+        (CsrfToken::new(vec![0xfb; 6]), CsrfCookie::new(b"cookie bytes".to_vec()))
+    }
+
+    #[test]
+    fn defaults() {
+        let (html, cookie) = CsrfNames::new().render_html_and_cookie(&pair(), |cookie| cookie)
+            .expect("conflicting cookie attributes");
+        assert_eq!(html, r#"<input type="hidden" name="csrf-token" value="+/v7+/v7">"#);
+        assert_eq!(cookie, "csrf=Y29va2llIGJ5dGVz; HttpOnly");
+    }
+
+    #[test]
+    fn names_shared() {
+        let names = CsrfNames::new().with_form_field("_csrf").with_cookie("__Secure-csrf");
+        let (html, cookie) = names.render_html_and_cookie(&pair(), |cookie: CookieBuilder| {
+            cookie.name("ignored").path("/").secure(true).same_site(SameSite::Lax)
+        }).expect("conflicting cookie attributes");
+        assert_eq!(html, r#"<input type="hidden" name="_csrf" value="+/v7+/v7">"#);
+        assert_eq!(cookie, "__Secure-csrf=Y29va2llIGJ5dGVz; Path=/; Secure; HttpOnly; SameSite=Lax");
+    }
+
+    #[test]
+    fn encoding_shared() {
+        let names = CsrfNames::new().with_encoding(TokenEncoding::Hex);
+        let (html, cookie) = names.render_html_and_cookie(&pair(), |cookie| cookie.encoding(TokenEncoding::Base64))
+            .expect("conflicting cookie attributes");
+        assert_eq!(html, r#"<input type="hidden" name="csrf-token" value="fbfbfbfbfbfb">"#);
+        assert_eq!(cookie, "csrf=636f6f6b6965206279746573; HttpOnly");
+    }
+
+    #[test]
+    fn names_escaped() {
+        let names = CsrfNames::new().with_form_field(r#""><script>"#);
+        let (html, _) = names.render_html_and_cookie(&pair(), |cookie| cookie)
+            .expect("conflicting cookie attributes");
+        assert_eq!(html, r#"<input type="hidden" name="&quot;&gt;&lt;script&gt;" value="+/v7+/v7">"#);
+        assert_eq!(escape_attribute("a&b'c"), "a&amp;b&#39;c");
+    }
+}
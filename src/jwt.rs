@@ -0,0 +1,230 @@
+//! Module containing a JWS envelope for carrying tokens through JWT-aware infrastructure
+
+use data_encoding::BASE64URL_NOPAD;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::SHA256;
+use ring::hmac;
+use serde_json::{self, Value};
+
+use core::{unix_time, CsrfError, CsrfProtection, CsrfToken, UnencryptedCsrfToken, MAX_ENCODED_LEN};
+
+/// The HKDF info used to derive the JWS signing key, keeping JWS signatures apart from tokens and
+/// cookies.
+const JWT_KEY_LABEL: &[u8] = b"rust-csrf-jwt";
+
+/// The only JWS header that is generated or accepted.
+const JWS_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// The longest JWS that `CsrfJwt::decode` will look at.
+const MAX_JWT_LEN: usize = 4 * MAX_ENCODED_LEN;
+
+/// The name of the claim that holds the token.
+pub const JWT_TOKEN_CLAIM: &str = "csrf";
+
+
+/// Wraps tokens as a claim in a compact JWS (RFC 7515) signed with HS256, for gateways and API
+/// management layers that route or inspect requests by JWT.
+///
+/// The payload is `{"csrf":token,"iat":issued_at,"exp":expires}`, where `token` is the unpadded
+/// base64url encoding of the token and the timestamps are in seconds. The signing key is derived
+/// from the key given to `from_key` with HKDF-SHA256, so the protection's own key can be used
+/// without a JWS signature ever being valid as a token or cookie, or the other way around.
+///
+/// The JWS only adds an outer signature and expiry. The token inside it must still be parsed by
+/// the protection that issued it and verified against its cookie, which `parse_token` does the
+/// first half of.
+pub struct CsrfJwt {
+    signing_key: hmac::SigningKey,
+}
+
+impl CsrfJwt {
+    /// Given the key of a protection, return a `CsrfJwt` that signs with a key derived from it.
+    pub fn from_key(key: [u8; 32]) -> Self {
+        CsrfJwt::from_signing_key(&hmac::SigningKey::new(&SHA256, &key))
+    }
+
+    /// Like `from_key`, but given the key as a `ring` HMAC-SHA256 key.
+    pub fn from_signing_key(key: &hmac::SigningKey) -> Self {
+        // HKDF-Expand of a single block is the HMAC of `info ‖ 0x01` under the key
+        let mut context = hmac::SigningContext::with_key(key);
+        context.update(JWT_KEY_LABEL);
+        context.update(&[1]);
+        CsrfJwt { signing_key: hmac::SigningKey::new(&SHA256, context.sign().as_ref()) }
+    }
+
+    /// Wrap the token in a JWS that expires `ttl_seconds` from now.
+    pub fn encode(&self, token: &CsrfToken, ttl_seconds: i64) -> String {
+        self.encode_at(token, ttl_seconds, unix_time())
+    }
+
+    /// Like `encode`, but the JWS is issued at `now`, in seconds, instead of the current time.
+    pub fn encode_at(&self, token: &CsrfToken, ttl_seconds: i64, now: i64) -> String {
+        let payload = format!(r#"{{"{}":"{}","iat":{},"exp":{}}}"#,
+                              JWT_TOKEN_CLAIM,
+                              token.b64_url_nopad_string(),
+                              now,
+                              now.saturating_add(ttl_seconds));
+
+        let mut jws = BASE64URL_NOPAD.encode(JWS_HEADER.as_bytes());
+        jws.push('.');
+        jws.push_str(&BASE64URL_NOPAD.encode(payload.as_bytes()));
+
+        let signature = hmac::sign(&self.signing_key, jws.as_bytes());
+        jws.push('.');
+        jws.push_str(&BASE64URL_NOPAD.encode(signature.as_ref()));
+        jws
+    }
+
+    /// Verify the signature and expiry of a JWS from `encode`, and return the token inside it.
+    ///
+    /// An empty JWS is rejected with `CsrfError::MissingToken`, and one that is not three base64url
+    /// segments of JSON with `CsrfError::DecodeError`. A JWS with another header, a bad signature,
+    /// or a missing claim is rejected with `CsrfError::ValidationFailure`, and one past its `exp`
+    /// with `CsrfError::Expired`.
+    pub fn decode(&self, jws: &str) -> Result<CsrfToken, CsrfError> {
+        self.decode_at(jws, unix_time())
+    }
+
+    /// Like `decode`, but the expiry is checked as of `now`, in seconds, instead of the current
+    /// time.
+    pub fn decode_at(&self, jws: &str, now: i64) -> Result<CsrfToken, CsrfError> {
+        if jws.is_empty() {
+            debug!("JWS was empty.");
+            return Err(CsrfError::MissingToken);
+        }
+
+        if jws.len() > MAX_JWT_LEN {
+            debug!("JWS had length {}, more than {}.", jws.len(), MAX_JWT_LEN);
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let segments: Vec<&str> = jws.split('.').collect();
+        if segments.len() != 3 {
+            debug!("JWS had {} segments, expected 3.", segments.len());
+            return Err(CsrfError::DecodeError);
+        }
+
+        let header = BASE64URL_NOPAD.decode(segments[0].as_bytes())?;
+        let payload = BASE64URL_NOPAD.decode(segments[1].as_bytes())?;
+        let signature = BASE64URL_NOPAD.decode(segments[2].as_bytes())?;
+
+        // only the exact header we generate is accepted, which rules out `"alg":"none"` and friends
+        if header != JWS_HEADER.as_bytes() {
+            info!("JWS had an unexpected header");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let signed = &jws[..segments[0].len() + 1 + segments[1].len()];
+        if verify_slices_are_equal(hmac::sign(&self.signing_key, signed.as_bytes()).as_ref(), &signature).is_err() {
+            info!("JWS had a bad signature");
+            return Err(CsrfError::ValidationFailure);
+        }
+
+        let claims: Value = serde_json::from_slice(&payload).map_err(|err| {
+            debug!("JWS payload was not JSON: {}", err);
+            CsrfError::DecodeError
+        })?;
+
+        let (token, expires) = match (claims[JWT_TOKEN_CLAIM].as_str(), claims["exp"].as_i64()) {
+            (Some(token), Some(expires)) => (token, expires),
+            _ => {
+                info!("JWS payload was missing the {} or exp claim", JWT_TOKEN_CLAIM);
+                return Err(CsrfError::ValidationFailure);
+            },
+        };
+
+        if expires <= now {
+            info!("JWS expired. Expiration: {}, Current time: {}", expires, now);
+            return Err(CsrfError::Expired);
+        }
+
+        Ok(CsrfToken::new(BASE64URL_NOPAD.decode(token.as_bytes())?))
+    }
+
+    /// Decode the JWS as by `decode`, and parse the token inside it with `protect`.
+    pub fn parse_token<P: CsrfProtection + ?Sized>(&self, jws: &str, protect: &P) -> Result<UnencryptedCsrfToken, CsrfError> {
+        protect.parse_token(self.decode(jws)?.value())
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+mod tests {
+    use data_encoding::BASE64URL_NOPAD;
+    use serde_json::{self, Value};
+
+    use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+               CsrfToken, HmacCsrfProtection};
+    use jwt::CsrfJwt;
+    use test_util::KEY_32;
+
+    fn round_trip<P: CsrfProtection>(protect: P, jwt: CsrfJwt) {
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        let jws = jwt.encode(&token, 300);
+        assert_eq!(jwt.decode(&jws), Ok(token));
+
+        let parsed_token = jwt.parse_token(&jws, &protect).expect("token not parsed");
+        let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+    }
+
+    #[test]
+    fn backends_round_trip() {
+        round_trip(HmacCsrfProtection::from_key(KEY_32), HmacCsrfProtection::from_key(KEY_32).jwt());
+        round_trip(AesGcmCsrfProtection::from_key(KEY_32), AesGcmCsrfProtection::from_key(KEY_32).jwt());
+        round_trip(ChaCha20Poly1305CsrfProtection::from_key(KEY_32),
+                   ChaCha20Poly1305CsrfProtection::from_key(KEY_32).jwt());
+    }
+
+    #[test]
+    fn standard_claims() {
+        let token = CsrfToken::new(vec![0xfb; 105]);
+        let jws = CsrfJwt::from_key(KEY_32).encode_at(&token, 300, 1000);
+        let segments: Vec<&str> = jws.split('.').collect();
+        assert_eq!(segments.len(), 3);
+
+        let header: Value = serde_json::from_slice(&BASE64URL_NOPAD.decode(segments[0].as_bytes()).unwrap()).unwrap();
+        let claims: Value = serde_json::from_slice(&BASE64URL_NOPAD.decode(segments[1].as_bytes()).unwrap()).unwrap();
+        assert_eq!(header["alg"], "HS256");
+        assert_eq!(claims["csrf"], Value::from(token.b64_url_nopad_string()));
+        assert_eq!((claims["iat"].as_i64(), claims["exp"].as_i64()), (Some(1000), Some(1300)));
+    }
+
+    #[test]
+    fn expired() {
+        let jwt = CsrfJwt::from_key(KEY_32);
+        let token = CsrfToken::new(vec![7; 105]);
+        let jws = jwt.encode_at(&token, 300, 1000);
+
+        assert_eq!(jwt.decode_at(&jws, 1299), Ok(token));
+        assert_eq!(jwt.decode_at(&jws, 1300), Err(CsrfError::Expired));
+        assert_eq!(jwt.decode(&jwt.encode(&CsrfToken::new(vec![7; 105]), -1)), Err(CsrfError::Expired));
+    }
+
+    #[test]
+    fn tampering_rejected() {
+        let jwt = CsrfJwt::from_key(KEY_32);
+        let jws = jwt.encode(&CsrfToken::new(vec![7; 105]), 300);
+        let segments: Vec<&str> = jws.split('.').collect();
+
+        let other_key = CsrfJwt::from_key([1; 32]).encode(&CsrfToken::new(vec![7; 105]), 300);
+        let unsigned_header = BASE64URL_NOPAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+        let forged_payload = BASE64URL_NOPAD.encode(br#"{"csrf":"AAAA","exp":99999999999}"#);
+
+        let cases = [
+            (other_key, CsrfError::ValidationFailure),
+            (format!("{}.{}.", unsigned_header, segments[1]), CsrfError::ValidationFailure),
+            (format!("{}.{}.{}", segments[0], forged_payload, segments[2]), CsrfError::ValidationFailure),
+            (format!("{}.{}", segments[0], segments[1]), CsrfError::DecodeError),
+            (format!("{}.{}.{}!", segments[0], segments[1], segments[2]), CsrfError::DecodeError),
+            (String::new(), CsrfError::MissingToken),
+            ("A".repeat(1 << 20), CsrfError::ValidationFailure),
+        ];
+        for (jws, err) in cases.iter() {
+            assert_eq!(jwt.decode(jws).as_ref(), Err(err), "{}", jws);
+        }
+    }
+
+}
@@ -44,6 +44,49 @@
 //! }
 //! ```
 //!
+//! ## Features
+//! Each backend is behind a feature, and all of them are enabled by default. A build that only
+//! needs one backend can disable the default features and enable just that one.
+//!
+//! * `hmac-backend`: `HmacCsrfProtection`
+//! * `aes-gcm-backend`: `AesGcmCsrfProtection`
+//! * `chacha20-backend`: `ChaCha20Poly1305CsrfProtection`
+//!
+//! Each backend is `AeadCsrfProtection` or `MacCsrfProtection` over one of the crate's own
+//! primitives. With no backend features, the crate provides only those two, for a cipher or MAC
+//! that is supplied by implementing the `Aead` or `Mac` trait.
+//!
+//! The optional `secure-mem` feature locks each AEAD backend's key into memory with `mlock` so that
+//! it is never written to swap, and zeroes it when the backend is dropped. It only has an effect on
+//! Unix targets, and is a no-op elsewhere. The HMAC backend keeps no copy of the raw key, only the
+//! HMAC state that `ring` prepares from it, which is not locked.
+//!
+//! Debug logs identify tokens and cookies by a short fingerprint rather than their value. The
+//! `log-secrets` feature logs the full base64 values instead, which helps when debugging a token
+//! mismatch, but writes secrets to the logs, so it must never be enabled in production.
+//!
+//! The `async` feature adds `extract_token_from_body`, which reads a token from a streamed form
+//! body, and `derive_from_password`, which runs the password KDF on its own thread so that it
+//! does not block an async runtime.
+//!
+//! The `cbor` feature adds `CborEnvelope`, a compact binary encoding of a token or cookie with
+//! its backend name and wire format version, for transports that do not use HTML forms.
+//!
+//! The `jwt` feature adds `CsrfJwt`, which wraps a token as a claim in an HS256 signed JWS for
+//! gateways that route or inspect requests by JWT.
+//!
+//! The `serde` feature adds `TokenResponse`, which hands a token to JavaScript clients as JSON,
+//! and `CsrfConfigSpec`, which deserializes a protection's settings from a config file.
+//!
+//! The `test-util` feature adds `InsecureTestCsrfProtection`, which does no cryptography so that
+//! integration tests run fast. Its tokens and cookies can be forged by anyone, so only ever enable
+//! it for `[dev-dependencies]`.
+//!
+//! ## Stateless tokens
+//! Single-page apps that do not use cookies can use `StatelessTokenProtection`, whose tokens carry
+//! their own expiry and are verified without a cookie. This is weaker than the double-submit
+//! cookie, as its documentation explains.
+//!
 //! ## Warning
 //! CSRF protection is not a substitute for authentication or authorization. It *only* exists to
 //! prevent malicious entities from forcing users to take actions they did not intend. If this is
@@ -54,12 +97,107 @@
 
 extern crate crypto;
 extern crate data_encoding;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(all(unix, feature = "secure-mem"))]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate ring;
+#[cfg(feature = "serde")]
+extern crate serde;
 extern crate time;
 #[cfg(feature = "iron")]
 extern crate typemap;
 
+#[cfg(test)]
+extern crate bytes;
+#[cfg(test)]
+extern crate futures;
+#[cfg(all(test, any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend")))]
+#[macro_use]
+extern crate proptest;
+#[cfg(any(test, feature = "jwt"))]
+extern crate serde_json;
+
 mod core;
 pub use core::*;
+
+mod clock;
+pub use clock::*;
+
+mod config;
+pub use config::*;
+
+mod cookie;
+pub use cookie::*;
+
+#[cfg(feature = "async")]
+mod derive;
+#[cfg(feature = "async")]
+pub use derive::*;
+
+mod domain;
+pub use domain::*;
+
+mod encoding;
+pub use encoding::*;
+
+#[cfg(feature = "cbor")]
+mod envelope;
+#[cfg(feature = "cbor")]
+pub use envelope::*;
+
+mod extract;
+pub use extract::*;
+
+mod fallback;
+pub use fallback::*;
+
+mod form;
+pub use form::*;
+
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "jwt")]
+pub use jwt::*;
+
+mod origin;
+pub use origin::*;
+
+mod primitives;
+pub use primitives::*;
+
+mod registry;
+pub use registry::*;
+
+mod replay;
+pub use replay::*;
+
+#[cfg(any(feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+mod secure_mem;
+
+mod source;
+pub use source::*;
+
+mod stateless;
+pub use stateless::*;
+
+#[cfg(test)]
+mod test_util;
+
+mod window;
+pub use window::*;
+
+mod wire;
+pub use wire::*;
+
+#[cfg(feature = "serde")]
+mod response;
+#[cfg(feature = "serde")]
+pub use response::*;
+
+#[cfg(feature = "serde")]
+mod spec;
+#[cfg(feature = "serde")]
+pub use spec::*;
@@ -0,0 +1,161 @@
+//! Module containing a same-origin check on the `Origin` and `Referer` request headers
+
+use core::CsrfError;
+
+
+/// The origins that a request may come from, checked against its `Origin` or `Referer` header as
+/// a second line of defense alongside the token. See `CsrfProtection::verify_raw_with_origin`.
+///
+/// An origin is a scheme, host, and optional port, such as `https://example.com:8443`, and is
+/// compared case-insensitively against the origin that the browser sends. Browsers omit default
+/// ports, so `https://example.com:443` never matches.
+///
+/// By default a request without either header passes, since some clients and privacy tools strip
+/// them. `with_require_header` rejects such requests too.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OriginPolicy {
+    allowed: Vec<String>,
+    require_header: bool,
+}
+
+impl OriginPolicy {
+    /// Create a policy that allows no origins.
+    pub fn new() -> Self {
+        OriginPolicy::default()
+    }
+
+    /// Allow requests from `origin`. A trailing `/` is ignored.
+    pub fn with_allowed_origin(mut self, origin: &str) -> Self {
+        self.allowed.push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    /// Reject requests that have neither an `Origin` nor a `Referer` header, instead of letting
+    /// them through on the token check alone.
+    pub fn with_require_header(mut self, require: bool) -> Self {
+        self.require_header = require;
+        self
+    }
+
+    /// Retrieve the allowed origins, lowercased.
+    pub fn allowed_origins(&self) -> &[String] {
+        &self.allowed
+    }
+
+    /// Retrieve whether a request without an `Origin` or `Referer` header is rejected.
+    pub fn require_header(&self) -> bool {
+        self.require_header
+    }
+
+    /// Given the value of the request's `Origin` header, or of its `Referer` header if it has no
+    /// `Origin`, return whether the request may proceed.
+    ///
+    /// A header whose origin is not allowed, including the opaque origin `null`, is rejected with
+    /// `CsrfError::OriginMismatch`, as is a missing header if `require_header` is set.
+    pub fn check(&self, header: Option<&str>) -> Result<(), CsrfError> {
+        let header = match header.map(str::trim).filter(|header| !header.is_empty()) {
+            Some(header) => header,
+            None if self.require_header => {
+                info!("Request had no Origin or Referer header");
+                return Err(CsrfError::OriginMismatch);
+            },
+            None => return Ok(()),
+        };
+
+        match request_origin(header) {
+            Some(origin) if self.allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) => Ok(()),
+            _ => {
+                info!("Request came from {:?}, which is not an allowed origin", header);
+                Err(CsrfError::OriginMismatch)
+            },
+        }
+    }
+}
+
+
+/// Return the origin of an `Origin` or `Referer` header value, which is everything up to the end
+/// of the authority, or `None` if the value is not an absolute URL.
+fn request_origin(header: &str) -> Option<&str> {
+    let authority_start = header.find("://")? + 3;
+    let authority_len = header[authority_start..].find(['/', '?', '#']).unwrap_or(header.len() - authority_start);
+    if authority_len == 0 {
+        return None;
+    }
+    Some(&header[..authority_start + authority_len])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use core::CsrfError;
+    use origin::{request_origin, OriginPolicy};
+
+    fn policy() -> OriginPolicy {
+        OriginPolicy::new().with_allowed_origin("https://Example.com/").with_allowed_origin("http://localhost:8080")
+    }
+
+    #[test]
+    fn origin_of_header() {
+        assert_eq!(request_origin("https://example.com"), Some("https://example.com"));
+        assert_eq!(request_origin("https://example.com:8443/a/b?c#d"), Some("https://example.com:8443"));
+        assert_eq!(request_origin("https://example.com?c"), Some("https://example.com"));
+        assert_eq!(request_origin("null"), None);
+        assert_eq!(request_origin("https:///path"), None);
+    }
+
+    #[test]
+    fn matching_origin() {
+        for &require in &[false, true] {
+            let policy = policy().with_require_header(require);
+            assert_eq!(policy.check(Some("https://example.com")), Ok(()));
+            assert_eq!(policy.check(Some("HTTPS://EXAMPLE.COM")), Ok(()));
+            assert_eq!(policy.check(Some("https://example.com/form?next=/")), Ok(()));
+            assert_eq!(policy.check(Some("http://localhost:8080/")), Ok(()));
+        }
+    }
+
+    #[test]
+    fn mismatched_origin() {
+        for &require in &[false, true] {
+            let policy = policy().with_require_header(require);
+            for header in &["https://evil.example", "https://example.com.evil.example", "http://example.com",
+                            "https://example.com:8443", "http://localhost", "null", "example.com"] {
+                assert_eq!(policy.check(Some(header)), Err(CsrfError::OriginMismatch), "{}", header);
+            }
+        }
+        assert_eq!(OriginPolicy::new().check(Some("https://example.com")), Err(CsrfError::OriginMismatch));
+    }
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(policy().check(None), Ok(()));
+        assert_eq!(policy().check(Some(" ")), Ok(()));
+
+        let strict = policy().with_require_header(true);
+        assert_eq!(strict.check(None), Err(CsrfError::OriginMismatch));
+        assert_eq!(strict.check(Some("")), Err(CsrfError::OriginMismatch));
+    }
+
+    #[cfg(feature = "hmac-backend")]
+    #[test]
+    fn verify_with_origin() {
+        use core::{CsrfProtection, HmacCsrfProtection};
+
+        let protect = HmacCsrfProtection::from_key(*b"01234567012345670123456701234567");
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let (other_token, _) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let strict = policy().with_require_header(true);
+
+        assert_eq!(protect.verify_raw_with_origin(token.value(), cookie.value(), Some("https://example.com"), &strict),
+                   Ok(()));
+        assert_eq!(protect.verify_raw_with_origin(token.value(), cookie.value(), Some("https://evil.example"), &strict),
+                   Err(CsrfError::OriginMismatch));
+        assert_eq!(protect.verify_raw_with_origin(token.value(), cookie.value(), None, &strict),
+                   Err(CsrfError::OriginMismatch));
+        assert_eq!(protect.verify_raw_with_origin(token.value(), cookie.value(), None, &policy()), Ok(()));
+        assert_eq!(protect.verify_raw_with_origin(other_token.value(), cookie.value(), None, &policy()),
+                   Err(CsrfError::TokenMismatch));
+    }
+}
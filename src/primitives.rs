@@ -0,0 +1,246 @@
+//! Module containing the cryptographic primitives that tokens and cookies are built on
+//!
+//! The wire formats are implemented once, by `AeadCsrfProtection` on top of any `Aead` and by
+//! `MacCsrfProtection` on top of any `Mac`. The built-in backends are those protections over the
+//! primitives from `rust-crypto` and `ring`: `AesGcmCsrfProtection` is
+//! `AeadCsrfProtection<AesGcmAead>`, `ChaCha20Poly1305CsrfProtection` is
+//! `AeadCsrfProtection<ChaCha20Poly1305Aead>`, and `HmacCsrfProtection` is
+//! `MacCsrfProtection<HmacSha256Mac>`.
+//!
+//! A deployment that must use a particular cryptographic module, such as one validated under
+//! FIPS 140, can implement these traits over it and disable the default features. An
+//! implementation of a standard algorithm produces the same tokens and cookies as the built-in
+//! backend for it, so it can be swapped in without invalidating those already issued.
+
+use ring::constant_time::verify_slices_are_equal;
+
+use registry::Algorithm;
+
+
+/// The length of the tag that an `Aead` authenticates a ciphertext with.
+pub const AEAD_TAG_LEN: usize = 16;
+
+/// The length of the tag that a `Mac` produces.
+pub const MAC_TAG_LEN: usize = 32;
+
+
+/// An authenticated cipher with associated data (AEAD) under a 32 byte key, such as AES-256-GCM.
+///
+/// Nonces are drawn at random for every token and cookie, so `nonce_len` must be long enough
+/// that they do not repeat, and the cipher must be secure with random nonces of that length.
+pub trait Aead: Clone + Send + Sync + 'static {
+    /// Given a key, return the cipher. This is used by `from_key` and by the constructors that
+    /// derive a key, such as `from_password`.
+    fn from_key(key: [u8; 32]) -> Self;
+
+    /// Return the length of the nonce in bytes.
+    fn nonce_len(&self) -> usize;
+
+    /// Encrypt `plaintext` under `nonce` into `ciphertext`, which has the same length, and write
+    /// a tag that authenticates the ciphertext and `aad` into `tag`.
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8], ciphertext: &mut [u8], tag: &mut [u8; AEAD_TAG_LEN]);
+
+    /// Check that `tag` authenticates `ciphertext` and `aad` under `nonce`, and if so, decrypt
+    /// `ciphertext` into `plaintext`, which has the same length, and return `true`. Return
+    /// `false` without releasing any plaintext if the tag is wrong.
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8; AEAD_TAG_LEN], plaintext: &mut [u8]) -> bool;
+
+    /// Return a fingerprint of the key, as described by `CsrfProtection::key_id`.
+    fn key_id(&self) -> [u8; 8];
+
+    /// Return the standard algorithm that this implements, if it produces the same tokens and cookies as a
+    /// built-in backend. The default is `None`, which no `CsrfConfig::with_allowed_algorithms` set
+    /// admits.
+    fn algorithm(&self) -> Option<Algorithm> {
+        None
+    }
+
+    /// Return the cipher under a key derived from this one, that `AeadCsrfProtection::seal` and
+    /// `open` use so that sealed values are kept apart from tokens and cookies. The default is
+    /// `None`, for which they fail with `CsrfError::InternalError`.
+    fn seal_cipher(&self) -> Option<Self> {
+        None
+    }
+}
+
+
+/// A message authentication code (MAC) under a 32 byte key with a 32 byte tag, such as
+/// HMAC-SHA256.
+pub trait Mac: Clone + Send + Sync + 'static {
+    /// Given a key, return the MAC. This is used by `from_key` and by the constructors that
+    /// derive a key, such as `from_password`.
+    fn from_key(key: [u8; 32]) -> Self;
+
+    /// Compute the tag over the concatenation of `parts`.
+    fn sign(&self, parts: &[&[u8]]) -> [u8; MAC_TAG_LEN];
+
+    /// Check that `tag` is the tag over the concatenation of `parts`. The default recomputes the
+    /// tag with `sign` and compares it in constant time.
+    fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> bool {
+        verify_slices_are_equal(&self.sign(parts), tag).is_ok()
+    }
+
+    /// Return a fingerprint of the key, as described by `CsrfProtection::key_id`.
+    fn key_id(&self) -> [u8; 8];
+
+    /// Return the standard algorithm that this implements, if it produces the same tokens and cookies as a
+    /// built-in backend. The default is `None`, which no `CsrfConfig::with_allowed_algorithms` set
+    /// admits.
+    fn algorithm(&self) -> Option<Algorithm> {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ring::constant_time::verify_slices_are_equal;
+    use ring::digest::{Context, SHA256};
+
+    use core::{AeadCsrfProtection, CsrfError, CsrfProtection, MacCsrfProtection};
+    use primitives::{Aead, Mac, AEAD_TAG_LEN, MAC_TAG_LEN};
+    use test_util::KEY_32;
+    use wire::{AEAD_PADDING_LEN, AES_GCM_TOKEN_LEN, WIRE_VERSION};
+
+    /// **INSECURE.** A stream cipher from SHA-256 in counter mode with a truncated SHA-256 tag,
+    /// which stands in for a cipher from another cryptographic module.
+    #[derive(Clone)]
+    struct ToyAead([u8; 32]);
+
+    impl ToyAead {
+        fn xor_keystream(&self, nonce: &[u8], input: &[u8], output: &mut [u8]) {
+            for (counter, (input, output)) in input.chunks(32).zip(output.chunks_mut(32)).enumerate() {
+                let mut block = Context::new(&SHA256);
+                block.update(&self.0);
+                block.update(nonce);
+                block.update(&(counter as u64).to_le_bytes());
+                for (byte, (input, output)) in block.finish().as_ref().iter().zip(input.iter().zip(output)) {
+                    *output = input ^ byte;
+                }
+            }
+        }
+
+        fn tag(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> [u8; AEAD_TAG_LEN] {
+            let mut context = Context::new(&SHA256);
+            for part in &[&self.0[..], &[0xff], nonce, &(aad.len() as u64).to_le_bytes(), aad, ciphertext] {
+                context.update(part);
+            }
+            let mut tag = [0; AEAD_TAG_LEN];
+            tag.copy_from_slice(&context.finish().as_ref()[..AEAD_TAG_LEN]);
+            tag
+        }
+    }
+
+    impl Aead for ToyAead {
+        fn from_key(key: [u8; 32]) -> Self {
+            ToyAead(key)
+        }
+
+        fn nonce_len(&self) -> usize {
+            16
+        }
+
+        fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8], ciphertext: &mut [u8], tag: &mut [u8; AEAD_TAG_LEN]) {
+            self.xor_keystream(nonce, plaintext, ciphertext);
+            *tag = self.tag(nonce, aad, ciphertext);
+        }
+
+        fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8; AEAD_TAG_LEN], plaintext: &mut [u8]) -> bool {
+            if verify_slices_are_equal(&self.tag(nonce, aad, ciphertext), tag).is_err() {
+                return false;
+            }
+            self.xor_keystream(nonce, ciphertext, plaintext);
+            true
+        }
+
+        fn key_id(&self) -> [u8; 8] {
+            let mut id = [0; 8];
+            id.copy_from_slice(&self.tag(&[], b"key id", &[])[..8]);
+            id
+        }
+    }
+
+    /// **INSECURE.** SHA-256 over the key and the message, which stands in for a MAC from another
+    /// cryptographic module.
+    #[derive(Clone)]
+    struct ToyMac([u8; 32]);
+
+    impl Mac for ToyMac {
+        fn from_key(key: [u8; 32]) -> Self {
+            ToyMac(key)
+        }
+
+        fn sign(&self, parts: &[&[u8]]) -> [u8; MAC_TAG_LEN] {
+            let mut context = Context::new(&SHA256);
+            context.update(&self.0);
+            for part in parts {
+                context.update(part);
+            }
+            let mut tag = [0; MAC_TAG_LEN];
+            tag.copy_from_slice(context.finish().as_ref());
+            tag
+        }
+
+        fn key_id(&self) -> [u8; 8] {
+            let mut id = [0; 8];
+            id.copy_from_slice(&self.sign(&[b"key id"])[..8]);
+            id
+        }
+    }
+
+    fn round_trip<P: CsrfProtection>(protect: &P) {
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        assert_eq!((token.value().len(), cookie.value().len()),
+                   (protect.expected_token_len(), protect.expected_cookie_len()));
+        assert_eq!(token.value()[0], WIRE_VERSION);
+
+        let parsed_token = protect.parse_token(token.value()).expect("token not parsed");
+        let parsed_cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+
+        let mut tampered = token.value().to_vec();
+        tampered[1] ^= 0x01;
+        assert_eq!(protect.parse_token(&tampered).err(), Some(CsrfError::ValidationFailure));
+
+        let bound = protect.generate_bound_token(&[7; 64], b"session").expect("couldn't generate token");
+        assert!(protect.parse_bound_token(bound.value(), b"session").is_ok());
+        assert_eq!(protect.parse_bound_token(bound.value(), b"other").err(), Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn toy_aead() {
+        let protect = AeadCsrfProtection::<ToyAead>::from_key(KEY_32);
+        assert_eq!(protect.expected_token_len(), 1 + AEAD_PADDING_LEN + 72 + 16 + AEAD_TAG_LEN);
+        assert!(protect.supports_encryption());
+        round_trip(&protect);
+
+        let other = AeadCsrfProtection::<ToyAead>::from_key([1; 32]);
+        let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+        assert_eq!(other.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+        assert_ne!(protect.key_id(), other.key_id());
+
+        // the toy cipher can not derive a seal key
+        assert_eq!(protect.seal(b"flash message").err(), Some(CsrfError::InternalError));
+        assert_eq!(protect.open(token.value()).err(), Some(CsrfError::InternalError));
+    }
+
+    #[test]
+    fn toy_aead_rejects_backend_lengths() {
+        let protect = AeadCsrfProtection::<ToyAead>::from_key(KEY_32);
+        let mut token = vec![0; AES_GCM_TOKEN_LEN];
+        token[0] = WIRE_VERSION;
+        assert_eq!(protect.parse_token(&token).err(), Some(CsrfError::AlgorithmMismatch));
+    }
+
+    #[test]
+    fn toy_mac() {
+        let protect = MacCsrfProtection::<ToyMac>::from_key(KEY_32);
+        assert!(!protect.supports_encryption());
+        round_trip(&protect);
+
+        let derived = protect.derive_subkey(b"context");
+        let token = protect.generate_token(&[7; 64]).expect("couldn't generate token");
+        assert_eq!(derived.parse_token(token.value()).err(), Some(CsrfError::ValidationFailure));
+    }
+}
@@ -0,0 +1,163 @@
+//! Module containing factories that select a `CsrfProtection` backend by name
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+
+use config::CsrfConfig;
+#[cfg(feature = "aes-gcm-backend")]
+use core::AesGcmCsrfProtection;
+#[cfg(feature = "chacha20-backend")]
+use core::ChaCha20Poly1305CsrfProtection;
+use core::{CsrfError, CsrfProtection};
+#[cfg(feature = "hmac-backend")]
+use core::HmacCsrfProtection;
+
+
+/// The algorithms of the built-in backends, for pinning the set that a protection accepts with
+/// `CsrfConfig::with_allowed_algorithms`.
+///
+/// With the `serde` feature, it deserializes from the backend's name, such as `"aes-gcm"`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum Algorithm {
+    /// HMAC-SHA256, as used by `HmacCsrfProtection`.
+    #[cfg_attr(feature = "serde", serde(rename = "hmac"))]
+    HmacSha256,
+    /// AES-256-GCM, as used by `AesGcmCsrfProtection`.
+    #[cfg_attr(feature = "serde", serde(rename = "aes-gcm"))]
+    AesGcm,
+    /// The original ChaCha20Poly1305, as used by `ChaCha20Poly1305CsrfProtection`.
+    #[cfg_attr(feature = "serde", serde(rename = "chacha20"))]
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    /// Return the name of the backend for this algorithm, as accepted by `protection_from_name`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::HmacSha256 => "hmac",
+            Algorithm::AesGcm => "aes-gcm",
+            Algorithm::ChaCha20Poly1305 => "chacha20",
+        }
+    }
+}
+
+
+/// Given the name of a backend and a key, return a boxed `CsrfProtection` for that backend.
+///
+/// The known names are `"aes-gcm"`, `"chacha20"`, and `"hmac"`, each only when the matching
+/// backend feature is enabled. Any other name is rejected with `CsrfError::UnknownAlgorithm`.
+#[cfg_attr(not(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend")),
+           allow(unused_variables))]
+pub fn protection_from_name(name: &str, key: [u8; 32]) -> Result<Box<dyn CsrfProtection>, CsrfError> {
+    protection_from_name_with_config(name, key, CsrfConfig::default())
+}
+
+/// Like `protection_from_name`, but give the backend `config` with `with_config`.
+#[cfg_attr(not(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend")),
+           allow(unused_variables))]
+pub fn protection_from_name_with_config(name: &str,
+                                        key: [u8; 32],
+                                        config: CsrfConfig)
+                                        -> Result<Box<dyn CsrfProtection>, CsrfError> {
+    match name {
+        #[cfg(feature = "aes-gcm-backend")]
+        "aes-gcm" => Ok(Box::new(AesGcmCsrfProtection::from_key(key).with_config(config))),
+        #[cfg(feature = "chacha20-backend")]
+        "chacha20" => Ok(Box::new(ChaCha20Poly1305CsrfProtection::from_key(key).with_config(config))),
+        #[cfg(feature = "hmac-backend")]
+        "hmac" => Ok(Box::new(HmacCsrfProtection::from_key(key).with_config(config))),
+        _ => {
+            warn!("Unknown CSRF protection name: {:?}", name);
+            Err(CsrfError::UnknownAlgorithm)
+        },
+    }
+}
+
+/// Like `protection_from_name`, but derive the key from a password using the backend's KDF.
+///
+/// # Panics
+/// This function may panic if the underlying crypto library fails catastrophically.
+#[cfg_attr(not(any(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend")),
+           allow(unused_variables))]
+pub fn protection_from_name_password(name: &str,
+                                     password: &[u8])
+                                     -> Result<Box<dyn CsrfProtection>, CsrfError> {
+    match name {
+        #[cfg(feature = "aes-gcm-backend")]
+        "aes-gcm" => Ok(Box::new(AesGcmCsrfProtection::from_password(password))),
+        #[cfg(feature = "chacha20-backend")]
+        "chacha20" => Ok(Box::new(ChaCha20Poly1305CsrfProtection::from_password(password))),
+        #[cfg(feature = "hmac-backend")]
+        "hmac" => Ok(Box::new(HmacCsrfProtection::from_password(password))),
+        _ => {
+            warn!("Unknown CSRF protection name: {:?}", name);
+            Err(CsrfError::UnknownAlgorithm)
+        },
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+mod tests {
+    use config::CsrfConfig;
+    use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfError, CsrfProtection,
+               HmacCsrfProtection};
+    use registry::{protection_from_name, protection_from_name_password, protection_from_name_with_config};
+    use test_util::KEY_32;
+
+    /// Check that the named protection parses a pair issued by `expected`, proving that the
+    /// factory built the right backend with the right key.
+    fn assert_builds<P: CsrfProtection>(name: &str, expected: P) {
+        let protect = protection_from_name(name, KEY_32).expect("name not known");
+        assert_eq!(protect.algorithm().map(|algorithm| algorithm.name()), Some(name));
+        let (token, cookie) = expected.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert!(protect.verify_token_pair(&token, &cookie));
+    }
+
+    #[test]
+    fn aes_gcm() {
+        assert_builds("aes-gcm", AesGcmCsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn chacha20() {
+        assert_builds("chacha20", ChaCha20Poly1305CsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn hmac() {
+        assert_builds("hmac", HmacCsrfProtection::from_key(KEY_32));
+    }
+
+    #[test]
+    fn password() {
+        let protect = protection_from_name_password("hmac", b"hunter2").expect("name not known");
+        let expected = HmacCsrfProtection::from_password(b"hunter2");
+        let token = expected.generate_token(&[7; 64]).expect("couldn't generate token");
+        assert!(protect.parse_token(token.value()).is_ok());
+    }
+
+    #[test]
+    fn with_config() {
+        for name in &["aes-gcm", "chacha20", "hmac"] {
+            let protect = protection_from_name_with_config(name, KEY_32, CsrfConfig::new().with_max_ttl(60))
+                .expect("name not known");
+            assert_eq!(protect.generate_token_pair(None, 300).err(), Some(CsrfError::InvalidTtl));
+            assert!(protect.generate_token_pair(None, 60).is_ok());
+        }
+        assert_eq!(protection_from_name_with_config("rot13", KEY_32, CsrfConfig::new()).err(),
+                   Some(CsrfError::UnknownAlgorithm));
+    }
+
+    #[test]
+    fn unknown_name() {
+        assert_eq!(protection_from_name("rot13", KEY_32).err(), Some(CsrfError::UnknownAlgorithm));
+        assert_eq!(protection_from_name("AES-GCM", KEY_32).err(), Some(CsrfError::UnknownAlgorithm));
+        assert_eq!(protection_from_name_password("rot13", b"hunter2").err(),
+                   Some(CsrfError::UnknownAlgorithm));
+    }
+}
@@ -0,0 +1,199 @@
+//! Module containing replay detection for one-time-use tokens
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use core::UnencryptedCsrfToken;
+
+
+/// Records which tokens have already been used, so that a token can be rejected the second time
+/// it is verified. See `CsrfProtection::parse_and_verify_once`.
+pub trait ReplayGuard: Send + Sync {
+    /// Given the ID of a token that has just been verified, the expiry of its cookie, and the time
+    /// it was verified at, return `true` and record the ID if it has not been seen before, or
+    /// `false` if it has.
+    ///
+    /// An ID only needs to be remembered until `expires`, since the token can not be verified
+    /// after its cookie has expired. `now` is the protection's `current_time`, so that a guard
+    /// follows the same clock as the expiry checks.
+    fn check_and_consume(&self, token_id: &[u8], expires: i64, now: i64) -> bool;
+}
+
+
+/// A `ReplayGuard` that keeps used token IDs in memory, forgetting each one once its cookie has
+/// expired.
+///
+/// The IDs are only known to the process that holds the guard, so deployments with several
+/// processes or hosts need a shared `ReplayGuard` instead.
+#[derive(Debug, Default)]
+pub struct InMemoryReplayGuard {
+    seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl InMemoryReplayGuard {
+    /// Create a new, empty guard.
+    pub fn new() -> Self {
+        InMemoryReplayGuard::default()
+    }
+
+    /// Return how many token IDs are currently remembered.
+    pub fn len(&self) -> usize {
+        self.seen.lock().map(|seen| seen.len()).unwrap_or(0)
+    }
+
+    /// Return whether no token IDs are currently remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ReplayGuard for InMemoryReplayGuard {
+    fn check_and_consume(&self, token_id: &[u8], expires: i64, now: i64) -> bool {
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(_) => {
+                warn!("Replay guard lock was poisoned");
+                return false;
+            },
+        };
+
+        seen.retain(|_, expires| *expires > now);
+
+        if seen.contains_key(token_id) {
+            info!("CSRF token was replayed");
+            return false;
+        }
+
+        seen.insert(token_id.to_vec(), expires);
+        true
+    }
+}
+
+
+/// A key for caches of tokens, such as a `HashMap` behind a custom `ReplayGuard`, built from the
+/// token's `fingerprint` rather than its value.
+///
+/// It does not expose the secret: it holds only the 16 byte fingerprint, which can not be turned
+/// back into the token value, so hashing, comparing or logging it leaks nothing about the value,
+/// and it is a quarter of the size. Two tokens have equal keys exactly when they have the same
+/// value, however many times they were reissued.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TokenKey([u8; 16]);
+
+impl TokenKey {
+    /// Return the key of `token`.
+    pub fn new(token: &UnencryptedCsrfToken) -> Self {
+        TokenKey(token.fingerprint())
+    }
+
+    /// Retrieve the fingerprint that the key is made of.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a UnencryptedCsrfToken> for TokenKey {
+    fn from(token: &'a UnencryptedCsrfToken) -> Self {
+        TokenKey::new(token)
+    }
+}
+
+
+#[cfg(all(test, feature = "hmac-backend"))]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+    use replay::{InMemoryReplayGuard, ReplayGuard, TokenKey};
+    use test_util::{MockClock, KEY_32};
+
+    #[test]
+    fn second_verification_fails() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let guard = InMemoryReplayGuard::new();
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard), Ok(()));
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard),
+                   Err(CsrfError::TokenReplayed));
+    }
+
+    #[test]
+    fn other_tokens_still_verify() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let guard = InMemoryReplayGuard::new();
+        for _ in 0..3 {
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard), Ok(()));
+        }
+        assert_eq!(guard.len(), 3);
+    }
+
+    #[test]
+    fn failed_verification_is_not_consumed() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let guard = InMemoryReplayGuard::new();
+        let (token, _) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let (_, other_cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert_eq!(protect.parse_and_verify_once(token.value(), other_cookie.value(), &guard),
+                   Err(CsrfError::TokenMismatch));
+        assert!(guard.is_empty());
+    }
+
+    #[test]
+    fn expired_ids_are_evicted() {
+        let guard = InMemoryReplayGuard::new();
+        let now = 1_000_000;
+        assert!(guard.check_and_consume(b"expired", now - 1, now));
+        assert!(guard.check_and_consume(b"live", now + 300, now));
+        assert_eq!(guard.len(), 1);
+        assert!(!guard.check_and_consume(b"live", now + 300, now));
+        assert!(guard.check_and_consume(b"live", now + 300, now + 300));
+    }
+
+    #[test]
+    fn eviction_follows_protection_clock() {
+        // the system time is long past these cookies' expiry, so they are only remembered if the
+        // guard is given the time of the protection's clock
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_clock(MockClock::new(Some(1_000_000)));
+        let guard = InMemoryReplayGuard::new();
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard), Ok(()));
+        assert_eq!(guard.len(), 1);
+        assert_eq!(protect.parse_and_verify_once(token.value(), cookie.value(), &guard),
+                   Err(CsrfError::TokenReplayed));
+    }
+
+    #[test]
+    fn token_keys_in_hash_map() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let mut uses = HashMap::new();
+        let mut tokens = Vec::new();
+        for _ in 0..3 {
+            let (token, _) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            assert_eq!(uses.insert(TokenKey::new(&token), 1), None);
+            tokens.push(token);
+        }
+        assert_eq!(uses.len(), 3);
+
+        // a reissued token has the same value, so it finds the same entry
+        let reissued = protect.generate_token(tokens[1].nonce_bytes()).expect("couldn't generate token");
+        let reissued = protect.parse_token(reissued.value()).expect("token not parsed");
+        *uses.get_mut(&TokenKey::from(&reissued)).expect("token not found") += 1;
+        assert_eq!(uses[&TokenKey::new(&tokens[1])], 2);
+        assert_eq!(uses[&TokenKey::new(&tokens[0])], 1);
+
+        let ordered: BTreeSet<_> = tokens.iter().map(TokenKey::from).collect();
+        assert_eq!(ordered.len(), 3);
+        assert_eq!(TokenKey::new(&tokens[2]).as_bytes(), &tokens[2].fingerprint());
+        assert!(!format!("{:?}", TokenKey::new(&tokens[2])).contains(&format!("{:?}", tokens[2].value())));
+    }
+}
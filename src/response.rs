@@ -0,0 +1,105 @@
+//! Module containing response types for APIs that hand CSRF tokens to JavaScript clients
+
+use serde::Serialize;
+
+use core::{CsrfCookie, CsrfToken, CSRF_HEADER};
+use encoding::TokenEncoding;
+
+
+/// A JSON friendly description of a CSRF token, for single page apps that fetch their token from
+/// an API endpoint rather than reading it from a form field.
+///
+/// Serializes as `{"token": "<base64 token>", "header": "X-CSRF-Token"}`, telling the client both
+/// the token and the header it should be sent back in. `new_encoded` uses another encoding.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize)]
+pub struct TokenResponse {
+    token: String,
+    header: String,
+}
+
+impl TokenResponse {
+    /// Create a new response for the given token, using `CSRF_HEADER` as the header name.
+    pub fn new(token: &CsrfToken) -> Self {
+        TokenResponse::new_encoded(token, TokenEncoding::Base64)
+    }
+
+    /// Given a token and cookie pair, create a new response for the token and return it with the
+    /// base64 encoded cookie value that must be set (as `CSRF_COOKIE_NAME`) on the same response.
+    pub fn from_pair(token: &CsrfToken, cookie: &CsrfCookie) -> (Self, String) {
+        (TokenResponse::new(token), cookie.b64_string())
+    }
+
+    /// Like `new`, but with the token in `encoding` rather than base64. This should be the
+    /// `encoding` of the protection that verifies the token.
+    pub fn new_encoded(token: &CsrfToken, encoding: TokenEncoding) -> Self {
+        TokenResponse {
+            token: token.encoded(encoding),
+            header: CSRF_HEADER.to_string(),
+        }
+    }
+
+    /// Like `from_pair`, but with the token and cookie in `encoding` rather than base64.
+    pub fn from_pair_encoded(token: &CsrfToken, cookie: &CsrfCookie, encoding: TokenEncoding) -> (Self, String) {
+        (TokenResponse::new_encoded(token, encoding), cookie.encoded(encoding))
+    }
+
+    /// Use the given header name instead of `CSRF_HEADER`.
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.header = header.to_string();
+        self
+    }
+
+    /// Retrieve the encoded token.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Retrieve the name of the header the client should send the token in.
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use core::{CsrfCookie, CsrfToken};
+    use encoding::TokenEncoding;
+    use response::TokenResponse;
+
+    #[test]
+    fn serializes_to_expected_shape() {
+        let token = CsrfToken::new(b"csrf token bytes".to_vec());
+        let json = serde_json::to_string(&TokenResponse::new(&token)).expect("not serialized");
+        assert_eq!(json, r#"{"token":"Y3NyZiB0b2tlbiBieXRlcw==","header":"X-CSRF-Token"}"#);
+    }
+
+    #[test]
+    fn custom_header() {
+        let token = CsrfToken::new(b"csrf token bytes".to_vec());
+        let response = TokenResponse::new(&token).with_header("X-XSRF-TOKEN");
+        let json = serde_json::to_string(&response).expect("not serialized");
+        assert_eq!(json, r#"{"token":"Y3NyZiB0b2tlbiBieXRlcw==","header":"X-XSRF-TOKEN"}"#);
+    }
+
+    #[test]
+    fn from_pair_returns_cookie() {
+        let token = CsrfToken::new(b"csrf token bytes".to_vec());
+        let cookie = CsrfCookie::new(b"csrf cookie bytes".to_vec());
+        let (response, cookie_value) = TokenResponse::from_pair(&token, &cookie);
+        assert_eq!(response.token(), token.b64_string());
+        assert_eq!(cookie_value, cookie.b64_string());
+    }
+
+    #[test]
+    fn from_pair_encoded() {
+        let token = CsrfToken::new(b"csrf token bytes".to_vec());
+        let cookie = CsrfCookie::new(b"csrf cookie bytes".to_vec());
+        let (response, cookie_value) = TokenResponse::from_pair_encoded(&token, &cookie, TokenEncoding::Hex);
+        assert_eq!(response.token(), token.encoded(TokenEncoding::Hex));
+        assert_eq!(cookie_value, cookie.encoded(TokenEncoding::Hex));
+        assert_eq!(TokenResponse::new(&token), TokenResponse::new_encoded(&token, TokenEncoding::Base64));
+    }
+}
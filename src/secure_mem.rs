@@ -0,0 +1,90 @@
+//! Module containing storage for key material that is kept out of swap
+
+use std::ops::Deref;
+
+#[cfg(all(unix, feature = "secure-mem"))]
+use libc;
+
+
+/// A heap allocated 32 byte key.
+///
+/// With the `secure-mem` feature on Unix targets, the page holding the key is locked into memory
+/// with `mlock` when it is created, and the key is zeroed and unlocked when it is dropped. If the
+/// page can not be locked, for example because `RLIMIT_MEMLOCK` is too low, a warning is logged
+/// and the key is used anyway. Elsewhere, or without the feature, this is a plain box.
+///
+/// Only this copy of the key is protected. Copies made before it was created, such as the array
+/// passed to `from_key`, and keys derived from it by the crypto libraries are not.
+pub struct LockedKey {
+    key: Box<[u8; 32]>,
+}
+
+impl LockedKey {
+    /// Move the key to the heap, locking it into memory if the `secure-mem` feature is enabled.
+    pub fn new(key: [u8; 32]) -> Self {
+        let key = LockedKey { key: Box::new(key) };
+        key.lock();
+        key
+    }
+
+    #[cfg(all(unix, feature = "secure-mem"))]
+    fn lock(&self) {
+        let ptr = self.key.as_ptr() as *const libc::c_void;
+        if unsafe { libc::mlock(ptr, self.key.len()) } != 0 {
+            warn!("Failed to lock CSRF key material into memory");
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "secure-mem")))]
+    fn lock(&self) {}
+}
+
+impl Deref for LockedKey {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+#[cfg(all(unix, feature = "secure-mem"))]
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        for byte in self.key.iter_mut() {
+            // volatile so that the zeroing is not optimized away as a dead store
+            unsafe { ::std::ptr::write_volatile(byte, 0) };
+        }
+
+        let ptr = self.key.as_ptr() as *const libc::c_void;
+        unsafe { libc::munlock(ptr, self.key.len()) };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use secure_mem::LockedKey;
+    use test_util::KEY_32;
+
+    #[test]
+    fn derefs_to_key() {
+        assert_eq!(*LockedKey::new(KEY_32), KEY_32);
+    }
+
+    #[cfg(all(unix, feature = "secure-mem", feature = "hmac-backend", feature = "aes-gcm-backend"))]
+    #[test]
+    fn locked_protection_verifies() {
+        use core::{AesGcmCsrfProtection, CsrfProtection, HmacCsrfProtection};
+
+        fn verify<P: CsrfProtection>(protect: P) {
+            let (token, cookie) = protect.generate_token_pair(None, 300)
+                .expect("couldn't generate token/cookie pair");
+            let token = protect.parse_token(token.value()).expect("token not parsed");
+            let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+            assert!(protect.verify_token_pair(&token, &cookie));
+        }
+
+        verify(HmacCsrfProtection::from_key(KEY_32));
+        verify(AesGcmCsrfProtection::from_key(KEY_32));
+    }
+}
@@ -0,0 +1,97 @@
+//! Module containing the plug-in point for generating token values
+
+use core::CsrfError;
+
+
+/// Produces the 64 byte values that new token and cookie pairs share, in place of the random
+/// number generator. See `with_token_value_source` on the backends.
+///
+/// This is for deployments that must draw token values from a hardware security module, or that
+/// number them with a counter so that each one can be traced in an audit log. A source must never
+/// return the same value twice, since a token verifies against every cookie that shares its value.
+/// The HMAC backend sends values in the clear, while the AEAD backends keep them confidential.
+pub trait TokenValueSource: Send + Sync {
+    /// Fill `buf` with the next token value, or return an error, such as
+    /// `CsrfError::EntropyUnavailable`, which is passed on to the caller of `generate_token_pair`.
+    fn next_value(&self, buf: &mut [u8; 64]) -> Result<(), CsrfError>;
+}
+
+
+#[cfg(all(test, feature = "hmac-backend"))]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use core::{CsrfError, CsrfProtection, HmacCsrfProtection};
+    use fallback::FallbackCsrfProtection;
+    use source::TokenValueSource;
+    use test_util::KEY_32;
+
+    /// Numbers token values with a little endian counter in the first 8 bytes.
+    struct CounterSource(AtomicU64);
+
+    impl TokenValueSource for CounterSource {
+        fn next_value(&self, buf: &mut [u8; 64]) -> Result<(), CsrfError> {
+            *buf = [0; 64];
+            buf[..8].copy_from_slice(&self.0.fetch_add(1, Ordering::SeqCst).to_le_bytes());
+            Ok(())
+        }
+    }
+
+    struct FailingSource;
+
+    impl TokenValueSource for FailingSource {
+        fn next_value(&self, _buf: &mut [u8; 64]) -> Result<(), CsrfError> {
+            Err(CsrfError::EntropyUnavailable)
+        }
+    }
+
+    fn counter_value(counter: u64) -> [u8; 64] {
+        let mut value = [0; 64];
+        value[..8].copy_from_slice(&counter.to_le_bytes());
+        value
+    }
+
+    fn assert_next_value<P: CsrfProtection + ?Sized>(protect: &P, counter: u64) {
+        let (token, cookie) = protect.generate_token_pair(None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        let cookie = protect.parse_cookie(cookie.value()).expect("cookie not parsed");
+        assert_eq!(token.value(), &counter_value(counter)[..]);
+        assert_eq!(cookie.value(), &counter_value(counter)[..]);
+        assert!(protect.verify_token_pair(&token, &cookie));
+    }
+
+    #[test]
+    fn values_from_source() {
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_token_value_source(CounterSource(AtomicU64::new(0)));
+        assert_next_value(&protect, 0);
+        assert_next_value(&protect, 1);
+        assert_next_value(&protect.clone(), 2);
+        assert_next_value(&protect.derive_subkey(b"context"), 3);
+        assert_next_value(&*protect.clone_box(), 4);
+        assert_next_value(&FallbackCsrfProtection::new(protect.clone()), 5);
+
+        let (token, _) = protect.generate_token_pair(Some(&[7; 64]), 300)
+            .expect("couldn't generate token/cookie pair");
+        let token = protect.parse_token(token.value()).expect("token not parsed");
+        assert_eq!(token.value(), &[7; 64][..]);
+        assert_next_value(&protect, 6);
+    }
+
+    #[test]
+    fn source_error_is_returned() {
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_token_value_source(FailingSource);
+        assert_eq!(protect.generate_token_pair(None, 300).err(), Some(CsrfError::EntropyUnavailable));
+        assert!(protect.generate_token_pair(Some(&[7; 64]), 300).is_ok());
+    }
+
+    #[test]
+    fn random_by_default() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        assert!(protect.token_value_source().is_none());
+        let (first, _) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        let (second, _) = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert_ne!(protect.parse_token(first.value()).expect("token not parsed").value(),
+                   protect.parse_token(second.value()).expect("token not parsed").value());
+    }
+}
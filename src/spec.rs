@@ -0,0 +1,254 @@
+//! Module containing a declarative description of a protection's settings, for config files
+
+use serde::Deserialize;
+
+use config::CsrfConfig;
+use cookie::{CookieBuilder, SameSite};
+use core::{CsrfError, CsrfProtection};
+use encoding::TokenEncoding;
+use form::CsrfNames;
+use registry::{protection_from_name_with_config, Algorithm};
+
+
+/// The settings of a CSRF protection that are not secret, deserialized from an application's
+/// config file: the backend, the cookie TTL and other `CsrfConfig` policy, the cookie's attributes
+/// and the names that the token and cookie are sent under.
+///
+/// The key is never part of the spec. It is passed to `protection` separately, from wherever the
+/// application keeps its secrets, so that the config file can be checked in and shared. Unknown
+/// fields are rejected, so a `key` that was put in the file by mistake fails to deserialize
+/// instead of being silently ignored.
+///
+/// ```
+/// extern crate csrf;
+/// extern crate serde_json;
+///
+/// use csrf::CsrfConfigSpec;
+///
+/// fn main() {
+///     let spec: CsrfConfigSpec = serde_json::from_str(r#"{
+///         "algorithm": "hmac",
+///         "ttl": 3600,
+///         "cookie": { "path": "/", "secure": true, "same_site": "Strict" },
+///         "names": { "form_field": "_csrf" }
+///     }"#).expect("spec not deserialized");
+///
+///     let protect = spec.protection(*b"01234567012345670123456701234567").expect("protection not built");
+///     let pair = protect.generate_token_pair(None, spec.ttl()).expect("couldn't generate token/cookie pair");
+///     let (html, cookie) = spec.names().render_html_and_cookie(&pair, |cookie| spec.cookie_options(cookie))
+///         .expect("conflicting cookie attributes");
+///
+///     assert!(html.contains(r#"name="_csrf""#));
+///     assert!(cookie.ends_with("; Max-Age=3600; Path=/; Secure; HttpOnly; SameSite=Strict"));
+/// }
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsrfConfigSpec {
+    algorithm: String,
+    ttl: i64,
+    #[serde(default)]
+    min_ttl: Option<i64>,
+    #[serde(default)]
+    max_ttl: Option<i64>,
+    #[serde(default)]
+    compact: bool,
+    #[serde(default)]
+    require_secure_transport: bool,
+    #[serde(default)]
+    allowed_algorithms: Option<Vec<Algorithm>>,
+    #[serde(default)]
+    encoding: TokenEncoding,
+    #[serde(default)]
+    cookie: CookieSpec,
+    #[serde(default)]
+    names: NamesSpec,
+}
+
+/// The attributes of the cookie, as set on a `CookieBuilder`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CookieSpec {
+    domain: Option<String>,
+    path: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+    host_prefix: bool,
+}
+
+impl Default for CookieSpec {
+    fn default() -> Self {
+        CookieSpec {
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: true,
+            same_site: None,
+            partitioned: false,
+            host_prefix: false,
+        }
+    }
+}
+
+/// The names in `CsrfNames`, each defaulting to the crate's own.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NamesSpec {
+    form_field: Option<String>,
+    cookie: Option<String>,
+}
+
+impl CsrfConfigSpec {
+    /// Retrieve the name of the backend, as accepted by `protection_from_name`.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Retrieve the TTL that cookies should be generated with, in seconds.
+    pub fn ttl(&self) -> i64 {
+        self.ttl
+    }
+
+    /// Return the `CsrfConfig` that the spec describes.
+    pub fn config(&self) -> CsrfConfig {
+        let mut config = CsrfConfig::new()
+            .with_compact(self.compact)
+            .with_require_secure_transport(self.require_secure_transport)
+            .with_encoding(self.encoding);
+        if let Some(min_ttl) = self.min_ttl {
+            config = config.with_min_ttl(min_ttl);
+        }
+        if let Some(max_ttl) = self.max_ttl {
+            config = config.with_max_ttl(max_ttl);
+        }
+        if let Some(allowed_algorithms) = &self.allowed_algorithms {
+            config = config.with_allowed_algorithms(allowed_algorithms);
+        }
+        config
+    }
+
+    /// Given the key, build the protection that the spec describes.
+    ///
+    /// Returns `CsrfError::UnknownAlgorithm` if the backend is not known, as by
+    /// `protection_from_name`, and `CsrfError::InvalidTtl` if `ttl` is outside `min_ttl` and
+    /// `max_ttl`, so that a bad config file is caught at startup rather than on the first request.
+    pub fn protection(&self, key: [u8; 32]) -> Result<Box<dyn CsrfProtection>, CsrfError> {
+        let config = self.config();
+        config.check_ttl(self.ttl)?;
+        protection_from_name_with_config(&self.algorithm, key, config)
+    }
+
+    /// Return the names that the spec describes, rendering in its encoding.
+    pub fn names(&self) -> CsrfNames {
+        let mut names = CsrfNames::new().with_encoding(self.encoding);
+        if let Some(form_field) = &self.names.form_field {
+            names = names.with_form_field(form_field);
+        }
+        if let Some(cookie) = &self.names.cookie {
+            names = names.with_cookie(cookie);
+        }
+        names
+    }
+
+    /// Set the cookie attributes and encoding that the spec describes on `cookie`, with a
+    /// `Max-Age` of `ttl`.
+    /// This can be passed to `CsrfNames::render_html_and_cookie` as its `cookie_opts`.
+    pub fn cookie_options(&self, cookie: CookieBuilder) -> CookieBuilder {
+        let spec = &self.cookie;
+        let mut cookie = cookie.max_age(self.ttl)
+            .encoding(self.encoding)
+            .secure(spec.secure)
+            .http_only(spec.http_only)
+            .partitioned(spec.partitioned)
+            .host_prefix(spec.host_prefix);
+        if let Some(domain) = &spec.domain {
+            cookie = cookie.domain(domain);
+        }
+        if let Some(path) = &spec.path {
+            cookie = cookie.path(path);
+        }
+        if let Some(same_site) = spec.same_site {
+            cookie = cookie.same_site(same_site);
+        }
+        cookie
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use config::CsrfConfig;
+    use core::CsrfError;
+    use form::CsrfNames;
+    use spec::CsrfConfigSpec;
+    use test_util::KEY_32;
+
+    fn spec(json: &str) -> CsrfConfigSpec {
+        serde_json::from_str(json).expect("spec not deserialized")
+    }
+
+    #[test]
+    fn defaults() {
+        let spec = spec(r#"{"algorithm": "aes-gcm", "ttl": 600}"#);
+        assert_eq!(spec.algorithm(), "aes-gcm");
+        assert_eq!(spec.ttl(), 600);
+        assert_eq!(spec.config(), CsrfConfig::new());
+        assert_eq!(spec.names(), CsrfNames::new());
+    }
+
+    #[cfg(feature = "aes-gcm-backend")]
+    #[test]
+    fn builds_working_protection() {
+        use encoding::TokenEncoding;
+        use registry::Algorithm;
+        use wire::COMPACT_WIRE_VERSION;
+
+        let spec = spec(r#"{
+            "algorithm": "aes-gcm",
+            "ttl": 600,
+            "max_ttl": 3600,
+            "compact": true,
+            "allowed_algorithms": ["aes-gcm"],
+            "encoding": "hex",
+            "cookie": {"path": "/", "same_site": "Lax", "host_prefix": true},
+            "names": {"form_field": "_csrf", "cookie": "csrf-session"}
+        }"#);
+        let protect = spec.protection(KEY_32).expect("protection not built");
+        let pair = protect.generate_token_pair(None, spec.ttl()).expect("couldn't generate token/cookie pair");
+        let (token, cookie) = &pair;
+        assert_eq!(protect.verify_raw(token.value(), cookie.value()), Ok(()));
+        assert_eq!(token.value()[0], COMPACT_WIRE_VERSION);
+        assert_eq!(protect.generate_token_pair(None, 7200).err(), Some(CsrfError::InvalidTtl));
+        assert_eq!(spec.config().allowed_algorithms(), Some(&[Algorithm::AesGcm][..]));
+        assert_eq!(spec.config().encoding(), TokenEncoding::Hex);
+        assert_eq!(protect.verify_encoded(&token.encoded(TokenEncoding::Hex), &cookie.encoded(TokenEncoding::Hex)),
+                   Ok(()));
+
+        let (html, header) = spec.names().render_html_and_cookie(&pair, |cookie| spec.cookie_options(cookie))
+            .expect("conflicting cookie attributes");
+        assert_eq!(html, format!(r#"<input type="hidden" name="_csrf" value="{}">"#, token.encoded(TokenEncoding::Hex)));
+        assert_eq!(header,
+                   format!("__Host-csrf-session={}; Max-Age=600; Path=/; Secure; HttpOnly; SameSite=Lax",
+                           cookie.encoded(TokenEncoding::Hex)));
+    }
+
+    #[test]
+    fn invalid_specs() {
+        for json in &[r#"{"algorithm": "hmac"}"#,
+                      r#"{"algorithm": "hmac", "ttl": 600, "key": "MDEyMzQ1Njc="}"#,
+                      r#"{"algorithm": "hmac", "ttl": 600, "cookie": {"same_site": "strict"}}"#,
+                      r#"{"algorithm": "hmac", "ttl": 600, "allowed_algorithms": ["HmacSha256"]}"#,
+                      r#"{"algorithm": "hmac", "ttl": 600, "encoding": "base32"}"#] {
+            assert!(serde_json::from_str::<CsrfConfigSpec>(json).is_err(), "{}", json);
+        }
+
+        assert_eq!(spec(r#"{"algorithm": "rot13", "ttl": 600}"#).protection(KEY_32).err(),
+                   Some(CsrfError::UnknownAlgorithm));
+        assert_eq!(spec(r#"{"algorithm": "hmac", "ttl": 600, "max_ttl": 60}"#).protection(KEY_32).err(),
+                   Some(CsrfError::InvalidTtl));
+    }
+}
@@ -0,0 +1,237 @@
+//! Module containing a cookie-less protection for clients that only send a token in a header
+
+use core::{check_issued_at, read_i64, token_value_or_random, CsrfError, CsrfProtection, CsrfToken,
+           UnencryptedCsrfToken};
+use wire::{STATELESS_HEADER_LEN, STATELESS_WIRE_VERSION};
+
+const STATELESS_LABEL: &[u8] = b"rust-csrf-stateless:";
+
+
+/// Issues and verifies tokens that carry their own expiry, for single-page apps that send the
+/// token in a header and never use cookies.
+///
+/// Each token is the backend's token, authenticated or encrypted as usual, and bound to a header
+/// with the issue and expiry times, as described in the `wire` module. Verification needs only the
+/// token and the key, so no cookie is issued.
+///
+/// This gives weaker guarantees than the double-submit cookie of `CsrfProtection`:
+///
+/// * A token is not tied to the browser it was issued to. The cookie normally ensures that a token
+///   only verifies on requests from that browser. A stateless token is a bearer credential that
+///   verifies for anyone who holds it until it expires, so a token leaked through a log, a
+///   `Referer` header or a script injection can be used from any session. The application should
+///   play the cookie's part itself: store the token value returned by `issue_token` with the
+///   user's server-side state, for example as a `TokenKey`, and compare it with the value
+///   returned by `verify_token`.
+/// * A token can be replayed as often as it is sent until it expires, unless the application
+///   records used tokens, for example in a `ReplayCache`.
+/// * A single token can not be revoked. Keep TTLs short, and a `max_age` shorter still where
+///   possible. Removing a key revokes every token issued under it.
+///
+/// Keys are rotated by wrapping a `FallbackCsrfProtection` whose primary holds the new key and
+/// whose alternates hold the old ones. New tokens are issued under the new key, tokens issued
+/// under an old key keep verifying, and the old key can be dropped once the longest TTL has
+/// passed.
+///
+/// Tokens from `CsrfProtection::generate_token_pair` are not accepted, and stateless tokens are
+/// not accepted by the backend's own `parse_token`, even under the same key.
+#[derive(Clone)]
+pub struct StatelessTokenProtection<P: CsrfProtection> {
+    inner: P,
+}
+
+impl<P: CsrfProtection> StatelessTokenProtection<P> {
+    /// Given a backend, return a protection that issues stateless tokens with it.
+    pub fn new(inner: P) -> Self {
+        StatelessTokenProtection { inner }
+    }
+
+    /// Retrieve the backend.
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Issue a token that expires `ttl_seconds` from now, and return it along with its token
+    /// value, to be kept in the user's server-side state.
+    ///
+    /// Returns `CsrfError::InvalidTtl` if the TTL is outside the backend's `ttl_bounds`.
+    pub fn issue_token(&self, ttl_seconds: i64) -> Result<(CsrfToken, [u8; 64]), CsrfError> {
+        let (min_ttl, max_ttl) = self.inner.ttl_bounds();
+        if min_ttl.is_some_and(|min| ttl_seconds < min) || max_ttl.is_some_and(|max| ttl_seconds > max) {
+            warn!("CSRF token TTL {} is outside the bounds {:?}..={:?}", ttl_seconds, min_ttl, max_ttl);
+            return Err(CsrfError::InvalidTtl);
+        }
+
+        let now = self.inner.current_time()?;
+        let mut header = [0; STATELESS_HEADER_LEN];
+        header[0] = STATELESS_WIRE_VERSION;
+        header[1..9].copy_from_slice(&now.to_le_bytes());
+        header[9..17].copy_from_slice(&now.saturating_add(ttl_seconds).to_le_bytes());
+
+        let value = token_value_or_random(&self.inner, None)?;
+        let token = self.inner.generate_bound_token(&value, &binding(&header))?;
+        let mut bytes = Vec::with_capacity(STATELESS_HEADER_LEN + token.value().len());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(token.value());
+        Ok((CsrfToken::new(bytes), value))
+    }
+
+    /// Given a token in the backend's `encoding`, as by `CsrfToken::encoded` on a token from
+    /// `issue_token`, verify it and return it parsed, with its issued at time.
+    ///
+    /// Returns `CsrfError::Expired` if the token has expired or was issued more than `max_age`
+    /// seconds ago. Any other failure to decode or verify the token is an error as by
+    /// `CsrfProtection::parse_token_encoded`.
+    pub fn verify_token(&self, token: &str, max_age: i64) -> Result<UnencryptedCsrfToken, CsrfError> {
+        let token = self.inner.encoding().decode(token)?;
+        if token.len() <= STATELESS_HEADER_LEN {
+            debug!("Stateless CSRF token had length {}, too short to hold a token.", token.len());
+            return Err(CsrfError::MalformedLength);
+        }
+        let (header, inner_token) = token.split_at(STATELESS_HEADER_LEN);
+        if header[0] != STATELESS_WIRE_VERSION {
+            debug!("Stateless CSRF token had version {}, not {}.", header[0], STATELESS_WIRE_VERSION);
+            return Err(CsrfError::MalformedLength);
+        }
+
+        let parsed = self.inner.parse_bound_token(inner_token, &binding(header))?;
+        let issued_at = read_i64(header, 1)?;
+        let expires = read_i64(header, 9)?;
+
+        let now = self.inner.current_time()?;
+        check_issued_at(issued_at, now)?;
+        if expires <= now || now.saturating_sub(issued_at) > max_age {
+            debug!("Stateless CSRF token {} expired. Issued at: {}, Expiration: {}, Maximum age: {}, Current time: {}",
+                   parsed.short_debug(),
+                   issued_at,
+                   expires,
+                   max_age,
+                   now);
+            return Err(CsrfError::Expired);
+        }
+        Ok(parsed.with_issued_at(issued_at))
+    }
+}
+
+/// Return the binding data that ties a backend token to its header.
+fn binding(header: &[u8]) -> Vec<u8> {
+    let mut binding = Vec::with_capacity(STATELESS_LABEL.len() + header.len());
+    binding.extend_from_slice(STATELESS_LABEL);
+    binding.extend_from_slice(header);
+    binding
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend"))]
+mod tests {
+    use config::CsrfConfig;
+    use core::{AesGcmCsrfProtection, CsrfError, CsrfProtection, CsrfToken, HmacCsrfProtection};
+    use encoding::TokenEncoding;
+    use fallback::FallbackCsrfProtection;
+    use stateless::StatelessTokenProtection;
+    use test_util::{MockClock, KEY_32, OTHER_KEY_32};
+    use wire::STATELESS_WIRE_VERSION;
+
+    fn at(now: i64) -> StatelessTokenProtection<HmacCsrfProtection> {
+        StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32).with_clock(MockClock::new(Some(now))))
+    }
+
+    fn assert_round_trip<P: CsrfProtection>(protect: &StatelessTokenProtection<P>) {
+        let (token, value) = protect.issue_token(300).expect("token not issued");
+        assert_eq!(token.value()[0], STATELESS_WIRE_VERSION);
+        let parsed = protect.verify_token(&token.b64_string(), 300).expect("token not verified");
+        assert_eq!(parsed.value(), &value[..]);
+        assert!(parsed.issued_at().is_some());
+
+        let (other, other_value) = protect.issue_token(300).expect("token not issued");
+        assert_ne!(other.value(), token.value());
+        assert_ne!(other_value, value);
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        assert_round_trip(&StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32)));
+        assert_round_trip(&StatelessTokenProtection::new(AesGcmCsrfProtection::from_key(KEY_32)));
+    }
+
+    #[test]
+    fn expiry() {
+        let (token, _) = at(1_000_000).issue_token(300).expect("token not issued");
+        let token = token.b64_string();
+        assert!(at(1_000_000).verify_token(&token, 300).is_ok());
+        assert!(at(1_000_299).verify_token(&token, 300).is_ok());
+        assert_eq!(at(1_000_300).verify_token(&token, 3600).err(), Some(CsrfError::Expired));
+        assert_eq!(at(1_000_100).verify_token(&token, 60).err(), Some(CsrfError::Expired));
+        assert_eq!(at(1_000_100).verify_token(&token, 100).map(|parsed| parsed.issued_at()),
+                   Ok(Some(1_000_000)));
+        assert_eq!(at(900_000).verify_token(&token, 300).err(), Some(CsrfError::ValidationFailure));
+    }
+
+    #[test]
+    fn ttl_bounds() {
+        let protect = StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32)
+            .with_config(CsrfConfig::new().with_max_ttl(600)));
+        assert!(protect.issue_token(600).is_ok());
+        assert_eq!(protect.issue_token(601).err(), Some(CsrfError::InvalidTtl));
+    }
+
+    #[test]
+    fn tampered() {
+        let protect = StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32));
+        let (token, _) = protect.issue_token(300).expect("token not issued");
+        for i in 0..token.value().len() {
+            let mut tampered = token.value().to_vec();
+            tampered[i] ^= 0x01;
+            let tampered = CsrfToken::new(tampered).b64_string();
+            assert!(protect.verify_token(&tampered, 300).is_err(), "byte {} not authenticated", i);
+        }
+
+        let truncated = CsrfToken::new(token.value()[..17].to_vec()).b64_string();
+        assert_eq!(protect.verify_token(&truncated, 300).err(), Some(CsrfError::MalformedLength));
+        assert_eq!(protect.verify_token("not base64!", 300).err(), Some(CsrfError::DecodeError));
+
+        let mut other_version = token.value().to_vec();
+        other_version[0] = STATELESS_WIRE_VERSION + 1;
+        assert_eq!(protect.verify_token(&CsrfToken::new(other_version).b64_string(), 300).err(),
+                   Some(CsrfError::MalformedLength));
+
+        let other_key = StatelessTokenProtection::new(HmacCsrfProtection::from_key(OTHER_KEY_32));
+        assert!(other_key.verify_token(&token.b64_string(), 300).is_err());
+    }
+
+    #[test]
+    fn backend_encoding() {
+        let protect = StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32)
+            .with_config(CsrfConfig::new().with_encoding(TokenEncoding::Hex)));
+        let (token, value) = protect.issue_token(300).expect("token not issued");
+        let parsed = protect.verify_token(&token.encoded(TokenEncoding::Hex), 300).expect("token not verified");
+        assert_eq!(parsed.value(), &value[..]);
+        assert_eq!(protect.verify_token(&token.b64_string(), 300).err(), Some(CsrfError::DecodeError));
+    }
+
+    #[test]
+    fn not_interchangeable_with_double_submit() {
+        let protect = StatelessTokenProtection::new(HmacCsrfProtection::from_key(KEY_32));
+        let (stateless, _) = protect.issue_token(300).expect("token not issued");
+        assert!(protect.inner().parse_token(stateless.value()).is_err());
+        assert!(protect.inner().parse_token(&stateless.value()[17..]).is_err());
+        assert!(protect.inner().parse_cookie(stateless.value()).is_err());
+
+        let (token, cookie) = protect.inner().generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+        assert!(protect.verify_token(&token.b64_string(), 300).is_err());
+        assert!(protect.verify_token(&cookie.b64_string(), 300).is_err());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let old = StatelessTokenProtection::new(AesGcmCsrfProtection::from_key(KEY_32));
+        let (old_token, _) = old.issue_token(300).expect("token not issued");
+
+        let rotated = StatelessTokenProtection::new(FallbackCsrfProtection::new(AesGcmCsrfProtection::from_key(OTHER_KEY_32))
+            .with_alternate(AesGcmCsrfProtection::from_key(KEY_32)));
+        let (new_token, _) = rotated.issue_token(300).expect("token not issued");
+        assert!(rotated.verify_token(&old_token.b64_string(), 300).is_ok());
+        assert!(rotated.verify_token(&new_token.b64_string(), 300).is_ok());
+        assert!(old.verify_token(&new_token.b64_string(), 300).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+//! Module containing fixtures shared by the unit tests
+
+#[cfg(feature = "hmac-backend")]
+use std::sync::atomic::{AtomicI64, Ordering};
+#[cfg(feature = "hmac-backend")]
+use std::sync::Arc;
+
+#[cfg(feature = "hmac-backend")]
+use clock::Clock;
+
+/// The key that the tests construct their protections from.
+pub const KEY_32: [u8; 32] = *b"01234567012345670123456701234567";
+
+/// A key other than `KEY_32`, for tests that need a second one.
+#[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend"))]
+pub const OTHER_KEY_32: [u8; 32] = *b"76543210765432107654321076543210";
+
+
+/// A clock that tells the time it is set to, or fails if it is set to `None`. Clones share the
+/// time, so a test can move it forward after handing a clone to a protection.
+#[cfg(feature = "hmac-backend")]
+#[derive(Clone)]
+pub struct MockClock(Arc<AtomicI64>);
+
+#[cfg(feature = "hmac-backend")]
+impl MockClock {
+    /// Return a clock set to `now`.
+    pub fn new(now: Option<i64>) -> Self {
+        MockClock(Arc::new(AtomicI64::new(now.unwrap_or(i64::MIN))))
+    }
+
+    /// Set the time of this clock and its clones to `now`.
+    pub fn set(&self, now: Option<i64>) {
+        self.0.store(now.unwrap_or(i64::MIN), Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "hmac-backend")]
+impl Clock for MockClock {
+    fn now(&self) -> Option<i64> {
+        Some(self.0.load(Ordering::SeqCst)).filter(|&now| now != i64::MIN)
+    }
+}
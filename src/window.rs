@@ -0,0 +1,246 @@
+//! Module containing support for several simultaneously valid tokens per cookie
+
+use crypto::util::fixed_time_eq;
+use ring::digest::SHA512;
+use ring::hmac;
+
+use core::{CsrfCookie, CsrfError, CsrfProtection, CsrfToken, UnencryptedCsrfCookie,
+           UnencryptedCsrfToken};
+
+const WINDOW_TAG_LABEL: &[u8] = b"rust-csrf-window:";
+
+
+/// Issues tokens so that any of the `size` most recently issued tokens verifies against the
+/// latest cookie, for users with several tabs open that each received a different token.
+///
+/// Rather than storing every token value, the cookie holds a secret seed and a counter of how
+/// many tokens have been issued. Each token carries its position in the sequence and a tag
+/// derived from the seed and that position, so the cookie stays the same size however large the
+/// window is. Every time a token is issued the cookie must be replaced with the one returned
+/// alongside it, which moves the window forward.
+///
+/// The trade-off is that a leaked token stays valid until `size` newer tokens have been issued
+/// or the cookie expires, rather than until the next token is issued. Keep the window as small
+/// as the application's tab usage allows.
+///
+/// Tokens issued this way must be verified with `TokenWindow::verify`, and not with
+/// `CsrfProtection::verify_token_pair`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TokenWindow {
+    size: u64,
+}
+
+impl TokenWindow {
+    /// Create a window in which the `size` most recently issued tokens are valid.
+    ///
+    /// # Panics
+    /// This function panics if `size` is zero.
+    pub fn new(size: u64) -> Self {
+        assert!(size > 0, "token window size must be at least 1");
+        TokenWindow { size }
+    }
+
+    /// Retrieve how many recently issued tokens are valid at once.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Issue the next token in the window along with the cookie that replaces `previous`. If
+    /// there is no previous cookie, a new window is started.
+    pub fn generate_token_pair<P: CsrfProtection + ?Sized>(&self,
+                                                          protect: &P,
+                                                          previous: Option<&UnencryptedCsrfCookie>,
+                                                          ttl_seconds: i64)
+                                                          -> Result<(CsrfToken, CsrfCookie), CsrfError> {
+        let mut cookie_value = [0; 64];
+        let counter = match previous {
+            Some(previous) => {
+                let (seed, counter) = split_cookie_value(previous.value())?;
+                cookie_value[0..56].copy_from_slice(seed);
+                counter.checked_add(1).ok_or(CsrfError::InternalError)?
+            },
+            None => {
+                protect.random_bytes(&mut cookie_value[0..56])?;
+                0
+            },
+        };
+        cookie_value[56..64].copy_from_slice(&counter.to_le_bytes());
+
+        let token_value = window_token_value(&cookie_value[0..56], counter);
+        Ok((protect.generate_token(&token_value)?, protect.generate_cookie(&cookie_value, ttl_seconds)?))
+    }
+
+    /// Given a token and cookie that have been parsed, decoded, decrypted, and verified by
+    /// `protect`, return whether the token is one of the `size` most recently issued for the
+    /// cookie and the cookie has not expired, as of `protect`'s `current_time`.
+    pub fn verify<P: CsrfProtection + ?Sized>(&self,
+                                              protect: &P,
+                                              token: &UnencryptedCsrfToken,
+                                              cookie: &UnencryptedCsrfCookie)
+                                              -> bool {
+        protect.current_time().is_ok_and(|now| self.verify_as_of(token, cookie, now))
+    }
+
+    /// Like `verify`, but the cookie is checked for expiry as of `now`, in seconds, instead of
+    /// the current time.
+    pub fn verify_as_of(&self, token: &UnencryptedCsrfToken, cookie: &UnencryptedCsrfCookie, now: i64) -> bool {
+        let (seed, latest) = match split_cookie_value(cookie.value()) {
+            Ok(parts) => parts,
+            Err(_) => return false,
+        };
+
+        if token.value().len() != 64 {
+            debug!("Windowed token had the wrong length.");
+            return false;
+        }
+        let counter = read_u64(&token.value()[0..8]);
+
+        if counter > latest || latest - counter >= self.size {
+            debug!("Windowed token {} is outside the window ending at {}", counter, latest);
+            return false;
+        }
+
+        if !fixed_time_eq(&window_token_value(seed, counter), token.value()) {
+            debug!("Windowed token did not match cookie");
+            return false;
+        }
+
+        if cookie.expires() <= now {
+            debug!("Cookie expired. Expiration: {}, Current time: {}", cookie.expires(), now);
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Split a windowed cookie value into its seed and the counter of the latest issued token.
+fn split_cookie_value(value: &[u8]) -> Result<(&[u8], u64), CsrfError> {
+    if value.len() != 64 {
+        debug!("Windowed cookie value had length {}, expected 64.", value.len());
+        return Err(CsrfError::MalformedLength);
+    }
+    Ok((&value[0..56], read_u64(&value[56..64])))
+}
+
+/// Compute the token value at position `counter` of the window with the given seed.
+fn window_token_value(seed: &[u8], counter: u64) -> [u8; 64] {
+    let key = hmac::SigningKey::new(&SHA512, seed);
+    let mut context = hmac::SigningContext::with_key(&key);
+    context.update(WINDOW_TAG_LABEL);
+    context.update(&counter.to_le_bytes());
+    let tag = context.sign();
+
+    let mut value = [0; 64];
+    value[0..8].copy_from_slice(&counter.to_le_bytes());
+    value[8..64].copy_from_slice(&tag.as_ref()[0..56]);
+    value
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+
+#[cfg(all(test, feature = "hmac-backend", feature = "aes-gcm-backend"))]
+mod tests {
+    use config::CsrfConfig;
+    use core::{AesGcmCsrfProtection, CsrfCookie, CsrfProtection, CsrfToken, HmacCsrfProtection,
+               UnencryptedCsrfCookie, UnencryptedCsrfToken};
+    use test_util::{MockClock, KEY_32};
+    use window::TokenWindow;
+
+    fn parse<P: CsrfProtection>(protect: &P,
+                                (token, cookie): (CsrfToken, CsrfCookie))
+                                -> (UnencryptedCsrfToken, UnencryptedCsrfCookie) {
+        (protect.parse_token(token.value()).expect("token not parsed"),
+         protect.parse_cookie(cookie.value()).expect("cookie not parsed"))
+    }
+
+    /// Issue `count` tokens in one window, returning them and the latest cookie.
+    fn issue<P: CsrfProtection>(protect: &P,
+                                window: &TokenWindow,
+                                count: usize)
+                                -> (Vec<UnencryptedCsrfToken>, UnencryptedCsrfCookie) {
+        let mut tokens = Vec::new();
+        let mut cookie: Option<UnencryptedCsrfCookie> = None;
+        for _ in 0..count {
+            let pair = window.generate_token_pair(protect, cookie.as_ref(), 300)
+                .expect("couldn't generate token/cookie pair");
+            let (token, next) = parse(protect, pair);
+            tokens.push(token);
+            cookie = Some(next);
+        }
+        (tokens, cookie.expect("no tokens issued"))
+    }
+
+    #[test]
+    fn last_k_tokens_verify() {
+        let protect = AesGcmCsrfProtection::from_key(KEY_32);
+        let window = TokenWindow::new(3);
+        let (tokens, cookie) = issue(&protect, &window, 3);
+        for token in tokens.iter() {
+            assert!(window.verify(&protect, token, &cookie));
+        }
+    }
+
+    #[test]
+    fn oldest_token_falls_out_of_window() {
+        let protect = HmacCsrfProtection::from_key(KEY_32);
+        let window = TokenWindow::new(3);
+        let (tokens, cookie) = issue(&protect, &window, 4);
+        assert!(!window.verify(&protect, &tokens[0], &cookie));
+        for token in tokens[1..].iter() {
+            assert!(window.verify(&protect, token, &cookie));
+        }
+    }
+
+    #[test]
+    fn token_from_other_window_fails() {
+        let protect = AesGcmCsrfProtection::from_key(KEY_32);
+        let window = TokenWindow::new(3);
+        let (tokens, _) = issue(&protect, &window, 1);
+        let (_, other_cookie) = issue(&protect, &window, 1);
+        assert!(!window.verify(&protect, &tokens[0], &other_cookie));
+    }
+
+    #[test]
+    fn future_token_fails() {
+        let protect = AesGcmCsrfProtection::from_key(KEY_32);
+        let window = TokenWindow::new(3);
+        let (_, first_cookie) = issue(&protect, &window, 1);
+        let pair = window.generate_token_pair(&protect, Some(&first_cookie), 300)
+            .expect("couldn't generate token/cookie pair");
+        let (next_token, _) = parse(&protect, pair);
+        assert!(!window.verify(&protect, &next_token, &first_cookie));
+    }
+
+    #[test]
+    fn expired_cookie_fails() {
+        let protect = AesGcmCsrfProtection::from_key(KEY_32);
+        let window = TokenWindow::new(3);
+        let pair = window.generate_token_pair(&protect, None, -1)
+            .expect("couldn't generate token/cookie pair");
+        let (token, cookie) = parse(&protect, pair);
+        assert!(!window.verify(&protect, &token, &cookie));
+    }
+
+    #[test]
+    fn expiry_follows_protection_clock() {
+        let protect = HmacCsrfProtection::from_key(KEY_32).with_clock(MockClock::new(Some(1_000_000)));
+        let window = TokenWindow::new(3);
+        let pair = window.generate_token_pair(&protect, None, 300)
+            .expect("couldn't generate token/cookie pair");
+        let (token, cookie) = parse(&protect, pair);
+        assert!(window.verify(&protect, &token, &cookie));
+        assert!(window.verify_as_of(&token, &cookie, 1_000_299));
+        assert!(!window.verify_as_of(&token, &cookie, 1_000_300));
+
+        let failing = HmacCsrfProtection::from_key(KEY_32)
+            .with_config(CsrfConfig::new().with_fail_closed_clock(true))
+            .with_clock(MockClock::new(None));
+        assert!(!window.verify(&failing, &token, &cookie));
+    }
+}
@@ -0,0 +1,281 @@
+//! Module containing the specification of the token and cookie wire formats
+//!
+//! This is the contract that a compatible implementation in another language must follow to issue
+//! tokens and cookies that this crate verifies, or to verify those that it issues. The golden
+//! vectors in `fixtures/wire_v1.json` are generated from fixed inputs by the reference
+//! implementation in `fixtures/wire_v1.py`, and this crate's tests check that it accepts all of
+//! them.
+//!
+//! ## Conventions
+//!
+//! * `key` is the 32 byte key given to `from_key`.
+//! * `value` is the 64 byte token value shared by a token and its cookie.
+//! * `issued_at` and `expires` are timestamps in whole seconds since the Unix epoch, encoded as
+//!   little endian `i64` on every target. They do not wrap in 2038, even on 32 bit targets, and
+//!   an implementation in another language must not narrow them to 32 bits either.
+//! * `binding` is the binding data given to `generate_bound_token`, and is empty for tokens from
+//!   `generate_token`. It is authenticated but never transmitted.
+//! * `padding` is `AEAD_PADDING_LEN` = 16 random bytes, unless another length is configured with
+//!   `CsrfConfig::with_aead_padding_len`.
+//! * `‖` is concatenation, and lengths are in bytes.
+//!
+//! Tokens and cookies are transmitted as the standard, padded base64 encoding of these bytes. A
+//! token may also be sent in the URL safe base64 alphabet.
+//!
+//! ## Version 1
+//!
+//! Every token and cookie starts with the version byte `WIRE_VERSION`, which is `0x01`.
+//!
+//! ### HMAC
+//!
+//! `tag` is HMAC-SHA256 under `key` over everything before the tag, followed by `binding` for a
+//! token.
+//!
+//! | | Layout | Length |
+//! |---|---|---|
+//! | token | `0x01 ‖ value ‖ issued_at ‖ tag` | `HMAC_TOKEN_LEN` = 105 |
+//! | cookie | `0x01 ‖ value ‖ issued_at ‖ expires ‖ tag` | `HMAC_COOKIE_LEN` = 113 |
+//!
+//! A `MacCsrfProtection` over another `Mac` uses the same layout with that MAC's 32 byte tag.
+//!
+//! ### AEAD
+//!
+//! Both AEAD backends transmit `0x01 ‖ ciphertext ‖ nonce ‖ tag`. The associated data is
+//! `0x01 ‖ binding`, and the plaintext is:
+//!
+//! | | Plaintext | Length |
+//! |---|---|---|
+//! | token | `padding ‖ issued_at ‖ value` | 88 with the default padding |
+//! | cookie | `padding ‖ issued_at ‖ expires ‖ value` | 96 with the default padding |
+//!
+//! AES-GCM uses AES-256 in GCM mode with a random 12 byte nonce and a 16 byte tag, so a token is
+//! `AES_GCM_TOKEN_LEN` = 117 bytes and a cookie is `AES_GCM_COOKIE_LEN` = 125 bytes.
+//!
+//! ChaCha20Poly1305 uses the original construction with a random 8 byte nonce and a 64 bit block
+//! counter, not the IETF variant of RFC 7539. The Poly1305 key is the first 32 bytes of the
+//! keystream block with counter 0, the plaintext is encrypted starting at counter 1, and the 16
+//! byte tag is Poly1305 over `aad ‖ len(aad) ‖ ciphertext ‖ len(ciphertext)`, with both lengths
+//! encoded as little endian `u64`. A token is `CHACHA20_TOKEN_LEN` = 113 bytes and a cookie is
+//! `CHACHA20_COOKIE_LEN` = 121 bytes.
+//!
+//! An `AeadCsrfProtection` over another `Aead` uses the same layout with that cipher's nonce and a
+//! 16 byte tag.
+//!
+//! ### Compact
+//!
+//! With `CsrfConfig::with_compact`, the AEAD backends instead transmit
+//! `0x81 ‖ ciphertext ‖ nonce ‖ tag`, where `0x81` is `COMPACT_WIRE_VERSION`, the associated data
+//! is `0x81 ‖ binding`, and the plaintext has no padding. Compact AES-GCM tokens and cookies are
+//! 101 and 109 bytes, and compact ChaCha20Poly1305 ones are 97 and 105 bytes. An AEAD backend
+//! accepts both formats regardless of its configuration. The HMAC backend has no compact format.
+//!
+//! With the default padding, the three backends' tokens all have different lengths, as do their
+//! cookies. A backend that is given a version 1 token or cookie with another backend's length
+//! rejects it with `CsrfError::AlgorithmMismatch`.
+//!
+//! ## Key id prefix
+//!
+//! A `FallbackCsrfProtection` with `with_key_ids` prefixes each token and cookie with the 8 byte
+//! `key_id` of the key that issued it, which is the first 8 bytes of HMAC-SHA256 under `key` over
+//! `"rust-csrf-key-id"`. The rest is a token or cookie in one of the formats above. The prefix is
+//! not authenticated, since a wrong one only selects a key that fails to verify.
+//!
+//! ## Split keys
+//!
+//! With `with_split_keys(true)`, tokens are authenticated or encrypted under `token_key` instead
+//! of `key`, and cookies under `cookie_key`, which are HKDF-Expand-SHA256 with `key` as the
+//! pseudorandom key, 32 bytes long, with the info `"rust-csrf-token-key"` and
+//! `"rust-csrf-cookie-key"`. The layouts are unchanged, and so is `key_id`, which is still derived
+//! from `key`.
+//!
+//! ## Stateless
+//!
+//! A token of `StatelessTokenProtection` is `STATELESS_WIRE_VERSION`, then `issued_at` and
+//! `expires` as 64 bit little endian Unix times, then a token of the backend in its own format,
+//! bound to `"rust-csrf-stateless:"` followed by those first 17 bytes. The binding covers the
+//! version and both times with the backend's HMAC or AEAD tag, and keeps the backend token from
+//! being accepted anywhere else:
+//!
+//! ```text
+//! version (1) || issued_at (8) || expires (8) || token
+//! ```
+//!
+//! ## Unversioned
+//!
+//! The original format has no version byte and no `issued_at`, and the AEAD backends use no
+//! associated data other than `binding`. It is still accepted but never generated, and is told
+//! apart from version 1 by its length.
+
+/// The version byte that prefixes every token and cookie in the current wire format.
+///
+/// Version history:
+///
+/// * unversioned: the original format, with no version byte and no issued at timestamp. These
+///   tokens and cookies are still accepted, and are recognized by their length.
+/// * `1`: adds the leading version byte and an issued at timestamp to tokens and cookies, and
+///   authenticates the version byte along with the rest of the payload.
+pub const WIRE_VERSION: u8 = 1;
+
+/// The version byte that prefixes AEAD tokens and cookies in the compact variant of version 1,
+/// which has no padding. It is `WIRE_VERSION` with the high bit set.
+pub const COMPACT_WIRE_VERSION: u8 = 0x80 | WIRE_VERSION;
+
+/// The version byte that prefixes the tokens of `StatelessTokenProtection`, which carry their own
+/// expiry and have no cookie. It is `WIRE_VERSION` with the second highest bit set.
+pub const STATELESS_WIRE_VERSION: u8 = 0x40 | WIRE_VERSION;
+
+/// The length of the version byte and the issue and expiry times that `StatelessTokenProtection`
+/// prefixes to the backend's token.
+pub const STATELESS_HEADER_LEN: usize = 17;
+
+/// The default length of the random padding at the start of the AEAD plaintext. The AEAD lengths
+/// below assume this padding, and change byte for byte with `CsrfConfig::with_aead_padding_len`.
+pub const AEAD_PADDING_LEN: usize = 16;
+
+/// The longest AEAD padding that `CsrfConfig::with_aead_padding_len` accepts. This keeps every
+/// token and cookie within `MAX_ENCODED_LEN` once encoded.
+pub const MAX_AEAD_PADDING_LEN: usize = 64;
+
+/// The length of a version 1 token from `HmacCsrfProtection`.
+pub const HMAC_TOKEN_LEN: usize = 105;
+
+/// The length of a version 1 cookie from `HmacCsrfProtection`.
+pub const HMAC_COOKIE_LEN: usize = 113;
+
+/// The length of a version 1 token from `AesGcmCsrfProtection`.
+pub const AES_GCM_TOKEN_LEN: usize = 117;
+
+/// The length of a version 1 cookie from `AesGcmCsrfProtection`.
+pub const AES_GCM_COOKIE_LEN: usize = 125;
+
+/// The length of a version 1 token from `ChaCha20Poly1305CsrfProtection`.
+pub const CHACHA20_TOKEN_LEN: usize = 113;
+
+/// The length of a version 1 cookie from `ChaCha20Poly1305CsrfProtection`.
+pub const CHACHA20_COOKIE_LEN: usize = 121;
+
+/// Return `true` if tokens and cookies with the given version byte can be parsed by this version
+/// of the crate. Middleware can use this to reject input from a newer, unknown format early.
+///
+/// Every version byte that the crate generates is compatible: `WIRE_VERSION`,
+/// `COMPACT_WIRE_VERSION` and `STATELESS_WIRE_VERSION`.
+///
+/// ```
+/// use csrf::{is_compatible, COMPACT_WIRE_VERSION, STATELESS_WIRE_VERSION, WIRE_VERSION};
+///
+/// assert!(is_compatible(WIRE_VERSION));
+/// assert!(is_compatible(COMPACT_WIRE_VERSION));
+/// assert!(is_compatible(STATELESS_WIRE_VERSION));
+/// assert!(!is_compatible(WIRE_VERSION + 1));
+/// ```
+pub fn is_compatible(version: u8) -> bool {
+    matches!(version, WIRE_VERSION | COMPACT_WIRE_VERSION | STATELESS_WIRE_VERSION)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wire::{is_compatible, COMPACT_WIRE_VERSION, STATELESS_WIRE_VERSION, WIRE_VERSION};
+
+    #[test]
+    fn current_version() {
+        assert_eq!(WIRE_VERSION, 1);
+        assert!(is_compatible(WIRE_VERSION));
+    }
+
+    #[test]
+    fn generated_variants_compatible() {
+        assert!(is_compatible(COMPACT_WIRE_VERSION));
+        assert!(is_compatible(STATELESS_WIRE_VERSION));
+    }
+
+    #[test]
+    fn unknown_versions_incompatible() {
+        assert!(!is_compatible(0));
+        assert!(!is_compatible(WIRE_VERSION + 1));
+        assert!(!is_compatible(0x80));
+        assert!(!is_compatible(0x40 | COMPACT_WIRE_VERSION));
+        assert!(!is_compatible(u8::MAX));
+    }
+
+    #[cfg(all(feature = "hmac-backend", feature = "aes-gcm-backend", feature = "chacha20-backend"))]
+    mod fixtures {
+        use data_encoding::HEXLOWER;
+        use serde_json::{self, Value};
+
+        use core::{AesGcmCsrfProtection, ChaCha20Poly1305CsrfProtection, CsrfProtection,
+                   HmacCsrfProtection};
+        use wire::{AES_GCM_COOKIE_LEN, AES_GCM_TOKEN_LEN, CHACHA20_COOKIE_LEN, CHACHA20_TOKEN_LEN,
+                   HMAC_COOKIE_LEN, HMAC_TOKEN_LEN};
+
+        fn vectors() -> Value {
+            serde_json::from_str(include_str!("../fixtures/wire_v1.json")).expect("fixtures not JSON")
+        }
+
+        fn hex(value: &Value) -> Vec<u8> {
+            HEXLOWER.decode(value.as_str().expect("not a string").as_bytes()).expect("not hex")
+        }
+
+        fn key(vectors: &Value) -> [u8; 32] {
+            let mut key = [0; 32];
+            key.copy_from_slice(&hex(&vectors["key"]));
+            key
+        }
+
+        fn check<P: CsrfProtection>(protect: P, name: &str, token_len: usize, cookie_len: usize) {
+            let vectors = vectors();
+            let backend = &vectors[name];
+            let (token, bound_token, cookie) =
+                (hex(&backend["token"]), hex(&backend["bound_token"]), hex(&backend["cookie"]));
+
+            assert_eq!(token.len(), token_len);
+            assert_eq!(bound_token.len(), token_len);
+            assert_eq!(cookie.len(), cookie_len);
+
+            let parsed_token = protect.parse_token(&token).expect("token not parsed");
+            let parsed_cookie = protect.parse_cookie(&cookie).expect("cookie not parsed");
+            assert_eq!(parsed_token.value(), &hex(&vectors["value"])[..]);
+            assert_eq!(parsed_token.issued_at(), vectors["issued_at"].as_i64());
+            assert_eq!(parsed_cookie.issued_at(), vectors["issued_at"].as_i64());
+            assert_eq!(Some(parsed_cookie.expires()), vectors["expires"].as_i64());
+            assert!(protect.verify_token_pair(&parsed_token, &parsed_cookie));
+
+            let binding = hex(&vectors["binding"]);
+            let parsed_bound = protect.parse_bound_token(&bound_token, &binding).expect("bound token not parsed");
+            assert_eq!(parsed_bound.value(), parsed_token.value());
+            assert!(protect.parse_token(&bound_token).is_err());
+        }
+
+        #[test]
+        fn hmac() {
+            let protect = HmacCsrfProtection::from_key(key(&vectors()));
+            check(protect, "hmac", HMAC_TOKEN_LEN, HMAC_COOKIE_LEN);
+        }
+
+        #[test]
+        fn hmac_generates_fixture() {
+            let vectors = vectors();
+            let protect = HmacCsrfProtection::from_key(key(&vectors));
+            let mut value = [0; 64];
+            value.copy_from_slice(&hex(&vectors["value"]));
+            let issued_at = vectors["issued_at"].as_i64().expect("issued_at not an integer");
+            let expires = vectors["expires"].as_i64().expect("expires not an integer");
+
+            let cookie = protect.generate_cookie_at(&value, expires - issued_at, issued_at)
+                .expect("couldn't generate cookie");
+            assert_eq!(cookie.value(), &hex(&vectors["hmac"]["cookie"])[..]);
+        }
+
+        #[test]
+        fn aes_gcm() {
+            let protect = AesGcmCsrfProtection::from_key(key(&vectors()));
+            check(protect, "aes-gcm", AES_GCM_TOKEN_LEN, AES_GCM_COOKIE_LEN);
+        }
+
+        #[test]
+        fn chacha20() {
+            let protect = ChaCha20Poly1305CsrfProtection::from_key(key(&vectors()));
+            check(protect, "chacha20", CHACHA20_TOKEN_LEN, CHACHA20_COOKIE_LEN);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! End to end test of the cookie and token plumbing over real HTTP connections
+//!
+//! A minimal HTTP/1.1 server built on `std::net` renders a form with the crate's helpers and
+//! verifies submissions with them, and a client with a manual cookie jar plays the browser. This
+//! catches mismatches in names and encodings between the code that issues tokens and the code
+//! that extracts them, which the unit tests of each side can not.
+
+#![cfg(feature = "aes-gcm-backend")]
+
+extern crate csrf;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use csrf::{extract_cookie_from_header, extract_token_from_form, AesGcmCsrfProtection, CookieBuilder,
+           CsrfNames, CsrfProtection, SameSite, TokenPrecedence, CSRF_HEADER};
+
+const KEY_32: [u8; 32] = *b"01234567012345670123456701234567";
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    set_cookie: Option<String>,
+    body: String,
+}
+
+fn read_request(stream: &mut TcpStream) -> Request {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("request line not read");
+    let mut parts = line.split_whitespace();
+    let method = parts.next().expect("no method").to_string();
+    let path = parts.next().expect("no path").to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("header not read");
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').expect("header has no colon");
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let len = headers.get("content-length").map_or(0, |len| len.parse().expect("bad content length"));
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).expect("body not read");
+    Request { method, path, headers, body }
+}
+
+fn write_response(stream: &mut TcpStream, status: u16, set_cookie: Option<&str>, body: &str) {
+    let mut response = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                               status,
+                               if status == 200 { "OK" } else { "Forbidden" },
+                               body.len());
+    if let Some(cookie) = set_cookie {
+        response.push_str(&format!("Set-Cookie: {}\r\n", cookie));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    stream.write_all(response.as_bytes()).expect("response not written");
+}
+
+/// Serve `GET /form` and `POST /submit` on a background thread, like a web framework with CSRF
+/// middleware would, with non-default names so that the test notices if either side ignores them.
+fn serve() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+    let addr = listener.local_addr().expect("no local address");
+    let protect = AesGcmCsrfProtection::from_key(KEY_32);
+    let names = CsrfNames::new().with_form_field("_csrf").with_cookie("csrf-session");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.expect("connection failed");
+            let request = read_request(&mut stream);
+            match (&request.method[..], &request.path[..]) {
+                ("GET", "/form") => {
+                    let pair = protect.generate_token_pair(None, 300).expect("couldn't generate token/cookie pair");
+                    let (html, cookie) = names.render_html_and_cookie(&pair, |cookie: CookieBuilder| {
+                        cookie.path("/").same_site(SameSite::Strict).max_age(300)
+                    }).expect("conflicting cookie attributes");
+                    write_response(&mut stream, 200, Some(&cookie), &format!("<form>{}</form>", html));
+                },
+                ("POST", "/submit") => {
+                    let cookie = request.headers.get("cookie")
+                        .and_then(|header| extract_cookie_from_header(header, names.cookie()).ok())
+                        .unwrap_or("");
+                    let form = extract_token_from_form(&request.body, names.form_field())
+                        .map(|token| String::from_utf8(token).expect("token not UTF-8"));
+                    let header = request.headers.get(&CSRF_HEADER.to_ascii_lowercase()).map(String::as_str);
+                    match protect.verify_header_or_form(header, form.as_deref(), cookie, TokenPrecedence::Header) {
+                        Ok(()) => write_response(&mut stream, 200, None, "ok"),
+                        Err(_) => write_response(&mut stream, 403, None, "forbidden"),
+                    }
+                },
+                _ => write_response(&mut stream, 403, None, "not found"),
+            }
+        }
+    });
+
+    addr
+}
+
+/// A client that keeps the cookies it is sent, like a browser.
+struct Browser {
+    addr: SocketAddr,
+    cookies: HashMap<String, String>,
+}
+
+impl Browser {
+    fn new(addr: SocketAddr) -> Self {
+        Browser { addr, cookies: HashMap::new() }
+    }
+
+    fn request(&mut self, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> Response {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+                                  method, path, self.addr, body.len());
+        if !self.cookies.is_empty() {
+            let cookies: Vec<_> = self.cookies.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+            request.push_str(&format!("Cookie: {}\r\n", cookies.join("; ")));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+
+        let mut stream = TcpStream::connect(self.addr).expect("couldn't connect");
+        stream.write_all(request.as_bytes()).expect("request not written");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("response not read");
+
+        let (head, body) = response.split_once("\r\n\r\n").expect("response has no body");
+        let mut lines = head.lines();
+        let status = lines.next().and_then(|line| line.split_whitespace().nth(1)).expect("no status");
+        let set_cookie = lines.filter_map(|line| line.split_once(": "))
+            .find(|&(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+            .map(|(_, value)| value.to_string());
+
+        if let Some(set_cookie) = &set_cookie {
+            let pair = set_cookie.split(';').next().expect("empty Set-Cookie");
+            let (name, value) = pair.split_once('=').expect("Set-Cookie has no value");
+            self.cookies.insert(name.to_string(), value.to_string());
+        }
+
+        Response {
+            status: status.parse().expect("status not a number"),
+            set_cookie,
+            body: body.to_string(),
+        }
+    }
+
+    /// Fetch the form and return the token from its hidden input.
+    fn get_token(&mut self) -> String {
+        let response = self.request("GET", "/form", &[], "");
+        assert_eq!(response.status, 200);
+        let set_cookie = response.set_cookie.expect("no cookie set");
+        assert!(set_cookie.starts_with("csrf-session="), "{}", set_cookie);
+        assert!(set_cookie.contains("; HttpOnly; SameSite=Strict"), "{}", set_cookie);
+
+        let start = response.body.find(r#"name="_csrf" value=""#).expect("no token field") + 20;
+        let len = response.body[start..].find('"').expect("unterminated token field");
+        response.body[start..start + len].to_string()
+    }
+
+    fn submit_form(&mut self, body: &str) -> u16 {
+        self.request("POST", "/submit", &[("Content-Type", "application/x-www-form-urlencoded")], body).status
+    }
+}
+
+/// Encode a form field value as a browser does, so that the `+`, `/` and `=` in base64 survive.
+fn form_urlencode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => (byte as char).to_string(),
+        b' ' => "+".to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+#[test]
+fn form_round_trip() {
+    let mut browser = Browser::new(serve());
+    let token = browser.get_token();
+    assert_eq!(browser.submit_form(&format!("name=value&_csrf={}", form_urlencode(&token))), 200);
+}
+
+#[test]
+fn header_round_trip() {
+    let mut browser = Browser::new(serve());
+    let token = browser.get_token();
+    assert_eq!(browser.request("POST", "/submit", &[(CSRF_HEADER, &token)], "").status, 200);
+}
+
+#[test]
+fn missing_token_forbidden() {
+    let mut browser = Browser::new(serve());
+    browser.get_token();
+    assert_eq!(browser.submit_form("name=value"), 403);
+}
+
+#[test]
+fn tampered_token_forbidden() {
+    let mut browser = Browser::new(serve());
+    let token = browser.get_token();
+    let mid = token.len() / 2;
+    let tampered = format!("{}{}{}", &token[..mid], if &token[mid..mid + 1] == "A" { "B" } else { "A" }, &token[mid + 1..]);
+    assert_eq!(browser.submit_form(&format!("_csrf={}", form_urlencode(&tampered))), 403);
+}
+
+#[test]
+fn missing_cookie_forbidden() {
+    let mut browser = Browser::new(serve());
+    let token = browser.get_token();
+    browser.cookies.clear();
+    assert_eq!(browser.submit_form(&format!("_csrf={}", form_urlencode(&token))), 403);
+}
+
+#[test]
+fn token_from_other_session_forbidden() {
+    let addr = serve();
+    let mut victim = Browser::new(addr);
+    let mut attacker = Browser::new(addr);
+    victim.get_token();
+    let token = attacker.get_token();
+    assert_eq!(victim.submit_form(&format!("_csrf={}", form_urlencode(&token))), 403);
+}